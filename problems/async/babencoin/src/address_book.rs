@@ -0,0 +1,254 @@
+use crate::data::PeerAddress;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const ADDRESS_BOOK_FILE_NAME: &str = "peers.json";
+
+/// Addresses not seen for this many days are forgotten.
+pub const MAX_ADDRESS_AGE_DAYS: i64 = 7;
+/// The least recently seen addresses are forgotten above this number.
+pub const MAX_ADDRESSES: usize = 10000;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Serialize, Deserialize)]
+struct AddressBookFile {
+    addresses: Vec<PeerAddress>,
+}
+
+struct Entry {
+    last_seen: DateTime<Utc>,
+    last_dialed: Option<Instant>,
+}
+
+/// Known addresses of other nodes, learned from `addr` messages and successful
+/// connections, along with the time they were last seen.
+#[derive(Default)]
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, Entry>,
+    /// File to persist the addresses in. They are kept in memory only if not set.
+    path: Option<PathBuf>,
+    is_dirty: bool,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the addresses saved in `data_dir` and save them there later. A damaged
+    /// file is ignored, since the addresses can be learned again.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(data_dir)
+            .with_context(|| format!("failed to create {}", data_dir.display()))?;
+        let path = data_dir.join(ADDRESS_BOOK_FILE_NAME);
+        let mut book = Self {
+            path: Some(path.clone()),
+            ..Self::default()
+        };
+
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(book),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        match serde_json::from_slice::<AddressBookFile>(&data) {
+            Ok(file) => book.add(file.addresses, Utc::now()),
+            Err(err) => warn!("ignoring address book {}: {}", path.display(), err),
+        }
+        book.is_dirty = false;
+        info!("loaded {} peer addresses", book.len());
+        Ok(book)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add addresses received from a peer. Times in the future are taken for `now`.
+    pub fn add(&mut self, addresses: impl IntoIterator<Item = PeerAddress>, now: DateTime<Utc>) {
+        let oldest = now - chrono::Duration::days(MAX_ADDRESS_AGE_DAYS);
+        for PeerAddress { address, last_seen } in addresses {
+            let last_seen = last_seen.min(now);
+            if last_seen < oldest {
+                continue;
+            }
+            let entry = self.entries.entry(address).or_insert(Entry {
+                last_seen,
+                last_dialed: None,
+            });
+            entry.last_seen = entry.last_seen.max(last_seen);
+            self.is_dirty = true;
+        }
+        self.forget_old(now);
+    }
+
+    /// Note a successful connection to the address.
+    pub fn mark_seen(&mut self, address: SocketAddr, now: DateTime<Utc>) {
+        self.add(
+            [PeerAddress {
+                address,
+                last_seen: now,
+            }],
+            now,
+        );
+    }
+
+    /// Note a connection attempt, so that the address is not dialed again too soon.
+    pub fn mark_dialed(&mut self, address: SocketAddr, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&address) {
+            entry.last_dialed = Some(now);
+        }
+    }
+
+    /// Addresses which have not been dialed for `dial_cooldown`, the most recently
+    /// seen first.
+    pub fn dial_candidates(&self, dial_cooldown: Duration, now: Instant) -> Vec<SocketAddr> {
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| match entry.last_dialed {
+                Some(last_dialed) => now.saturating_duration_since(last_dialed) >= dial_cooldown,
+                None => true,
+            })
+            .collect();
+        candidates.sort_by_key(|(_, entry)| Reverse(entry.last_seen));
+        candidates
+            .into_iter()
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    /// Up to `limit` of the most recently seen addresses, to respond to `getaddr` with.
+    pub fn recent(&self, limit: usize) -> Vec<PeerAddress> {
+        let mut addresses: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(address, entry)| PeerAddress {
+                address: *address,
+                last_seen: entry.last_seen,
+            })
+            .collect();
+        addresses.sort_by_key(|address| Reverse(address.last_seen));
+        addresses.truncate(limit);
+        addresses
+    }
+
+    /// Write the addresses to the file if they have changed since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) if self.is_dirty => path,
+            _ => return Ok(()),
+        };
+        let data = serde_json::to_vec(&AddressBookFile {
+            addresses: self.recent(MAX_ADDRESSES),
+        })?;
+
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        file.write_all(&data)?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to replace {}", path.display()))?;
+        self.is_dirty = false;
+        Ok(())
+    }
+
+    fn forget_old(&mut self, now: DateTime<Utc>) {
+        let oldest = now - chrono::Duration::days(MAX_ADDRESS_AGE_DAYS);
+        self.entries.retain(|_, entry| entry.last_seen >= oldest);
+        if self.entries.len() > MAX_ADDRESSES {
+            let keep: HashSet<_> = self
+                .recent(MAX_ADDRESSES)
+                .into_iter()
+                .map(|peer_address| peer_address.address)
+                .collect();
+            self.entries.retain(|address, _| keep.contains(address));
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_address(port: u16, last_seen: DateTime<Utc>) -> PeerAddress {
+        PeerAddress {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn test_dial_candidates() {
+        let now = Utc::now();
+        let mut book = AddressBook::new();
+        book.add(
+            vec![
+                peer_address(1, now - chrono::Duration::hours(2)),
+                peer_address(2, now + chrono::Duration::hours(1)),
+                peer_address(3, now - chrono::Duration::days(MAX_ADDRESS_AGE_DAYS + 1)),
+            ],
+            now,
+        );
+        book.mark_seen(
+            peer_address(4, now).address,
+            now - chrono::Duration::hours(1),
+        );
+        assert_eq!(book.len(), 3);
+
+        let ports = |addresses: Vec<SocketAddr>| -> Vec<_> {
+            addresses.iter().map(|address| address.port()).collect()
+        };
+        let cooldown = Duration::from_secs(3);
+        let instant = Instant::now();
+        assert_eq!(ports(book.dial_candidates(cooldown, instant)), [2, 4, 1]);
+        book.mark_dialed(peer_address(2, now).address, instant);
+        assert_eq!(ports(book.dial_candidates(cooldown, instant)), [4, 1]);
+        assert_eq!(
+            ports(book.dial_candidates(cooldown, instant + cooldown)),
+            [2, 4, 1]
+        );
+        assert_eq!(book.recent(1)[0].last_seen, now);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let mut book = AddressBook::open(dir.path()).unwrap();
+        assert!(book.is_empty());
+        book.add(vec![peer_address(1, now), peer_address(2, now)], now);
+        book.save().unwrap();
+
+        let book = AddressBook::open(dir.path()).unwrap();
+        assert_eq!(book.len(), 2);
+
+        fs::write(dir.path().join(ADDRESS_BOOK_FILE_NAME), "garbage").unwrap();
+        assert!(AddressBook::open(dir.path()).unwrap().is_empty());
+    }
+}
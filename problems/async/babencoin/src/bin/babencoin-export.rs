@@ -0,0 +1,94 @@
+#![forbid(unsafe_code)]
+
+use babencoin::{
+    block_forest::BlockForest,
+    export::{write_records, ChainExport, Format},
+    util::{encode_wallet_id, parse_pkcs8_public},
+};
+
+use anyhow::{ensure, Context, Result};
+use structopt::StructOpt;
+
+use std::{
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Exports the chain stored by a babencoin node")]
+struct Opts {
+    /// Data directory of the node, as `data_dir` in its config. The node should
+    /// be stopped, since a block being written may be cut off as damaged
+    #[structopt(short, long, parse(from_os_str))]
+    data_dir: PathBuf,
+    /// Output format: csv or jsonl
+    #[structopt(short, long, default_value = "csv")]
+    format: Format,
+    #[structopt(subcommand)]
+    table: Table,
+}
+
+#[derive(StructOpt, Debug)]
+enum Table {
+    /// Print the blocks from the genesis block to the head
+    Blocks,
+    /// Print the transactions in the chain order
+    Transactions,
+    /// Print the balance of every wallet after each block which changed it
+    Balances {
+        /// Print the history of this wallet only
+        #[structopt(short, long)]
+        wallet: Option<String>,
+    },
+    /// Print the wallets by their balance at the head, the richest first
+    RichList {
+        #[structopt(short, long)]
+        limit: Option<usize>,
+    },
+}
+
+fn do_main() -> Result<()> {
+    let opts = Opts::from_args();
+    ensure!(
+        opts.data_dir.is_dir(),
+        "{:?} is not a directory",
+        opts.data_dir
+    );
+    let forest = BlockForest::open(&opts.data_dir).context("failed to open block store")?;
+    let export = ChainExport::new(&forest)?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    match opts.table {
+        Table::Blocks => write_records(&mut out, opts.format, export.blocks())?,
+        Table::Transactions => write_records(&mut out, opts.format, export.transactions())?,
+        Table::Balances { wallet: None } => {
+            write_records(&mut out, opts.format, export.balance_history())?
+        }
+        Table::Balances {
+            wallet: Some(wallet),
+        } => {
+            // Wallets are compared in the canonical encoding.
+            let public_key = parse_pkcs8_public(&wallet).context("invalid wallet")?;
+            let wallet = encode_wallet_id(&public_key.into())?;
+            let history = export
+                .balance_history()
+                .iter()
+                .filter(|record| record.wallet == wallet);
+            write_records(&mut out, opts.format, history)?
+        }
+        Table::RichList { limit } => {
+            write_records(&mut out, opts.format, &export.rich_list(limit))?
+        }
+    }
+    out.flush().context("failed to write output")
+}
+
+fn main() {
+    if let Err(err) = do_main() {
+        eprintln!("error: {:#}", err);
+        std::process::exit(1);
+    }
+}
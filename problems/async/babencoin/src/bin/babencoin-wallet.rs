@@ -0,0 +1,163 @@
+#![forbid(unsafe_code)]
+
+use babencoin::{
+    data::{PeerMessage, Transaction, VerifiedTransaction},
+    util::{encode_wallet_id, parse_pkcs8_private, parse_pkcs8_public},
+};
+
+use anyhow::{Context, Result};
+use rand::rngs::OsRng;
+use rsa::{PrivateKeyPemEncoding, RSAPrivateKey};
+use structopt::StructOpt;
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Manages babencoin keys and transactions")]
+enum Opts {
+    /// Generate a private RSA key in PKCS#8 PEM and print its wallet
+    Generate {
+        /// Path to write the key to. It must not exist
+        #[structopt(short, long, parse(from_os_str))]
+        key: PathBuf,
+        #[structopt(long, default_value = "2048")]
+        bits: usize,
+    },
+    /// Print the wallet of a private key, as used in transactions and configs
+    Show {
+        #[structopt(short, long, parse(from_os_str))]
+        key: PathBuf,
+    },
+    /// Sign a transfer and print it as JSON
+    Sign {
+        /// Private key of the sender
+        #[structopt(short, long, parse(from_os_str))]
+        key: PathBuf,
+        /// Wallet of the receiver
+        #[structopt(short, long)]
+        to: String,
+        #[structopt(short, long)]
+        amount: u64,
+        #[structopt(short, long, default_value = "0")]
+        fee: u64,
+        /// Number of transactions sent from the wallet before, starting with 0
+        #[structopt(short, long)]
+        nonce: u64,
+        #[structopt(short, long, default_value = "")]
+        comment: String,
+    },
+    /// Send a signed transaction to a node's peer port
+    Send {
+        /// Address of the node, e.g. localhost:9090
+        #[structopt(short, long)]
+        peer: String,
+        /// File with the transaction as printed by `sign`. Read from stdin if not given
+        #[structopt(parse(from_os_str))]
+        transaction: Option<PathBuf>,
+    },
+}
+
+fn read_private_key(path: &Path) -> Result<RSAPrivateKey> {
+    let raw = fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    parse_pkcs8_private(&raw).with_context(|| format!("failed to parse private key {:?}", path))
+}
+
+fn write_private_key(path: &Path, key: &RSAPrivateKey) -> Result<()> {
+    let pem = key.to_pem_pkcs8().context("failed to encode private key")?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create {:?}", path))?;
+    file.write_all(pem.as_bytes())
+        .with_context(|| format!("failed to write {:?}", path))
+}
+
+fn read_transaction(path: Option<&Path>) -> Result<VerifiedTransaction> {
+    let raw = match path {
+        Some(path) => {
+            fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?
+        }
+        None => {
+            let mut raw = String::new();
+            io::stdin()
+                .read_to_string(&mut raw)
+                .context("failed to read stdin")?;
+            raw
+        }
+    };
+    let tx: Transaction = serde_json::from_str(&raw).context("failed to parse transaction")?;
+    tx.verified().context("transaction is not signed properly")
+}
+
+fn send_transaction(peer: &str, tx: VerifiedTransaction) -> Result<()> {
+    let mut stream =
+        TcpStream::connect(peer).with_context(|| format!("failed to connect to {}", peer))?;
+    let message = PeerMessage::Transaction(Box::new(tx.into()));
+    serde_json::to_writer(&mut stream, &message).context("failed to send transaction")?;
+    stream
+        .write_all(b"\0")
+        .context("failed to send transaction")?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write).ok();
+    Ok(())
+}
+
+fn do_main() -> Result<()> {
+    match Opts::from_args() {
+        Opts::Generate { key, bits } => {
+            let private_key =
+                RSAPrivateKey::new(&mut OsRng, bits).context("failed to generate key")?;
+            write_private_key(&key, &private_key)?;
+            println!("{}", encode_wallet_id(&private_key.to_public_key().into())?);
+        }
+        Opts::Show { key } => {
+            let private_key = read_private_key(&key)?;
+            println!("{}", encode_wallet_id(&private_key.to_public_key().into())?);
+        }
+        Opts::Sign {
+            key,
+            to,
+            amount,
+            fee,
+            nonce,
+            comment,
+        } => {
+            let private_key = read_private_key(&key)?;
+            let receiver = parse_pkcs8_public(&to).context("invalid receiver wallet")?;
+            let tx = VerifiedTransaction::sign(
+                &private_key,
+                receiver.into(),
+                amount,
+                fee,
+                nonce,
+                comment,
+            )
+            .context("failed to sign transaction")?;
+            println!("{}", serde_json::to_string_pretty(&Transaction::from(tx))?);
+        }
+        Opts::Send { peer, transaction } => {
+            let tx = read_transaction(transaction.as_deref())?;
+            let hash = base64::encode(tx.hash());
+            send_transaction(&peer, tx)?;
+            println!("{}", hash);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = do_main() {
+        eprintln!("error: {:#}", err);
+        std::process::exit(1);
+    }
+}
@@ -1,21 +1,41 @@
-use crate::data::{
-    BlockHash, TransactionHash, VerifiedBlock, VerifiedTransaction, WalletId, HASH_LEN,
+use crate::{
+    block_store::{BalanceSnapshot, BlockStore},
+    data::{
+        BlockAttributes, BlockHash, TransactionHash, VerifiedBlock, VerifiedBlockHeader,
+        VerifiedTransaction, WalletId, HASH_LEN, MAX_LOCATOR_LEN,
+    },
+    mempool::{Mempool, MempoolConfig},
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use chrono::Duration;
-use log::debug;
+use log::{debug, error, info, warn};
 use num_bigint::BigUint;
 
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    path::Path,
     sync::Arc,
+    time::Instant,
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub const EPOCH_SIZE: usize = 16;
 pub const TARGET_BLOCK_MINING_TIME_SECONDS: u64 = 10;
+/// Number of stored blocks between balance snapshots.
+pub const SNAPSHOT_INTERVAL: usize = 1024;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// State of a wallet as of some block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WalletState {
+    pub balance: u64,
+    /// Nonce of the next transaction sent from the wallet.
+    pub next_nonce: u64,
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -25,9 +45,13 @@ pub struct BlockForest {
     children_hashes: HashMap<BlockHash, Vec<BlockHash>>,
     bad_block_hashes: HashSet<BlockHash>,
     unknown_block_hashes: HashSet<BlockHash>,
-    balance_snapshots: HashMap<BlockHash, HashMap<WalletId, u64>>,
-    pending_transactions: HashMap<TransactionHash, VerifiedTransaction>,
-    pending_snapshot: HashMap<WalletId, u64>,
+    balance_snapshots: HashMap<BlockHash, HashMap<WalletId, WalletState>>,
+    mempool: Mempool,
+    /// Balances at the head with the transactions from the mempool applied.
+    pending_snapshot: HashMap<WalletId, WalletState>,
+    store: Option<BlockStore>,
+    /// Blocks validated against the balances, in order, which are yet to be stored.
+    unstored_block_hashes: Vec<BlockHash>,
 }
 
 impl Default for BlockForest {
@@ -47,8 +71,10 @@ impl Default for BlockForest {
             bad_block_hashes: HashSet::new(),
             unknown_block_hashes: HashSet::new(),
             balance_snapshots,
-            pending_transactions: HashMap::new(),
+            mempool: Mempool::default(),
             pending_snapshot: HashMap::new(),
+            store: None,
+            unstored_block_hashes: vec![],
         }
     }
 }
@@ -58,6 +84,156 @@ impl BlockForest {
         Self::default()
     }
 
+    /// Restore the forest from the block store in `data_dir` and keep storing new blocks
+    /// there. Only blocks connected to genesis are stored, so blocks with unknown
+    /// ancestors have to be requested again after a restart.
+    ///
+    /// Blocks covered by the balance snapshot are not validated again, and the rest
+    /// are replayed through `add_block`. A damaged log is cut off at the first bad block.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let mut store = BlockStore::open(data_dir)?;
+        let snapshot = match store.load_snapshot() {
+            Ok(Some(snapshot)) if snapshot.position <= store.len() => Some(snapshot),
+            Ok(Some(_)) => {
+                warn!("balance snapshot is ahead of the block log, ignoring it");
+                None
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!("ignoring balance snapshot: {:#}", err);
+                None
+            }
+        };
+
+        let mut forest = match Self::replay(&mut store, snapshot.as_ref()) {
+            Ok(forest) => forest,
+            Err(err) => {
+                warn!(
+                    "failed to restore balance snapshot, validating all the blocks: {:#}",
+                    err
+                );
+                store.remove_snapshot()?;
+                Self::replay(&mut store, None)?
+            }
+        };
+        info!(
+            "restored {} blocks from {}, head is #{}",
+            store.len(),
+            data_dir.display(),
+            forest.head.index
+        );
+        forest.store = Some(store);
+        Ok(forest)
+    }
+
+    fn replay(store: &mut BlockStore, snapshot: Option<&BalanceSnapshot>) -> Result<Self> {
+        let mut forest = Self::new();
+        let trusted_len = snapshot.map_or(0, |snapshot| snapshot.position);
+        for position in 0..store.len() {
+            let block = match store
+                .read_block(position)
+                .and_then(|block| block.verified())
+            {
+                Ok(block) => block,
+                Err(err) => {
+                    warn!(
+                        "dropping block log starting from block #{}: {:#}",
+                        position, err
+                    );
+                    store.truncate(position)?;
+                    ensure!(position >= trusted_len, "snapshot covers dropped blocks");
+                    break;
+                }
+            };
+
+            if position < trusted_len {
+                forest.insert_trusted_block(block)?;
+            } else if let Err(err) = forest.add_block(block) {
+                // Blocks are stored once validated, so this may only happen due to
+                // changes in the validation rules.
+                warn!("stored block #{} is invalid: {:#}", position, err);
+            }
+
+            if let Some(snapshot) = snapshot.filter(|_| position + 1 == trusted_len) {
+                forest.restore_snapshot(snapshot)?;
+            }
+        }
+        Ok(forest)
+    }
+
+    /// Insert a block validated before without validating it again. Its parent must be known.
+    fn insert_trusted_block(&mut self, block: VerifiedBlock) -> Result<()> {
+        ensure!(
+            self.blocks.contains_key(&block.prev_hash),
+            "parent of block {} is unknown",
+            base64::encode(block.hash())
+        );
+        self.children_hashes
+            .entry(block.prev_hash)
+            .or_default()
+            .push(*block.hash());
+        self.blocks.insert(*block.hash(), Arc::new(block));
+        Ok(())
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &BalanceSnapshot) -> Result<()> {
+        let head = self
+            .blocks
+            .get(&snapshot.head_hash)
+            .context("snapshot head is not stored")?
+            .clone();
+        self.balance_snapshots
+            .insert(snapshot.head_hash, snapshot.wallets.clone());
+        self.pending_snapshot = snapshot.wallets.clone();
+        self.head = head;
+        Ok(())
+    }
+
+    /// Store the blocks validated since the last call and save a balance snapshot
+    /// every `SNAPSHOT_INTERVAL` blocks. Failures are only logged, since the block
+    /// has been added anyway, and the blocks that could not be stored are retried
+    /// on the next call.
+    fn store_validated_blocks(&mut self) {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => {
+                self.unstored_block_hashes.clear();
+                return;
+            }
+        };
+
+        let old_len = store.len();
+        let mut stored_count = 0;
+        for hash in &self.unstored_block_hashes {
+            if let Some(block) = self.blocks.get(hash) {
+                if let Err(err) = store.append(block) {
+                    error!("failed to store block {}: {:#}", base64::encode(hash), err);
+                    break;
+                }
+            }
+            stored_count += 1;
+        }
+        // The rest keeps its order, so that parents still precede their children in the log.
+        self.unstored_block_hashes.drain(..stored_count);
+        if !self.unstored_block_hashes.is_empty() {
+            return;
+        }
+
+        if store.len() / SNAPSHOT_INTERVAL > old_len / SNAPSHOT_INTERVAL
+            && store.contains(self.head.hash())
+        {
+            let snapshot = BalanceSnapshot {
+                position: store.len(),
+                head_hash: *self.head.hash(),
+                wallets: self.balance_snapshots[self.head.hash()].clone(),
+            };
+            match store.save_snapshot(&snapshot) {
+                Ok(()) => debug!("saved balance snapshot at block #{}", self.head.index),
+                Err(err) => error!("failed to save balance snapshot: {:#}", err),
+            }
+        }
+    }
+
     pub fn head(&self) -> &Arc<VerifiedBlock> {
         &self.head
     }
@@ -66,14 +242,106 @@ impl BlockForest {
         &self.unknown_block_hashes
     }
 
-    pub fn pending_transactions(&self) -> &HashMap<TransactionHash, VerifiedTransaction> {
-        &self.pending_transactions
+    pub fn pending_transactions(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    /// Change the mempool limits, evicting transactions if they are exceeded.
+    pub fn set_mempool_config(&mut self, config: MempoolConfig) {
+        let transactions = self.mempool.take_all();
+        self.mempool = Mempool::new(config);
+        self.rebuild_mempool(transactions);
+        self.evict_transactions(self.mempool.overflow(), "mempool is full");
+    }
+
+    /// Number of the known blocks without children other than the head, that is, tips
+    /// of the branches which lost to the head or have unknown ancestors.
+    pub fn fork_count(&self) -> usize {
+        self.blocks
+            .keys()
+            .filter(|hash| {
+                *hash != self.head.hash()
+                    && !self.bad_block_hashes.contains(*hash)
+                    && self
+                        .children_hashes
+                        .get(*hash)
+                        .is_none_or(|children| children.is_empty())
+            })
+            .count()
     }
 
     pub fn find_block(&self, hash: &BlockHash) -> Option<&Arc<VerifiedBlock>> {
         self.blocks.get(hash)
     }
 
+    /// Find the block of the given index on the chain ending at the head.
+    pub fn find_block_by_index(&self, index: u64) -> Option<&Arc<VerifiedBlock>> {
+        let mut block = &self.head;
+        while block.index > index {
+            block = self.blocks.get(&block.prev_hash)?;
+        }
+        (block.index == index).then_some(block)
+    }
+
+    /// Blocks of the chain ending at the head, from the head back to the genesis block.
+    pub fn canonical_chain(&self) -> impl Iterator<Item = &Arc<VerifiedBlock>> {
+        std::iter::successors(Some(&self.head), |block| {
+            (block.index > 0).then(|| &self.blocks[&block.prev_hash])
+        })
+    }
+
+    /// Balance of the wallet at the head.
+    pub fn balance(&self, wallet: &WalletId) -> u64 {
+        self.balance_snapshots
+            .get(self.head.hash())
+            .and_then(|snapshot| snapshot.get(wallet))
+            .map_or(0, |state| state.balance)
+    }
+
+    /// Nonce of the next transaction from the wallet, counting the pending ones.
+    pub fn next_nonce(&self, wallet: &WalletId) -> u64 {
+        self.pending_snapshot
+            .get(wallet)
+            .map_or(0, |state| state.next_nonce)
+    }
+
+    /// Hashes of the blocks on the chain ending at the head, densely near the head
+    /// and exponentially sparser towards the genesis block, which is always the last one.
+    pub fn locator(&self) -> Vec<BlockHash> {
+        let mut locator = vec![];
+        let mut block = &self.head;
+        let mut step = 1;
+        while block.index > 0 && locator.len() < MAX_LOCATOR_LEN - 1 {
+            locator.push(*block.hash());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            for _ in 0..step.min(block.index) {
+                block = &self.blocks[&block.prev_hash];
+            }
+        }
+        locator.push(*VerifiedBlock::genesis().hash());
+        locator
+    }
+
+    /// Headers of up to `limit` blocks following the first block of `locator` which is
+    /// on the chain ending at the head, or following the genesis block if there is none.
+    pub fn headers_after(&self, locator: &[BlockHash], limit: usize) -> Vec<VerifiedBlockHeader> {
+        let locator: HashSet<_> = locator.iter().collect();
+        let mut chain = vec![];
+        let mut block = &self.head;
+        while block.index > 0 && !locator.contains(block.hash()) {
+            chain.push(block);
+            block = &self.blocks[&block.prev_hash];
+        }
+        chain
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|block| block.header())
+            .collect()
+    }
+
     pub fn next_max_hash(&self) -> BlockHash {
         let next_index = self.head.index + 1;
         if next_index % EPOCH_SIZE as u64 > 0 {
@@ -85,10 +353,16 @@ impl BlockForest {
         prev_epoch.push(&self.head);
 
         assert_eq!(prev_epoch.len(), EPOCH_SIZE);
-        self.compute_epoch_max_hash(&prev_epoch)
+        Self::compute_epoch_max_hash(&prev_epoch)
     }
 
     pub fn add_block(&mut self, block: VerifiedBlock) -> Result<()> {
+        let result = self.try_add_block(block);
+        self.store_validated_blocks();
+        result
+    }
+
+    fn try_add_block(&mut self, block: VerifiedBlock) -> Result<()> {
         if self.bad_block_hashes.contains(block.hash()) {
             bail!("block {} is known to be bad", base64::encode(block.hash()));
         }
@@ -132,15 +406,124 @@ impl BlockForest {
     }
 
     pub fn add_transaction(&mut self, tx: VerifiedTransaction) -> Result<()> {
-        if self.pending_transactions.contains_key(tx.hash()) {
+        if self.mempool.contains(tx.hash()) {
             return Ok(());
         }
 
         Self::try_apply_tx_to_snapshot(&tx, &mut self.pending_snapshot)?;
-        self.pending_transactions.insert(*tx.hash(), tx);
+        let hash = *tx.hash();
+        self.mempool.insert(tx, Instant::now());
+        self.evict_transactions(self.mempool.overflow(), "mempool is full");
+        ensure!(
+            self.mempool.contains(&hash),
+            "mempool is full and the transaction fee is too low"
+        );
         Ok(())
     }
 
+    /// Remove the transactions which have been waiting for longer than the mempool
+    /// expiry, along with the ones depending on them. Returns the number of
+    /// removed transactions.
+    pub fn remove_expired_transactions(&mut self) -> usize {
+        let count = self.mempool.len();
+        self.evict_transactions(self.mempool.expired(Instant::now()), "expired");
+        count - self.mempool.len()
+    }
+
+    /// Up to `limit` pending transactions to mine, the highest fees first. Every prefix
+    /// of the result is valid on top of the head, so transactions of a sender follow
+    /// in the nonce order even if a later one has a higher fee.
+    pub fn select_transactions(&self, limit: usize) -> Vec<VerifiedTransaction> {
+        // Transactions are identified by their positions in the fee order.
+        let transactions: Vec<_> = self.mempool.iter().collect();
+        let mut by_sender: HashMap<_, HashMap<_, _>> = HashMap::new();
+        for (rank, tx) in transactions.iter().enumerate() {
+            by_sender
+                .entry(&tx.sender)
+                .or_default()
+                .insert(tx.nonce, rank);
+        }
+
+        let mut snapshot = self.balance_snapshots[self.head.hash()].clone();
+        let next_rank = |snapshot: &HashMap<WalletId, WalletState>, sender: &WalletId| {
+            let nonce = snapshot.get(sender).copied().unwrap_or_default().next_nonce;
+            by_sender
+                .get(sender)
+                .and_then(|ranks| ranks.get(&nonce))
+                .map(|rank| Reverse(*rank))
+        };
+        let mut ready: BinaryHeap<_> = by_sender
+            .keys()
+            .filter_map(|sender| next_rank(&snapshot, sender))
+            .collect();
+        // Transactions waiting for funds from the other selected transactions.
+        let mut blocked = HashMap::new();
+
+        let mut selected = vec![];
+        while selected.len() < limit {
+            let Some(Reverse(rank)) = ready.pop() else {
+                break;
+            };
+            let tx = transactions[rank];
+            if Self::try_apply_tx_to_snapshot(tx, &mut snapshot).is_err() {
+                blocked.insert(&tx.sender, Reverse(rank));
+                continue;
+            }
+            selected.push(tx.clone());
+            ready.extend(next_rank(&snapshot, &tx.sender));
+            ready.extend(blocked.remove(&tx.receiver));
+        }
+        selected
+    }
+
+    /// Remove the transactions from the mempool and the ones which can't be applied
+    /// without them.
+    fn evict_transactions(&mut self, hashes: Vec<TransactionHash>, reason: &str) {
+        if hashes.is_empty() {
+            return;
+        }
+        for hash in hashes {
+            self.mempool.remove(&hash);
+            debug!("evicting transaction {}: {}", base64::encode(hash), reason);
+        }
+        let transactions = self.mempool.take_all();
+        self.rebuild_mempool(transactions);
+    }
+
+    /// Fill the empty mempool with `candidates` applied on top of the head, in order.
+    /// Transactions may depend on each other through nonces and balances, so they are
+    /// applied in rounds until none of the rest can be applied.
+    fn rebuild_mempool(&mut self, mut candidates: Vec<(VerifiedTransaction, Instant)>) {
+        let mut snapshot = self.balance_snapshots[self.head.hash()].clone();
+        loop {
+            let candidate_count = candidates.len();
+            candidates.retain(|(tx, added_at)| {
+                if self.mempool.contains(tx.hash()) {
+                    return false;
+                }
+                if Self::try_apply_tx_to_snapshot(tx, &mut snapshot).is_err() {
+                    return true;
+                }
+                self.mempool.insert(tx.clone(), *added_at);
+                false
+            });
+            if candidates.len() == candidate_count {
+                break;
+            }
+        }
+
+        for (tx, _) in candidates {
+            if let Err(err) = Self::try_apply_tx_to_snapshot(&tx, &mut snapshot) {
+                debug!(
+                    "discarding transaction {}: {:#}",
+                    base64::encode(tx.hash()),
+                    err,
+                );
+            }
+        }
+        self.pending_snapshot = snapshot;
+    }
+
     fn mark_bad_block(&mut self, root_hash: &BlockHash) {
         let root_block = &self.blocks[root_hash];
         if root_block.index > 0 {
@@ -154,6 +537,7 @@ impl BlockForest {
         let mut stack = vec![*root_hash];
         while let Some(hash) = stack.pop() {
             self.blocks.remove(&hash);
+            self.balance_snapshots.remove(&hash);
             self.bad_block_hashes.insert(hash);
             if let Some(children_hashes) = self.children_hashes.remove(&hash) {
                 stack.extend(children_hashes);
@@ -259,24 +643,25 @@ impl BlockForest {
                 return None;
             }
             prev_epoch.reverse();
-            Some(self.compute_epoch_max_hash(&prev_epoch))
+            Some(Self::compute_epoch_max_hash(&prev_epoch))
         }
     }
 
-    fn get_ancestors(&self, block: &VerifiedBlock, limit: usize) -> Vec<&VerifiedBlock> {
+    fn get_ancestors(&self, block: &BlockAttributes, limit: usize) -> Vec<&BlockAttributes> {
         let mut ancestors = Vec::with_capacity(limit);
         let mut hash = block.prev_hash;
         while let Some(ancestor) = self.find_block(&hash) {
             if ancestors.len() == limit {
                 break;
             }
-            ancestors.push(ancestor as &VerifiedBlock);
+            ancestors.push(ancestor as &BlockAttributes);
             hash = ancestor.prev_hash;
         }
         ancestors
     }
 
-    fn compute_epoch_max_hash(&self, epoch: &[&VerifiedBlock]) -> BlockHash {
+    /// Max hash of the epoch following `epoch`, which lists the blocks of an epoch in order.
+    pub(crate) fn compute_epoch_max_hash(epoch: &[&BlockAttributes]) -> BlockHash {
         assert_eq!(epoch.len(), EPOCH_SIZE);
         let epoch_id = epoch[0].index / 16;
         assert_eq!(epoch[0].index, epoch_id * EPOCH_SIZE as u64);
@@ -330,6 +715,10 @@ impl BlockForest {
         let genesis_hash = *VerifiedBlock::genesis().hash();
         let mut last_hash = *hash;
         while last_hash != genesis_hash {
+            // Balances are only computed for blocks connected to genesis.
+            if self.balance_snapshots.contains_key(&last_hash) {
+                return true;
+            }
             if let Some(parent) = self.blocks.get(&last_hash) {
                 last_hash = parent.prev_hash;
             } else {
@@ -378,6 +767,7 @@ impl BlockForest {
             }
 
             self.balance_snapshots.insert(*block.hash(), snapshot);
+            self.unstored_block_hashes.push(*block.hash());
 
             if let Some(children_hashes) = self.children_hashes.get(block.hash()) {
                 for child_hash in children_hashes {
@@ -429,34 +819,19 @@ impl BlockForest {
             .map(|tx| *tx.hash())
             .collect();
 
-        let old_branch_txs = self.list_transactions(&self.head, lca);
-
-        let mut new_pending_transactions = HashMap::new();
-        let mut new_snapshot = self.balance_snapshots.get(new_head.hash()).unwrap().clone();
-        for tx in old_branch_txs
-            .iter()
-            .chain(self.pending_transactions.values())
-        {
-            if new_branch_tx_hashes.contains(tx.hash())
-                || new_pending_transactions.contains_key(tx.hash())
-            {
-                continue;
-            }
-
-            if let Err(err) = Self::try_apply_tx_to_snapshot(tx, &mut new_snapshot) {
-                debug!(
-                    "discarding transaction {}: {:#}",
-                    base64::encode(tx.hash()),
-                    err,
-                );
-            } else {
-                new_pending_transactions.insert(*tx.hash(), tx.clone());
-            }
-        }
+        // Transactions of the orphaned blocks go back to the mempool.
+        let now = Instant::now();
+        let candidates: Vec<_> = self
+            .list_transactions(&self.head, lca)
+            .into_iter()
+            .map(|tx| (tx, now))
+            .chain(self.mempool.take_all())
+            .filter(|(tx, _)| !new_branch_tx_hashes.contains(tx.hash()))
+            .collect();
 
         self.head = new_head;
-        self.pending_transactions = new_pending_transactions;
-        self.pending_snapshot = new_snapshot;
+        self.rebuild_mempool(candidates);
+        self.evict_transactions(self.mempool.overflow(), "mempool is full");
     }
 
     fn find_lca<'a>(
@@ -476,9 +851,9 @@ impl BlockForest {
         first
     }
 
-    fn try_apply_issuer_reward_to_snapshot(
+    pub(crate) fn try_apply_issuer_reward_to_snapshot(
         block: &VerifiedBlock,
-        snapshot: &mut HashMap<WalletId, u64>,
+        snapshot: &mut HashMap<WalletId, WalletState>,
     ) -> Result<()> {
         let mut reward = block.reward;
         for tx in block.transactions() {
@@ -487,62 +862,310 @@ impl BlockForest {
                 .context("reward + fees overflows u64")?;
         }
 
-        let old_balance = *snapshot.get(&block.issuer).unwrap_or(&0);
-        let new_balance = old_balance
+        let mut issuer = snapshot.get(&block.issuer).copied().unwrap_or_default();
+        issuer.balance = issuer
+            .balance
             .checked_add(reward)
             .context("issuer balance overflows u64")?;
-
-        if new_balance > 0 {
-            snapshot.insert(block.issuer.clone(), new_balance);
-        } else {
-            snapshot.remove(&block.issuer);
-        }
+        Self::set_wallet_state(snapshot, &block.issuer, issuer);
 
         Ok(())
     }
 
-    fn try_apply_tx_to_snapshot(
+    pub(crate) fn try_apply_tx_to_snapshot(
         tx: &VerifiedTransaction,
-        snapshot: &mut HashMap<WalletId, u64>,
+        snapshot: &mut HashMap<WalletId, WalletState>,
     ) -> Result<()> {
-        let old_sender_balance = *snapshot.get(&tx.sender).unwrap_or(&0);
-        let new_sender_balance = old_sender_balance
+        let mut sender = snapshot.get(&tx.sender).copied().unwrap_or_default();
+        ensure!(
+            tx.nonce >= sender.next_nonce,
+            "nonce {} has been used already",
+            tx.nonce
+        );
+        ensure!(
+            tx.nonce == sender.next_nonce,
+            "nonce gap: expected {}, got {}",
+            sender.next_nonce,
+            tx.nonce
+        );
+        sender.balance = sender
+            .balance
             .checked_sub(tx.amount)
             .and_then(|value| value.checked_sub(tx.fee))
             .context("sender has insufficient funds")?;
+        sender.next_nonce = sender
+            .next_nonce
+            .checked_add(1)
+            .context("sender nonce overflows u64")?;
 
-        let old_receiver_balance = *snapshot.get(&tx.receiver).unwrap_or(&0);
-        let new_receiver_balance = old_receiver_balance
+        let mut receiver = if tx.receiver == tx.sender {
+            sender
+        } else {
+            snapshot.get(&tx.receiver).copied().unwrap_or_default()
+        };
+        receiver.balance = receiver
+            .balance
             .checked_add(tx.amount)
             .context("receiver balance overflows u64")?;
 
-        for (key, value) in [
-            (tx.sender.clone(), new_sender_balance),
-            (tx.receiver.clone(), new_receiver_balance),
-        ]
-        .into_iter()
-        {
-            if value > 0 {
-                snapshot.insert(key, value);
-            } else {
-                snapshot.remove(&key);
-            }
-        }
-
+        Self::set_wallet_state(snapshot, &tx.sender, sender);
+        Self::set_wallet_state(snapshot, &tx.receiver, receiver);
         Ok(())
     }
 
+    fn set_wallet_state(
+        snapshot: &mut HashMap<WalletId, WalletState>,
+        wallet: &WalletId,
+        state: WalletState,
+    ) {
+        if state == WalletState::default() {
+            snapshot.remove(wallet);
+        } else {
+            snapshot.insert(wallet.clone(), state);
+        }
+    }
+
+    /// Transactions of the blocks in the chain order.
     fn list_transactions(
         &self,
         inclusive_from: &Arc<VerifiedBlock>,
         exclusive_to: &Arc<VerifiedBlock>,
     ) -> Vec<VerifiedTransaction> {
-        let mut transactions = vec![];
+        let mut blocks = vec![];
         let mut block = inclusive_from;
         while block.hash() != exclusive_to.hash() {
-            transactions.extend(block.transactions().iter().cloned());
+            blocks.push(block);
             block = &self.blocks[&block.prev_hash];
         }
-        transactions
+        blocks
+            .into_iter()
+            .rev()
+            .flat_map(|block| block.transactions().iter().cloned())
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::MAX_REWARD,
+        test_util::{make_chain, next_block, test_key},
+    };
+
+    use rand::thread_rng;
+    use rsa::RSAPrivateKey;
+
+    use std::{thread, time::Duration};
+
+    fn transfer(key: &RSAPrivateKey, nonce: u64, comment: &str) -> VerifiedTransaction {
+        VerifiedTransaction::sign(key, WalletId::of_genesis(), 10, 1, nonce, comment.into())
+            .unwrap()
+    }
+
+    fn transfer_with_fee(key: &RSAPrivateKey, nonce: u64, fee: u64) -> VerifiedTransaction {
+        VerifiedTransaction::sign(key, WalletId::of_genesis(), 10, fee, nonce, String::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pending_transaction_nonces() {
+        let key = test_key();
+        let mut forest = BlockForest::new();
+        for block in make_chain(&key, 1) {
+            forest.add_block(block).unwrap();
+        }
+        let wallet = key.to_public_key().into();
+        assert_eq!(forest.next_nonce(&wallet), 0);
+
+        assert!(forest.add_transaction(transfer(&key, 1, "gap")).is_err());
+        forest.add_transaction(transfer(&key, 0, "first")).unwrap();
+        forest.add_transaction(transfer(&key, 0, "first")).unwrap();
+        assert!(forest.add_transaction(transfer(&key, 0, "again")).is_err());
+        forest.add_transaction(transfer(&key, 1, "second")).unwrap();
+
+        assert_eq!(forest.pending_transactions().len(), 2);
+        assert_eq!(forest.next_nonce(&wallet), 2);
+        assert_eq!(forest.balance(&wallet), MAX_REWARD);
+    }
+
+    #[test]
+    fn test_block_transaction_nonces() {
+        let key = test_key();
+        let chain = make_chain(&key, 1);
+        let mut forest = BlockForest::new();
+        forest.add_block(chain[0].clone()).unwrap();
+
+        let gap = next_block(&key, &chain[0], vec![transfer(&key, 1, "gap")]);
+        assert!(forest.add_block(gap).is_err());
+        let duplicate = next_block(
+            &key,
+            &chain[0],
+            vec![transfer(&key, 0, "first"), transfer(&key, 0, "again")],
+        );
+        assert!(forest.add_block(duplicate).is_err());
+
+        let block = next_block(
+            &key,
+            &chain[0],
+            vec![transfer(&key, 0, "first"), transfer(&key, 1, "second")],
+        );
+        forest.add_block(block.clone()).unwrap();
+        let replay = next_block(&key, &block, vec![transfer(&key, 1, "second")]);
+        assert!(forest.add_block(replay).is_err());
+
+        assert_eq!(forest.head().hash(), block.hash());
+        assert_eq!(forest.next_nonce(&key.to_public_key().into()), 2);
+    }
+
+    #[test]
+    fn test_switch_head_readmits_transactions() {
+        let key = test_key();
+        let chain = make_chain(&key, 1);
+        let mut forest = BlockForest::new();
+        forest.add_block(chain[0].clone()).unwrap();
+
+        let first = transfer(&key, 0, "first");
+        let old_branch = next_block(&key, &chain[0], vec![first.clone()]);
+        forest.add_block(old_branch).unwrap();
+        let second = transfer(&key, 1, "second");
+        forest.add_transaction(second.clone()).unwrap();
+
+        let new_branch = make_chain(&key, 3).split_off(1);
+        for block in new_branch {
+            forest.add_block(block).unwrap();
+        }
+        assert_eq!(forest.head().index, 3);
+        assert_eq!(forest.fork_count(), 1);
+        assert!(forest.pending_transactions().contains(first.hash()));
+        assert!(forest.pending_transactions().contains(second.hash()));
+        assert_eq!(forest.next_nonce(&key.to_public_key().into()), 2);
+    }
+
+    #[test]
+    fn test_mempool_eviction() {
+        let key = test_key();
+        let other_key = RSAPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let chain = make_chain(&key, 1);
+        let funding = VerifiedTransaction::sign(
+            &key,
+            other_key.to_public_key().into(),
+            100,
+            0,
+            0,
+            String::new(),
+        )
+        .unwrap();
+        let mut forest = BlockForest::new();
+        forest.add_block(chain[0].clone()).unwrap();
+        forest
+            .add_block(next_block(&key, &chain[0], vec![funding]))
+            .unwrap();
+        forest.set_mempool_config(MempoolConfig {
+            max_count: 3,
+            ..MempoolConfig::default()
+        });
+
+        let first = transfer_with_fee(&key, 1, 1);
+        let second = transfer_with_fee(&key, 2, 5);
+        let other_first = transfer_with_fee(&other_key, 0, 3);
+        for tx in [&first, &second, &other_first] {
+            forest.add_transaction(tx.clone()).unwrap();
+        }
+
+        // Only the last transaction of a sender may be evicted.
+        assert!(forest
+            .add_transaction(transfer_with_fee(&other_key, 1, 4))
+            .is_err());
+        let other_second = transfer_with_fee(&other_key, 1, 9);
+        forest.add_transaction(other_second.clone()).unwrap();
+        let mempool = forest.pending_transactions();
+        assert_eq!(mempool.len(), 3);
+        assert!(!mempool.contains(second.hash()));
+        assert_eq!(forest.next_nonce(&key.to_public_key().into()), 2);
+
+        let fees: Vec<_> = mempool.iter().map(|tx| tx.fee).collect();
+        assert_eq!(fees, [9, 3, 1]);
+        let selected: Vec<_> = forest
+            .select_transactions(3)
+            .iter()
+            .map(|tx| *tx.hash())
+            .collect();
+        assert_eq!(
+            selected,
+            [*other_first.hash(), *other_second.hash(), *first.hash()]
+        );
+        assert_eq!(forest.select_transactions(1).len(), 1);
+    }
+
+    #[test]
+    fn test_select_transactions_respects_nonces() {
+        let key = test_key();
+        let chain = make_chain(&key, 1);
+        let mut forest = BlockForest::new();
+        forest.add_block(chain[0].clone()).unwrap();
+
+        let first = transfer_with_fee(&key, 0, 1);
+        let second = transfer_with_fee(&key, 1, 5);
+        forest.add_transaction(first.clone()).unwrap();
+        forest.add_transaction(second.clone()).unwrap();
+
+        let selected = forest.select_transactions(10);
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].hash(), first.hash());
+        assert_eq!(selected[1].hash(), second.hash());
+
+        let block = next_block(&key, &chain[0], forest.select_transactions(1));
+        forest.add_block(block).unwrap();
+        assert_eq!(forest.pending_transactions().len(), 1);
+        assert_eq!(forest.select_transactions(10)[0].hash(), second.hash());
+    }
+
+    #[test]
+    fn test_mempool_expiry() {
+        let key = test_key();
+        let mut forest = BlockForest::new();
+        forest.add_block(make_chain(&key, 1).remove(0)).unwrap();
+        forest.set_mempool_config(MempoolConfig {
+            expiry: Duration::from_millis(100),
+            ..MempoolConfig::default()
+        });
+
+        forest.add_transaction(transfer(&key, 0, "first")).unwrap();
+        thread::sleep(Duration::from_millis(150));
+        forest.add_transaction(transfer(&key, 1, "second")).unwrap();
+        assert_eq!(forest.remove_expired_transactions(), 2);
+        assert!(forest.pending_transactions().is_empty());
+        assert_eq!(forest.next_nonce(&key.to_public_key().into()), 0);
+
+        forest.add_transaction(transfer(&key, 0, "first")).unwrap();
+        assert_eq!(forest.remove_expired_transactions(), 0);
+    }
+
+    #[test]
+    fn test_store_retries_failed_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = make_chain(&test_key(), 4);
+        let mut forest = BlockForest::open(dir.path()).unwrap();
+        forest.add_block(chain[0].clone()).unwrap();
+
+        forest.store.as_mut().unwrap().fail_index_writes();
+        forest.add_block(chain[1].clone()).unwrap();
+        forest.add_block(chain[2].clone()).unwrap();
+        assert_eq!(forest.unstored_block_hashes.len(), 2);
+
+        forest.store = None;
+        forest.store = Some(BlockStore::open(dir.path()).unwrap());
+        forest.add_block(chain[3].clone()).unwrap();
+        assert!(forest.unstored_block_hashes.is_empty());
+        forest.store = None;
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), chain.len());
+        for (position, block) in chain.iter().enumerate() {
+            assert_eq!(store.read_block(position).unwrap(), block.to_block());
+        }
     }
 }
@@ -0,0 +1,575 @@
+use crate::{
+    block_forest::WalletState,
+    data::{Block, BlockHash, VerifiedBlock, WalletId, HASH_LEN},
+    util::{
+        deserialize_base64_fixed, deserialize_wallet_id, serialize_base64, serialize_wallet_id,
+    },
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_512};
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const LOG_FILE_NAME: &str = "blocks.log";
+const INDEX_FILE_NAME: &str = "blocks.idx";
+const SNAPSHOT_FILE_NAME: &str = "snapshot.json";
+
+/// Payload length and checksum.
+const RECORD_HEADER_LEN: u64 = 12;
+/// Block hash and record offset.
+const INDEX_ENTRY_LEN: usize = HASH_LEN + 8;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Balances and nonces of all wallets at some block stored in the log, so that
+/// the blocks preceding it need not be validated again on startup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BalanceSnapshot {
+    /// Number of log records preceding the snapshot, the last of them being `head_hash`.
+    pub position: usize,
+    pub head_hash: BlockHash,
+    pub wallets: HashMap<WalletId, WalletState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    position: usize,
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    head_hash: BlockHash,
+    wallets: Vec<WalletEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalletEntry {
+    #[serde(
+        serialize_with = "serialize_wallet_id",
+        deserialize_with = "deserialize_wallet_id"
+    )]
+    wallet: WalletId,
+    balance: u64,
+    next_nonce: u64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct IndexEntry {
+    hash: BlockHash,
+    offset: u64,
+}
+
+/// Append-only log of blocks with an index of record offsets.
+///
+/// Every record of `blocks.log` is a block serialized to JSON, preceded by its length
+/// and a checksum, so that a torn write at the tail is detected and cut off on open.
+/// The index in `blocks.idx` is written after the log is synced and is rebuilt from
+/// the log if it lags behind.
+///
+/// A failed append is rolled back, so that the next record does not follow a partial
+/// one. If even that fails, the store refuses further appends until it is reopened.
+pub struct BlockStore {
+    dir: PathBuf,
+    log: File,
+    log_len: u64,
+    index: File,
+    entries: Vec<IndexEntry>,
+    positions: HashMap<BlockHash, usize>,
+    failed: bool,
+}
+
+impl BlockStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        let open = |name: &str| {
+            let path = dir.join(name);
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&path)
+                .with_context(|| format!("failed to open {}", path.display()))
+        };
+        let mut store = Self {
+            dir: dir.to_owned(),
+            log: open(LOG_FILE_NAME)?,
+            log_len: 0,
+            index: open(INDEX_FILE_NAME)?,
+            entries: vec![],
+            positions: HashMap::new(),
+            failed: false,
+        };
+        store.log_len = store.log.metadata()?.len();
+        store.recover().context("failed to recover block log")?;
+        Ok(store)
+    }
+
+    /// Load the index and bring it in line with the log, dropping a torn record
+    /// at the end of the log.
+    fn recover(&mut self) -> Result<()> {
+        let mut index_bytes = vec![];
+        (&self.index).seek(SeekFrom::Start(0))?;
+        (&self.index).read_to_end(&mut index_bytes)?;
+        for chunk in index_bytes.chunks_exact(INDEX_ENTRY_LEN) {
+            let mut hash = [0u8; HASH_LEN];
+            hash.copy_from_slice(&chunk[..HASH_LEN]);
+            self.push_entry(IndexEntry {
+                hash,
+                offset: LittleEndian::read_u64(&chunk[HASH_LEN..]),
+            });
+        }
+
+        // The index is written after the log, so it may only point past the end
+        // of the log if the file system has reordered the writes.
+        while let Some(entry) = self.entries.last() {
+            match self.read_record_len(entry.offset) {
+                Ok(Some(len)) if entry.offset + RECORD_HEADER_LEN + len <= self.log_len => break,
+                _ => self.pop_entry(),
+            }
+        }
+        let indexed_len = self.entries.len();
+
+        let mut offset = self.record_end(indexed_len);
+        while offset < self.log_len {
+            match self.read_record(offset) {
+                Ok(block) => {
+                    self.push_entry(IndexEntry {
+                        hash: block.compute_hash(),
+                        offset,
+                    });
+                    offset = self.record_end(self.entries.len());
+                }
+                Err(err) => {
+                    warn!(
+                        "dropping {} bytes at the end of the block log: {:#}",
+                        self.log_len - offset,
+                        err
+                    );
+                    self.log.set_len(offset)?;
+                    self.log_len = offset;
+                }
+            }
+        }
+
+        self.index
+            .set_len((indexed_len * INDEX_ENTRY_LEN) as u64)
+            .context("failed to truncate index")?;
+        for position in indexed_len..self.entries.len() {
+            self.write_index_entry(position)?;
+        }
+        if self.entries.len() > indexed_len {
+            debug!(
+                "indexed {} blocks missing from the index",
+                self.entries.len() - indexed_len
+            );
+        }
+        Ok(())
+    }
+
+    /// Number of blocks in the log.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.positions.contains_key(hash)
+    }
+
+    /// Read the block at `position` in the log.
+    pub fn read_block(&self, position: usize) -> Result<Block> {
+        let entry = self
+            .entries
+            .get(position)
+            .with_context(|| format!("block log has only {} blocks", self.entries.len()))?;
+        let block = self.read_record(entry.offset)?;
+        ensure!(
+            block.compute_hash() == entry.hash,
+            "block #{} in the log does not match its index entry",
+            position
+        );
+        Ok(block)
+    }
+
+    pub fn append(&mut self, block: &VerifiedBlock) -> Result<()> {
+        if self.contains(block.hash()) {
+            return Ok(());
+        }
+        ensure!(!self.failed, "block log is unusable after a failed write");
+
+        let payload = serde_json::to_vec(&block.to_block())?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.write_u64::<LittleEndian>(checksum(&payload))?;
+        record.extend_from_slice(&payload);
+
+        let position = self.entries.len();
+        let offset = self.log_len;
+        let result = self.write_record(
+            &record,
+            IndexEntry {
+                hash: *block.hash(),
+                offset,
+            },
+        );
+        if result.is_err() {
+            while self.entries.len() > position {
+                self.pop_entry();
+            }
+            self.log_len = offset;
+            let rollback = self
+                .log
+                .set_len(offset)
+                .and_then(|_| self.index.set_len((position * INDEX_ENTRY_LEN) as u64));
+            if let Err(err) = rollback {
+                error!("failed to roll back block log: {}", err);
+                self.failed = true;
+            }
+        }
+        result
+    }
+
+    /// Append `record` to the log and `entry` to the index.
+    fn write_record(&mut self, record: &[u8], entry: IndexEntry) -> Result<()> {
+        self.log
+            .write_all(record)
+            .and_then(|_| self.log.sync_data())
+            .context("failed to write block log")?;
+        self.log_len += record.len() as u64;
+        self.push_entry(entry);
+        self.write_index_entry(self.entries.len() - 1)
+    }
+
+    /// Drop all the blocks starting from `position`.
+    pub fn truncate(&mut self, position: usize) -> Result<()> {
+        if position >= self.entries.len() {
+            return Ok(());
+        }
+        self.log_len = self.entries[position].offset;
+        while self.entries.len() > position {
+            self.pop_entry();
+        }
+        self.log.set_len(self.log_len)?;
+        self.index
+            .set_len((position * INDEX_ENTRY_LEN) as u64)
+            .context("failed to truncate index")?;
+        Ok(())
+    }
+
+    pub fn load_snapshot(&self) -> Result<Option<BalanceSnapshot>> {
+        let path = self.dir.join(SNAPSHOT_FILE_NAME);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let snapshot: SnapshotFile = serde_json::from_slice(&data)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        Ok(Some(BalanceSnapshot {
+            position: snapshot.position,
+            head_hash: snapshot.head_hash,
+            wallets: snapshot
+                .wallets
+                .into_iter()
+                .map(|entry| {
+                    let state = WalletState {
+                        balance: entry.balance,
+                        next_nonce: entry.next_nonce,
+                    };
+                    (entry.wallet, state)
+                })
+                .collect(),
+        }))
+    }
+
+    /// Replace the snapshot atomically.
+    pub fn save_snapshot(&self, snapshot: &BalanceSnapshot) -> Result<()> {
+        let data = serde_json::to_vec(&SnapshotFile {
+            position: snapshot.position,
+            head_hash: snapshot.head_hash,
+            wallets: snapshot
+                .wallets
+                .iter()
+                .map(|(wallet, state)| WalletEntry {
+                    wallet: wallet.clone(),
+                    balance: state.balance,
+                    next_nonce: state.next_nonce,
+                })
+                .collect(),
+        })?;
+
+        let path = self.dir.join(SNAPSHOT_FILE_NAME);
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        file.write_all(&data)?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("failed to replace {}", path.display()))
+    }
+
+    pub fn remove_snapshot(&self) -> Result<()> {
+        match fs::remove_file(self.dir.join(SNAPSHOT_FILE_NAME)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn push_entry(&mut self, entry: IndexEntry) {
+        self.positions.insert(entry.hash, self.entries.len());
+        self.entries.push(entry);
+    }
+
+    fn pop_entry(&mut self) {
+        if let Some(entry) = self.entries.pop() {
+            self.positions.remove(&entry.hash);
+        }
+    }
+
+    fn write_index_entry(&mut self, position: usize) -> Result<()> {
+        let entry = &self.entries[position];
+        let mut bytes = Vec::with_capacity(INDEX_ENTRY_LEN);
+        bytes.extend_from_slice(&entry.hash);
+        bytes.write_u64::<LittleEndian>(entry.offset)?;
+        self.index
+            .write_all(&bytes)
+            .context("failed to write index")
+    }
+
+    /// Offset right after the first `len` records.
+    fn record_end(&self, len: usize) -> u64 {
+        match len.checked_sub(1).map(|position| &self.entries[position]) {
+            None => 0,
+            Some(entry) => {
+                let payload_len = self
+                    .read_record_len(entry.offset)
+                    .ok()
+                    .flatten()
+                    .expect("indexed records are complete");
+                entry.offset + RECORD_HEADER_LEN + payload_len
+            }
+        }
+    }
+
+    /// Read the payload length of the record at `offset`. Returns `None` if the header
+    /// is cut off.
+    fn read_record_len(&self, offset: u64) -> io::Result<Option<u64>> {
+        if offset + RECORD_HEADER_LEN > self.log_len {
+            return Ok(None);
+        }
+        let mut log = &self.log;
+        log.seek(SeekFrom::Start(offset))?;
+        Ok(Some(log.read_u32::<LittleEndian>()?.into()))
+    }
+
+    fn read_record(&self, offset: u64) -> Result<Block> {
+        let len = match self.read_record_len(offset)? {
+            Some(len) if offset + RECORD_HEADER_LEN + len <= self.log_len => len,
+            _ => bail!("record at offset {} is truncated", offset),
+        };
+        let mut log = &self.log;
+        let expected_checksum = log.read_u64::<LittleEndian>()?;
+        let mut payload = vec![0u8; len as usize];
+        log.read_exact(&mut payload)?;
+        ensure!(
+            checksum(&payload) == expected_checksum,
+            "record at offset {} is corrupted",
+            offset
+        );
+        serde_json::from_slice(&payload)
+            .with_context(|| format!("failed to parse block at offset {}", offset))
+    }
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    LittleEndian::read_u64(&Sha3_512::digest(data)[..8])
+}
+
+#[cfg(test)]
+impl BlockStore {
+    /// Make the index read-only, so that the next append fails and cannot be rolled back.
+    pub(crate) fn fail_index_writes(&mut self) {
+        self.index = File::open(self.dir.join(INDEX_FILE_NAME)).unwrap();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_forest::{BlockForest, SNAPSHOT_INTERVAL},
+        data::{VerifiedTransaction, MAX_REWARD},
+        test_util::{make_chain, next_block, test_key},
+    };
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = make_chain(&test_key(), 5);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert!(store.is_empty());
+        for block in &chain {
+            store.append(block).unwrap();
+        }
+        store.append(&chain[0]).unwrap();
+        drop(store);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), chain.len());
+        for (position, block) in chain.iter().enumerate() {
+            assert!(store.contains(block.hash()));
+            assert_eq!(store.read_block(position).unwrap(), block.to_block());
+        }
+        assert!(store.read_block(chain.len()).is_err());
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = make_chain(&test_key(), 3);
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        for block in &chain {
+            store.append(block).unwrap();
+        }
+        drop(store);
+
+        // Cut the last record in the middle and lose its index entry.
+        let log_path = dir.path().join(LOG_FILE_NAME);
+        let log_len = fs::metadata(&log_path).unwrap().len();
+        File::options()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(log_len - 10)
+            .unwrap();
+        let index_path = dir.path().join(INDEX_FILE_NAME);
+        File::options()
+            .write(true)
+            .open(&index_path)
+            .unwrap()
+            .set_len((2 * INDEX_ENTRY_LEN - 1) as u64)
+            .unwrap();
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!store.contains(chain[2].hash()));
+        store.append(&chain[2]).unwrap();
+        drop(store);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.read_block(2).unwrap(), chain[2].to_block());
+    }
+
+    #[test]
+    fn test_failed_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = make_chain(&test_key(), 3);
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        store.append(&chain[0]).unwrap();
+        let log_path = dir.path().join(LOG_FILE_NAME);
+        let log_len = fs::metadata(&log_path).unwrap().len();
+
+        store.fail_index_writes();
+        assert!(store.append(&chain[1]).is_err());
+        assert_eq!(store.len(), 1);
+        assert!(!store.contains(chain[1].hash()));
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), log_len);
+        assert!(store.append(&chain[2]).is_err());
+        drop(store);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 1);
+        store.append(&chain[1]).unwrap();
+        store.append(&chain[2]).unwrap();
+        drop(store);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.read_block(2).unwrap(), chain[2].to_block());
+    }
+
+    #[test]
+    fn test_missing_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = make_chain(&test_key(), 4);
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        for block in &chain {
+            store.append(block).unwrap();
+        }
+        drop(store);
+
+        fs::remove_file(dir.path().join(INDEX_FILE_NAME)).unwrap();
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.read_block(3).unwrap(), chain[3].to_block());
+        assert_eq!(
+            fs::metadata(dir.path().join(INDEX_FILE_NAME))
+                .unwrap()
+                .len(),
+            (4 * INDEX_ENTRY_LEN) as u64
+        );
+    }
+
+    #[test]
+    fn test_forest_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = test_key();
+        let chain = make_chain(&key, SNAPSHOT_INTERVAL + 3);
+
+        let mut forest = BlockForest::open(dir.path()).unwrap();
+        // The second block arrives before its parent, so both get stored at once.
+        forest.add_block(chain[1].clone()).unwrap();
+        for block in &chain {
+            forest.add_block(block.clone()).unwrap();
+        }
+        assert_eq!(forest.head().hash(), chain.last().unwrap().hash());
+        drop(forest);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), chain.len());
+        let snapshot = store.load_snapshot().unwrap().unwrap();
+        assert_eq!(snapshot.position, SNAPSHOT_INTERVAL);
+        assert_eq!(
+            snapshot.wallets[&key.to_public_key().into()].balance,
+            SNAPSHOT_INTERVAL as u64 * MAX_REWARD
+        );
+        drop(store);
+
+        // Spending the rewards of restored blocks needs their balances.
+        let mut forest = BlockForest::open(dir.path()).unwrap();
+        assert_eq!(forest.head().hash(), chain.last().unwrap().hash());
+        let tx = VerifiedTransaction::sign(
+            &key,
+            WalletId::of_genesis(),
+            chain.len() as u64 * MAX_REWARD - 1,
+            1,
+            0,
+            "all in".into(),
+        )
+        .unwrap();
+        let block = next_block(&key, chain.last().unwrap(), vec![tx]);
+        forest.add_block(block.clone()).unwrap();
+        assert_eq!(forest.head().hash(), block.hash());
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{
+    block_forest::{BlockForest, EPOCH_SIZE},
+    data::{
+        BlockAttributes, BlockHash, VerifiedBlockHeader, MAX_BLOCKS_PER_REQUEST,
+        MAX_HEADERS_PER_MESSAGE, MAX_LOCATOR_LEN,
+    },
+};
+
+use anyhow::{ensure, Context, Result};
+use log::*;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// How long to wait for a requested block before requesting it from another peer.
+pub const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Headers-first synchronization. Headers requested with `PeerMessage::GetHeaders`
+/// are validated against the known chain, and then the blocks are requested from
+/// all the peers in parallel with `PeerMessage::GetBlocks`. `P` identifies a peer.
+pub struct BlockSync<P> {
+    /// Validated headers of the blocks which are not in the forest yet.
+    headers: HashMap<BlockHash, VerifiedBlockHeader>,
+    best_header_hash: Option<BlockHash>,
+    /// Blocks to request, in the chain order.
+    queue: VecDeque<BlockHash>,
+    in_flight: HashMap<BlockHash, (P, Instant)>,
+}
+
+impl<P> Default for BlockSync<P> {
+    fn default() -> Self {
+        Self {
+            headers: HashMap::new(),
+            best_header_hash: None,
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+}
+
+impl<P: Copy + Eq + Hash> BlockSync<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether there are blocks to download.
+    pub fn is_syncing(&self) -> bool {
+        !self.queue.is_empty() || !self.in_flight.is_empty()
+    }
+
+    /// Whether the header of the block has been received, so that the block is
+    /// going to be requested unless it has been already.
+    pub fn is_expected(&self, hash: &BlockHash) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Locator to request the headers following the best known header with.
+    pub fn locator(&self, block_forest: &BlockForest) -> Vec<BlockHash> {
+        let mut locator = block_forest.locator();
+        if let Some(hash) = self
+            .best_header_hash
+            .filter(|hash| self.headers.contains_key(hash))
+        {
+            locator.insert(0, hash);
+            if locator.len() > MAX_LOCATOR_LEN {
+                // Keep the genesis block last.
+                locator.remove(locator.len() - 2);
+            }
+        }
+        locator
+    }
+
+    fn find_attrs<'a>(
+        &'a self,
+        block_forest: &'a BlockForest,
+        hash: &BlockHash,
+    ) -> Option<&'a BlockAttributes> {
+        match self.headers.get(hash) {
+            Some(header) => Some(&header.attrs),
+            None => block_forest
+                .find_block(hash)
+                .map(|block| block as &BlockAttributes),
+        }
+    }
+
+    /// Max hash of a block following `parent`, by the same rules as `BlockForest::next_max_hash()`.
+    fn next_max_hash(
+        &self,
+        block_forest: &BlockForest,
+        parent: &BlockAttributes,
+    ) -> Result<BlockHash> {
+        if !(parent.index + 1).is_multiple_of(EPOCH_SIZE as u64) {
+            return Ok(parent.max_hash);
+        }
+
+        let mut epoch = vec![parent];
+        while epoch.len() < EPOCH_SIZE {
+            let prev_hash = epoch.last().unwrap().prev_hash;
+            let ancestor = self
+                .find_attrs(block_forest, &prev_hash)
+                .context("ancestors of the header are unknown")?;
+            epoch.push(ancestor);
+        }
+        epoch.reverse();
+        Ok(BlockForest::compute_epoch_max_hash(&epoch))
+    }
+
+    /// Validate headers sent by a peer in response to `GetHeaders`, in the chain order,
+    /// and queue the blocks for download. Returns whether the peer may have more
+    /// headers, which should be requested with a fresh locator.
+    pub fn add_headers(
+        &mut self,
+        block_forest: &BlockForest,
+        headers: Vec<VerifiedBlockHeader>,
+    ) -> Result<bool> {
+        let is_full = headers.len() == MAX_HEADERS_PER_MESSAGE;
+        for header in headers {
+            let hash = *header.hash();
+            if self.headers.contains_key(&hash) || block_forest.find_block(&hash).is_some() {
+                continue;
+            }
+
+            let parent = self
+                .find_attrs(block_forest, &header.prev_hash)
+                .with_context(|| format!("parent of header {} is unknown", base64::encode(hash)))?;
+            ensure!(
+                header.index == parent.index + 1,
+                "wrong header index: expected {}, got {}",
+                parent.index + 1,
+                header.index
+            );
+            ensure!(
+                header.timestamp > parent.timestamp,
+                "header timestamp <= parent timestamp (header ts: {}, parent ts: {})",
+                header.timestamp,
+                parent.timestamp
+            );
+            let expected_max_hash = self.next_max_hash(block_forest, parent)?;
+            ensure!(
+                header.max_hash == expected_max_hash,
+                "wrong max_hash: expected {:?}, got {:?}",
+                expected_max_hash,
+                header.max_hash
+            );
+
+            let is_best = match self
+                .best_header_hash
+                .and_then(|hash| self.headers.get(&hash))
+            {
+                Some(best) => header.index > best.index,
+                None => header.index > block_forest.head().index,
+            };
+            if is_best {
+                self.best_header_hash = Some(hash);
+            }
+            self.headers.insert(hash, header);
+            self.queue.push_back(hash);
+        }
+        Ok(is_full)
+    }
+
+    /// Distribute the queued blocks between `peers`, keeping at most `MAX_BLOCKS_PER_REQUEST`
+    /// blocks in flight per peer. Blocks not received in `BLOCK_REQUEST_TIMEOUT` are
+    /// requested again. Returns the `GetBlocks` requests to send.
+    pub fn next_requests(
+        &mut self,
+        block_forest: &BlockForest,
+        peers: &[P],
+        now: Instant,
+    ) -> Vec<(P, Vec<BlockHash>)> {
+        let mut timed_out: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, requested_at))| {
+                now.duration_since(*requested_at) >= BLOCK_REQUEST_TIMEOUT
+            })
+            .map(|(hash, _)| *hash)
+            .collect();
+        timed_out.sort_by_key(|hash| self.headers.get(hash).map(|header| header.index));
+        for hash in timed_out.into_iter().rev() {
+            debug!("block {} request timed out", base64::encode(hash));
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+        }
+        self.queue
+            .retain(|hash| block_forest.find_block(hash).is_none());
+
+        let mut in_flight_counts = HashMap::new();
+        for (peer, _) in self.in_flight.values() {
+            *in_flight_counts.entry(*peer).or_insert(0) += 1;
+        }
+
+        let mut requests = vec![];
+        for (i, &peer) in peers.iter().enumerate() {
+            // Split the rest evenly between the remaining peers.
+            let fair_share = (self.queue.len() + peers.len() - i - 1) / (peers.len() - i);
+            let capacity =
+                MAX_BLOCKS_PER_REQUEST.saturating_sub(*in_flight_counts.get(&peer).unwrap_or(&0));
+            let hashes: Vec<_> = self.queue.drain(..fair_share.min(capacity)).collect();
+            if hashes.is_empty() {
+                continue;
+            }
+            for hash in &hashes {
+                self.in_flight.insert(*hash, (peer, now));
+            }
+            requests.push((peer, hashes));
+        }
+        requests
+    }
+
+    /// Note that a block has been received, whether it was requested or not.
+    pub fn on_block(&mut self, hash: &BlockHash) {
+        self.in_flight.remove(hash);
+        self.headers.remove(hash);
+    }
+
+    /// Request the blocks in flight to a disconnected peer from the other peers.
+    pub fn remove_peer(&mut self, peer: P) {
+        let mut hashes: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (in_flight_peer, _))| *in_flight_peer == peer)
+            .map(|(hash, _)| *hash)
+            .collect();
+        hashes.sort_by_key(|hash| self.headers.get(hash).map(|header| header.index));
+        for hash in hashes.into_iter().rev() {
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{Block, VerifiedBlock},
+        test_util::{make_chain, next_block, test_key},
+    };
+
+    fn full_forest(chain: &[VerifiedBlock]) -> BlockForest {
+        let mut forest = BlockForest::new();
+        for block in chain {
+            forest.add_block(block.clone()).unwrap();
+        }
+        forest
+    }
+
+    #[test]
+    fn test_sync() {
+        let chain = make_chain(&test_key(), 2 * EPOCH_SIZE + 8);
+        let source = full_forest(&chain);
+        let locator = source.locator();
+        assert_eq!(locator[0], *chain.last().unwrap().hash());
+        assert_eq!(locator.last(), Some(VerifiedBlock::genesis().hash()));
+
+        let mut forest = BlockForest::new();
+        let mut sync = BlockSync::new();
+        let headers = source.headers_after(&sync.locator(&forest), MAX_HEADERS_PER_MESSAGE);
+        assert_eq!(headers.len(), MAX_HEADERS_PER_MESSAGE);
+        assert!(sync.add_headers(&forest, headers).unwrap());
+        let headers = source.headers_after(&sync.locator(&forest), MAX_HEADERS_PER_MESSAGE);
+        assert_eq!(headers[0].index, MAX_HEADERS_PER_MESSAGE as u64 + 1);
+        assert!(!sync.add_headers(&forest, headers).unwrap());
+
+        let now = Instant::now();
+        let requests = sync.next_requests(&forest, &[1, 2, 3], now);
+        let sizes: Vec<_> = requests.iter().map(|(_, hashes)| hashes.len()).collect();
+        assert_eq!(sizes, [14, 13, 13]);
+        assert!(sync.next_requests(&forest, &[1, 2, 3], now).is_empty());
+
+        // Blocks arrive out of order from different peers.
+        for (_, hashes) in requests.into_iter().rev() {
+            for hash in hashes {
+                let block = source.find_block(&hash).unwrap();
+                forest.add_block(VerifiedBlock::clone(block)).unwrap();
+                sync.on_block(&hash);
+            }
+        }
+        assert!(!sync.is_syncing());
+        assert_eq!(forest.head().hash(), source.head().hash());
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        let key = test_key();
+        let chain = make_chain(&key, 3);
+        let forest = full_forest(&chain[..1]);
+        let mut sync = BlockSync::<u32>::new();
+
+        let orphan = chain[2].header();
+        assert!(sync.add_headers(&forest, vec![orphan]).is_err());
+
+        let mut block = Block::from(next_block(&key, &chain[0], vec![]));
+        block.index += 1;
+        let header = block.verified().unwrap().header();
+        assert!(sync.add_headers(&forest, vec![header]).is_err());
+
+        // The max hash may only change at the start of an epoch.
+        let mut block = Block::from(next_block(&key, &chain[0], vec![]));
+        block.max_hash[0] = 127;
+        let header = loop {
+            match block.clone().verified() {
+                Ok(block) => break block.header(),
+                Err(_) => block.nonce += 1,
+            }
+        };
+        assert!(sync.add_headers(&forest, vec![header]).is_err());
+
+        assert!(!sync.is_syncing());
+        sync.add_headers(&forest, vec![chain[1].header()]).unwrap();
+        assert!(sync.is_syncing());
+    }
+
+    #[test]
+    fn test_rerequest() {
+        let chain = make_chain(&test_key(), 4);
+        let source = full_forest(&chain);
+        let forest = BlockForest::new();
+        let mut sync = BlockSync::new();
+        let headers = source.headers_after(&forest.locator(), MAX_HEADERS_PER_MESSAGE);
+        sync.add_headers(&forest, headers).unwrap();
+
+        let now = Instant::now();
+        let requests = sync.next_requests(&forest, &[1], now);
+        assert_eq!(requests, [(1, chain.iter().map(|b| *b.hash()).collect())]);
+
+        sync.remove_peer(1);
+        let requests = sync.next_requests(&forest, &[2], now);
+        assert_eq!(requests[0].1.len(), chain.len());
+
+        sync.on_block(chain[0].hash());
+        let later = now + BLOCK_REQUEST_TIMEOUT;
+        let requests = sync.next_requests(&forest, &[2, 3], later);
+        assert_eq!(
+            requests,
+            [
+                (2, vec![*chain[1].hash(), *chain[2].hash()]),
+                (3, vec![*chain[3].hash()])
+            ]
+        );
+    }
+}
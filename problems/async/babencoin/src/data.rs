@@ -1,9 +1,10 @@
 use crate::util::{
-    deserialize_base64, deserialize_base64_fixed, deserialize_utc, deserialize_wallet_id,
-    parse_pkcs8_public, serialize_base64, serialize_utc, serialize_wallet_id,
+    deserialize_base64, deserialize_base64_fixed, deserialize_base64_fixed_list, deserialize_utc,
+    deserialize_wallet_id, parse_pkcs8_public, serialize_base64, serialize_base64_list,
+    serialize_utc, serialize_wallet_id,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
 use chrono::{DateTime, TimeZone, Utc};
 use rsa::{padding::PaddingScheme, PublicKey, PublicKeyParts, RSAPrivateKey, RSAPublicKey};
@@ -12,6 +13,7 @@ use sha3::{Digest, Sha3_512};
 
 use std::{
     hash::Hash,
+    net::SocketAddr,
     ops::{Deref, DerefMut},
 };
 
@@ -21,6 +23,14 @@ pub const GENESIS_TIMESTAMP: i64 = 1626002428;
 pub const MAX_REWARD: u64 = 1000;
 pub const HASH_LEN: usize = 64;
 
+pub const MAX_LOCATOR_LEN: usize = 64;
+pub const MAX_HEADERS_PER_MESSAGE: usize = 32;
+pub const MAX_BLOCKS_PER_REQUEST: usize = 64;
+pub const MAX_ADDRESSES_PER_MESSAGE: usize = 1000;
+/// Limit on the size of a block serialized to JSON, so that a block message fits
+/// in a frame of `wire::DEFAULT_MAX_FRAME_SIZE` in either encoding.
+pub const MAX_BLOCK_SIZE: usize = 65000;
+
 pub type BlockHash = [u8; HASH_LEN];
 pub type TransactionHash = [u8; HASH_LEN];
 
@@ -64,6 +74,8 @@ impl From<RSAPublicKey> for WalletId {
 #[serde(tag = "kind")]
 #[serde(rename_all = "lowercase")]
 pub enum PeerMessage {
+    /// The first message of a session, see `Handshake`.
+    Handshake(Handshake),
     Block(Box<Block>),
     Transaction(Box<Transaction>),
     Request {
@@ -73,14 +85,79 @@ pub enum PeerMessage {
         )]
         block_hash: BlockHash,
     },
+    /// Request headers of the blocks following the first block of `locator` known
+    /// to the recipient, see `BlockForest::locator()`.
+    GetHeaders {
+        #[serde(
+            serialize_with = "serialize_base64_list",
+            deserialize_with = "deserialize_base64_fixed_list::<'_, _, HASH_LEN>"
+        )]
+        locator: Vec<BlockHash>,
+    },
+    Headers {
+        headers: Vec<BlockHeader>,
+    },
+    /// Request several blocks at once. Each of them is sent back in a separate
+    /// block message.
+    GetBlocks {
+        #[serde(
+            serialize_with = "serialize_base64_list",
+            deserialize_with = "deserialize_base64_fixed_list::<'_, _, HASH_LEN>"
+        )]
+        block_hashes: Vec<BlockHash>,
+    },
+    /// Request addresses of other nodes known to the recipient.
+    GetAddr,
+    Addr {
+        addresses: Vec<PeerAddress>,
+    },
 }
 
 impl PeerMessage {
     pub fn verified(self) -> Result<VerifiedPeerMessage> {
         match self {
+            Self::Handshake(handshake) => Ok(VerifiedPeerMessage::Handshake(handshake)),
             Self::Block(block) => Ok(VerifiedPeerMessage::Block(Box::new(block.verified()?))),
             Self::Transaction(tx) => Ok(VerifiedPeerMessage::Transaction(Box::new(tx.verified()?))),
             Self::Request { block_hash } => Ok(VerifiedPeerMessage::Request { block_hash }),
+            Self::GetHeaders { locator } => {
+                ensure!(
+                    !locator.is_empty() && locator.len() <= MAX_LOCATOR_LEN,
+                    "locator must have from 1 to {} hashes",
+                    MAX_LOCATOR_LEN
+                );
+                Ok(VerifiedPeerMessage::GetHeaders { locator })
+            }
+            Self::Headers { headers } => {
+                ensure!(
+                    headers.len() <= MAX_HEADERS_PER_MESSAGE,
+                    "too many headers: {}",
+                    headers.len()
+                );
+                let headers = headers
+                    .into_iter()
+                    .map(BlockHeader::verified)
+                    .collect::<Result<_>>()
+                    .context("header verification failed")?;
+                Ok(VerifiedPeerMessage::Headers { headers })
+            }
+            Self::GetBlocks { block_hashes } => {
+                ensure!(
+                    block_hashes.len() <= MAX_BLOCKS_PER_REQUEST,
+                    "too many blocks requested: {}",
+                    block_hashes.len()
+                );
+                Ok(VerifiedPeerMessage::GetBlocks { block_hashes })
+            }
+            Self::GetAddr => Ok(VerifiedPeerMessage::GetAddr),
+            Self::Addr { addresses } => {
+                ensure!(
+                    addresses.len() <= MAX_ADDRESSES_PER_MESSAGE,
+                    "too many addresses: {}",
+                    addresses.len()
+                );
+                Ok(VerifiedPeerMessage::Addr { addresses })
+            }
         }
     }
 }
@@ -88,11 +165,21 @@ impl PeerMessage {
 impl From<VerifiedPeerMessage> for PeerMessage {
     fn from(other: VerifiedPeerMessage) -> Self {
         match other {
+            VerifiedPeerMessage::Handshake(handshake) => PeerMessage::Handshake(handshake),
             VerifiedPeerMessage::Block(block) => PeerMessage::Block(Box::new((*block).into())),
             VerifiedPeerMessage::Transaction(tx) => {
                 PeerMessage::Transaction(Box::new((*tx).into()))
             }
             VerifiedPeerMessage::Request { block_hash } => PeerMessage::Request { block_hash },
+            VerifiedPeerMessage::GetHeaders { locator } => PeerMessage::GetHeaders { locator },
+            VerifiedPeerMessage::Headers { headers } => PeerMessage::Headers {
+                headers: headers.into_iter().map(|header| header.into()).collect(),
+            },
+            VerifiedPeerMessage::GetBlocks { block_hashes } => {
+                PeerMessage::GetBlocks { block_hashes }
+            }
+            VerifiedPeerMessage::GetAddr => PeerMessage::GetAddr,
+            VerifiedPeerMessage::Addr { addresses } => PeerMessage::Addr { addresses },
         }
     }
 }
@@ -101,9 +188,61 @@ impl From<VerifiedPeerMessage> for PeerMessage {
 
 #[derive(Clone, Debug)]
 pub enum VerifiedPeerMessage {
+    Handshake(Handshake),
     Block(Box<VerifiedBlock>),
     Transaction(Box<VerifiedTransaction>),
     Request { block_hash: BlockHash },
+    GetHeaders { locator: Vec<BlockHash> },
+    Headers { headers: Vec<VerifiedBlockHeader> },
+    GetBlocks { block_hashes: Vec<BlockHash> },
+    GetAddr,
+    Addr { addresses: Vec<PeerAddress> },
+}
+
+impl VerifiedPeerMessage {
+    /// The `kind` field of the message in JSON.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Handshake(_) => "handshake",
+            Self::Block(_) => "block",
+            Self::Transaction(_) => "transaction",
+            Self::Request { .. } => "request",
+            Self::GetHeaders { .. } => "getheaders",
+            Self::Headers { .. } => "headers",
+            Self::GetBlocks { .. } => "getblocks",
+            Self::GetAddr => "getaddr",
+            Self::Addr { .. } => "addr",
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Sent by both sides when a session starts. Nodes which don't send it are
+/// assumed to speak protocol version 1, i.e. JSON only.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Handshake {
+    /// The latest protocol version the sender supports, see `wire::PROTOCOL_VERSION`.
+    pub version: u32,
+    /// Random id of the sender, picked on start, to recognize connections to itself.
+    pub node_id: u64,
+    pub head_index: u64,
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>"
+    )]
+    pub head_hash: BlockHash,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Listening address of a node, as exchanged in `addr` messages.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeerAddress {
+    pub address: SocketAddr,
+    /// When a connection to the node last succeeded.
+    #[serde(serialize_with = "serialize_utc", deserialize_with = "deserialize_utc")]
+    pub last_seen: DateTime<Utc>,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -136,6 +275,25 @@ pub struct BlockAttributes {
     pub prev_hash: BlockHash,
 }
 
+impl BlockAttributes {
+    /// Checks which don't depend on the other blocks.
+    fn validate(&self) -> Result<()> {
+        if self.timestamp.timestamp() < GENESIS_TIMESTAMP {
+            bail!("block timestamp is less than genesis timestamp");
+        }
+        if self.timestamp > Utc::now() {
+            bail!("block timestamp is greater than now");
+        }
+        if self.reward > MAX_REWARD {
+            bail!("block reward is greater than max reward");
+        }
+        if self.index == 1 && self.prev_hash != VerifiedBlock::genesis().hash {
+            bail!("block index is 1, but prev_hash != genesis");
+        }
+        Ok(())
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -183,21 +341,12 @@ impl Block {
     }
 
     pub fn verified(self) -> Result<VerifiedBlock> {
-        if self.timestamp.timestamp() < GENESIS_TIMESTAMP {
-            bail!("block timestamp is less than genesis timestamp");
-        }
-        if self.timestamp > Utc::now() {
-            bail!("block timestamp is greater than now");
-        }
-        if self.reward > MAX_REWARD {
-            bail!("block reward is greater than max reward");
-        }
+        self.attrs.validate()?;
         if self.index == 0 && self != Self::genesis() {
             bail!("block index is 0, but not the genesis block");
         }
-        if self.index == 1 && self.prev_hash != VerifiedBlock::genesis().hash {
-            bail!("block index is 1, but prev_hash != genesis");
-        }
+        let size = serde_json::to_vec(&self)?.len();
+        ensure!(size <= MAX_BLOCK_SIZE, "block is too large: {} bytes", size);
 
         let mut transactions = Vec::with_capacity(self.transactions.len());
         for tx in self.transactions.into_iter() {
@@ -283,6 +432,16 @@ impl VerifiedBlock {
         &self.transactions
     }
 
+    pub fn header(&self) -> VerifiedBlockHeader {
+        VerifiedBlockHeader {
+            inner: BlockHeader {
+                attrs: self.attrs.clone(),
+                transaction_hashes: self.transactions.iter().map(|tx| *tx.hash()).collect(),
+            },
+            hash: self.hash,
+        }
+    }
+
     pub fn to_block(&self) -> Block {
         Block {
             attrs: self.attrs.clone(),
@@ -297,10 +456,84 @@ impl VerifiedBlock {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Block without the transactions, which still allows to compute the block hash.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockHeader {
+    #[serde(flatten)]
+    pub attrs: BlockAttributes,
+
+    #[serde(
+        serialize_with = "serialize_base64_list",
+        deserialize_with = "deserialize_base64_fixed_list::<'_, _, HASH_LEN>"
+    )]
+    pub transaction_hashes: Vec<TransactionHash>,
+}
+
+impl Deref for BlockHeader {
+    type Target = BlockAttributes;
+
+    fn deref(&self) -> &Self::Target {
+        &self.attrs
+    }
+}
+
+impl BlockHeader {
+    pub fn compute_hash(&self) -> BlockHash {
+        Block::compute_hash_inner(&self.attrs, self.transaction_hashes.iter().copied())
+    }
+
+    pub fn verified(self) -> Result<VerifiedBlockHeader> {
+        self.attrs.validate()?;
+        if self.index == 0 && self != VerifiedBlock::genesis().header().inner {
+            bail!("block index is 0, but not the genesis block");
+        }
+
+        let hash = self.compute_hash();
+        if hash > self.attrs.max_hash {
+            bail!("block hash is greater than max_hash");
+        }
+
+        Ok(VerifiedBlockHeader { inner: self, hash })
+    }
+}
+
+impl From<VerifiedBlockHeader> for BlockHeader {
+    fn from(other: VerifiedBlockHeader) -> Self {
+        other.inner
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedBlockHeader {
+    inner: BlockHeader,
+    hash: BlockHash,
+}
+
+impl Deref for VerifiedBlockHeader {
+    type Target = BlockHeader;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl VerifiedBlockHeader {
+    pub fn hash(&self) -> &BlockHash {
+        &self.hash
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Transaction {
     pub amount: u64,
     pub fee: u64,
+    /// Number of transactions made by the sender before this one, so that
+    /// a transaction can't be included twice.
+    pub nonce: u64,
     pub comment: String,
 
     #[serde(
@@ -339,6 +572,7 @@ impl Transaction {
         let mut hasher = Sha3_512::new();
         hasher.write_u64::<LittleEndian>(self.amount).unwrap();
         hasher.write_u64::<LittleEndian>(self.fee).unwrap();
+        hasher.write_u64::<LittleEndian>(self.nonce).unwrap();
         hasher.update(self.comment.as_bytes());
         hasher.update(self.sender.public_key.n().to_bytes_le());
         hasher.update(self.sender.public_key.e().to_bytes_le());
@@ -389,6 +623,7 @@ impl VerifiedTransaction {
         receiver: WalletId,
         amount: u64,
         fee: u64,
+        nonce: u64,
         comment: String,
     ) -> Result<VerifiedTransaction> {
         let mut transaction = Transaction {
@@ -397,6 +632,7 @@ impl VerifiedTransaction {
             receiver,
             amount,
             fee,
+            nonce,
             comment,
         };
 
@@ -427,10 +663,92 @@ mod tests {
     fn test_transaction_sign() {
         let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
         let genesis_key = Block::genesis().issuer.clone();
-        let tx = VerifiedTransaction::sign(&priv_key, genesis_key, 100, 5, "ping".into()).unwrap();
+        let tx =
+            VerifiedTransaction::sign(&priv_key, genesis_key, 100, 5, 0, "ping".into()).unwrap();
         (&tx as &Transaction).clone().verified().unwrap();
     }
 
+    #[test]
+    fn test_sync_messages_json() {
+        let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
+        let block = block.verified().unwrap();
+
+        let message = PeerMessage::from(VerifiedPeerMessage::Headers {
+            headers: vec![block.header()],
+        });
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["kind"], "headers");
+        assert_eq!(
+            json["headers"][0]["transaction_hashes"][0],
+            base64::encode(block.transactions()[0].hash())
+        );
+        let message: PeerMessage = serde_json::from_value(json).unwrap();
+        match message.verified().unwrap() {
+            VerifiedPeerMessage::Headers { headers } => assert_eq!(headers[0].hash(), block.hash()),
+            _ => panic!("wrong message kind"),
+        }
+
+        let mut header = BlockHeader::from(block.header());
+        header.transaction_hashes.clear();
+        assert_ne!(header.compute_hash(), *block.hash());
+
+        let message = PeerMessage::GetBlocks {
+            block_hashes: vec![*block.hash(); MAX_BLOCKS_PER_REQUEST + 1],
+        };
+        assert!(message.verified().is_err());
+        let message = r#"{"kind": "getheaders", "locator": ["AAAA"]}"#;
+        assert!(serde_json::from_str::<PeerMessage>(message).is_err());
+    }
+
+    #[test]
+    fn test_addr_messages_json() {
+        let message: PeerMessage = serde_json::from_str(r#"{"kind": "getaddr"}"#).unwrap();
+        let message = message.verified().unwrap();
+        assert!(matches!(message, VerifiedPeerMessage::GetAddr));
+        assert_eq!(message.kind(), "getaddr");
+
+        let message = r#"{"kind": "addr", "addresses": [{"address": "127.0.0.1:9090", "last_seen": 1626002428}]}"#;
+        let message: PeerMessage = serde_json::from_str(message).unwrap();
+        match message.verified().unwrap() {
+            VerifiedPeerMessage::Addr { addresses } => {
+                assert_eq!(addresses[0].address, "127.0.0.1:9090".parse().unwrap());
+                assert_eq!(addresses[0].last_seen.timestamp(), GENESIS_TIMESTAMP);
+            }
+            _ => panic!("wrong message kind"),
+        }
+
+        let message =
+            r#"{"kind": "addr", "addresses": [{"address": "localhost", "last_seen": 0}]}"#;
+        assert!(serde_json::from_str::<PeerMessage>(message).is_err());
+        let address = PeerAddress {
+            address: "127.0.0.1:9090".parse().unwrap(),
+            last_seen: Utc::now(),
+        };
+        let message = PeerMessage::Addr {
+            addresses: vec![address; MAX_ADDRESSES_PER_MESSAGE + 1],
+        };
+        assert!(message.verified().is_err());
+    }
+
+    #[test]
+    fn test_handshake_json() {
+        let message = PeerMessage::Handshake(Handshake {
+            version: 2,
+            node_id: 42,
+            head_index: 0,
+            head_hash: *VerifiedBlock::genesis().hash(),
+        });
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["kind"], "handshake");
+        assert_eq!(json["version"], 2);
+        assert_eq!(
+            json["head_hash"],
+            base64::encode(VerifiedBlock::genesis().hash())
+        );
+        let message: PeerMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(message.verified().unwrap().kind(), "handshake");
+    }
+
     #[test]
     fn test_block_json() {
         let block: Block = serde_json::from_str(include_str!("../data/test_block.json")).unwrap();
@@ -456,6 +774,7 @@ mod tests {
                     genesis.issuer.clone(),
                     500,
                     30,
+                    0,
                     "hi".into()
                 )
                 .unwrap()
@@ -0,0 +1,474 @@
+use crate::{
+    block_forest::{BlockForest, WalletState},
+    data::{VerifiedBlock, WalletId},
+    util::encode_wallet_id,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use std::{cmp::Reverse, collections::HashMap, io::Write, str::FromStr};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A header line with the field names and a line per record.
+    Csv,
+    /// A JSON object per line.
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::JsonLines),
+            _ => bail!("unknown format {:?}, expected csv or jsonl", s),
+        }
+    }
+}
+
+/// A row of an exported table. Hashes and wallets are encoded in base64 and
+/// timestamps are Unix times, as in the protocol messages.
+pub trait Record: Serialize {
+    const FIELDS: &'static [&'static str];
+
+    /// Values of `FIELDS`, in the same order.
+    fn values(&self) -> Vec<String>;
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct BlockRecord {
+    pub index: u64,
+    pub hash: String,
+    pub prev_hash: String,
+    pub timestamp: i64,
+    pub issuer: String,
+    pub reward: u64,
+    /// Sum of the transaction fees, which the issuer gets along with the reward.
+    pub fees: u64,
+    pub transaction_count: usize,
+}
+
+impl Record for BlockRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "index",
+        "hash",
+        "prev_hash",
+        "timestamp",
+        "issuer",
+        "reward",
+        "fees",
+        "transaction_count",
+    ];
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.index.to_string(),
+            self.hash.clone(),
+            self.prev_hash.clone(),
+            self.timestamp.to_string(),
+            self.issuer.clone(),
+            self.reward.to_string(),
+            self.fees.to_string(),
+            self.transaction_count.to_string(),
+        ]
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct TransactionRecord {
+    pub hash: String,
+    pub block_index: u64,
+    pub block_hash: String,
+    pub timestamp: i64,
+    pub sender: String,
+    pub receiver: String,
+    pub amount: u64,
+    pub fee: u64,
+    pub nonce: u64,
+    pub comment: String,
+}
+
+impl Record for TransactionRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "hash",
+        "block_index",
+        "block_hash",
+        "timestamp",
+        "sender",
+        "receiver",
+        "amount",
+        "fee",
+        "nonce",
+        "comment",
+    ];
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            self.block_index.to_string(),
+            self.block_hash.clone(),
+            self.timestamp.to_string(),
+            self.sender.clone(),
+            self.receiver.clone(),
+            self.amount.to_string(),
+            self.fee.to_string(),
+            self.nonce.to_string(),
+            self.comment.clone(),
+        ]
+    }
+}
+
+/// Balance of a wallet after a block which changed it.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct BalanceRecord {
+    pub block_index: u64,
+    pub block_hash: String,
+    pub timestamp: i64,
+    pub wallet: String,
+    pub previous_balance: u64,
+    pub balance: u64,
+}
+
+impl Record for BalanceRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "block_index",
+        "block_hash",
+        "timestamp",
+        "wallet",
+        "previous_balance",
+        "balance",
+    ];
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.block_index.to_string(),
+            self.block_hash.clone(),
+            self.timestamp.to_string(),
+            self.wallet.clone(),
+            self.previous_balance.to_string(),
+            self.balance.to_string(),
+        ]
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct RichListRecord {
+    /// Position in the list, starting with 1.
+    pub rank: usize,
+    pub wallet: String,
+    pub balance: u64,
+    /// Number of transactions sent from the wallet.
+    pub next_nonce: u64,
+}
+
+impl Record for RichListRecord {
+    const FIELDS: &'static [&'static str] = &["rank", "wallet", "balance", "next_nonce"];
+
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.rank.to_string(),
+            self.wallet.clone(),
+            self.balance.to_string(),
+            self.next_nonce.to_string(),
+        ]
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Blocks, transactions and balances of the chain ending at the head of a forest,
+/// computed by replaying the chain from the genesis block.
+pub struct ChainExport {
+    blocks: Vec<BlockRecord>,
+    transactions: Vec<TransactionRecord>,
+    balance_history: Vec<BalanceRecord>,
+    /// Wallets at the head.
+    wallets: HashMap<String, WalletState>,
+}
+
+impl ChainExport {
+    pub fn new(forest: &BlockForest) -> Result<Self> {
+        let mut chain: Vec<_> = forest.canonical_chain().collect();
+        chain.reverse();
+
+        let mut export = Self {
+            blocks: vec![],
+            transactions: vec![],
+            balance_history: vec![],
+            wallets: HashMap::new(),
+        };
+        let mut encoded_wallets = HashMap::new();
+        let mut encode = |wallet: &WalletId| -> Result<String> {
+            if let Some(encoded) = encoded_wallets.get(wallet) {
+                return Ok(String::clone(encoded));
+            }
+            let encoded = encode_wallet_id(wallet)?;
+            encoded_wallets.insert(wallet.clone(), encoded.clone());
+            Ok(encoded)
+        };
+
+        let mut snapshot = HashMap::new();
+        for block in chain {
+            export.add_block(block, &mut snapshot, &mut encode)?;
+        }
+        for (wallet, state) in snapshot {
+            export.wallets.insert(encode(&wallet)?, state);
+        }
+        Ok(export)
+    }
+
+    fn add_block(
+        &mut self,
+        block: &VerifiedBlock,
+        snapshot: &mut HashMap<WalletId, WalletState>,
+        encode: &mut impl FnMut(&WalletId) -> Result<String>,
+    ) -> Result<()> {
+        let block_hash = base64::encode(block.hash());
+        let timestamp = block.timestamp.timestamp();
+
+        let mut touched_wallets = vec![&block.issuer];
+        for tx in block.transactions() {
+            touched_wallets.extend([&tx.sender, &tx.receiver]);
+        }
+        let mut previous_balances = vec![];
+        for wallet in touched_wallets {
+            if previous_balances.iter().all(|(other, _)| *other != wallet) {
+                let balance = snapshot.get(wallet).map_or(0, |state| state.balance);
+                previous_balances.push((wallet, balance));
+            }
+        }
+
+        BlockForest::try_apply_issuer_reward_to_snapshot(block, snapshot)
+            .and_then(|()| {
+                block
+                    .transactions()
+                    .iter()
+                    .try_for_each(|tx| BlockForest::try_apply_tx_to_snapshot(tx, snapshot))
+            })
+            .with_context(|| format!("failed to replay block #{}", block.index))?;
+
+        for (wallet, previous_balance) in previous_balances {
+            let balance = snapshot.get(wallet).map_or(0, |state| state.balance);
+            if balance != previous_balance {
+                self.balance_history.push(BalanceRecord {
+                    block_index: block.index,
+                    block_hash: block_hash.clone(),
+                    timestamp,
+                    wallet: encode(wallet)?,
+                    previous_balance,
+                    balance,
+                });
+            }
+        }
+
+        for tx in block.transactions() {
+            self.transactions.push(TransactionRecord {
+                hash: base64::encode(tx.hash()),
+                block_index: block.index,
+                block_hash: block_hash.clone(),
+                timestamp,
+                sender: encode(&tx.sender)?,
+                receiver: encode(&tx.receiver)?,
+                amount: tx.amount,
+                fee: tx.fee,
+                nonce: tx.nonce,
+                comment: tx.comment.clone(),
+            });
+        }
+
+        self.blocks.push(BlockRecord {
+            index: block.index,
+            hash: block_hash,
+            prev_hash: base64::encode(block.prev_hash),
+            timestamp,
+            issuer: encode(&block.issuer)?,
+            reward: block.reward,
+            fees: block.transactions().iter().map(|tx| tx.fee).sum(),
+            transaction_count: block.transactions().len(),
+        });
+        Ok(())
+    }
+
+    /// Blocks from the genesis block to the head.
+    pub fn blocks(&self) -> &[BlockRecord] {
+        &self.blocks
+    }
+
+    /// Transactions in the chain order.
+    pub fn transactions(&self) -> &[TransactionRecord] {
+        &self.transactions
+    }
+
+    /// Balances of the wallets after every block which changed them, in the chain order.
+    pub fn balance_history(&self) -> &[BalanceRecord] {
+        &self.balance_history
+    }
+
+    /// Wallets with a positive balance at the head, the richest first.
+    pub fn rich_list(&self, limit: Option<usize>) -> Vec<RichListRecord> {
+        let mut wallets: Vec<_> = self
+            .wallets
+            .iter()
+            .filter(|(_, state)| state.balance > 0)
+            .collect();
+        wallets.sort_by_key(|(wallet, state)| (Reverse(state.balance), *wallet));
+        wallets
+            .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .enumerate()
+            .map(|(i, (wallet, state))| RichListRecord {
+                rank: i + 1,
+                wallet: wallet.clone(),
+                balance: state.balance,
+                next_nonce: state.next_nonce,
+            })
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn write_records<'a, T: Record + 'a>(
+    writer: &mut impl Write,
+    format: Format,
+    records: impl IntoIterator<Item = &'a T>,
+) -> Result<()> {
+    match format {
+        Format::Csv => {
+            write_csv_line(writer, T::FIELDS.iter().copied())?;
+            for record in records {
+                write_csv_line(writer, record.values().iter().map(String::as_str))?;
+            }
+        }
+        Format::JsonLines => {
+            for record in records {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fields with commas, quotes or line breaks are quoted, as in RFC 4180.
+fn write_csv_line<'a>(
+    writer: &mut impl Write,
+    fields: impl Iterator<Item = &'a str>,
+) -> Result<()> {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        if field.contains(&[',', '"', '\r', '\n'][..]) {
+            write!(writer, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+    writer.write_all(b"\r\n")?;
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{VerifiedTransaction, MAX_REWARD},
+        test_util::{make_chain, next_block, test_key},
+    };
+
+    fn make_export() -> ChainExport {
+        let key = test_key();
+        let chain = make_chain(&key, 2);
+        let tx = VerifiedTransaction::sign(
+            &key,
+            WalletId::of_genesis(),
+            10,
+            1,
+            0,
+            "thanks, \"genesis\"".into(),
+        )
+        .unwrap();
+        let last = next_block(&key, &chain[1], vec![tx]);
+
+        let mut forest = BlockForest::new();
+        for block in chain.into_iter().chain([last]) {
+            forest.add_block(block).unwrap();
+        }
+        ChainExport::new(&forest).unwrap()
+    }
+
+    #[test]
+    fn test_chain_export() {
+        let export = make_export();
+        let key = encode_wallet_id(&test_key().to_public_key().into()).unwrap();
+        let genesis = encode_wallet_id(&WalletId::of_genesis()).unwrap();
+
+        let indices: Vec<_> = export.blocks().iter().map(|block| block.index).collect();
+        assert_eq!(indices, [0, 1, 2, 3]);
+        assert_eq!(export.blocks()[3].fees, 1);
+        assert_eq!(export.blocks()[3].prev_hash, export.blocks()[2].hash);
+        assert_eq!(export.transactions().len(), 1);
+        assert_eq!(export.transactions()[0].block_index, 3);
+
+        let history: Vec<_> = export
+            .balance_history()
+            .iter()
+            .map(|record| {
+                (
+                    record.block_index,
+                    record.wallet == key,
+                    record.previous_balance,
+                    record.balance,
+                )
+            })
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (1, true, 0, MAX_REWARD),
+                (2, true, MAX_REWARD, 2 * MAX_REWARD),
+                (3, true, 2 * MAX_REWARD, 3 * MAX_REWARD - 10),
+                (3, false, 0, 10),
+            ]
+        );
+
+        let rich_list = export.rich_list(None);
+        assert_eq!(rich_list.len(), 2);
+        assert_eq!(rich_list[0].wallet, key);
+        assert_eq!(rich_list[0].next_nonce, 1);
+        assert_eq!(rich_list[1].wallet, genesis);
+        assert_eq!(rich_list[1].rank, 2);
+        assert_eq!(export.rich_list(Some(1)).len(), 1);
+    }
+
+    #[test]
+    fn test_write_records() {
+        let export = make_export();
+
+        let mut csv = vec![];
+        write_records(&mut csv, Format::Csv, export.transactions()).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], TransactionRecord::FIELDS.join(","));
+        assert!(lines[1].ends_with(",0,\"thanks, \"\"genesis\"\"\""));
+
+        let mut json = vec![];
+        write_records(&mut json, Format::JsonLines, export.blocks()).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(json.lines().count(), 4);
+        let block: serde_json::Value = serde_json::from_str(json.lines().last().unwrap()).unwrap();
+        assert_eq!(block["index"], 3);
+        assert_eq!(block["transaction_count"], 1);
+
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
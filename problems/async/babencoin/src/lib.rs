@@ -1,6 +1,16 @@
 #![forbid(unsafe_code)]
 
+pub mod address_book;
 pub mod block_forest;
+pub mod block_store;
+pub mod block_sync;
 pub mod data;
+pub mod export;
+pub mod mempool;
 pub mod node;
+pub mod peer_scores;
 pub mod util;
+pub mod wire;
+
+#[cfg(test)]
+mod test_util;
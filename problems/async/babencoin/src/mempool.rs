@@ -0,0 +1,193 @@
+use crate::data::{Transaction, TransactionHash, VerifiedTransaction, WalletId};
+
+use serde::{Deserialize, Serialize};
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MempoolConfig {
+    pub max_count: usize,
+    /// Limit on the total size of the transactions serialized to JSON.
+    pub max_bytes: usize,
+    /// How long a transaction may wait to be mined.
+    #[serde(with = "humantime_serde")]
+    pub expiry: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_count: 10000,
+            max_bytes: 32 << 20,
+            expiry: Duration::from_secs(3600),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Entry {
+    tx: VerifiedTransaction,
+    size: usize,
+    added_at: Instant,
+    seq: u64,
+}
+
+/// Pending transactions ordered by fee. It doesn't validate transactions, which is
+/// up to `BlockForest`, and only tells which ones exceed the limits. Nonces of the
+/// transactions of every sender are expected to be consecutive.
+pub struct Mempool {
+    config: MempoolConfig,
+    entries: HashMap<TransactionHash, Entry>,
+    /// Fee and insertion order of every entry, the older ones last among equal fees.
+    by_fee: BTreeSet<(u64, Reverse<u64>, TransactionHash)>,
+    bytes: usize,
+    next_seq: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MempoolConfig::default())
+    }
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            by_fee: BTreeSet::new(),
+            bytes: 0,
+            next_seq: 0,
+        }
+    }
+
+    pub fn config(&self) -> &MempoolConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size of the transactions serialized to JSON.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, hash: &TransactionHash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &TransactionHash) -> Option<&VerifiedTransaction> {
+        self.entries.get(hash).map(|entry| &entry.tx)
+    }
+
+    /// Transactions from the highest fee to the lowest.
+    pub fn iter(&self) -> impl Iterator<Item = &VerifiedTransaction> {
+        self.by_fee
+            .iter()
+            .rev()
+            .map(move |(_, _, hash)| &self.entries[hash].tx)
+    }
+
+    /// Returns false if the transaction is there already.
+    pub(crate) fn insert(&mut self, tx: VerifiedTransaction, added_at: Instant) -> bool {
+        if self.entries.contains_key(tx.hash()) {
+            return false;
+        }
+        let size = serde_json::to_vec(&tx as &Transaction)
+            .expect("transactions are serializable")
+            .len();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.by_fee.insert((tx.fee, Reverse(seq), *tx.hash()));
+        self.bytes += size;
+        self.entries.insert(
+            *tx.hash(),
+            Entry {
+                tx,
+                size,
+                added_at,
+                seq,
+            },
+        );
+        true
+    }
+
+    pub(crate) fn remove(&mut self, hash: &TransactionHash) -> Option<VerifiedTransaction> {
+        let entry = self.entries.remove(hash)?;
+        self.by_fee
+            .remove(&(entry.tx.fee, Reverse(entry.seq), *hash));
+        self.bytes -= entry.size;
+        Some(entry.tx)
+    }
+
+    /// Remove all the transactions, returning them in the insertion order along
+    /// with the time they were added.
+    pub(crate) fn take_all(&mut self) -> Vec<(VerifiedTransaction, Instant)> {
+        let mut entries: Vec<_> = self.entries.drain().map(|(_, entry)| entry).collect();
+        entries.sort_by_key(|entry| entry.seq);
+        self.by_fee.clear();
+        self.bytes = 0;
+        entries
+            .into_iter()
+            .map(|entry| (entry.tx, entry.added_at))
+            .collect()
+    }
+
+    /// Transactions to evict for the rest to fit in the limits, the lowest fees first.
+    /// Only the last transaction of a sender is evicted, so that the rest stay valid.
+    pub(crate) fn overflow(&self) -> Vec<TransactionHash> {
+        let mut last_nonces: HashMap<&WalletId, u64> = HashMap::new();
+        for entry in self.entries.values() {
+            let nonce = last_nonces.entry(&entry.tx.sender).or_default();
+            *nonce = (*nonce).max(entry.tx.nonce);
+        }
+
+        let mut count = self.entries.len();
+        let mut bytes = self.bytes;
+        let mut evicted = HashSet::new();
+        let mut result = vec![];
+        while count > self.config.max_count || bytes > self.config.max_bytes {
+            let entry = self
+                .by_fee
+                .iter()
+                .map(|(_, _, hash)| &self.entries[hash])
+                .find(|entry| {
+                    !evicted.contains(entry.tx.hash())
+                        && last_nonces.get(&entry.tx.sender) == Some(&entry.tx.nonce)
+                })
+                .expect("every sender has a last transaction");
+            match entry.tx.nonce.checked_sub(1) {
+                Some(nonce) => last_nonces.insert(&entry.tx.sender, nonce),
+                None => last_nonces.remove(&entry.tx.sender),
+            };
+            count -= 1;
+            bytes -= entry.size;
+            evicted.insert(*entry.tx.hash());
+            result.push(*entry.tx.hash());
+        }
+        result
+    }
+
+    pub(crate) fn expired(&self, now: Instant) -> Vec<TransactionHash> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| {
+                now.saturating_duration_since(entry.added_at) >= self.config.expiry
+            })
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+}
@@ -1,16 +1,23 @@
 mod gossip_service;
+mod metrics_service;
 mod mining_service;
 mod peer_service;
+mod rpc_service;
+pub mod simulation;
 
-use gossip_service::{GossipService, GossipServiceConfig};
+use gossip_service::{GossipChannels, GossipService, GossipServiceConfig};
+use metrics_service::{Metrics, MetricsService, MetricsServiceConfig};
 use mining_service::{MiningService, MiningServiceConfig};
 use peer_service::{PeerService, PeerServiceConfig};
+use rpc_service::{RpcService, RpcServiceConfig};
+
+use crate::{address_book::AddressBook, block_forest::BlockForest, mempool::MempoolConfig};
 
 use anyhow::{Context, Result};
 use crossbeam::channel;
 use serde::{Deserialize, Serialize};
 
-use std::thread;
+use std::{path::PathBuf, sync::Arc, thread};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -19,6 +26,15 @@ pub struct Config {
     pub peer_service: PeerServiceConfig,
     pub gossip_service: GossipServiceConfig,
     pub mining_service: MiningServiceConfig,
+    /// Directory to store blocks and known peer addresses in, so that they survive
+    /// restarts. They are kept in memory only if not set.
+    pub data_dir: Option<PathBuf>,
+    /// JSON-RPC over HTTP is served only if set.
+    pub rpc_service: Option<RpcServiceConfig>,
+    /// Metrics in the Prometheus text format are served over HTTP only if set.
+    pub metrics_service: Option<MetricsServiceConfig>,
+    #[serde(default)]
+    pub mempool: MempoolConfig,
 }
 
 pub fn run_forever(config: Config) -> Result<()> {
@@ -27,20 +43,69 @@ pub fn run_forever(config: Config) -> Result<()> {
     let (block_sender, block_receiver) = channel::bounded(1000);
     let (mining_info_sender, mining_info_receiver) = channel::bounded(1000);
 
-    let mut peer_service =
-        PeerService::new(config.peer_service, peer_event_sender, command_receiver)
-            .context("failed to create peer service")?;
+    let metrics = Arc::new(Metrics::new());
+    if let Some(metrics_config) = config.metrics_service {
+        let mut metrics_service = MetricsService::new(metrics_config, metrics.clone())
+            .context("failed to create metrics service")?;
+        thread::spawn(move || {
+            metrics_service.run();
+            panic!("metrics service terminated");
+        });
+    }
+
+    let address_book = match &config.data_dir {
+        Some(data_dir) => AddressBook::open(data_dir).context("failed to open address book")?,
+        None => AddressBook::new(),
+    };
+    let mut peer_service = PeerService::new(
+        config.peer_service,
+        address_book,
+        peer_event_sender,
+        command_receiver,
+        metrics.clone(),
+    )
+    .context("failed to create peer service")?;
+
+    let mut block_forest = match &config.data_dir {
+        Some(data_dir) => BlockForest::open(data_dir).context("failed to open block store")?,
+        None => BlockForest::new(),
+    };
+    block_forest.set_mempool_config(config.mempool);
+
+    // Calls are queued until the gossip service starts.
+    let rpc_call_receiver = match config.rpc_service {
+        Some(rpc_config) => {
+            let (rpc_call_sender, rpc_call_receiver) = channel::bounded(1000);
+            let mut rpc_service = RpcService::new(rpc_config, rpc_call_sender)
+                .context("failed to create rpc service")?;
+            thread::spawn(move || {
+                rpc_service.run();
+                panic!("rpc service terminated");
+            });
+            rpc_call_receiver
+        }
+        None => channel::never(),
+    };
 
     let mut gossip_service = GossipService::new(
         config.gossip_service,
-        peer_event_receiver,
-        command_sender,
-        block_receiver,
-        mining_info_sender,
+        block_forest,
+        GossipChannels {
+            event_receiver: peer_event_receiver,
+            command_sender,
+            block_receiver,
+            mining_info_sender,
+            rpc_call_receiver,
+        },
+        metrics.clone(),
     );
 
-    let mut mining_service =
-        MiningService::new(config.mining_service, mining_info_receiver, block_sender);
+    let mut mining_service = MiningService::new(
+        config.mining_service,
+        mining_info_receiver,
+        block_sender,
+        metrics,
+    );
 
     thread::spawn(move || {
         gossip_service.run();
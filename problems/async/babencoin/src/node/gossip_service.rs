@@ -2,24 +2,31 @@
 
 use crate::{
     block_forest::BlockForest,
-    data::{BlockHash, TransactionHash, VerifiedBlock, VerifiedPeerMessage, VerifiedTransaction},
+    block_sync::BlockSync,
+    data::{
+        BlockHash, Handshake, TransactionHash, VerifiedBlock, VerifiedPeerMessage,
+        VerifiedTransaction, MAX_HEADERS_PER_MESSAGE,
+    },
+    node::metrics_service::Metrics,
     node::mining_service::MiningInfo,
     node::peer_service::{PeerCommand, PeerCommandKind, PeerEvent, PeerEventKind, SessionId},
+    node::rpc_service::RpcCall,
+    peer_scores::Misbehaviour,
+    wire::PROTOCOL_VERSION,
 };
 
-use anyhow::{Context, Result};
 use crossbeam::{
     channel::{self, Receiver, Sender},
     select,
 };
 use log::*;
-use rand::{seq::SliceRandom, thread_rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
 use std::{
-    collections::{HashMap, HashSet},
-    thread,
-    time::Duration,
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -30,32 +37,372 @@ pub struct GossipServiceConfig {
     pub eager_requests_interval: Duration,
 }
 
+////////////////////////////////////////////////////////////////////////////////
+
+/// How often to drop the expired transactions, to retry the block requests and
+/// to update the metrics.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+/// The mining service takes up to `max_tx_per_block` of them.
+const MAX_MINED_TRANSACTIONS: usize = 1000;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Channels of the gossip service to the peer, mining and rpc services.
+pub struct GossipChannels {
+    pub event_receiver: Receiver<PeerEvent>,
+    pub command_sender: Sender<PeerCommand>,
+    pub block_receiver: Receiver<VerifiedBlock>,
+    pub mining_info_sender: Sender<MiningInfo>,
+    pub rpc_call_receiver: Receiver<RpcCall>,
+}
+
 pub struct GossipService {
     config: GossipServiceConfig,
     event_receiver: Receiver<PeerEvent>,
     command_sender: Sender<PeerCommand>,
     block_receiver: Receiver<VerifiedBlock>,
     mining_info_sender: Sender<MiningInfo>,
+    rpc_call_receiver: Receiver<RpcCall>,
     block_forest: BlockForest,
-    // TODO: your code goes here.
+    metrics: Arc<Metrics>,
+    /// Sent in the handshakes to recognize connections to itself.
+    node_id: u64,
+    sessions: HashSet<SessionId>,
+    block_sync: BlockSync<SessionId>,
+    /// Parent and transactions of the last block sent to the mining service.
+    last_mining_info: Option<(BlockHash, Vec<TransactionHash>)>,
 }
 
 impl GossipService {
     pub fn new(
         config: GossipServiceConfig,
-        event_receiver: Receiver<PeerEvent>,
-        command_sender: Sender<PeerCommand>,
-        block_receiver: Receiver<VerifiedBlock>,
-        mining_info_sender: Sender<MiningInfo>,
+        block_forest: BlockForest,
+        channels: GossipChannels,
+        metrics: Arc<Metrics>,
     ) -> Self {
-        // TODO: your code goes here.
-        unimplemented!()
+        Self {
+            config,
+            event_receiver: channels.event_receiver,
+            command_sender: channels.command_sender,
+            block_receiver: channels.block_receiver,
+            mining_info_sender: channels.mining_info_sender,
+            rpc_call_receiver: channels.rpc_call_receiver,
+            block_forest,
+            metrics,
+            node_id: thread_rng().gen(),
+            sessions: HashSet::new(),
+            block_sync: BlockSync::new(),
+            last_mining_info: None,
+        }
     }
 
     pub fn run(&mut self) {
-        // TODO: your code goes here.
-        unimplemented!()
+        let eager_requests = if self.config.eager_requests_interval.is_zero() {
+            channel::never()
+        } else {
+            channel::tick(self.config.eager_requests_interval)
+        };
+        let maintenance = channel::tick(MAINTENANCE_INTERVAL);
+
+        self.metrics.update_block_forest(&self.block_forest);
+        self.update_mining_info();
+        loop {
+            select! {
+                recv(self.event_receiver) -> event => match event {
+                    Ok(event) => self.handle_event(event),
+                    Err(_) => {
+                        info!("peer service has stopped, stopping gossip");
+                        return;
+                    }
+                },
+                recv(self.block_receiver) -> block => match block {
+                    Ok(block) => self.handle_block(None, block),
+                    Err(_) => {
+                        info!("mining service has stopped, stopping gossip");
+                        return;
+                    }
+                },
+                recv(self.rpc_call_receiver) -> call => match call {
+                    Ok(call) => self.handle_rpc_call(call),
+                    Err(_) => {
+                        info!("rpc service has stopped, stopping gossip");
+                        return;
+                    }
+                },
+                recv(eager_requests) -> _ => self.request_unknown_blocks(),
+                recv(maintenance) -> _ => self.maintain(),
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: PeerEvent) {
+        let session_id = event.session_id;
+        match event.event_kind {
+            PeerEventKind::Connected => {
+                debug!("session {} started", session_id);
+                self.sessions.insert(session_id);
+                let head = self.block_forest.head();
+                let handshake = Handshake {
+                    version: PROTOCOL_VERSION,
+                    node_id: self.node_id,
+                    head_index: head.index,
+                    head_hash: *head.hash(),
+                };
+                self.send(session_id, VerifiedPeerMessage::Handshake(handshake));
+                self.send(
+                    session_id,
+                    VerifiedPeerMessage::Block(Box::new(VerifiedBlock::clone(
+                        self.block_forest.head(),
+                    ))),
+                );
+                let transactions: Vec<_> = self
+                    .block_forest
+                    .pending_transactions()
+                    .iter()
+                    .cloned()
+                    .collect();
+                for tx in transactions {
+                    self.send(session_id, VerifiedPeerMessage::Transaction(Box::new(tx)));
+                }
+                self.request_headers(session_id);
+            }
+            PeerEventKind::Disconnected => {
+                debug!("session {} ended", session_id);
+                self.sessions.remove(&session_id);
+                self.block_sync.remove_peer(session_id);
+                self.request_blocks();
+            }
+            PeerEventKind::NewMessage(message) => self.handle_message(session_id, message),
+        }
+    }
+
+    fn handle_message(&mut self, session_id: SessionId, message: VerifiedPeerMessage) {
+        match message {
+            VerifiedPeerMessage::Handshake(handshake) => {
+                if handshake.node_id == self.node_id {
+                    info!("session {} is a connection to itself", session_id);
+                    self.send_command(session_id, PeerCommandKind::Drop);
+                } else {
+                    debug!(
+                        "session {} speaks version {} and has head #{}",
+                        session_id, handshake.version, handshake.head_index
+                    );
+                }
+            }
+            VerifiedPeerMessage::Block(block) => self.handle_block(Some(session_id), *block),
+            VerifiedPeerMessage::Transaction(tx) => self.handle_transaction(session_id, *tx),
+            VerifiedPeerMessage::Request { block_hash } => {
+                if let Some(block) = self.block_forest.find_block(&block_hash) {
+                    let block = VerifiedBlock::clone(block);
+                    self.send(session_id, VerifiedPeerMessage::Block(Box::new(block)));
+                }
+            }
+            VerifiedPeerMessage::GetHeaders { locator } => {
+                let headers = self
+                    .block_forest
+                    .headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                self.send(session_id, VerifiedPeerMessage::Headers { headers });
+            }
+            VerifiedPeerMessage::Headers { headers } => {
+                match self.block_sync.add_headers(&self.block_forest, headers) {
+                    Ok(true) => self.request_headers(session_id),
+                    Ok(false) => {}
+                    Err(err) => {
+                        warn!("session {} sent invalid headers: {:#}", session_id, err);
+                        self.send_command(
+                            session_id,
+                            PeerCommandKind::Penalize(Misbehaviour::InvalidHeaders),
+                        );
+                        // Its later headers would not connect to the valid ones either.
+                        self.send_command(session_id, PeerCommandKind::Drop);
+                    }
+                }
+                self.request_blocks();
+            }
+            VerifiedPeerMessage::GetBlocks { block_hashes } => {
+                for hash in block_hashes {
+                    if let Some(block) = self.block_forest.find_block(&hash) {
+                        let block = VerifiedBlock::clone(block);
+                        self.send(session_id, VerifiedPeerMessage::Block(Box::new(block)));
+                    }
+                }
+            }
+            message => debug!(
+                "ignoring {} message from session {}",
+                message.kind(),
+                session_id
+            ),
+        }
+    }
+
+    /// Add a block received from a session, or mined by this node if `source` is `None`,
+    /// and forward it to the other sessions if it's new. Blocks downloaded by the
+    /// headers-first sync are not forwarded.
+    fn handle_block(&mut self, source: Option<SessionId>, block: VerifiedBlock) {
+        let hash = *block.hash();
+        let is_synced = self.block_sync.is_expected(&hash);
+        self.block_sync.on_block(&hash);
+        if self.block_forest.find_block(&hash).is_some() {
+            return;
+        }
+
+        let head_hash = *self.block_forest.head().hash();
+        if let Err(err) = self.block_forest.add_block(block.clone()) {
+            warn!("rejected block {}: {:#}", base64::encode(hash), err);
+            if let Some(session_id) = source {
+                self.send_command(
+                    session_id,
+                    PeerCommandKind::Penalize(Misbehaviour::InvalidBlock),
+                );
+            }
+            return;
+        }
+
+        let prev_hash = block.prev_hash;
+        if !is_synced {
+            self.broadcast(VerifiedPeerMessage::Block(Box::new(block)), source);
+        }
+        if let Some(session_id) = source {
+            if self.block_forest.find_block(&prev_hash).is_none()
+                && !self.block_sync.is_expected(&prev_hash)
+            {
+                self.send(
+                    session_id,
+                    VerifiedPeerMessage::Request {
+                        block_hash: prev_hash,
+                    },
+                );
+            }
+        }
+        if self.block_forest.head().hash() != &head_hash {
+            info!(
+                "new head #{} {}",
+                self.block_forest.head().index,
+                base64::encode(self.block_forest.head().hash())
+            );
+            self.metrics.update_block_forest(&self.block_forest);
+            self.update_mining_info();
+        }
+        self.request_blocks();
+    }
+
+    fn handle_transaction(&mut self, session_id: SessionId, tx: VerifiedTransaction) {
+        if self.block_forest.pending_transactions().contains(tx.hash()) {
+            return;
+        }
+        if let Err(err) = self.block_forest.add_transaction(tx.clone()) {
+            debug!(
+                "discarding transaction {}: {:#}",
+                base64::encode(tx.hash()),
+                err
+            );
+            return;
+        }
+        self.broadcast(
+            VerifiedPeerMessage::Transaction(Box::new(tx)),
+            Some(session_id),
+        );
+        self.update_mining_info();
     }
 
-    // TODO: your code goes here.
+    fn handle_rpc_call(&mut self, call: RpcCall) {
+        if let Some(tx) = call.execute(&mut self.block_forest) {
+            self.broadcast(VerifiedPeerMessage::Transaction(Box::new(tx)), None);
+            self.update_mining_info();
+        }
+    }
+
+    fn request_headers(&self, session_id: SessionId) {
+        let locator = self.block_sync.locator(&self.block_forest);
+        self.send(session_id, VerifiedPeerMessage::GetHeaders { locator });
+    }
+
+    /// Request the blocks of the received headers from all the sessions in parallel.
+    fn request_blocks(&mut self) {
+        if !self.block_sync.is_syncing() {
+            return;
+        }
+        let sessions: Vec<_> = self.sessions.iter().copied().collect();
+        let requests = self
+            .block_sync
+            .next_requests(&self.block_forest, &sessions, Instant::now());
+        for (session_id, block_hashes) in requests {
+            self.send(session_id, VerifiedPeerMessage::GetBlocks { block_hashes });
+        }
+    }
+
+    /// Request the missing parents of the known blocks, each from a random session.
+    fn request_unknown_blocks(&mut self) {
+        let sessions: Vec<_> = self.sessions.iter().copied().collect();
+        let mut rng = thread_rng();
+        let requests: Vec<_> = self
+            .block_forest
+            .unknown_block_hashes()
+            .iter()
+            .filter_map(|hash| Some((*sessions.choose(&mut rng)?, *hash)))
+            .collect();
+        for (session_id, block_hash) in requests {
+            self.send(session_id, VerifiedPeerMessage::Request { block_hash });
+        }
+    }
+
+    fn maintain(&mut self) {
+        let expired_count = self.block_forest.remove_expired_transactions();
+        if expired_count > 0 {
+            info!("removed {} expired transactions", expired_count);
+            self.update_mining_info();
+        }
+        self.metrics.update_block_forest(&self.block_forest);
+        self.request_blocks();
+    }
+
+    /// Send the head and the pending transactions to the mining service, unless
+    /// they are the same as the last time.
+    fn update_mining_info(&mut self) {
+        let head = self.block_forest.head();
+        let transactions = self
+            .block_forest
+            .select_transactions(MAX_MINED_TRANSACTIONS);
+        let key = (
+            *head.hash(),
+            transactions.iter().map(|tx| *tx.hash()).collect(),
+        );
+        if self.last_mining_info.as_ref() == Some(&key) {
+            return;
+        }
+        let info = MiningInfo {
+            block_index: head.index + 1,
+            prev_hash: *head.hash(),
+            prev_timestamp: head.timestamp,
+            max_hash: self.block_forest.next_max_hash(),
+            transactions,
+        };
+        self.last_mining_info = Some(key);
+        if self.mining_info_sender.send(info).is_err() {
+            warn!("mining service has stopped");
+        }
+    }
+
+    fn send(&self, session_id: SessionId, message: VerifiedPeerMessage) {
+        self.send_command(session_id, PeerCommandKind::SendMessage(message));
+    }
+
+    /// Send the message to all the sessions except `except`.
+    fn broadcast(&self, message: VerifiedPeerMessage, except: Option<SessionId>) {
+        for &session_id in &self.sessions {
+            if Some(session_id) != except {
+                self.send(session_id, message.clone());
+            }
+        }
+    }
+
+    fn send_command(&self, session_id: SessionId, command_kind: PeerCommandKind) {
+        let command = PeerCommand {
+            session_id,
+            command_kind,
+        };
+        if self.command_sender.send(command).is_err() {
+            warn!("peer service has stopped");
+        }
+    }
 }
@@ -0,0 +1,399 @@
+#![forbid(unsafe_code)]
+
+use crate::{
+    block_forest::BlockForest,
+    data::VerifiedPeerMessage,
+    node::rpc_service::{read_line, write_response, IO_TIMEOUT},
+};
+
+use anyhow::{Context, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const METRICS_PATH: &str = "/metrics";
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The hash rate is averaged over at least this long.
+const HASH_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct MetricsServiceConfig {
+    pub listen_address: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn label(self) -> &'static str {
+        match self {
+            Self::In => "in",
+            Self::Out => "out",
+        }
+    }
+}
+
+#[derive(Default)]
+struct ForestStats {
+    head_index: u64,
+    head_hash: String,
+    fork_count: usize,
+    mempool_count: usize,
+    mempool_bytes: usize,
+}
+
+struct HashRate {
+    window_start: Instant,
+    window_start_hashes: u64,
+    rate: f64,
+}
+
+/// Node health metrics, updated by the services and rendered in the Prometheus
+/// text format by the metrics service.
+pub struct Metrics {
+    peers: AtomicU64,
+    sessions: AtomicU64,
+    messages: Mutex<BTreeMap<(Direction, &'static str), u64>>,
+    forest: Mutex<ForestStats>,
+    mining_threads: AtomicU64,
+    hashes: AtomicU64,
+    hash_rate: Mutex<HashRate>,
+    blocks_mined: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            peers: AtomicU64::new(0),
+            sessions: AtomicU64::new(0),
+            messages: Mutex::new(BTreeMap::new()),
+            forest: Mutex::new(ForestStats::default()),
+            mining_threads: AtomicU64::new(0),
+            hashes: AtomicU64::new(0),
+            hash_rate: Mutex::new(HashRate {
+                window_start: Instant::now(),
+                window_start_hashes: 0,
+                rate: 0.,
+            }),
+            blocks_mined: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the number of open sessions and of the distinct addresses they are with.
+    pub fn set_connections(&self, peers: usize, sessions: usize) {
+        self.peers.store(peers as u64, Ordering::Relaxed);
+        self.sessions.store(sessions as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_message(&self, direction: Direction, message: &VerifiedPeerMessage) {
+        *self
+            .messages
+            .lock()
+            .unwrap()
+            .entry((direction, message.kind()))
+            .or_default() += 1;
+    }
+
+    /// Take the head, forks and mempool size from the forest.
+    pub fn update_block_forest(&self, block_forest: &BlockForest) {
+        let head = block_forest.head();
+        let mut forest = self.forest.lock().unwrap();
+        forest.head_index = head.index;
+        forest.head_hash = base64::encode(head.hash());
+        forest.fork_count = block_forest.fork_count();
+        forest.mempool_count = block_forest.pending_transactions().len();
+        forest.mempool_bytes = block_forest.pending_transactions().bytes();
+    }
+
+    pub fn set_mining_threads(&self, thread_count: usize) {
+        self.mining_threads
+            .store(thread_count as u64, Ordering::Relaxed);
+    }
+
+    /// Count the hashes computed by a mining thread. Threads should report them in
+    /// batches rather than one by one.
+    pub fn record_hashes(&self, count: u64) {
+        self.hashes.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_block_mined(&self) {
+        self.blocks_mined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Hashes per second over the last `HASH_RATE_WINDOW` or longer.
+    pub fn hash_rate(&self) -> f64 {
+        let hashes = self.hashes.load(Ordering::Relaxed);
+        let mut hash_rate = self.hash_rate.lock().unwrap();
+        let elapsed = hash_rate.window_start.elapsed();
+        if elapsed >= HASH_RATE_WINDOW {
+            hash_rate.rate =
+                (hashes - hash_rate.window_start_hashes) as f64 / elapsed.as_secs_f64();
+            hash_rate.window_start += elapsed;
+            hash_rate.window_start_hashes = hashes;
+        }
+        hash_rate.rate
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            writeln!(out, "# HELP babencoin_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE babencoin_{} {}", name, kind).unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "babencoin_{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        let value = |value: &dyn ToString| vec![(String::new(), value.to_string())];
+
+        metric(
+            "peers",
+            "gauge",
+            "Distinct addresses of the open sessions.",
+            &value(&self.peers.load(Ordering::Relaxed)),
+        );
+        metric(
+            "sessions",
+            "gauge",
+            "Open peer sessions.",
+            &value(&self.sessions.load(Ordering::Relaxed)),
+        );
+        let messages: Vec<_> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((direction, kind), count)| {
+                let labels = format!("{{direction=\"{}\",kind=\"{}\"}}", direction.label(), kind);
+                (labels, count.to_string())
+            })
+            .collect();
+        metric(
+            "peer_messages_total",
+            "counter",
+            "Peer messages received and sent, by kind.",
+            &messages,
+        );
+
+        let forest = self.forest.lock().unwrap();
+        metric(
+            "head_index",
+            "gauge",
+            "Index of the head block.",
+            &value(&forest.head_index),
+        );
+        if !forest.head_hash.is_empty() {
+            metric(
+                "head_info",
+                "gauge",
+                "Hash of the head block.",
+                &[(format!("{{hash=\"{}\"}}", forest.head_hash), "1".into())],
+            );
+        }
+        metric(
+            "forks",
+            "gauge",
+            "Known blocks without children other than the head.",
+            &value(&forest.fork_count),
+        );
+        metric(
+            "mempool_transactions",
+            "gauge",
+            "Pending transactions.",
+            &value(&forest.mempool_count),
+        );
+        metric(
+            "mempool_bytes",
+            "gauge",
+            "Total size of the pending transactions in JSON.",
+            &value(&forest.mempool_bytes),
+        );
+        drop(forest);
+
+        metric(
+            "mining_threads",
+            "gauge",
+            "Mining threads.",
+            &value(&self.mining_threads.load(Ordering::Relaxed)),
+        );
+        metric(
+            "hashes_total",
+            "counter",
+            "Block hashes computed by all the mining threads.",
+            &value(&self.hashes.load(Ordering::Relaxed)),
+        );
+        metric(
+            "hash_rate",
+            "gauge",
+            "Block hashes per second computed by all the mining threads.",
+            &value(&self.hash_rate()),
+        );
+        metric(
+            "blocks_mined_total",
+            "counter",
+            "Blocks found by the mining service.",
+            &value(&self.blocks_mined.load(Ordering::Relaxed)),
+        );
+        out
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Serves the metrics on HTTP GET requests to `/metrics`.
+pub struct MetricsService {
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsService {
+    pub fn new(config: MetricsServiceConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let listener = TcpListener::bind(&config.listen_address)
+            .with_context(|| format!("failed to bind to {}", config.listen_address))?;
+        Ok(Self { listener, metrics })
+    }
+
+    pub fn run(&mut self) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("failed to accept metrics connection: {}", err);
+                    continue;
+                }
+            };
+            let metrics = self.metrics.clone();
+            thread::spawn(move || {
+                if let Err(err) = Self::handle_connection(stream, &metrics) {
+                    debug!("metrics connection failed: {:#}", err);
+                }
+            });
+        }
+    }
+
+    fn handle_connection(stream: TcpStream, metrics: &Metrics) -> Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let request_line = read_line(&mut reader)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let path = parts.next().unwrap_or_default();
+        while !read_line(&mut reader)?.is_empty() {}
+
+        if method != "GET" {
+            return write_response(stream, "405 Method Not Allowed", TEXT_CONTENT_TYPE, b"");
+        }
+        if path != METRICS_PATH {
+            return write_response(stream, "404 Not Found", TEXT_CONTENT_TYPE, b"");
+        }
+        write_response(
+            stream,
+            "200 OK",
+            TEXT_CONTENT_TYPE,
+            metrics.render().as_bytes(),
+        )
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{Read, Write},
+        net::SocketAddr,
+    };
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.set_connections(2, 3);
+        let message = VerifiedPeerMessage::GetAddr;
+        metrics.record_message(Direction::In, &message);
+        metrics.record_message(Direction::In, &message);
+        metrics.record_message(Direction::Out, &message);
+        metrics.update_block_forest(&BlockForest::new());
+        metrics.set_mining_threads(4);
+        metrics.record_hashes(1000);
+        metrics.record_block_mined();
+
+        let mut service = MetricsService::new(
+            MetricsServiceConfig {
+                listen_address: "127.0.0.1:0".into(),
+            },
+            metrics,
+        )
+        .unwrap();
+        let addr = service.listener.local_addr().unwrap();
+        thread::spawn(move || service.run());
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let samples: Vec<_> = body.lines().filter(|line| !line.starts_with('#')).collect();
+        for sample in [
+            "babencoin_peers 2",
+            "babencoin_sessions 3",
+            "babencoin_peer_messages_total{direction=\"in\",kind=\"getaddr\"} 2",
+            "babencoin_peer_messages_total{direction=\"out\",kind=\"getaddr\"} 1",
+            "babencoin_head_index 0",
+            "babencoin_forks 0",
+            "babencoin_mempool_transactions 0",
+            "babencoin_mining_threads 4",
+            "babencoin_hashes_total 1000",
+            "babencoin_blocks_mined_total 1",
+        ] {
+            assert!(
+                samples.contains(&sample),
+                "{} is missing in:\n{}",
+                sample,
+                body
+            );
+        }
+        assert!(body.contains("# TYPE babencoin_hashes_total counter"));
+        assert!(body.contains(&format!(
+            "babencoin_head_info{{hash=\"{}\"}} 1",
+            base64::encode(crate::data::VerifiedBlock::genesis().hash())
+        )));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -1,21 +1,19 @@
 #![forbid(unsafe_code)]
 
-use std::{
-    sync::{Arc, RwLock},
-    thread,
-};
+use std::{sync::Arc, thread, time::Duration};
 
 use crate::{
     data::{
-        Block, BlockAttributes, BlockHash, Transaction, VerifiedBlock, VerifiedTransaction,
-        WalletId, MAX_REWARD,
+        Block, BlockAttributes, BlockHash, BlockHeader, Transaction, VerifiedBlock,
+        VerifiedTransaction, WalletId, MAX_BLOCK_SIZE, MAX_REWARD,
     },
+    node::metrics_service::Metrics,
     util::{deserialize_wallet_id, serialize_wallet_id},
 };
 
 use anyhow::{Context, Result};
-use chrono::Utc;
-use crossbeam::channel::{Receiver, Sender};
+use chrono::{DateTime, TimeZone, Utc};
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
 use crossbeam::{channel, select};
 use log::*;
 use rand::{thread_rng, Rng};
@@ -24,6 +22,13 @@ use serde::{Deserialize, Serialize};
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Nonces to try before checking for a new task.
+const HASH_BATCH_SIZE: u64 = 1000;
+/// How long to wait for the clock to pass the timestamp of the parent block.
+const TIMESTAMP_WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+////////////////////////////////////////////////////////////////////////////////
+
 #[derive(Serialize, Deserialize)]
 pub struct MiningServiceConfig {
     pub thread_count: usize,
@@ -52,15 +57,121 @@ impl Default for MiningServiceConfig {
 pub struct MiningInfo {
     pub block_index: u64,
     pub prev_hash: BlockHash,
+    /// Timestamp of the block to mine on. Mined blocks must have a later one.
+    pub prev_timestamp: DateTime<Utc>,
     pub max_hash: BlockHash,
+    /// Pending transactions as returned by `BlockForest::select_transactions()`:
+    /// the first `max_tx_per_block` of them make a valid block, as long as it is
+    /// within `MAX_BLOCK_SIZE`.
     pub transactions: Vec<VerifiedTransaction>,
 }
 
+/// Block to find a nonce for, shared by the mining threads.
+struct MiningTask {
+    id: u64,
+    header: BlockHeader,
+    prev_timestamp: DateTime<Utc>,
+    transactions: Vec<VerifiedTransaction>,
+}
+
+impl MiningTask {
+    fn new(id: u64, info: MiningInfo, config: &MiningServiceConfig) -> Self {
+        let attrs = BlockAttributes {
+            index: info.block_index,
+            reward: MAX_REWARD,
+            nonce: 0,
+            timestamp: info.prev_timestamp,
+            issuer: config.public_key.clone(),
+            max_hash: info.max_hash,
+            prev_hash: info.prev_hash,
+        };
+        let mut transactions = info.transactions;
+        transactions.truncate(config.max_tx_per_block);
+        transactions.truncate(Self::fitting_transaction_count(&attrs, &transactions));
+        let header = BlockHeader {
+            attrs,
+            transaction_hashes: transactions.iter().map(|tx| *tx.hash()).collect(),
+        };
+        Self {
+            id,
+            header,
+            prev_timestamp: info.prev_timestamp,
+            transactions,
+        }
+    }
+
+    /// How many of the first `transactions` fit in a block of `MAX_BLOCK_SIZE`
+    /// with `attrs`, whatever nonce is found for it.
+    fn fitting_transaction_count(
+        attrs: &BlockAttributes,
+        transactions: &[VerifiedTransaction],
+    ) -> usize {
+        let empty_block = Block {
+            attrs: BlockAttributes {
+                nonce: u64::MAX,
+                ..attrs.clone()
+            },
+            transactions: vec![],
+        };
+        let mut size = serde_json::to_vec(&empty_block)
+            .expect("blocks are serializable")
+            .len();
+        transactions
+            .iter()
+            .take_while(|tx| {
+                // A comma separates the transactions.
+                size += serde_json::to_vec(tx as &Transaction)
+                    .expect("transactions are serializable")
+                    .len()
+                    + 1;
+                size <= MAX_BLOCK_SIZE
+            })
+            .count()
+    }
+
+    /// Try `HASH_BATCH_SIZE` random nonces with the current timestamp. Returns the
+    /// number of hashes computed and the block if one of them fits the max hash.
+    fn try_nonces(&self, rng: &mut impl Rng) -> Result<(u64, Option<VerifiedBlock>)> {
+        // Timestamps have a precision of a second and must grow along the chain.
+        let now = Utc::now().timestamp();
+        if now <= self.prev_timestamp.timestamp() {
+            thread::sleep(TIMESTAMP_WAIT_INTERVAL);
+            return Ok((0, None));
+        }
+
+        let mut header = self.header.clone();
+        header.attrs.timestamp = Utc
+            .timestamp_opt(now, 0)
+            .single()
+            .context("invalid timestamp")?;
+        let start_nonce: u64 = rng.gen();
+        for i in 0..HASH_BATCH_SIZE {
+            header.attrs.nonce = start_nonce.wrapping_add(i);
+            if header.compute_hash() > header.max_hash {
+                continue;
+            }
+            let block = Block {
+                attrs: header.attrs,
+                transactions: self
+                    .transactions
+                    .iter()
+                    .map(|tx| Transaction::from(tx.clone()))
+                    .collect(),
+            };
+            let block = block.verified().context("mined an invalid block")?;
+            return Ok((i + 1, Some(block)));
+        }
+        Ok((HASH_BATCH_SIZE, None))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 pub struct MiningService {
     config: MiningServiceConfig,
     info_receiver: Receiver<MiningInfo>,
     block_sender: Sender<VerifiedBlock>,
-    // TODO: your code goes here.
+    metrics: Arc<Metrics>,
 }
 
 impl MiningService {
@@ -68,16 +179,144 @@ impl MiningService {
         config: MiningServiceConfig,
         info_receiver: Receiver<MiningInfo>,
         block_sender: Sender<VerifiedBlock>,
+        metrics: Arc<Metrics>,
     ) -> Self {
-        // TODO: your code goes here.
-        unimplemented!()
+        Self {
+            config,
+            info_receiver,
+            block_sender,
+            metrics,
+        }
     }
 
     pub fn run(&mut self) {
-        // TODO: your code goes here.
-        unimplemented!()
+        if self.config.thread_count == 0 {
+            info!("mining is disabled");
+            self.metrics.set_mining_threads(0);
+            // Keep the gossip service from blocking on a full channel.
+            for _ in self.info_receiver.iter() {}
+            return;
+        }
+
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(self.config.thread_count)
+            .thread_name(|index| format!("mining-{}", index))
+            .build()
+            .expect("failed to start mining threads");
+        self.metrics.set_mining_threads(self.config.thread_count);
+        let (found_sender, found_receiver) = channel::unbounded();
+        let task_senders: Vec<_> = (0..self.config.thread_count)
+            .map(|_| self.spawn_worker(&thread_pool, found_sender.clone()))
+            .collect();
+
+        let mut current_task: Option<Arc<MiningTask>> = None;
+        let mut next_task_id = 0;
+        // Blocks are never mined twice on the same parent, even if a stale info arrives.
+        let mut last_mined_prev_hash = None;
+        loop {
+            select! {
+                recv(self.info_receiver) -> info => {
+                    let info = match info {
+                        Ok(info) => info,
+                        Err(_) => {
+                            info!("gossip service has stopped, stopping mining");
+                            return;
+                        }
+                    };
+                    if Some(info.prev_hash) == last_mined_prev_hash {
+                        continue;
+                    }
+                    let task = Arc::new(MiningTask::new(next_task_id, info, &self.config));
+                    next_task_id += 1;
+                    debug!(
+                        "mining block #{} with {} transactions",
+                        task.header.index,
+                        task.transactions.len()
+                    );
+                    for sender in &task_senders {
+                        sender.send(Some(task.clone())).ok();
+                    }
+                    current_task = Some(task);
+                }
+                recv(found_receiver) -> found => {
+                    let (task_id, block) = found.expect("mining threads never stop first");
+                    // Several threads may find a block at once, or a stale task may be done.
+                    if current_task.as_ref().map(|task| task.id) != Some(task_id) {
+                        continue;
+                    }
+                    current_task = None;
+                    for sender in &task_senders {
+                        sender.send(None).ok();
+                    }
+
+                    info!(
+                        "mined block #{} {}",
+                        block.index,
+                        base64::encode(block.hash())
+                    );
+                    self.metrics.record_block_mined();
+                    last_mined_prev_hash = Some(block.prev_hash);
+                    if self.block_sender.send(block).is_err() {
+                        info!("gossip service has stopped, stopping mining");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start a mining thread which takes tasks from the returned sender. `None`
+    /// stops mining until the next task.
+    fn spawn_worker(
+        &self,
+        thread_pool: &ThreadPool,
+        found_sender: Sender<(u64, VerifiedBlock)>,
+    ) -> Sender<Option<Arc<MiningTask>>> {
+        let (task_sender, task_receiver) = channel::unbounded();
+        let metrics = self.metrics.clone();
+        thread_pool.spawn(move || {
+            if let Err(err) = Self::mine(task_receiver, found_sender, &metrics) {
+                error!("mining thread failed: {:#}", err);
+            }
+        });
+        task_sender
     }
 
-    // TODO: your code goes here.
+    fn mine(
+        task_receiver: Receiver<Option<Arc<MiningTask>>>,
+        found_sender: Sender<(u64, VerifiedBlock)>,
+        metrics: &Metrics,
+    ) -> Result<()> {
+        let mut rng = thread_rng();
+        let mut task = None;
+        loop {
+            if task.is_none() {
+                match task_receiver.recv() {
+                    Ok(next) => task = next,
+                    Err(_) => return Ok(()),
+                }
+            }
+            loop {
+                match task_receiver.try_recv() {
+                    Ok(next) => task = next,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+
+            let current = match &task {
+                Some(current) => current,
+                None => continue,
+            };
+            let (hash_count, block) = current.try_nonces(&mut rng)?;
+            metrics.record_hashes(hash_count);
+            if let Some(block) = block {
+                if found_sender.send((current.id, block)).is_err() {
+                    return Ok(());
+                }
+                task = None;
+            }
+        }
+    }
 }
 
@@ -1,39 +1,83 @@
 #![forbid(unsafe_code)]
 
-use crate::data::{PeerMessage, VerifiedPeerMessage};
+use crate::{
+    address_book::AddressBook,
+    data::{PeerMessage, VerifiedPeerMessage, MAX_ADDRESSES_PER_MESSAGE},
+    node::metrics_service::{Direction, Metrics},
+    peer_scores::{Misbehaviour, PeerScores, PeerScoringConfig},
+    wire::{Encoding, Frame, FrameReader, DEFAULT_MAX_FRAME_SIZE, MIN_PROTOCOL_VERSION},
+};
 
-use anyhow::{bail, Context, Result};
-use crossbeam::channel::{self, Receiver, Sender};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use crossbeam::{
+    channel::{self, Receiver, Sender, TrySendError},
+    select,
+};
 use log::*;
 use serde::{Deserialize, Serialize};
 
 use std::{
-    collections::HashMap,
-    fmt::{self, Display},
-    io::{self, BufReader, ErrorKind, Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
-    },
+    collections::{HashMap, HashSet},
+    io::{self, BufReader, BufWriter, ErrorKind, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 
-const BUF_SIZE: usize = 65536;
+/// How often to dial the addresses which are not connected.
+const DIAL_INTERVAL: Duration = Duration::from_millis(100);
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to save the address book and to forget the scores of idle peers.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Sessions which don't read what's sent to them for this long are closed.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages waiting to be written to a session, above which it is closed.
+const MAX_SEND_QUEUE_LEN: usize = 10000;
+/// How long to wait for a refused peer to stop sending before dropping the connection.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+////////////////////////////////////////////////////////////////////////////////
 
 pub type SessionId = u64;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct PeerServiceConfig {
     #[serde(with = "humantime_serde")]
     pub dial_cooldown: Duration,
     pub dial_addresses: Vec<String>,
     pub listen_address: Option<String>,
+    /// How many outbound connections to keep, dialing addresses from the address
+    /// book in addition to `dial_addresses`. No addresses are dialed if it's 0.
+    #[serde(default)]
+    pub target_outbound_count: usize,
+    #[serde(default)]
+    pub scoring: PeerScoringConfig,
+    /// Sessions which send a larger frame are dropped.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+}
+
+fn default_max_frame_size() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+impl Default for PeerServiceConfig {
+    fn default() -> Self {
+        Self {
+            dial_cooldown: Duration::default(),
+            dial_addresses: vec![],
+            listen_address: None,
+            target_outbound_count: 0,
+            scoring: PeerScoringConfig::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -59,32 +103,561 @@ pub struct PeerCommand {
 pub enum PeerCommandKind {
     SendMessage(VerifiedPeerMessage),
     Drop,
+    /// Add penalty points to the peer of the session, banning it if it has too many.
+    Penalize(Misbehaviour),
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Sent to the service loop by the accepting, dialing and reading threads.
+enum Notification {
+    Accepted(TcpStream),
+    Dialed {
+        target: String,
+        result: io::Result<TcpStream>,
+    },
+    Received {
+        session_id: SessionId,
+        message: Result<VerifiedPeerMessage>,
+    },
+    Closed(SessionId),
+}
+
+struct Session {
+    address: SocketAddr,
+    /// The address from `dial_addresses` or the address book of an outbound session.
+    dial_target: Option<String>,
+    stream: TcpStream,
+    message_sender: Sender<(VerifiedPeerMessage, Encoding)>,
+    /// Version from the handshake of the peer, if it has sent one.
+    peer_version: Option<u32>,
+}
+
 pub struct PeerService {
     config: PeerServiceConfig,
     peer_event_sender: Sender<PeerEvent>,
     command_receiver: Receiver<PeerCommand>,
-    // TODO: your code goes here.
+    peer_scores: PeerScores,
+    address_book: AddressBook,
+    metrics: Arc<Metrics>,
+    listener: Option<TcpListener>,
+    notification_sender: Sender<Notification>,
+    notification_receiver: Receiver<Notification>,
+    sessions: HashMap<SessionId, Session>,
+    next_session_id: SessionId,
+    /// Dial targets which are being dialed or have a session.
+    busy_targets: HashSet<String>,
+    /// When the dial targets have failed or disconnected last.
+    released_targets: HashMap<String, Instant>,
 }
 
 impl PeerService {
     pub fn new(
         config: PeerServiceConfig,
+        address_book: AddressBook,
         peer_event_sender: Sender<PeerEvent>,
         command_receiver: Receiver<PeerCommand>,
+        metrics: Arc<Metrics>,
     ) -> Result<Self> {
-        // TODO: your code goes here.
-        unimplemented!()
+        let listener = match &config.listen_address {
+            Some(address) => Some(
+                TcpListener::bind(address)
+                    .with_context(|| format!("failed to bind to {}", address))?,
+            ),
+            None => None,
+        };
+        let (notification_sender, notification_receiver) = channel::unbounded();
+        Ok(Self {
+            peer_scores: PeerScores::new(config.scoring.clone()),
+            config,
+            peer_event_sender,
+            command_receiver,
+            address_book,
+            metrics,
+            listener,
+            notification_sender,
+            notification_receiver,
+            sessions: HashMap::new(),
+            next_session_id: 0,
+            busy_targets: HashSet::new(),
+            released_targets: HashMap::new(),
+        })
     }
 
     pub fn run(&mut self) {
-        // TODO: your code goes here.
-        unimplemented!()
+        if let Some(listener) = self.listener.take() {
+            if let Ok(address) = listener.local_addr() {
+                info!("listening on {}", address);
+            }
+            let notification_sender = self.notification_sender.clone();
+            thread::spawn(move || Self::accept(listener, notification_sender));
+        }
+
+        let ticker = channel::tick(DIAL_INTERVAL);
+        let maintenance_ticker = channel::tick(MAINTENANCE_INTERVAL);
+        self.dial();
+        loop {
+            select! {
+                recv(self.command_receiver) -> command => match command {
+                    Ok(command) => self.handle_command(command),
+                    Err(_) => {
+                        info!("gossip service has stopped, stopping peer service");
+                        self.save_address_book();
+                        return;
+                    }
+                },
+                recv(self.notification_receiver) -> notification => {
+                    self.handle_notification(notification.expect("the service holds a sender"));
+                }
+                recv(ticker) -> _ => self.dial(),
+                recv(maintenance_ticker) -> _ => self.maintain(),
+            }
+        }
+    }
+
+    fn maintain(&mut self) {
+        let now = Instant::now();
+        self.peer_scores.forget_idle(now);
+        let dial_cooldown = self.config.dial_cooldown;
+        self.released_targets
+            .retain(|_, released_at| now.saturating_duration_since(*released_at) < dial_cooldown);
+        self.save_address_book();
+    }
+
+    fn save_address_book(&mut self) {
+        if let Err(err) = self.address_book.save() {
+            warn!("failed to save address book: {:#}", err);
+        }
+    }
+
+    fn accept(listener: TcpListener, notification_sender: Sender<Notification>) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if notification_sender
+                        .send(Notification::Accepted(stream))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(err) => warn!("failed to accept connection: {}", err),
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: PeerCommand) {
+        let session_id = command.session_id;
+        if !self.sessions.contains_key(&session_id) {
+            debug!("session {} is closed, ignoring command", session_id);
+            return;
+        }
+        match command.command_kind {
+            PeerCommandKind::SendMessage(message) => self.send_message(session_id, message),
+            PeerCommandKind::Drop => self.close_session(session_id),
+            PeerCommandKind::Penalize(misbehaviour) => self.penalize(session_id, misbehaviour),
+        }
+    }
+
+    fn send_message(&mut self, session_id: SessionId, message: VerifiedPeerMessage) {
+        let session = match self.sessions.get(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        self.metrics.record_message(Direction::Out, &message);
+        // Peers which don't know the handshake yet can read it only in JSON.
+        let encoding = match message {
+            VerifiedPeerMessage::Handshake(_) => Encoding::Json,
+            _ => Encoding::negotiate(session.peer_version),
+        };
+        if let Err(TrySendError::Full(_)) = session.message_sender.try_send((message, encoding)) {
+            warn!("session {} doesn't keep up with the messages", session_id);
+            self.close_session(session_id);
+        }
+    }
+
+    fn penalize(&mut self, session_id: SessionId, misbehaviour: Misbehaviour) {
+        let ip = match self.sessions.get(&session_id) {
+            Some(session) => session.address.ip(),
+            None => return,
+        };
+        if self.peer_scores.penalize(ip, misbehaviour, Instant::now()) {
+            self.drop_peer(ip);
+        }
+    }
+
+    /// Close all the sessions with a banned peer.
+    fn drop_peer(&mut self, ip: IpAddr) {
+        let session_ids = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.address.ip() == ip)
+            .map(|(session_id, _)| *session_id)
+            .collect::<Vec<_>>();
+        for session_id in session_ids {
+            self.close_session(session_id);
+        }
     }
 
-    // TODO: your code goes here.
+    fn handle_notification(&mut self, notification: Notification) {
+        match notification {
+            Notification::Accepted(stream) => {
+                if self.is_banned(&stream) {
+                    thread::spawn(move || Self::refuse(stream));
+                } else {
+                    self.start_session(stream, None);
+                }
+            }
+            Notification::Dialed { target, result } => match result {
+                Ok(stream) if self.is_banned(&stream) => {
+                    debug!("dialed banned peer {}, dropping connection", target);
+                    self.release_target(target);
+                }
+                Ok(stream) => self.start_session(stream, Some(target)),
+                Err(err) => {
+                    warn!("failed to dial {}: {}", target, err);
+                    self.release_target(target);
+                }
+            },
+            Notification::Received {
+                session_id,
+                message,
+            } => {
+                let ip = match self.sessions.get(&session_id) {
+                    Some(session) => session.address.ip(),
+                    None => return,
+                };
+                match message {
+                    Ok(message) => {
+                        self.metrics.record_message(Direction::In, &message);
+                        let is_request = matches!(
+                            message,
+                            VerifiedPeerMessage::Request { .. }
+                                | VerifiedPeerMessage::GetHeaders { .. }
+                                | VerifiedPeerMessage::GetBlocks { .. }
+                                | VerifiedPeerMessage::GetAddr
+                        );
+                        if is_request && self.peer_scores.record_request(ip, Instant::now()) {
+                            self.drop_peer(ip);
+                            return;
+                        }
+                        match message {
+                            VerifiedPeerMessage::Handshake(handshake)
+                                if handshake.version < MIN_PROTOCOL_VERSION =>
+                            {
+                                warn!(
+                                    "session {} has incompatible protocol version {}",
+                                    session_id, handshake.version
+                                );
+                                self.close_session(session_id);
+                            }
+                            VerifiedPeerMessage::Handshake(handshake) => {
+                                if let Some(session) = self.sessions.get_mut(&session_id) {
+                                    session.peer_version = Some(handshake.version);
+                                }
+                                self.send_event(
+                                    session_id,
+                                    PeerEventKind::NewMessage(VerifiedPeerMessage::Handshake(
+                                        handshake,
+                                    )),
+                                );
+                            }
+                            VerifiedPeerMessage::GetAddr => {
+                                let addresses = self.address_book.recent(MAX_ADDRESSES_PER_MESSAGE);
+                                self.send_message(
+                                    session_id,
+                                    VerifiedPeerMessage::Addr { addresses },
+                                );
+                            }
+                            VerifiedPeerMessage::Addr { addresses } => {
+                                debug!("session {} sent {} addresses", session_id, addresses.len());
+                                self.address_book.add(addresses, Utc::now());
+                            }
+                            message => {
+                                self.send_event(session_id, PeerEventKind::NewMessage(message))
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!("session {} sent a malformed message: {:#}", session_id, err);
+                        self.penalize(session_id, Misbehaviour::MalformedMessage);
+                        self.close_session(session_id);
+                    }
+                }
+            }
+            Notification::Closed(session_id) => self.close_session(session_id),
+        }
+    }
+
+    fn is_banned(&mut self, stream: &TcpStream) -> bool {
+        match stream.peer_addr() {
+            Ok(address) => self.peer_scores.is_banned(address.ip(), Instant::now()),
+            Err(_) => false,
+        }
+    }
+
+    /// Close a connection without a session, reading what the peer has already sent
+    /// so that it gets the end of the stream rather than a reset.
+    fn refuse(mut stream: TcpStream) {
+        if let Ok(address) = stream.peer_addr() {
+            debug!("refusing connection from banned peer {}", address);
+        }
+        stream.shutdown(Shutdown::Write).ok();
+        if stream.set_read_timeout(Some(REFUSE_TIMEOUT)).is_ok() {
+            io::copy(&mut stream, &mut io::sink()).ok();
+        }
+    }
+
+    /// Dial the addresses from `dial_addresses` which are not connected, unless they
+    /// have failed or disconnected less than `dial_cooldown` ago or are banned.
+    fn dial(&mut self) {
+        let now = Instant::now();
+        for target in self.config.dial_addresses.clone() {
+            if self.busy_targets.contains(&target) {
+                continue;
+            }
+            if let Some(released_at) = self.released_targets.get(&target) {
+                if now.saturating_duration_since(*released_at) < self.config.dial_cooldown {
+                    continue;
+                }
+            }
+
+            let addresses = match target.to_socket_addrs() {
+                Ok(addresses) => addresses
+                    .filter(|address| !self.peer_scores.is_banned(address.ip(), now))
+                    .collect::<Vec<_>>(),
+                Err(err) => {
+                    warn!("failed to resolve {}: {}", target, err);
+                    self.released_targets.insert(target, now);
+                    continue;
+                }
+            };
+            if addresses.is_empty() {
+                debug!("not dialing {}, the peer is banned", target);
+                self.released_targets.insert(target, now);
+                continue;
+            }
+            self.spawn_dial(target, addresses);
+        }
+        self.dial_address_book(now);
+    }
+
+    /// Dial the addresses from the address book which are neither connected nor
+    /// banned, while there are fewer than `target_outbound_count` outbound sessions
+    /// and dials in progress.
+    fn dial_address_book(&mut self, now: Instant) {
+        let mut outbound_count = self.busy_targets.len();
+        if outbound_count >= self.config.target_outbound_count {
+            return;
+        }
+        let connected = self
+            .sessions
+            .values()
+            .filter(|session| session.dial_target.is_some())
+            .map(|session| session.address)
+            .collect::<HashSet<_>>();
+        for address in self
+            .address_book
+            .dial_candidates(self.config.dial_cooldown, now)
+        {
+            if outbound_count >= self.config.target_outbound_count {
+                break;
+            }
+            let target = address.to_string();
+            if self.busy_targets.contains(&target)
+                || connected.contains(&address)
+                || self.peer_scores.is_banned(address.ip(), now)
+            {
+                continue;
+            }
+            self.address_book.mark_dialed(address, now);
+            self.spawn_dial(target, vec![address]);
+            outbound_count += 1;
+        }
+    }
+
+    fn spawn_dial(&mut self, target: String, addresses: Vec<SocketAddr>) {
+        debug!("dialing {}", target);
+        self.busy_targets.insert(target.clone());
+        let notification_sender = self.notification_sender.clone();
+        thread::spawn(move || {
+            let result = Self::connect(&addresses);
+            notification_sender
+                .send(Notification::Dialed { target, result })
+                .ok();
+        });
+    }
+
+    fn connect(addresses: &[SocketAddr]) -> io::Result<TcpStream> {
+        let mut last_err = io::Error::new(ErrorKind::NotFound, "no addresses to dial");
+        for address in addresses {
+            match TcpStream::connect_timeout(address, DIAL_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn release_target(&mut self, target: String) {
+        self.busy_targets.remove(&target);
+        self.released_targets.insert(target, Instant::now());
+    }
+
+    fn start_session(&mut self, stream: TcpStream, dial_target: Option<String>) {
+        let session_id = self.next_session_id;
+        let session = match self.spawn_session(session_id, stream, dial_target.clone()) {
+            Ok(session) => session,
+            Err(err) => {
+                warn!("failed to start session: {:#}", err);
+                if let Some(target) = dial_target {
+                    self.release_target(target);
+                }
+                return;
+            }
+        };
+        self.next_session_id += 1;
+        let is_outbound = session.dial_target.is_some();
+        info!(
+            "session {} with {} started ({})",
+            session_id,
+            session.address,
+            if is_outbound { "outbound" } else { "inbound" }
+        );
+        if is_outbound {
+            self.address_book.mark_seen(session.address, Utc::now());
+        }
+        self.sessions.insert(session_id, session);
+        self.update_connection_metrics();
+        self.send_event(session_id, PeerEventKind::Connected);
+        if is_outbound {
+            self.send_message(session_id, VerifiedPeerMessage::GetAddr);
+        }
+    }
+
+    /// Start the reading and writing threads of a session.
+    fn spawn_session(
+        &self,
+        session_id: SessionId,
+        stream: TcpStream,
+        dial_target: Option<String>,
+    ) -> Result<Session> {
+        let address = stream.peer_addr().context("failed to get peer address")?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let reader_stream = stream.try_clone()?;
+        let writer_stream = stream.try_clone()?;
+
+        let notification_sender = self.notification_sender.clone();
+        let max_frame_size = self.config.max_frame_size;
+        thread::spawn(move || {
+            Self::read_messages(
+                session_id,
+                reader_stream,
+                max_frame_size,
+                notification_sender,
+            )
+        });
+        let (message_sender, message_receiver) = channel::bounded(MAX_SEND_QUEUE_LEN);
+        thread::spawn(move || Self::write_messages(session_id, writer_stream, message_receiver));
+
+        Ok(Session {
+            address,
+            dial_target,
+            stream,
+            message_sender,
+            peer_version: None,
+        })
+    }
+
+    fn close_session(&mut self, session_id: SessionId) {
+        let session = match self.sessions.remove(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        // The reading thread stops, and the writing one does once the sender is dropped.
+        session.stream.shutdown(Shutdown::Both).ok();
+        info!("session {} with {} ended", session_id, session.address);
+        if let Some(target) = session.dial_target {
+            self.release_target(target);
+        }
+        self.update_connection_metrics();
+        self.send_event(session_id, PeerEventKind::Disconnected);
+    }
+
+    fn update_connection_metrics(&self) {
+        let peers = self
+            .sessions
+            .values()
+            .map(|session| session.address.ip())
+            .collect::<HashSet<_>>();
+        self.metrics
+            .set_connections(peers.len(), self.sessions.len());
+    }
+
+    fn send_event(&self, session_id: SessionId, event_kind: PeerEventKind) {
+        let event = PeerEvent {
+            session_id,
+            event_kind,
+        };
+        if self.peer_event_sender.send(event).is_err() {
+            warn!("gossip service has stopped");
+        }
+    }
+
+    fn read_messages(
+        session_id: SessionId,
+        stream: TcpStream,
+        max_frame_size: usize,
+        notification_sender: Sender<Notification>,
+    ) {
+        let mut reader = FrameReader::new(BufReader::new(stream), max_frame_size);
+        loop {
+            let message = match reader.read_frame() {
+                Ok(Some(frame)) => frame.decode().and_then(PeerMessage::verified),
+                Err(err) if err.kind() == ErrorKind::InvalidData => Err(err.into()),
+                Ok(None) => break,
+                Err(err) => {
+                    debug!("failed to read from session {}: {}", session_id, err);
+                    break;
+                }
+            };
+            let is_malformed = message.is_err();
+            let notification = Notification::Received {
+                session_id,
+                message,
+            };
+            if notification_sender.send(notification).is_err() || is_malformed {
+                return;
+            }
+        }
+        notification_sender
+            .send(Notification::Closed(session_id))
+            .ok();
+    }
+
+    fn write_messages(
+        session_id: SessionId,
+        stream: TcpStream,
+        message_receiver: Receiver<(VerifiedPeerMessage, Encoding)>,
+    ) {
+        let mut writer = BufWriter::new(&stream);
+        for (message, encoding) in message_receiver.iter() {
+            let result = Frame::encode(&PeerMessage::from(message), encoding)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("{:#}", err)))
+                .and_then(|frame| frame.write_to(&mut writer))
+                .and_then(|()| {
+                    if message_receiver.is_empty() {
+                        writer.flush()?;
+                    }
+                    Ok(())
+                });
+            if let Err(err) = result {
+                debug!("failed to write to session {}: {}", session_id, err);
+                // Let the reading thread notice.
+                stream.shutdown(Shutdown::Both).ok();
+                return;
+            }
+        }
+    }
 }
 
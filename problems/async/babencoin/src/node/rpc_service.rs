@@ -0,0 +1,472 @@
+#![forbid(unsafe_code)]
+
+use crate::{
+    block_forest::BlockForest,
+    data::{
+        Block, BlockHash, Transaction, TransactionHash, VerifiedBlock, VerifiedTransaction,
+        WalletId, HASH_LEN,
+    },
+    util::{deserialize_base64_fixed, deserialize_wallet_id, serialize_base64},
+};
+
+use anyhow::{bail, Context, Result};
+use crossbeam::channel::{self, Sender};
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const MAX_BODY_SIZE: usize = 1 << 20;
+const MAX_LINE_SIZE: u64 = 8192;
+pub(super) const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const JSON_CONTENT_TYPE: &str = "application/json";
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// See https://www.jsonrpc.org/specification#error_object.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const TRANSACTION_REJECTED: i64 = -32000;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct RpcServiceConfig {
+    pub listen_address: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum RpcRequest {
+    GetHead,
+    GetBlockByHash(BlockHash),
+    /// Block of the given index on the chain ending at the head.
+    GetBlockByIndex(u64),
+    GetPendingTransactions,
+    /// Balance at the head.
+    GetBalance(WalletId),
+    SubmitTransaction(Box<Transaction>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// A request waiting to be served by the gossip service, which owns the `BlockForest`.
+pub struct RpcCall {
+    pub request: RpcRequest,
+    response_sender: Sender<Result<Value, RpcError>>,
+}
+
+impl RpcCall {
+    /// Send the request to the gossip service and wait for the response.
+    pub(crate) fn send(
+        call_sender: &Sender<RpcCall>,
+        request: RpcRequest,
+    ) -> Result<Value, RpcError> {
+        let (response_sender, response_receiver) = channel::bounded(1);
+        call_sender
+            .send(RpcCall {
+                request,
+                response_sender,
+            })
+            .map_err(|_| RpcError::new(INTERNAL_ERROR, "node is shutting down"))?;
+        response_receiver
+            .recv_timeout(RESPONSE_TIMEOUT)
+            .map_err(|_| RpcError::new(INTERNAL_ERROR, "request timed out"))?
+    }
+
+    /// Serve the request from `block_forest` and send the response back. Returns
+    /// the submitted transaction if it has been accepted, so that the caller could
+    /// forward it to the peers.
+    pub fn execute(self, block_forest: &mut BlockForest) -> Option<VerifiedTransaction> {
+        let mut accepted_tx = None;
+        let result = match self.request {
+            RpcRequest::GetHead => Ok(block_info(block_forest.head())),
+            RpcRequest::GetBlockByHash(hash) => Ok(block_forest
+                .find_block(&hash)
+                .map_or(Value::Null, block_info)),
+            RpcRequest::GetBlockByIndex(index) => Ok(block_forest
+                .find_block_by_index(index)
+                .map_or(Value::Null, block_info)),
+            RpcRequest::GetPendingTransactions => Ok(Value::Array(
+                block_forest
+                    .pending_transactions()
+                    .iter()
+                    .map(|tx| transaction_info(tx.hash(), tx))
+                    .collect(),
+            )),
+            RpcRequest::GetBalance(wallet) => Ok(json!({
+                "balance": block_forest.balance(&wallet),
+                "block_index": block_forest.head().index,
+                "next_nonce": block_forest.next_nonce(&wallet),
+            })),
+            RpcRequest::SubmitTransaction(tx) => tx
+                .verified()
+                .context("transaction verification failed")
+                .and_then(|tx| {
+                    block_forest.add_transaction(tx.clone())?;
+                    Ok(tx)
+                })
+                .map(|tx| {
+                    let result = json!({ "hash": base64::encode(tx.hash()) });
+                    accepted_tx = Some(tx);
+                    result
+                })
+                .map_err(|err| RpcError::new(TRANSACTION_REJECTED, format!("{:#}", err))),
+        };
+        // The client may have gone already.
+        self.response_sender.send(result).ok();
+        accepted_tx
+    }
+}
+
+#[derive(Serialize)]
+struct BlockInfo {
+    #[serde(serialize_with = "serialize_base64")]
+    hash: BlockHash,
+    #[serde(flatten)]
+    block: Block,
+}
+
+#[derive(Serialize)]
+struct TransactionInfo<'a> {
+    #[serde(serialize_with = "serialize_base64")]
+    hash: TransactionHash,
+    #[serde(flatten)]
+    tx: &'a Transaction,
+}
+
+fn block_info(block: &impl AsRef<VerifiedBlock>) -> Value {
+    let block = block.as_ref();
+    serde_json::to_value(BlockInfo {
+        hash: *block.hash(),
+        block: block.to_block(),
+    })
+    .expect("blocks are serializable")
+}
+
+fn transaction_info(hash: &TransactionHash, tx: &Transaction) -> Value {
+    serde_json::to_value(TransactionInfo { hash: *hash, tx })
+        .expect("transactions are serializable")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct HashParams {
+    #[serde(deserialize_with = "deserialize_base64_fixed::<'_, _, HASH_LEN>")]
+    hash: BlockHash,
+}
+
+#[derive(Deserialize)]
+struct IndexParams {
+    index: u64,
+}
+
+#[derive(Deserialize)]
+struct WalletParams {
+    #[serde(deserialize_with = "deserialize_wallet_id")]
+    wallet: WalletId,
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn parse_request(method: &str, params: Value) -> Result<RpcRequest, RpcError> {
+    Ok(match method {
+        "get_head" => RpcRequest::GetHead,
+        "get_block_by_hash" => RpcRequest::GetBlockByHash(parse_params::<HashParams>(params)?.hash),
+        "get_block_by_index" => {
+            RpcRequest::GetBlockByIndex(parse_params::<IndexParams>(params)?.index)
+        }
+        "get_pending_transactions" => RpcRequest::GetPendingTransactions,
+        "get_balance" => RpcRequest::GetBalance(parse_params::<WalletParams>(params)?.wallet),
+        "submit_transaction" => RpcRequest::SubmitTransaction(parse_params(params)?),
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {}", method),
+            ))
+        }
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Serves JSON-RPC 2.0 requests sent as HTTP POST requests. Every connection serves
+/// a single request in its own thread.
+pub struct RpcService {
+    listener: TcpListener,
+    call_sender: Sender<RpcCall>,
+}
+
+impl RpcService {
+    pub fn new(config: RpcServiceConfig, call_sender: Sender<RpcCall>) -> Result<Self> {
+        let listener = TcpListener::bind(&config.listen_address)
+            .with_context(|| format!("failed to bind to {}", config.listen_address))?;
+        Ok(Self {
+            listener,
+            call_sender,
+        })
+    }
+
+    pub fn run(&mut self) {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("failed to accept rpc connection: {}", err);
+                    continue;
+                }
+            };
+            let call_sender = self.call_sender.clone();
+            thread::spawn(move || {
+                if let Err(err) = Self::handle_connection(stream, &call_sender) {
+                    debug!("rpc connection failed: {:#}", err);
+                }
+            });
+        }
+    }
+
+    fn handle_connection(stream: TcpStream, call_sender: &Sender<RpcCall>) -> Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let request_line = read_line(&mut reader)?;
+        let method = request_line.split_whitespace().next().unwrap_or_default();
+        let mut content_length = 0;
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().context("invalid content length")?;
+                }
+            }
+        }
+
+        if method != "POST" {
+            return write_response(stream, "405 Method Not Allowed", JSON_CONTENT_TYPE, b"");
+        }
+        if content_length > MAX_BODY_SIZE {
+            return write_response(stream, "413 Payload Too Large", JSON_CONTENT_TYPE, b"");
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body)?;
+
+        let response = Self::serve(&body, call_sender);
+        write_response(
+            stream,
+            "200 OK",
+            JSON_CONTENT_TYPE,
+            &serde_json::to_vec(&response)?,
+        )
+    }
+
+    fn serve(body: &[u8], call_sender: &Sender<RpcCall>) -> Value {
+        let envelope: Envelope = match serde_json::from_slice::<Value>(body) {
+            Err(err) => {
+                return error_response(Value::Null, RpcError::new(PARSE_ERROR, err.to_string()))
+            }
+            Ok(value) => match serde_json::from_value(value) {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    return error_response(
+                        Value::Null,
+                        RpcError::new(INVALID_REQUEST, err.to_string()),
+                    )
+                }
+            },
+        };
+
+        let result = parse_request(&envelope.method, envelope.params)
+            .and_then(|request| RpcCall::send(call_sender, request));
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": envelope.id, "result": result }),
+            Err(err) => error_response(envelope.id, err),
+        }
+    }
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": err.code, "message": err.message },
+    })
+}
+
+pub(super) fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_SIZE).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        bail!("http request line is too long or incomplete");
+    }
+    Ok(line.trim_end().to_string())
+}
+
+pub(super) fn write_response(
+    mut stream: TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    Ok(stream.flush()?)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::parse_pkcs8_private;
+
+    use std::net::SocketAddr;
+
+    fn start_rpc_service(mut block_forest: BlockForest) -> SocketAddr {
+        let (call_sender, call_receiver) = channel::bounded::<RpcCall>(1);
+        let mut service = RpcService::new(
+            RpcServiceConfig {
+                listen_address: "127.0.0.1:0".into(),
+            },
+            call_sender,
+        )
+        .unwrap();
+        let addr = service.listener.local_addr().unwrap();
+        thread::spawn(move || service.run());
+        thread::spawn(move || {
+            for call in call_receiver {
+                call.execute(&mut block_forest);
+            }
+        });
+        addr
+    }
+
+    fn post(addr: SocketAddr, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn call(addr: SocketAddr, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let response = post(addr, &request.to_string());
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_rpc() {
+        let key = parse_pkcs8_private(include_str!("../../data/test.pem")).unwrap();
+        let block: Block =
+            serde_json::from_str(include_str!("../../data/test_block.json")).unwrap();
+        let block = block.verified().unwrap();
+        let genesis = VerifiedBlock::genesis();
+
+        let mut block_forest = BlockForest::new();
+        block_forest.add_block(block.clone()).unwrap();
+        let addr = start_rpc_service(block_forest);
+
+        let head = call(addr, "get_head", Value::Null);
+        assert_eq!(head["result"]["index"], 1);
+        assert_eq!(head["result"]["hash"], base64::encode(block.hash()));
+
+        let response = call(addr, "get_block_by_index", json!({ "index": 0 }));
+        assert_eq!(response["result"]["hash"], base64::encode(genesis.hash()));
+        let response = call(addr, "get_block_by_index", json!({ "index": 2 }));
+        assert_eq!(response["result"], Value::Null);
+        let response = call(
+            addr,
+            "get_block_by_hash",
+            json!({ "hash": head["result"]["hash"] }),
+        );
+        assert_eq!(response["result"], head["result"]);
+
+        let tx = VerifiedTransaction::sign(&key, genesis.issuer.clone(), 100, 5, 1, "rpc".into())
+            .unwrap();
+        let tx_json = serde_json::to_value(Transaction::from(tx.clone())).unwrap();
+        let response = call(addr, "get_balance", json!({ "wallet": tx_json["sender"] }));
+        assert_eq!(response["result"]["balance"], 500);
+        assert_eq!(response["result"]["next_nonce"], 1);
+
+        let mut forged_tx_json = tx_json.clone();
+        forged_tx_json["amount"] = json!(200);
+        let response = call(addr, "submit_transaction", forged_tx_json);
+        assert_eq!(response["error"]["code"], TRANSACTION_REJECTED);
+
+        let response = call(addr, "submit_transaction", tx_json.clone());
+        assert_eq!(response["result"]["hash"], base64::encode(tx.hash()));
+        let response = call(addr, "get_pending_transactions", Value::Null);
+        let pending = response["result"].as_array().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0]["hash"], base64::encode(tx.hash()));
+        assert_eq!(pending[0]["comment"], "rpc");
+        let response = call(addr, "get_balance", json!({ "wallet": tx_json["sender"] }));
+        assert_eq!(response["result"]["balance"], 500);
+        assert_eq!(response["result"]["next_nonce"], 2);
+
+        let response = call(addr, "get_balance", json!({ "wallet": "???" }));
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = call(addr, "get_mempool", Value::Null);
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(response["id"], 1);
+
+        let response = post(addr, "{");
+        assert!(response.contains(&PARSE_ERROR.to_string()));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+    }
+}
@@ -0,0 +1,566 @@
+#![forbid(unsafe_code)]
+
+use crate::{
+    block_forest::BlockForest,
+    data::{Block, Transaction, VerifiedBlock, VerifiedPeerMessage, VerifiedTransaction},
+    node::{
+        gossip_service::{GossipChannels, GossipService},
+        metrics_service::Metrics,
+        mining_service::MiningService,
+        peer_service::{PeerCommand, PeerCommandKind, PeerEvent, PeerEventKind, SessionId},
+        rpc_service::{RpcCall, RpcError, RpcRequest},
+        Config,
+    },
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam::channel::{self, Receiver, Select, Sender};
+use log::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::Value;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConfig {
+    /// Seed of the random losses and jitter.
+    pub seed: u64,
+    /// Delay of every message.
+    pub latency: Duration,
+    /// Random extra delay of up to this. Messages of a session still arrive in order.
+    pub jitter: Duration,
+    /// Probability to lose a message, from 0 to 1.
+    pub loss_rate: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub delivered: u64,
+    pub lost: u64,
+}
+
+/// Peer service side of a simulated node: what `PeerService::new()` would get.
+pub struct PeerEndpoint {
+    pub event_receiver: Receiver<PeerEvent>,
+    pub command_sender: Sender<PeerCommand>,
+}
+
+struct Delivery {
+    from: usize,
+    to: usize,
+    message: VerifiedPeerMessage,
+}
+
+struct NetworkState {
+    config: NetworkConfig,
+    rng: StdRng,
+    event_senders: Vec<Sender<PeerEvent>>,
+    /// Partition group of every node. Only nodes of the same group are connected.
+    groups: Vec<usize>,
+    /// `connected[i][j]` tells whether node `i` has a session with node `j`.
+    connected: Vec<Vec<bool>>,
+    /// Messages in flight by delivery time and sequence number.
+    queue: BTreeMap<(Instant, u64), Delivery>,
+    last_delivery: HashMap<(usize, usize), Instant>,
+    next_seq: u64,
+    stats: NetworkStats,
+}
+
+impl NetworkState {
+    fn send_event(&self, node: usize, peer: usize, event_kind: PeerEventKind) {
+        // The node may have stopped already.
+        self.event_senders[node]
+            .send(PeerEvent {
+                session_id: peer as SessionId,
+                event_kind,
+            })
+            .ok();
+    }
+
+    fn set_connected(&mut self, first: usize, second: usize, connected: bool) {
+        if self.connected[first][second] == connected {
+            return;
+        }
+        self.connected[first][second] = connected;
+        self.connected[second][first] = connected;
+        let event_kind = if connected {
+            PeerEventKind::Connected
+        } else {
+            PeerEventKind::Disconnected
+        };
+        debug!(
+            "nodes {} and {} are {}",
+            first,
+            second,
+            if connected {
+                "connected"
+            } else {
+                "disconnected"
+            }
+        );
+        self.send_event(first, second, event_kind.clone());
+        self.send_event(second, first, event_kind);
+    }
+
+    /// Connect the nodes of the same group and disconnect the rest.
+    fn apply_groups(&mut self) {
+        for first in 0..self.groups.len() {
+            for second in first + 1..self.groups.len() {
+                let connected = self.groups[first] == self.groups[second];
+                self.set_connected(first, second, connected);
+            }
+        }
+    }
+
+    fn handle_command(&mut self, from: usize, command: PeerCommand, now: Instant) {
+        let to = command.session_id as usize;
+        if to >= self.groups.len() || to == from {
+            warn!("node {} sent a command to unknown session {}", from, to);
+            return;
+        }
+
+        match command.command_kind {
+            PeerCommandKind::SendMessage(message) => {
+                if !self.connected[from][to] {
+                    return;
+                }
+                if self.rng.gen_bool(self.config.loss_rate) {
+                    self.stats.lost += 1;
+                    return;
+                }
+                let jitter = self.rng.gen_range(Duration::ZERO..=self.config.jitter);
+                let last_delivery = self.last_delivery.entry((from, to)).or_insert(now);
+                let deliver_at = (now + self.config.latency + jitter).max(*last_delivery);
+                *last_delivery = deliver_at;
+                self.queue
+                    .insert((deliver_at, self.next_seq), Delivery { from, to, message });
+                self.next_seq += 1;
+            }
+            PeerCommandKind::Drop => self.set_connected(from, to, false),
+            PeerCommandKind::Penalize(misbehaviour) => {
+                info!("node {} penalized node {} for {}", from, to, misbehaviour)
+            }
+        }
+    }
+
+    /// Deliver the messages due by `now`. Messages of the sessions closed while they
+    /// were in flight are lost.
+    fn deliver(&mut self, now: Instant) {
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let Delivery { from, to, message } = entry.remove();
+            if self.connected[from][to] {
+                self.stats.delivered += 1;
+                self.send_event(to, from, PeerEventKind::NewMessage(message));
+            } else {
+                self.stats.lost += 1;
+            }
+        }
+    }
+
+    fn next_delivery(&self) -> Option<Instant> {
+        self.queue.keys().next().map(|(deliver_at, _)| *deliver_at)
+    }
+}
+
+/// In-memory replacement of the peer services of several nodes. Every node has a
+/// session with every other node it's connected to, and the id of the session is
+/// the index of the other node.
+///
+/// Losses and jitter are drawn from a seeded generator, so a simulation is
+/// reproducible as long as the nodes send the same messages in the same order.
+pub struct VirtualNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl VirtualNetwork {
+    /// Start routing messages between `node_count` connected nodes. The routing
+    /// thread stops once any of the nodes drops its command sender.
+    pub fn new(config: NetworkConfig, node_count: usize) -> (Self, Vec<PeerEndpoint>) {
+        let mut endpoints = vec![];
+        let mut event_senders = vec![];
+        let mut command_receivers = vec![];
+        for _ in 0..node_count {
+            let (event_sender, event_receiver) = channel::unbounded();
+            let (command_sender, command_receiver) = channel::unbounded();
+            endpoints.push(PeerEndpoint {
+                event_receiver,
+                command_sender,
+            });
+            event_senders.push(event_sender);
+            command_receivers.push(command_receiver);
+        }
+
+        let mut state = NetworkState {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            event_senders,
+            groups: vec![0; node_count],
+            connected: vec![vec![false; node_count]; node_count],
+            queue: BTreeMap::new(),
+            last_delivery: HashMap::new(),
+            next_seq: 0,
+            stats: NetworkStats::default(),
+        };
+        state.apply_groups();
+
+        let state = Arc::new(Mutex::new(state));
+        let router_state = state.clone();
+        thread::Builder::new()
+            .name("virtual-network".into())
+            .spawn(move || Self::route(router_state, command_receivers))
+            .expect("failed to spawn virtual network thread");
+        (Self { state }, endpoints)
+    }
+
+    fn route(state: Arc<Mutex<NetworkState>>, command_receivers: Vec<Receiver<PeerCommand>>) {
+        let mut select = Select::new();
+        for receiver in &command_receivers {
+            select.recv(receiver);
+        }
+
+        loop {
+            let next_delivery = state.lock().unwrap().next_delivery();
+            let operation = match next_delivery {
+                Some(deadline) => select.select_deadline(deadline).ok(),
+                None => Some(select.select()),
+            };
+            if let Some(operation) = operation {
+                let from = operation.index();
+                match operation.recv(&command_receivers[from]) {
+                    Ok(command) => {
+                        state
+                            .lock()
+                            .unwrap()
+                            .handle_command(from, command, Instant::now());
+                    }
+                    Err(_) => {
+                        debug!("node {} has stopped, stopping virtual network", from);
+                        return;
+                    }
+                }
+            }
+            state.lock().unwrap().deliver(Instant::now());
+        }
+    }
+
+    /// Split the nodes into groups which can't reach each other. Nodes which are not
+    /// listed are isolated. Sessions between the groups are closed, and the missing
+    /// sessions within the groups are opened.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut state = self.state.lock().unwrap();
+        let node_count = state.groups.len();
+        // Isolated nodes get groups of their own.
+        let mut node_groups: Vec<_> = (groups.len()..groups.len() + node_count).collect();
+        for (group, nodes) in groups.iter().enumerate() {
+            for &node in nodes.iter() {
+                node_groups[node] = group;
+            }
+        }
+        info!("partitioning the network: {:?}", groups);
+        state.groups = node_groups;
+        state.apply_groups();
+    }
+
+    /// Connect all the nodes, including the ones which have dropped their sessions.
+    pub fn heal(&self) {
+        let mut state = self.state.lock().unwrap();
+        info!("healing the network");
+        state.groups.iter_mut().for_each(|group| *group = 0);
+        state.apply_groups();
+    }
+
+    pub fn set_latency(&self, latency: Duration, jitter: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.config.latency = latency;
+        state.config.jitter = jitter;
+    }
+
+    pub fn set_loss_rate(&self, loss_rate: f64) {
+        self.state.lock().unwrap().config.loss_rate = loss_rate;
+    }
+
+    pub fn is_connected(&self, first: usize, second: usize) -> bool {
+        self.state.lock().unwrap().connected[first][second]
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct SimulatedNode {
+    block_sender: Sender<VerifiedBlock>,
+    rpc_call_sender: Sender<RpcCall>,
+}
+
+/// Several nodes running the gossip and mining services in one process, connected
+/// through a `VirtualNetwork` in place of the peer services.
+pub struct Simulation {
+    network: VirtualNetwork,
+    nodes: Vec<SimulatedNode>,
+}
+
+impl Simulation {
+    /// Start a node for every config. Their peer and RPC service settings are ignored.
+    pub fn new(network_config: NetworkConfig, configs: Vec<Config>) -> Result<Self> {
+        let (network, endpoints) = VirtualNetwork::new(network_config, configs.len());
+        let mut nodes = vec![];
+        for (index, (config, endpoint)) in configs.into_iter().zip(endpoints).enumerate() {
+            let mut block_forest = match &config.data_dir {
+                Some(data_dir) => BlockForest::open(data_dir)
+                    .with_context(|| format!("failed to open block store of node {}", index))?,
+                None => BlockForest::new(),
+            };
+            block_forest.set_mempool_config(config.mempool);
+
+            let (block_sender, block_receiver) = channel::bounded(1000);
+            let (mining_info_sender, mining_info_receiver) = channel::bounded(1000);
+            let (rpc_call_sender, rpc_call_receiver) = channel::bounded(1000);
+            let metrics = Arc::new(Metrics::new());
+
+            let mut gossip_service = GossipService::new(
+                config.gossip_service,
+                block_forest,
+                GossipChannels {
+                    event_receiver: endpoint.event_receiver,
+                    command_sender: endpoint.command_sender,
+                    block_receiver,
+                    mining_info_sender,
+                    rpc_call_receiver,
+                },
+                metrics.clone(),
+            );
+            let mut mining_service = MiningService::new(
+                config.mining_service,
+                mining_info_receiver,
+                block_sender.clone(),
+                metrics,
+            );
+
+            thread::Builder::new()
+                .name(format!("node-{}-gossip", index))
+                .spawn(move || gossip_service.run())
+                .context("failed to spawn gossip service")?;
+            thread::Builder::new()
+                .name(format!("node-{}-mining", index))
+                .spawn(move || mining_service.run())
+                .context("failed to spawn mining service")?;
+
+            nodes.push(SimulatedNode {
+                block_sender,
+                rpc_call_sender,
+            });
+        }
+        Ok(Self { network, nodes })
+    }
+
+    pub fn network(&self) -> &VirtualNetwork {
+        &self.network
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Hand the block to the node as if it has been mined by its mining service.
+    pub fn add_block(&self, node: usize, block: VerifiedBlock) -> Result<()> {
+        self.nodes[node]
+            .block_sender
+            .send(block)
+            .map_err(|_| anyhow!("node {} has stopped", node))
+    }
+
+    pub fn submit_transaction(&self, node: usize, tx: VerifiedTransaction) -> Result<()> {
+        let request = RpcRequest::SubmitTransaction(Box::new(Transaction::from(tx)));
+        self.call(node, request)?;
+        Ok(())
+    }
+
+    pub fn head(&self, node: usize) -> Result<VerifiedBlock> {
+        let head = self.call(node, RpcRequest::GetHead)?;
+        let block: Block = serde_json::from_value(head).context("failed to parse head")?;
+        block.verified()
+    }
+
+    /// Wait until all the nodes have the same head and return it.
+    pub fn wait_for_consensus(&self, timeout: Duration) -> Result<VerifiedBlock> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let heads = (0..self.node_count())
+                .map(|node| self.head(node))
+                .collect::<Result<Vec<_>>>()?;
+            if heads.iter().all(|head| head.hash() == heads[0].hash()) {
+                return heads.into_iter().next().context("there are no nodes");
+            }
+            if Instant::now() >= deadline {
+                let indices: Vec<_> = heads.iter().map(|head| head.index).collect();
+                bail!("no consensus in {:?}, head indices: {:?}", timeout, indices);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Wait until the head of the node satisfies `predicate` and return it.
+    pub fn wait_for_head(
+        &self,
+        node: usize,
+        timeout: Duration,
+        mut predicate: impl FnMut(&VerifiedBlock) -> bool,
+    ) -> Result<VerifiedBlock> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let head = self.head(node)?;
+            if predicate(&head) {
+                return Ok(head);
+            }
+            if Instant::now() >= deadline {
+                bail!("node {} is stuck at head #{}", node, head.index);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn call(&self, node: usize, request: RpcRequest) -> Result<Value> {
+        RpcCall::send(&self.nodes[node].rpc_call_sender, request)
+            .map_err(|RpcError { code, message }| anyhow!("{} (code {})", message, code))
+            .with_context(|| format!("rpc call to node {} failed", node))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect_event(event: PeerEvent) -> (SessionId, bool) {
+        match event.event_kind {
+            PeerEventKind::Connected => (event.session_id, true),
+            PeerEventKind::Disconnected => (event.session_id, false),
+            kind => panic!("unexpected event: {:?}", kind),
+        }
+    }
+
+    fn send(endpoint: &PeerEndpoint, session_id: SessionId) {
+        let message = VerifiedPeerMessage::Request {
+            block_hash: *VerifiedBlock::genesis().hash(),
+        };
+        endpoint
+            .command_sender
+            .send(PeerCommand {
+                session_id,
+                command_kind: PeerCommandKind::SendMessage(message),
+            })
+            .unwrap();
+    }
+
+    fn recv_from(endpoint: &PeerEndpoint, timeout: Duration) -> Option<SessionId> {
+        let event = endpoint.event_receiver.recv_timeout(timeout).ok()?;
+        match event.event_kind {
+            PeerEventKind::NewMessage(_) => Some(event.session_id),
+            kind => panic!("unexpected event: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_partition() {
+        let (network, endpoints) = VirtualNetwork::new(NetworkConfig::default(), 3);
+        let connected: Vec<_> = endpoints[0]
+            .event_receiver
+            .try_iter()
+            .map(connect_event)
+            .collect();
+        assert_eq!(connected, [(1, true), (2, true)]);
+        endpoints[1].event_receiver.try_iter().count();
+        endpoints[2].event_receiver.try_iter().count();
+
+        network.partition(&[&[0, 1]]);
+        assert!(network.is_connected(0, 1));
+        assert!(!network.is_connected(1, 2));
+        let events: Vec<_> = endpoints[2]
+            .event_receiver
+            .try_iter()
+            .map(connect_event)
+            .collect();
+        assert_eq!(events, [(0, false), (1, false)]);
+        for (endpoint, peer) in endpoints[..2].iter().zip([0, 1]) {
+            let events: Vec<_> = endpoint
+                .event_receiver
+                .try_iter()
+                .map(connect_event)
+                .collect();
+            assert_eq!(events, [(2, false)], "node {}", peer);
+        }
+
+        send(&endpoints[2], 0);
+        send(&endpoints[0], 1);
+        assert_eq!(recv_from(&endpoints[1], Duration::from_secs(1)), Some(0));
+        assert_eq!(recv_from(&endpoints[0], Duration::from_millis(100)), None);
+
+        endpoints[1]
+            .command_sender
+            .send(PeerCommand {
+                session_id: 0,
+                command_kind: PeerCommandKind::Drop,
+            })
+            .unwrap();
+        let event = endpoints[0]
+            .event_receiver
+            .recv_timeout(Duration::from_secs(1));
+        assert_eq!(connect_event(event.unwrap()), (1, false));
+
+        network.heal();
+        let events: Vec<_> = endpoints[0]
+            .event_receiver
+            .try_iter()
+            .map(connect_event)
+            .collect();
+        assert_eq!(events, [(1, true), (2, true)]);
+    }
+
+    #[test]
+    fn test_latency_and_loss() {
+        let config = NetworkConfig {
+            seed: 42,
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(50),
+            loss_rate: 0.5,
+        };
+        let lost_counts: Vec<_> = (0..2)
+            .map(|_| {
+                let (network, endpoints) = VirtualNetwork::new(config.clone(), 2);
+                endpoints[1].event_receiver.try_iter().count();
+
+                let start = Instant::now();
+                for _ in 0..100 {
+                    send(&endpoints[0], 1);
+                }
+                let mut delivered = 0;
+                while recv_from(&endpoints[1], Duration::from_millis(300)).is_some() {
+                    delivered += 1;
+                }
+                assert!(start.elapsed() >= config.latency);
+                assert_eq!(network.stats().delivered, delivered);
+                network.stats().lost
+            })
+            .collect();
+        assert!(lost_counts[0] > 0 && lost_counts[0] < 100);
+        assert_eq!(lost_counts[0], lost_counts[1]);
+    }
+}
@@ -0,0 +1,251 @@
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Penalty points are forgiven at the rate of one point per interval.
+pub const POINT_DECAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Length of the window in which requests are counted.
+pub const REQUEST_WINDOW: Duration = Duration::from_secs(1);
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerScoringConfig {
+    /// Peers are banned once they accumulate this many penalty points.
+    pub ban_threshold: u32,
+    #[serde(with = "humantime_serde")]
+    pub ban_duration: Duration,
+    /// Requests over this number in a `REQUEST_WINDOW` are a flood.
+    pub max_requests_per_window: u32,
+}
+
+impl Default for PeerScoringConfig {
+    fn default() -> Self {
+        Self {
+            ban_threshold: 100,
+            ban_duration: Duration::from_secs(24 * 3600),
+            max_requests_per_window: 50,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// A block that `BlockForest::add_block()` rejected.
+    InvalidBlock,
+    /// Headers that `BlockSync::add_headers()` rejected.
+    InvalidHeaders,
+    /// A message which is not valid JSON or fails verification.
+    MalformedMessage,
+    /// Too many requests in a `REQUEST_WINDOW`.
+    RequestFlood,
+}
+
+impl Misbehaviour {
+    pub fn penalty(self) -> u32 {
+        match self {
+            Self::InvalidBlock => 100,
+            Self::InvalidHeaders => 50,
+            Self::MalformedMessage => 50,
+            Self::RequestFlood => 20,
+        }
+    }
+}
+
+impl Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::InvalidBlock => "invalid block",
+            Self::InvalidHeaders => "invalid headers",
+            Self::MalformedMessage => "malformed message",
+            Self::RequestFlood => "request flood",
+        };
+        f.write_str(description)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Score {
+    points: u32,
+    decayed_at: Instant,
+    window_start: Instant,
+    window_requests: u32,
+}
+
+impl Score {
+    fn new(now: Instant) -> Self {
+        Self {
+            points: 0,
+            decayed_at: now,
+            window_start: now,
+            window_requests: 0,
+        }
+    }
+
+    fn decay(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.decayed_at);
+        let intervals = elapsed.as_secs() / POINT_DECAY_INTERVAL.as_secs();
+        self.points = self
+            .points
+            .saturating_sub(intervals.try_into().unwrap_or(u32::MAX));
+        self.decayed_at += POINT_DECAY_INTERVAL * intervals as u32;
+    }
+}
+
+/// Penalty points and bans of peers by IP address.
+pub struct PeerScores {
+    config: PeerScoringConfig,
+    scores: HashMap<IpAddr, Score>,
+    /// Banned addresses along with the end of the ban.
+    bans: HashMap<IpAddr, Instant>,
+}
+
+impl PeerScores {
+    pub fn new(config: PeerScoringConfig) -> Self {
+        Self {
+            config,
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Add penalty points to the peer. Returns true if the peer has got banned,
+    /// in which case all its sessions should be dropped.
+    pub fn penalize(&mut self, ip: IpAddr, misbehaviour: Misbehaviour, now: Instant) -> bool {
+        if self.is_banned(ip, now) {
+            return true;
+        }
+
+        let score = self.scores.entry(ip).or_insert_with(|| Score::new(now));
+        score.decay(now);
+        score.points = score.points.saturating_add(misbehaviour.penalty());
+        warn!(
+            "peer {} sent {}, penalty points: {}/{}",
+            ip, misbehaviour, score.points, self.config.ban_threshold
+        );
+        if score.points < self.config.ban_threshold {
+            return false;
+        }
+
+        self.scores.remove(&ip);
+        self.bans.insert(ip, now + self.config.ban_duration);
+        warn!(
+            "banned peer {} for {:?}, {} peers banned",
+            ip,
+            self.config.ban_duration,
+            self.bans.len()
+        );
+        true
+    }
+
+    /// Count a request from the peer, penalizing it for a flood once per window.
+    /// Returns true if the peer has got banned.
+    pub fn record_request(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let score = self.scores.entry(ip).or_insert_with(|| Score::new(now));
+        if now.saturating_duration_since(score.window_start) >= REQUEST_WINDOW {
+            score.window_start = now;
+            score.window_requests = 0;
+        }
+        score.window_requests += 1;
+        if score.window_requests != self.config.max_requests_per_window + 1 {
+            return false;
+        }
+        self.penalize(ip, Misbehaviour::RequestFlood, now)
+    }
+
+    /// Whether connections from and to the address should be refused.
+    pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        match self.bans.get(&ip) {
+            Some(until) if *until <= now => {
+                self.bans.remove(&ip);
+                info!(
+                    "ban of peer {} has expired, {} peers banned",
+                    ip,
+                    self.bans.len()
+                );
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Banned addresses along with the end of their bans.
+    pub fn bans(&self) -> impl Iterator<Item = (&IpAddr, &Instant)> {
+        self.bans.iter()
+    }
+
+    /// Forget the scores of the peers which have no points left, e.g. when they disconnect.
+    pub fn forget_idle(&mut self, now: Instant) {
+        self.scores.retain(|_, score| {
+            score.decay(now);
+            score.points > 0 || now.saturating_duration_since(score.window_start) < REQUEST_WINDOW
+        });
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER_PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn test_ban() {
+        let mut scores = PeerScores::new(PeerScoringConfig::default());
+        let now = Instant::now();
+        assert!(!scores.penalize(PEER, Misbehaviour::MalformedMessage, now));
+        assert!(!scores.is_banned(PEER, now));
+
+        // Points decay over time.
+        let later = now + POINT_DECAY_INTERVAL * 10;
+        assert!(!scores.penalize(PEER, Misbehaviour::InvalidHeaders, later));
+        assert!(scores.penalize(PEER, Misbehaviour::RequestFlood, later));
+        assert!(scores.is_banned(PEER, later));
+        assert!(!scores.is_banned(OTHER_PEER, later));
+        assert!(scores.penalize(OTHER_PEER, Misbehaviour::InvalidBlock, later));
+        assert_eq!(scores.bans().count(), 2);
+
+        let unbanned_at = later + PeerScoringConfig::default().ban_duration;
+        assert!(!scores.is_banned(PEER, unbanned_at));
+        assert!(!scores.penalize(PEER, Misbehaviour::RequestFlood, unbanned_at));
+    }
+
+    #[test]
+    fn test_request_flood() {
+        let mut scores = PeerScores::new(PeerScoringConfig {
+            max_requests_per_window: 2,
+            ban_threshold: 40,
+            ..PeerScoringConfig::default()
+        });
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(!scores.record_request(PEER, now));
+            assert!(!scores.record_request(PEER, now));
+        }
+
+        let next_window = now + REQUEST_WINDOW;
+        for _ in 0..2 {
+            assert!(!scores.record_request(PEER, next_window));
+        }
+        assert!(scores.record_request(PEER, next_window));
+        assert!(scores.is_banned(PEER, next_window));
+    }
+}
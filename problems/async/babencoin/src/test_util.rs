@@ -0,0 +1,41 @@
+use crate::{
+    data::{Block, BlockAttributes, VerifiedBlock, VerifiedTransaction, MAX_REWARD},
+    util::parse_pkcs8_private,
+};
+
+use chrono::Duration;
+use rsa::RSAPrivateKey;
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn test_key() -> RSAPrivateKey {
+    parse_pkcs8_private(include_str!("../data/test.pem")).unwrap()
+}
+
+/// Blocks are 10 seconds apart, so that the max hash stays the same.
+pub fn next_block(
+    key: &RSAPrivateKey,
+    prev: &VerifiedBlock,
+    transactions: Vec<VerifiedTransaction>,
+) -> VerifiedBlock {
+    let mut block = Block {
+        attrs: BlockAttributes::clone(prev),
+        transactions: transactions.into_iter().map(|tx| tx.into()).collect(),
+    };
+    block.index += 1;
+    block.timestamp = prev.timestamp + Duration::seconds(10);
+    block.reward = MAX_REWARD;
+    block.issuer = key.to_public_key().into();
+    block.prev_hash = *prev.hash();
+    block.verified().unwrap()
+}
+
+pub fn make_chain(key: &RSAPrivateKey, len: usize) -> Vec<VerifiedBlock> {
+    let mut chain = vec![VerifiedBlock::genesis()];
+    for _ in 0..len {
+        let block = next_block(key, chain.last().unwrap(), vec![]);
+        chain.push(block);
+    }
+    chain.remove(0);
+    chain
+}
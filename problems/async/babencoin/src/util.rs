@@ -32,6 +32,15 @@ pub fn parse_pkcs8_private(raw: &str) -> Result<RSAPrivateKey> {
     RSAPrivateKey::from_pkcs8(&der_bytes).context("failed to decode pkcs8 bytes")
 }
 
+/// Wallet in the base64 form used in transactions and configs.
+pub fn encode_wallet_id(wallet: &WalletId) -> Result<String> {
+    let bytes = wallet
+        .public_key
+        .to_pkcs8()
+        .context("failed to encode public key")?;
+    Ok(base64::encode(bytes))
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn serialize_base64<T, S>(array: &T, serializer: S) -> Result<S::Ok, S::Error>
@@ -72,6 +81,36 @@ where
     Ok(array)
 }
 
+pub fn serialize_base64_list<T, S>(arrays: &[T], serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: AsRef<[u8]>,
+    S: Serializer,
+{
+    serializer.collect_seq(arrays.iter().map(|array| base64::encode(array.as_ref())))
+}
+
+pub fn deserialize_base64_fixed_list<'de, D, const SIZE: usize>(
+    deserializer: D,
+) -> Result<Vec<[u8; SIZE]>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|string| {
+            let bytes = base64::decode(&string)
+                .map_err(|err| de::Error::custom(format!("invalid base64: {}", err)))?;
+            bytes.try_into().map_err(|bytes: Vec<u8>| {
+                de::Error::custom(format!(
+                    "invalid length: expected {}, got {}",
+                    SIZE,
+                    bytes.len()
+                ))
+            })
+        })
+        .collect()
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub fn serialize_wallet_id<S>(wallet: &WalletId, serializer: S) -> Result<S::Ok, S::Error>
@@ -0,0 +1,600 @@
+use crate::data::{
+    Block, BlockAttributes, BlockHash, BlockHeader, Handshake, PeerAddress, PeerMessage,
+    Transaction, WalletId, HASH_LEN,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, LocalResult, TimeZone, Utc};
+use rsa::{PublicKeyEncoding, RSAPublicKey};
+
+use std::{
+    io::{self, BufRead, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Version 1 is the original protocol: JSON frames only and no handshake.
+/// Version 2 adds the handshake and binary frames.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Peers whose handshake has an older version are dropped.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Binary frames may be sent to peers whose handshake has at least this version.
+pub const BINARY_FRAMES_VERSION: u32 = 2;
+
+/// Frames are limited to the size of the buffer of version 1 nodes by default.
+/// `data::MAX_BLOCK_SIZE` is below it, so that the largest blocks can still be sent.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 65536;
+
+const JSON_FRAME_DELIMITER: u8 = 0;
+/// Starts a binary frame. It never occurs in JSON, which is valid UTF-8.
+const BINARY_FRAME_MARKER: u8 = 0xff;
+
+mod tag {
+    pub const HANDSHAKE: u8 = 0;
+    pub const BLOCK: u8 = 1;
+    pub const TRANSACTION: u8 = 2;
+    pub const REQUEST: u8 = 3;
+    pub const GET_HEADERS: u8 = 4;
+    pub const HEADERS: u8 = 5;
+    pub const GET_BLOCKS: u8 = 6;
+    pub const GET_ADDR: u8 = 7;
+    pub const ADDR: u8 = 8;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// A JSON message followed by a zero byte.
+    Json,
+    /// `BINARY_FRAME_MARKER`, the length of the payload as u32 LE and the payload.
+    Binary,
+}
+
+impl Encoding {
+    /// The encoding of the messages to send to a peer, given the version from its
+    /// handshake, or `None` if it hasn't sent one.
+    pub fn negotiate(peer_version: Option<u32>) -> Self {
+        match peer_version {
+            Some(version) if version >= BINARY_FRAMES_VERSION => Self::Binary,
+            _ => Self::Json,
+        }
+    }
+}
+
+/// A message read from the stream which is yet to be decoded.
+pub struct Frame {
+    pub encoding: Encoding,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(message: &PeerMessage, encoding: Encoding) -> Result<Self> {
+        let payload = match encoding {
+            Encoding::Json => serde_json::to_vec(message)?,
+            Encoding::Binary => encode_binary(message)?,
+        };
+        Ok(Self { encoding, payload })
+    }
+
+    pub fn decode(&self) -> Result<PeerMessage> {
+        match self.encoding {
+            Encoding::Json => {
+                serde_json::from_slice(&self.payload).context("failed to decode json frame")
+            }
+            Encoding::Binary => {
+                decode_binary(&self.payload).context("failed to decode binary frame")
+            }
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self.encoding {
+            Encoding::Json => {
+                writer.write_all(&self.payload)?;
+                writer.write_all(&[JSON_FRAME_DELIMITER])
+            }
+            Encoding::Binary => {
+                let len = u32::try_from(self.payload.len())
+                    .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "frame is too large"))?;
+                writer.write_u8(BINARY_FRAME_MARKER)?;
+                writer.write_u32::<LittleEndian>(len)?;
+                writer.write_all(&self.payload)
+            }
+        }
+    }
+}
+
+/// Reads frames of both encodings from a stream, so that the peer may switch to
+/// binary frames at any moment after the handshake.
+pub struct FrameReader<R> {
+    reader: R,
+    max_frame_size: usize,
+}
+
+impl<R: BufRead> FrameReader<R> {
+    pub fn new(reader: R, max_frame_size: usize) -> Self {
+        Self {
+            reader,
+            max_frame_size,
+        }
+    }
+
+    /// Read the next frame. Returns `None` when the stream ends between frames,
+    /// and an `InvalidData` error if the frame is larger than `max_frame_size`.
+    pub fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let first_byte = match self.reader.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
+        if first_byte == BINARY_FRAME_MARKER {
+            self.reader.consume(1);
+            self.read_binary_payload().map(Some)
+        } else {
+            self.read_json_payload().map(Some)
+        }
+    }
+
+    fn read_binary_payload(&mut self) -> io::Result<Frame> {
+        let len = self.reader.read_u32::<LittleEndian>()? as usize;
+        if len > self.max_frame_size {
+            return Err(self.too_large(len));
+        }
+        let mut payload = vec![0; len];
+        self.reader.read_exact(&mut payload)?;
+        Ok(Frame {
+            encoding: Encoding::Binary,
+            payload,
+        })
+    }
+
+    fn read_json_payload(&mut self) -> io::Result<Frame> {
+        let mut payload = vec![];
+        (&mut self.reader)
+            .take(self.max_frame_size as u64 + 1)
+            .read_until(JSON_FRAME_DELIMITER, &mut payload)?;
+        if payload.last() != Some(&JSON_FRAME_DELIMITER) {
+            if payload.len() > self.max_frame_size {
+                return Err(self.too_large(payload.len()));
+            }
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        payload.pop();
+        Ok(Frame {
+            encoding: Encoding::Json,
+            payload,
+        })
+    }
+
+    fn too_large(&self, len: usize) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes exceeds the limit of {} bytes",
+                len, self.max_frame_size
+            ),
+        )
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Integers are little endian, hashes are raw bytes, wallets are PKCS8 DER and
+// variable length data is prefixed with its length as u32.
+
+fn encode_binary(message: &PeerMessage) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    match message {
+        PeerMessage::Handshake(handshake) => {
+            out.write_u8(tag::HANDSHAKE)?;
+            out.write_u32::<LittleEndian>(handshake.version)?;
+            out.write_u64::<LittleEndian>(handshake.node_id)?;
+            out.write_u64::<LittleEndian>(handshake.head_index)?;
+            out.write_all(&handshake.head_hash)?;
+        }
+        PeerMessage::Block(block) => {
+            out.write_u8(tag::BLOCK)?;
+            write_attrs(&mut out, &block.attrs)?;
+            write_len(&mut out, block.transactions.len())?;
+            for tx in &block.transactions {
+                write_transaction(&mut out, tx)?;
+            }
+        }
+        PeerMessage::Transaction(tx) => {
+            out.write_u8(tag::TRANSACTION)?;
+            write_transaction(&mut out, tx)?;
+        }
+        PeerMessage::Request { block_hash } => {
+            out.write_u8(tag::REQUEST)?;
+            out.write_all(block_hash)?;
+        }
+        PeerMessage::GetHeaders { locator } => {
+            out.write_u8(tag::GET_HEADERS)?;
+            write_hashes(&mut out, locator)?;
+        }
+        PeerMessage::Headers { headers } => {
+            out.write_u8(tag::HEADERS)?;
+            write_len(&mut out, headers.len())?;
+            for header in headers {
+                write_attrs(&mut out, &header.attrs)?;
+                write_hashes(&mut out, &header.transaction_hashes)?;
+            }
+        }
+        PeerMessage::GetBlocks { block_hashes } => {
+            out.write_u8(tag::GET_BLOCKS)?;
+            write_hashes(&mut out, block_hashes)?;
+        }
+        PeerMessage::GetAddr => out.write_u8(tag::GET_ADDR)?,
+        PeerMessage::Addr { addresses } => {
+            out.write_u8(tag::ADDR)?;
+            write_len(&mut out, addresses.len())?;
+            for address in addresses {
+                write_peer_address(&mut out, address)?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn decode_binary(mut data: &[u8]) -> Result<PeerMessage> {
+    let input = &mut data;
+    let message = match input.read_u8()? {
+        tag::HANDSHAKE => PeerMessage::Handshake(Handshake {
+            version: input.read_u32::<LittleEndian>()?,
+            node_id: input.read_u64::<LittleEndian>()?,
+            head_index: input.read_u64::<LittleEndian>()?,
+            head_hash: read_hash(input)?,
+        }),
+        tag::BLOCK => {
+            let attrs = read_attrs(input)?;
+            let transactions = read_list(input, read_transaction)?;
+            PeerMessage::Block(Box::new(Block {
+                attrs,
+                transactions,
+            }))
+        }
+        tag::TRANSACTION => PeerMessage::Transaction(Box::new(read_transaction(input)?)),
+        tag::REQUEST => PeerMessage::Request {
+            block_hash: read_hash(input)?,
+        },
+        tag::GET_HEADERS => PeerMessage::GetHeaders {
+            locator: read_list(input, read_hash)?,
+        },
+        tag::HEADERS => PeerMessage::Headers {
+            headers: read_list(input, |input| {
+                Ok(BlockHeader {
+                    attrs: read_attrs(input)?,
+                    transaction_hashes: read_list(input, read_hash)?,
+                })
+            })?,
+        },
+        tag::GET_BLOCKS => PeerMessage::GetBlocks {
+            block_hashes: read_list(input, read_hash)?,
+        },
+        tag::GET_ADDR => PeerMessage::GetAddr,
+        tag::ADDR => PeerMessage::Addr {
+            addresses: read_list(input, read_peer_address)?,
+        },
+        tag => bail!("unknown message tag {}", tag),
+    };
+    ensure!(
+        input.is_empty(),
+        "{} trailing bytes after the message",
+        input.len()
+    );
+    Ok(message)
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) -> Result<()> {
+    let len = u32::try_from(len).context("length does not fit in u32")?;
+    out.write_u32::<LittleEndian>(len)?;
+    Ok(())
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    write_len(out, bytes.len())?;
+    out.write_all(bytes)?;
+    Ok(())
+}
+
+fn write_hashes(out: &mut Vec<u8>, hashes: &[BlockHash]) -> Result<()> {
+    write_len(out, hashes.len())?;
+    for hash in hashes {
+        out.write_all(hash)?;
+    }
+    Ok(())
+}
+
+fn write_wallet(out: &mut Vec<u8>, wallet: &WalletId) -> Result<()> {
+    let der = wallet
+        .public_key
+        .to_pkcs8()
+        .map_err(|err| anyhow::anyhow!("failed to encode key as PKCS8: {}", err))?;
+    write_bytes(out, &der)
+}
+
+fn write_attrs(out: &mut Vec<u8>, attrs: &BlockAttributes) -> Result<()> {
+    out.write_u64::<LittleEndian>(attrs.index)?;
+    out.write_u64::<LittleEndian>(attrs.reward)?;
+    out.write_u64::<LittleEndian>(attrs.nonce)?;
+    out.write_i64::<LittleEndian>(attrs.timestamp.timestamp())?;
+    write_wallet(out, &attrs.issuer)?;
+    out.write_all(&attrs.max_hash)?;
+    out.write_all(&attrs.prev_hash)?;
+    Ok(())
+}
+
+fn write_transaction(out: &mut Vec<u8>, tx: &Transaction) -> Result<()> {
+    out.write_u64::<LittleEndian>(tx.amount)?;
+    out.write_u64::<LittleEndian>(tx.fee)?;
+    out.write_u64::<LittleEndian>(tx.nonce)?;
+    write_bytes(out, tx.comment.as_bytes())?;
+    write_wallet(out, &tx.sender)?;
+    write_wallet(out, &tx.receiver)?;
+    write_bytes(out, &tx.signature)
+}
+
+fn write_peer_address(out: &mut Vec<u8>, peer_address: &PeerAddress) -> Result<()> {
+    match peer_address.address.ip() {
+        IpAddr::V4(ip) => {
+            out.write_u8(4)?;
+            out.write_all(&ip.octets())?;
+        }
+        IpAddr::V6(ip) => {
+            out.write_u8(6)?;
+            out.write_all(&ip.octets())?;
+        }
+    }
+    out.write_u16::<LittleEndian>(peer_address.address.port())?;
+    out.write_i64::<LittleEndian>(peer_address.last_seen.timestamp())?;
+    Ok(())
+}
+
+fn read_len(input: &mut &[u8]) -> Result<usize> {
+    let len = input.read_u32::<LittleEndian>()? as usize;
+    // Every item takes at least a byte, so a longer list can't be valid.
+    ensure!(len <= input.len(), "length {} exceeds the frame", len);
+    Ok(len)
+}
+
+fn read_list<T>(input: &mut &[u8], read_item: impl Fn(&mut &[u8]) -> Result<T>) -> Result<Vec<T>> {
+    let len = read_len(input)?;
+    (0..len).map(|_| read_item(input)).collect()
+}
+
+fn read_bytes(input: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; read_len(input)?];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_hash(input: &mut &[u8]) -> Result<BlockHash> {
+    let mut hash = [0; HASH_LEN];
+    input.read_exact(&mut hash)?;
+    Ok(hash)
+}
+
+fn read_wallet(input: &mut &[u8]) -> Result<WalletId> {
+    let der = read_bytes(input)?;
+    RSAPublicKey::from_pkcs8(&der)
+        .map(WalletId::from)
+        .map_err(|err| anyhow::anyhow!("invalid PKCS8: {}", err))
+}
+
+fn read_timestamp(input: &mut &[u8]) -> Result<DateTime<Utc>> {
+    match Utc.timestamp_opt(input.read_i64::<LittleEndian>()?, 0) {
+        LocalResult::Single(timestamp) => Ok(timestamp),
+        _ => bail!("invalid timestamp"),
+    }
+}
+
+fn read_attrs(input: &mut &[u8]) -> Result<BlockAttributes> {
+    Ok(BlockAttributes {
+        index: input.read_u64::<LittleEndian>()?,
+        reward: input.read_u64::<LittleEndian>()?,
+        nonce: input.read_u64::<LittleEndian>()?,
+        timestamp: read_timestamp(input)?,
+        issuer: read_wallet(input)?,
+        max_hash: read_hash(input)?,
+        prev_hash: read_hash(input)?,
+    })
+}
+
+fn read_transaction(input: &mut &[u8]) -> Result<Transaction> {
+    Ok(Transaction {
+        amount: input.read_u64::<LittleEndian>()?,
+        fee: input.read_u64::<LittleEndian>()?,
+        nonce: input.read_u64::<LittleEndian>()?,
+        comment: String::from_utf8(read_bytes(input)?).context("comment is not utf-8")?,
+        sender: read_wallet(input)?,
+        receiver: read_wallet(input)?,
+        signature: read_bytes(input)?,
+    })
+}
+
+fn read_peer_address(input: &mut &[u8]) -> Result<PeerAddress> {
+    let ip = match input.read_u8()? {
+        4 => {
+            let mut octets = [0; 4];
+            input.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        6 => {
+            let mut octets = [0; 16];
+            input.read_exact(&mut octets)?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        family => bail!("unknown address family {}", family),
+    };
+    let port = input.read_u16::<LittleEndian>()?;
+    Ok(PeerAddress {
+        address: SocketAddr::new(ip, port),
+        last_seen: read_timestamp(input)?,
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{VerifiedPeerMessage, MAX_BLOCK_SIZE};
+
+    use std::io::Cursor;
+
+    fn test_block() -> Block {
+        serde_json::from_str(include_str!("../data/test_block.json")).unwrap()
+    }
+
+    fn test_messages() -> Vec<PeerMessage> {
+        let block = test_block();
+        let header = BlockHeader::from(block.clone().verified().unwrap().header());
+        vec![
+            PeerMessage::Handshake(Handshake {
+                version: PROTOCOL_VERSION,
+                node_id: 42,
+                head_index: block.index,
+                head_hash: block.compute_hash(),
+            }),
+            PeerMessage::Transaction(Box::new(block.transactions[0].clone())),
+            PeerMessage::Block(Box::new(block)),
+            PeerMessage::Request {
+                block_hash: [7; HASH_LEN],
+            },
+            PeerMessage::GetHeaders {
+                locator: vec![[1; HASH_LEN], [2; HASH_LEN]],
+            },
+            PeerMessage::Headers {
+                headers: vec![header],
+            },
+            PeerMessage::GetBlocks {
+                block_hashes: vec![[3; HASH_LEN]],
+            },
+            PeerMessage::GetAddr,
+            PeerMessage::Addr {
+                addresses: vec![
+                    PeerAddress {
+                        address: "127.0.0.1:9090".parse().unwrap(),
+                        last_seen: Utc.timestamp_opt(1626002428, 0).unwrap(),
+                    },
+                    PeerAddress {
+                        address: "[::1]:9091".parse().unwrap(),
+                        last_seen: Utc.timestamp_opt(1626002429, 0).unwrap(),
+                    },
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_frames() {
+        let messages = test_messages();
+        let mut stream = vec![];
+        for (i, message) in messages.iter().enumerate() {
+            let encoding = if i % 2 == 0 {
+                Encoding::Binary
+            } else {
+                Encoding::Json
+            };
+            Frame::encode(message, encoding)
+                .unwrap()
+                .write_to(&mut stream)
+                .unwrap();
+        }
+
+        let mut reader = FrameReader::new(Cursor::new(stream), DEFAULT_MAX_FRAME_SIZE);
+        for (i, message) in messages.into_iter().enumerate() {
+            let frame = reader.read_frame().unwrap().unwrap();
+            assert_eq!(frame.encoding == Encoding::Binary, i % 2 == 0);
+            let decoded = frame.decode().unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&message).unwrap()
+            );
+            let verified = decoded.verified().unwrap();
+            if let VerifiedPeerMessage::Block(block) = verified {
+                assert_eq!(block.hash(), &test_block().compute_hash());
+            }
+        }
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_binary_is_compact() {
+        let message = PeerMessage::Block(Box::new(test_block()));
+        let json = Frame::encode(&message, Encoding::Json).unwrap();
+        let binary = Frame::encode(&message, Encoding::Binary).unwrap();
+        assert!(binary.payload.len() * 4 < json.payload.len() * 3);
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let message = PeerMessage::GetBlocks {
+            block_hashes: vec![[0; HASH_LEN]; 4],
+        };
+        for encoding in [Encoding::Json, Encoding::Binary] {
+            let mut stream = vec![];
+            let frame = Frame::encode(&message, encoding).unwrap();
+            frame.write_to(&mut stream).unwrap();
+
+            let mut reader = FrameReader::new(Cursor::new(&stream), frame.payload.len());
+            assert!(reader.read_frame().unwrap().is_some());
+            let mut reader = FrameReader::new(Cursor::new(&stream), frame.payload.len() - 1);
+            let err = reader.read_frame().err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            let mut reader = FrameReader::new(Cursor::new(&stream[..stream.len() - 1]), 1000);
+            let err = reader.read_frame().err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn test_largest_block_fits_in_frame() {
+        let mut block = test_block();
+        block.attrs.max_hash = [0xff; HASH_LEN];
+        let tx = block.transactions[0].clone();
+        while serde_json::to_vec(&block).unwrap().len() <= MAX_BLOCK_SIZE {
+            block.transactions.push(tx.clone());
+        }
+        assert!(block.clone().verified().is_err());
+        block.transactions.pop();
+        block.clone().verified().unwrap();
+
+        let message = PeerMessage::Block(Box::new(block));
+        for encoding in [Encoding::Json, Encoding::Binary] {
+            let frame = Frame::encode(&message, encoding).unwrap();
+            assert!(frame.payload.len() <= DEFAULT_MAX_FRAME_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_malformed_binary() {
+        let frame = Frame::encode(
+            &PeerMessage::Block(Box::new(test_block())),
+            Encoding::Binary,
+        )
+        .unwrap();
+        for len in [0, 1, 20, frame.payload.len() - 1] {
+            let truncated = Frame {
+                encoding: Encoding::Binary,
+                payload: frame.payload[..len].to_vec(),
+            };
+            assert!(truncated.decode().is_err());
+        }
+
+        let mut trailing = frame.payload.clone();
+        trailing.push(0);
+        let unknown_tag = vec![200];
+        let huge_list = vec![tag::GET_BLOCKS, 255, 255, 255, 255];
+        for payload in [trailing, unknown_tag, huge_list] {
+            let frame = Frame {
+                encoding: Encoding::Binary,
+                payload,
+            };
+            assert!(frame.decode().is_err());
+        }
+    }
+}
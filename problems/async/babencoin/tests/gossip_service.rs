@@ -4,12 +4,15 @@ mod helpers;
 use core::time;
 
 use helpers::{
-    ensure_absence, generate_private_key, generate_public_key, random_block, send_message, sync,
-    wait_for_message,
+    ensure_absence, generate_private_key, generate_public_key, random_block, recv_message,
+    send_message, sync, wait_for_message,
 };
 
 use babencoin::{
-    data::{Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction},
+    data::{
+        Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction,
+        MAX_HEADERS_PER_MESSAGE,
+    },
     node,
 };
 
@@ -64,8 +67,8 @@ fn tx_send() {
     let env = test_env!("test_tx_send");
 
     let key = generate_private_key();
-    let tx =
-        VerifiedTransaction::sign(&key, generate_public_key().into(), 0, 0, "Test".into()).unwrap();
+    let tx = VerifiedTransaction::sign(&key, generate_public_key().into(), 0, 0, 0, "Test".into())
+        .unwrap();
 
     let mut conn_one = env.connect_to_node().unwrap();
     send_message(
@@ -89,8 +92,15 @@ fn tx_discard() {
     let env = test_env!("test_tx_discard");
 
     let key = generate_private_key();
-    let tx = VerifiedTransaction::sign(&key, generate_public_key().into(), 100, 100, "Test".into())
-        .unwrap();
+    let tx = VerifiedTransaction::sign(
+        &key,
+        generate_public_key().into(),
+        100,
+        100,
+        0,
+        "Test".into(),
+    )
+    .unwrap();
 
     let mut conn_one = env.connect_to_node().unwrap();
     send_message(
@@ -149,6 +159,7 @@ fn head_switch() {
         generate_public_key().into(),
         0,
         0,
+        0,
         "Test".into(),
     )
     .unwrap();
@@ -158,6 +169,7 @@ fn head_switch() {
         generate_public_key().into(),
         0,
         0,
+        0,
         "Test".into(),
     )
     .unwrap();
@@ -313,3 +325,63 @@ fn eager_requests() {
     })
     .unwrap();
 }
+
+#[test]
+fn headers_first_sync() {
+    let env = test_env!("test_headers_first_sync");
+
+    // Long enough to take several headers requests.
+    let mut chain = vec![VerifiedBlock::genesis()];
+    for index in 1..=(2 * MAX_HEADERS_PER_MESSAGE + 5) as u64 {
+        let mut block = random_block(index);
+        block.attrs.prev_hash = *chain.last().unwrap().hash();
+        chain.push(block.verified().unwrap());
+    }
+    let tip_hash = *chain.last().unwrap().hash();
+
+    let mut conn = env.connect_to_node().unwrap();
+    conn.set_read_timeout(Some(time::Duration::from_secs(10)))
+        .unwrap();
+    let mut headers_requests = 0;
+    let mut sent_blocks = 0;
+    loop {
+        match recv_message(&mut conn).unwrap() {
+            PeerMessage::GetHeaders { locator } => {
+                headers_requests += 1;
+                let start = locator
+                    .iter()
+                    .find_map(|hash| chain.iter().position(|block| block.hash() == hash))
+                    .expect("locator must end with genesis");
+                let headers = chain[start + 1..]
+                    .iter()
+                    .take(MAX_HEADERS_PER_MESSAGE)
+                    .map(|block| block.header().into())
+                    .collect();
+                send_message(&mut conn, PeerMessage::Headers { headers }).unwrap();
+            }
+            PeerMessage::GetBlocks { block_hashes } => {
+                for hash in block_hashes {
+                    let block = chain.iter().find(|block| block.hash() == &hash).unwrap();
+                    send_message(
+                        &mut conn,
+                        PeerMessage::Block(Box::new(block.clone().into())),
+                    )
+                    .unwrap();
+                    sent_blocks += 1;
+                }
+                if sent_blocks == chain.len() - 1 {
+                    send_message(
+                        &mut conn,
+                        PeerMessage::Request {
+                            block_hash: tip_hash,
+                        },
+                    )
+                    .unwrap();
+                }
+            }
+            PeerMessage::Block(block) if block.compute_hash() == tip_hash => break,
+            _ => {}
+        }
+    }
+    assert!(headers_requests >= 3);
+}
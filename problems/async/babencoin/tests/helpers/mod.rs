@@ -121,6 +121,10 @@ impl Env {
         panic!("failed to wait for node liveness");
     }
 
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     pub fn connect_to_node(&self) -> io::Result<TcpStream> {
         let conn = TcpStream::connect(&self.addr)?;
         conn.set_read_timeout(Some(DEFAULT_READ_TIMEOUT)).unwrap();
@@ -0,0 +1,73 @@
+#[macro_use]
+mod helpers;
+
+use helpers::{random_block, send_message, sync};
+
+use babencoin::{
+    data::{Block, PeerMessage},
+    node,
+};
+
+use rand::{thread_rng, Rng};
+use serde_json::json;
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn scrape(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    body.to_owned()
+}
+
+#[test]
+fn running_node() {
+    let port = thread_rng().gen_range(49152..65536);
+    let metrics_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let config = node::Config {
+        metrics_service: serde_json::from_value(json!({ "listen_address": metrics_addr })).unwrap(),
+        ..node::Config::default()
+    };
+
+    let env = test_env!("test_metrics_running_node", config);
+    let mut conn = env.connect_to_node().unwrap();
+    let mut block = random_block(1);
+    block.attrs.prev_hash = Block::genesis().compute_hash();
+    send_message(&mut conn, PeerMessage::Block(Box::new(block))).unwrap();
+    sync(&mut conn).unwrap();
+
+    let metrics = scrape(metrics_addr);
+    let lines: Vec<_> = metrics.lines().collect();
+    for expected in [
+        "babencoin_peers 1",
+        "babencoin_sessions 1",
+        "babencoin_head_index 1",
+    ] {
+        assert!(
+            lines.contains(&expected),
+            "no '{}' in:\n{}",
+            expected,
+            metrics
+        );
+    }
+    // The connection checking that the node is alive counts as well.
+    for direction in ["in", "out"] {
+        let prefix = format!(
+            "babencoin_peer_messages_total{{direction=\"{}\",kind=\"block\"}} ",
+            direction
+        );
+        assert!(
+            lines.iter().any(|line| line.starts_with(&prefix)),
+            "no '{}' in:\n{}",
+            prefix,
+            metrics
+        );
+    }
+}
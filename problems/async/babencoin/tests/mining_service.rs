@@ -152,6 +152,7 @@ fn max_tx_per_block() {
                 generate_public_key().into(),
                 0,
                 0,
+                0,
                 format!("tx #{}", i),
             )
             .unwrap()
@@ -1,19 +1,26 @@
 #[macro_use]
 mod helpers;
 
-use helpers::send_message;
+use helpers::{generate_private_key, generate_public_key, send_message, wait_for_message};
 
 use babencoin::{
-    data::{Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction, MAX_REWARD},
+    data::{
+        Block, Handshake, PeerAddress, PeerMessage, Transaction, VerifiedBlock,
+        VerifiedTransaction, WalletId, MAX_BLOCK_SIZE, MAX_REWARD,
+    },
     node,
     util::parse_pkcs8_private,
+    wire::{Encoding, FrameReader, DEFAULT_MAX_FRAME_SIZE, PROTOCOL_VERSION},
 };
 
+use chrono::Utc;
+
 use std::{
-    io::{ErrorKind, Read, Write},
+    collections::HashSet,
+    io::{BufReader, ErrorKind, Read, Write},
     net::TcpListener,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -27,7 +34,8 @@ fn simple() {
 
     let genesis_key = Block::genesis().attrs.issuer;
     let priv_key = parse_pkcs8_private(include_str!("../data/test.pem")).unwrap();
-    let tx = VerifiedTransaction::sign(&priv_key, genesis_key, 100, 10, "comment".into()).unwrap();
+    let tx =
+        VerifiedTransaction::sign(&priv_key, genesis_key, 100, 10, 0, "comment".into()).unwrap();
     send_message(&mut conn, PeerMessage::Transaction(Box::new(tx.into()))).unwrap();
 
     send_message(
@@ -61,6 +69,7 @@ fn invalid_messages() {
     let invalid_tx = Transaction {
         amount: 1000,
         fee: 30,
+        nonce: 0,
         comment: "foo".into(),
         sender: genesis_key.clone(),
         receiver: genesis_key,
@@ -119,3 +128,213 @@ fn dial() {
         listener.accept().unwrap();
     }
 }
+
+#[test]
+fn ban() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut config = node::Config::default();
+    config.peer_service.dial_addresses = vec![listener.local_addr().unwrap().to_string()];
+    config.peer_service.dial_cooldown = Duration::from_millis(100);
+    let env = test_env!("test_ban", config);
+
+    // Two malformed messages are enough to get banned.
+    for _ in 0..2 {
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        conn.write_all(b"{\"index\": 10]\0").unwrap();
+        let mut buf = vec![];
+        if conn.read_to_end(&mut buf).is_err() {
+            panic!("node didn't drop connection");
+        }
+    }
+
+    listener.set_nonblocking(true).unwrap();
+    sleep(Duration::from_secs(1));
+    let error_kind = listener.accept().unwrap_err().kind();
+    assert_eq!(
+        error_kind,
+        ErrorKind::WouldBlock,
+        "node redialed banned peer"
+    );
+
+    let mut conn = env.connect_to_node().unwrap();
+    let mut buf = vec![];
+    if conn.read_to_end(&mut buf).is_err() {
+        panic!("node didn't refuse banned peer");
+    }
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn address_book() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let other_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let other_address = other_listener.local_addr().unwrap();
+
+    let mut config = node::Config::default();
+    config.peer_service.dial_addresses = vec![listener.local_addr().unwrap().to_string()];
+    config.peer_service.target_outbound_count = 2;
+    let env = test_env!("test_address_book", config);
+
+    // Outbound sessions ask for addresses, and the received ones are dialed.
+    let (mut conn, _) = listener.accept().unwrap();
+    wait_for_message(&mut conn, 3, |msg| matches!(msg, PeerMessage::GetAddr)).unwrap();
+    send_message(
+        &mut conn,
+        PeerMessage::Addr {
+            addresses: vec![PeerAddress {
+                address: other_address,
+                last_seen: Utc::now(),
+            }],
+        },
+    )
+    .unwrap();
+
+    other_listener.set_nonblocking(true).unwrap();
+    let start = Instant::now();
+    while let Err(err) = other_listener.accept() {
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "node didn't dial address from address book"
+        );
+        sleep(Duration::from_millis(100));
+    }
+
+    let mut conn = env.connect_to_node().unwrap();
+    send_message(&mut conn, PeerMessage::GetAddr).unwrap();
+    let msg =
+        wait_for_message(&mut conn, 3, |msg| matches!(msg, PeerMessage::Addr { .. })).unwrap();
+    if let PeerMessage::Addr { addresses } = msg {
+        let mut addresses = addresses
+            .iter()
+            .map(|peer| peer.address)
+            .collect::<Vec<_>>();
+        addresses.sort();
+        let mut expected = vec![listener.local_addr().unwrap(), other_address];
+        expected.sort();
+        assert_eq!(addresses, expected);
+    }
+}
+
+fn handshake(version: u32) -> PeerMessage {
+    PeerMessage::Handshake(Handshake {
+        version,
+        node_id: 1,
+        head_index: 0,
+        head_hash: *VerifiedBlock::genesis().hash(),
+    })
+}
+
+#[test]
+fn binary_frames() {
+    let env = test_env!("test_binary_frames");
+    let mut conn = env.connect_to_node().unwrap();
+    send_message(&mut conn, handshake(PROTOCOL_VERSION)).unwrap();
+    send_message(
+        &mut conn,
+        PeerMessage::Request {
+            block_hash: *VerifiedBlock::genesis().hash(),
+        },
+    )
+    .unwrap();
+
+    // The handshake of the node comes first and in JSON, and the block in response
+    // to the request is sent in binary once the node has got the handshake.
+    let mut reader = FrameReader::new(BufReader::new(conn), DEFAULT_MAX_FRAME_SIZE);
+    let frame = reader.read_frame().unwrap().unwrap();
+    assert_eq!(frame.encoding, Encoding::Json);
+    match frame.decode().unwrap() {
+        PeerMessage::Handshake(handshake) => assert_eq!(handshake.version, PROTOCOL_VERSION),
+        message => panic!("expected handshake, got {:?}", message),
+    }
+    loop {
+        let frame = reader.read_frame().unwrap().unwrap();
+        if frame.encoding == Encoding::Binary {
+            match frame.decode().unwrap() {
+                PeerMessage::Block(block) => assert_eq!(*block, Block::genesis()),
+                message => panic!("expected block, got {:?}", message),
+            }
+            break;
+        }
+    }
+}
+
+#[test]
+fn incompatible_version() {
+    let env = test_env!("test_incompatible_version");
+    let mut conn = env.connect_to_node().unwrap();
+    send_message(&mut conn, handshake(0)).unwrap();
+
+    let mut data = vec![];
+    if conn.read_to_end(&mut data).is_err() {
+        panic!("node didn't drop connection");
+    }
+}
+
+#[test]
+fn huge_binary_frame() {
+    let env = test_env!("test_huge_binary_frame");
+    let mut conn = env.connect_to_node().unwrap();
+    conn.write_all(&[0xff]).unwrap();
+    conn.write_all(&(DEFAULT_MAX_FRAME_SIZE as u32 + 1).to_le_bytes())
+        .unwrap();
+
+    let mut data = vec![];
+    if conn.read_to_end(&mut data).is_err() {
+        panic!("node didn't drop connection");
+    }
+}
+
+#[test]
+fn full_block() {
+    let mut config = node::Config::default();
+    config.mining_service.thread_count = 1;
+    config.mining_service.max_tx_per_block = 1000;
+    config.mining_service.public_key = generate_public_key().into();
+    let miner_env = test_env!("test_full_block_miner", config);
+
+    let mut config = node::Config::default();
+    config.peer_service.dial_addresses = vec![miner_env.addr().to_string()];
+    let env = test_env!("test_full_block", config);
+    let mut conn = env.connect_to_node().unwrap();
+
+    // The transactions take more than two blocks of `MAX_BLOCK_SIZE`.
+    let key = generate_private_key();
+    let receiver: WalletId = generate_public_key().into();
+    let transactions: Vec<_> = (0..150)
+        .map(|nonce| {
+            let comment = format!("{:0200}", nonce);
+            VerifiedTransaction::sign(&key, receiver.clone(), 0, 0, nonce, comment).unwrap()
+        })
+        .collect();
+    let tx_size = serde_json::to_vec(&Transaction::from(transactions[0].clone()))
+        .unwrap()
+        .len();
+    let mut miner_conn = miner_env.connect_to_node().unwrap();
+    for tx in &transactions {
+        send_message(
+            &mut miner_conn,
+            PeerMessage::Transaction(Box::new(tx.clone().into())),
+        )
+        .unwrap();
+    }
+
+    // The blocks are mined with as many transactions as fit and get to the other
+    // node over binary frames.
+    let mut received = HashSet::new();
+    let mut max_block_size = 0;
+    wait_for_message(&mut conn, 30, |msg| match msg {
+        PeerMessage::Block(block) => {
+            let size = serde_json::to_vec(block).unwrap().len();
+            assert!(size <= MAX_BLOCK_SIZE);
+            max_block_size = max_block_size.max(size);
+            received.extend(block.transactions.iter().map(|tx| tx.compute_hash()));
+            received.len() == transactions.len()
+        }
+        _ => false,
+    })
+    .unwrap();
+    assert!(max_block_size + tx_size > MAX_BLOCK_SIZE);
+}
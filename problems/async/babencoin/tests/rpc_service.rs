@@ -0,0 +1,93 @@
+#[macro_use]
+mod helpers;
+
+use helpers::{
+    generate_private_key, generate_public_key, random_block, send_message, sync, wait_for_message,
+};
+
+use babencoin::{
+    data::{Block, PeerMessage, Transaction, VerifiedBlock, VerifiedTransaction},
+    node,
+};
+
+use rand::{thread_rng, Rng};
+use serde_json::{json, Value};
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn call(addr: SocketAddr, method: &str, params: Value) -> Value {
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn running_node() {
+    let port = thread_rng().gen_range(49152..65536);
+    let rpc_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let config = node::Config {
+        rpc_service: serde_json::from_value(json!({ "listen_address": rpc_addr })).unwrap(),
+        ..node::Config::default()
+    };
+
+    let env = test_env!("test_rpc_running_node", config);
+    let head = call(rpc_addr, "get_head", Value::Null);
+    assert_eq!(
+        head["result"]["hash"],
+        base64::encode(VerifiedBlock::genesis().hash())
+    );
+
+    let mut block = random_block(1);
+    block.attrs.prev_hash = Block::genesis().compute_hash();
+    let mut conn = env.connect_to_node().unwrap();
+    send_message(&mut conn, PeerMessage::Block(Box::new(block.clone()))).unwrap();
+    sync(&mut conn).unwrap();
+
+    let head = call(rpc_addr, "get_head", Value::Null);
+    assert_eq!(head["result"]["index"], 1);
+    assert_eq!(head["result"]["hash"], base64::encode(block.compute_hash()));
+
+    let tx = VerifiedTransaction::sign(
+        &generate_private_key(),
+        generate_public_key().into(),
+        0,
+        0,
+        0,
+        "rpc".into(),
+    )
+    .unwrap();
+    let tx_json = serde_json::to_value(Transaction::from(tx.clone())).unwrap();
+    let tx = Transaction::from(tx);
+    let response = call(rpc_addr, "submit_transaction", tx_json);
+    assert_eq!(
+        response["result"]["hash"],
+        base64::encode(tx.compute_hash())
+    );
+
+    // Accepted transactions are forwarded to the peers.
+    wait_for_message(&mut conn, 10, |msg| match msg {
+        PeerMessage::Transaction(recv_tx) => **recv_tx == tx,
+        _ => false,
+    })
+    .unwrap();
+    let response = call(rpc_addr, "get_pending_transactions", Value::Null);
+    assert_eq!(
+        response["result"][0]["hash"],
+        base64::encode(tx.compute_hash())
+    );
+}
@@ -0,0 +1,122 @@
+use babencoin::{
+    data::{Block, BlockAttributes, VerifiedBlock, WalletId, MAX_HEADERS_PER_MESSAGE, MAX_REWARD},
+    node::{
+        self,
+        simulation::{NetworkConfig, Simulation},
+    },
+};
+
+use std::time::Duration;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn configs(count: usize) -> Vec<node::Config> {
+    (0..count)
+        .map(|_| {
+            let mut config = node::Config::default();
+            config.gossip_service.eager_requests_interval = Duration::from_millis(100);
+            config
+        })
+        .collect()
+}
+
+/// Blocks of the genesis difficulty, `interval` seconds apart.
+fn make_chain(prev: &VerifiedBlock, len: usize, interval: i64) -> Vec<VerifiedBlock> {
+    let mut chain: Vec<VerifiedBlock> = vec![];
+    for _ in 0..len {
+        let prev = chain.last().unwrap_or(prev);
+        let mut block = Block {
+            attrs: BlockAttributes::clone(prev),
+            transactions: vec![],
+        };
+        block.index += 1;
+        block.timestamp = prev.timestamp + chrono::Duration::seconds(interval);
+        block.reward = MAX_REWARD;
+        block.issuer = WalletId::of_genesis();
+        block.prev_hash = *prev.hash();
+        chain.push(block.verified().unwrap());
+    }
+    chain
+}
+
+#[test]
+fn fork_resolution() {
+    let network_config = NetworkConfig {
+        latency: Duration::from_millis(10),
+        ..NetworkConfig::default()
+    };
+    let sim = Simulation::new(network_config, configs(3)).unwrap();
+    sim.network().partition(&[&[0, 1], &[2]]);
+
+    let genesis = VerifiedBlock::genesis();
+    let short_chain = make_chain(&genesis, 2, 10);
+    let long_chain = make_chain(&genesis, 3, 11);
+    for block in &short_chain {
+        sim.add_block(0, block.clone()).unwrap();
+    }
+    for block in &long_chain {
+        sim.add_block(2, block.clone()).unwrap();
+    }
+    let short_tip = short_chain.last().unwrap().hash();
+    let long_tip = long_chain.last().unwrap().hash();
+    sim.wait_for_head(1, TIMEOUT, |head| head.hash() == short_tip)
+        .unwrap();
+    sim.wait_for_head(2, TIMEOUT, |head| head.hash() == long_tip)
+        .unwrap();
+    assert_eq!(sim.head(0).unwrap().hash(), short_tip);
+
+    sim.network().heal();
+    let head = sim.wait_for_consensus(TIMEOUT).unwrap();
+    assert_eq!(head.hash(), long_tip);
+}
+
+#[test]
+fn lossy_network() {
+    let network_config = NetworkConfig {
+        seed: 7,
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(20),
+        loss_rate: 0.3,
+    };
+    let sim = Simulation::new(network_config, configs(4)).unwrap();
+
+    let chain = make_chain(&VerifiedBlock::genesis(), 6, 10);
+    for block in &chain[..5] {
+        sim.add_block(0, block.clone()).unwrap();
+    }
+    sim.wait_for_head(0, TIMEOUT, |head| head.index == 5)
+        .unwrap();
+
+    // The missing ancestors of the last block are requested again.
+    sim.network().set_loss_rate(0.);
+    sim.add_block(0, chain[5].clone()).unwrap();
+    let head = sim.wait_for_consensus(TIMEOUT).unwrap();
+    assert_eq!(head.hash(), chain[5].hash());
+    assert!(sim.network().stats().delivered > 0);
+}
+
+#[test]
+fn lagging_node_catches_up() {
+    let network_config = NetworkConfig {
+        latency: Duration::from_millis(10),
+        ..NetworkConfig::default()
+    };
+    let sim = Simulation::new(network_config, configs(3)).unwrap();
+    sim.network().partition(&[&[0, 1], &[2]]);
+
+    // Takes several headers requests, and the blocks are downloaded from both peers.
+    let chain = make_chain(&VerifiedBlock::genesis(), 3 * MAX_HEADERS_PER_MESSAGE, 10);
+    for block in &chain {
+        sim.add_block(0, block.clone()).unwrap();
+    }
+    let tip = chain.last().unwrap().hash();
+    sim.wait_for_head(1, TIMEOUT, |head| head.hash() == tip)
+        .unwrap();
+    assert_eq!(sim.head(2).unwrap().index, 0);
+
+    sim.network().heal();
+    let head = sim.wait_for_consensus(TIMEOUT).unwrap();
+    assert_eq!(head.hash(), tip);
+}
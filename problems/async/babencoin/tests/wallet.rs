@@ -0,0 +1,109 @@
+#[allow(unused_macros)]
+mod helpers;
+
+use helpers::recv_message;
+
+use babencoin::{
+    data::{PeerMessage, Transaction},
+    util::parse_pkcs8_public,
+};
+
+use std::{
+    io::Write,
+    net::TcpListener,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn run_wallet(args: &[&str], stdin: &[u8]) -> Output {
+    let binary_path = if cfg!(debug_assertions) {
+        "../../../target/debug/babencoin-wallet"
+    } else {
+        "../../../target/release/babencoin-wallet"
+    };
+    let mut child = Command::new(binary_path)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn run_wallet_ok(args: &[&str]) -> String {
+    let output = run_wallet(args, b"");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn wallet() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_path = dir.path().join("key.pem");
+    let key = path_str(&key_path);
+
+    let wallet = run_wallet_ok(&["generate", "--key", key, "--bits", "1024"]);
+    parse_pkcs8_public(&wallet).unwrap();
+    assert_eq!(run_wallet_ok(&["show", "--key", key]), wallet);
+    assert!(!run_wallet(&["generate", "--key", key], b"")
+        .status
+        .success());
+
+    let raw_tx = run_wallet_ok(&[
+        "sign",
+        "--key",
+        key,
+        "--to",
+        &wallet,
+        "--amount",
+        "10",
+        "--fee",
+        "2",
+        "--nonce",
+        "3",
+        "--comment",
+        "hi",
+    ]);
+    let tx: Transaction = serde_json::from_str(&raw_tx).unwrap();
+    assert_eq!(
+        (tx.amount, tx.fee, tx.nonce, tx.comment.as_str()),
+        (10, 2, 3, "hi")
+    );
+    let tx = tx.verified().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = listener.local_addr().unwrap().to_string();
+    let output = run_wallet(&["send", "--peer", &peer], raw_tx.as_bytes());
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().trim(),
+        base64::encode(tx.hash())
+    );
+    let (mut conn, _) = listener.accept().unwrap();
+    match recv_message(&mut conn).unwrap() {
+        PeerMessage::Transaction(received) => {
+            assert_eq!(received.verified().unwrap().hash(), tx.hash())
+        }
+        message => panic!("unexpected message: {:?}", message),
+    }
+
+    let forged_tx = raw_tx.replace("\"amount\": 10", "\"amount\": 11");
+    assert_ne!(forged_tx, raw_tx);
+    assert!(
+        !run_wallet(&["send", "--peer", &peer], forged_tx.as_bytes())
+            .status
+            .success()
+    );
+}
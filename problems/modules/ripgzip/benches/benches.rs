@@ -0,0 +1,72 @@
+use std::io::{self, Read};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use ripgzip::{compress, decompress, GzipDecoder};
+
+fn corpus() -> Vec<u8> {
+    let mut data = vec![];
+    for compressed in [
+        &include_bytes!("../data/ok/05-app.gz")[..],
+        include_bytes!("../data/ok/06-war-and-peace.txt.gz"),
+        include_bytes!("../data/ok/03-photo.jpg.gz"),
+    ] {
+        decompress(compressed, &mut data).unwrap();
+    }
+    data
+}
+
+fn bench_decompress(c: &mut Criterion) {
+    let data = corpus();
+
+    let mut group = c.benchmark_group("decompress");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+
+    for level in [1, 6, 9] {
+        let mut compressed = vec![];
+        compress(data.as_slice(), &mut compressed, level).unwrap();
+
+        group.bench_function(format!("level_{}", level), |b| {
+            b.iter(|| decompress(compressed.as_slice(), io::sink()).unwrap())
+        });
+    }
+
+    let mut compressed = vec![];
+    compress(data.as_slice(), &mut compressed, 6).unwrap();
+    group.bench_function("gzip_decoder", |b| {
+        b.iter(|| {
+            let mut decoder = GzipDecoder::new(compressed.as_slice());
+            io::copy(&mut decoder, &mut io::sink()).unwrap()
+        })
+    });
+    group.bench_function("gzip_decoder_read_to_end", |b| {
+        b.iter(|| {
+            let mut output = Vec::with_capacity(data.len());
+            GzipDecoder::new(compressed.as_slice())
+                .read_to_end(&mut output)
+                .unwrap()
+        })
+    });
+
+    group.finish();
+}
+
+fn bench_compress(c: &mut Criterion) {
+    let data = corpus();
+
+    let mut group = c.benchmark_group("compress");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+
+    for level in [1, 6, 9] {
+        group.bench_function(format!("level_{}", level), |b| {
+            b.iter(|| compress(data.as_slice(), io::sink(), level).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_decompress, bench_compress);
+criterion_main!(benches);
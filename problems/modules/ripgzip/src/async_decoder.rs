@@ -0,0 +1,363 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    io::{self, BufRead, Read},
+    pin::Pin,
+    task::{ready, Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Context, Result};
+use log::*;
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+use crate::{
+    bit_reader::{BitReader, PartialByte},
+    deflate::{BlockDecoder, DeflateReader},
+    gzip::{GzipReader, MemberReader},
+    tracking_writer::TrackingWriter,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Amount of decompressed data produced at once. The output buffer may exceed it
+/// by at most one back reference.
+const OUTPUT_CHUNK_SIZE: usize = 32768;
+
+/// Input to wait for before making a step: a token takes at most 6 bytes, and there is
+/// always an 8-byte footer after it, so waiting for this much never stalls decoding.
+const MIN_STEP_INPUT: usize = 8;
+
+/// Compressed data received so far. Running out of it before the input is over is
+/// reported as `WouldBlock`, after which the reader is rewound to the last commit.
+#[derive(Default)]
+struct PendingInput {
+    buf: Vec<u8>,
+    position: usize,
+    committed: usize,
+    is_over: bool,
+}
+
+impl PendingInput {
+    fn extend(&mut self, data: &[u8]) {
+        // Committed data is never read again.
+        self.buf.drain(..self.committed);
+        self.position -= self.committed;
+        self.committed = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    fn available(&self) -> usize {
+        self.buf.len() - self.position
+    }
+
+    fn commit(&mut self) {
+        self.committed = self.position;
+    }
+
+    fn rewind(&mut self) {
+        self.position = self.committed;
+    }
+}
+
+impl Read for PendingInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for PendingInput {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.buf.len() && !self.is_over {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(&self.buf[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.buf.len());
+    }
+}
+
+fn is_pending(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<io::Error>(),
+            Some(err) if err.kind() == io::ErrorKind::WouldBlock
+        )
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum State {
+    Header,
+    Body {
+        partial_byte: PartialByte,
+        block: Option<BlockDecoder>,
+        is_final: bool,
+    },
+    Done,
+    Failed,
+}
+
+/// Asynchronous counterpart of `GzipDecoder`. Decoding advances in steps: a member
+/// header, a block header, a single token or a piece of a stored block. A step that
+/// runs out of the received input is rolled back and retried once more data arrives.
+pub struct AsyncGzipDecoder<R> {
+    reader: R,
+    input: PendingInput,
+    state: State,
+    writer: TrackingWriter<Vec<u8>>,
+    position: usize,
+    member_index: usize,
+    /// Amount of input to wait for before making a step. It grows exponentially after
+    /// running out of input, so that long block headers are not parsed over and over
+    /// as bytes trickle in.
+    required_input: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncGzipDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            input: PendingInput::default(),
+            state: State::Header,
+            writer: TrackingWriter::new(Vec::with_capacity(OUTPUT_CHUNK_SIZE)),
+            position: 0,
+            member_index: 0,
+            required_input: MIN_STEP_INPUT,
+        }
+    }
+
+    /// Return the underlying reader. Compressed data received but not decoded yet is lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Decode until a chunk of output is ready, the stream is over or more input
+    /// is needed. Returns `false` in the last case if no output was produced.
+    fn fill_output(&mut self) -> Result<bool> {
+        while self.writer.get_mut().len() < OUTPUT_CHUNK_SIZE {
+            match self.state {
+                State::Done => break,
+                State::Failed => bail!("decoder has failed before"),
+                _ => {}
+            }
+            if !self.input.is_over && self.input.available() < self.required_input {
+                return Ok(!self.writer.get_mut().is_empty());
+            }
+
+            self.input.commit();
+            match self.step() {
+                Ok(()) => self.required_input = MIN_STEP_INPUT,
+                Err(err) if is_pending(&err) => {
+                    self.input.rewind();
+                    self.required_input = 2 * self.input.available();
+                    return Ok(!self.writer.get_mut().is_empty());
+                }
+                Err(err) => {
+                    self.state = State::Failed;
+                    return Err(err);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Make a single step. The state is left intact if it fails.
+    fn step(&mut self) -> Result<()> {
+        let (partial_byte, block, is_final) = match &mut self.state {
+            State::Header => {
+                match GzipReader::new(&mut self.input).next_member() {
+                    None => self.state = State::Done,
+                    Some(member) => {
+                        let (header, _) = member.with_context(|| {
+                            format!("failed to read header of member #{}", self.member_index)
+                        })?;
+                        debug!("decompressing member #{}: {:?}", self.member_index, header);
+                        self.state = State::Body {
+                            partial_byte: PartialByte::default(),
+                            block: None,
+                            is_final: false,
+                        };
+                    }
+                }
+                return Ok(());
+            }
+            State::Body {
+                partial_byte,
+                block,
+                is_final,
+            } => (partial_byte, block, is_final),
+            State::Done | State::Failed => unreachable!(),
+        };
+
+        let member_index = self.member_index;
+        let context = || format!("failed to decompress member #{}", member_index);
+        let mut bit_reader = BitReader::with_partial_byte(&mut self.input, *partial_byte);
+        match block {
+            Some(decoder) => {
+                // Tokens are decoded one by one, so that a step never writes partially.
+                let limit = match decoder {
+                    BlockDecoder::Uncompressed { .. } => bit_reader.get_ref().available().max(1),
+                    BlockDecoder::Huffman { .. } => 1,
+                };
+                if decoder
+                    .decode(&mut bit_reader, &mut self.writer, limit)
+                    .with_context(context)?
+                {
+                    *block = None;
+                }
+                *partial_byte = bit_reader.partial_byte();
+            }
+            None if !*is_final => {
+                let mut deflate_reader = DeflateReader::new(bit_reader);
+                let (header, bit_reader) = deflate_reader
+                    .next_block()
+                    .expect("a new reader has seen no final block")
+                    .with_context(context)?;
+                *block = Some(BlockDecoder::new(&header, bit_reader).with_context(context)?);
+                *is_final = header.is_final;
+                *partial_byte = bit_reader.partial_byte();
+            }
+            None => {
+                let member_reader = MemberReader::new(bit_reader.into_inner());
+                let (footer, _) = member_reader.read_footer().with_context(context)?;
+                footer.verify(&self.writer).with_context(context)?;
+
+                // Members are independent, so start tracking from scratch.
+                let output =
+                    std::mem::replace(&mut self.writer, TrackingWriter::new(vec![])).into_inner();
+                *self.writer.get_mut() = output;
+                self.member_index += 1;
+                self.state = State::Header;
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_input(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let data = ready!(Pin::new(&mut self.reader).poll_fill_buf(cx))?;
+        let len = data.len();
+        if len == 0 {
+            self.input.is_over = true;
+        } else {
+            self.input.extend(data);
+        }
+        Pin::new(&mut self.reader).consume(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for AsyncGzipDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let available = &this.writer.get_mut()[this.position..];
+            if !available.is_empty() {
+                let len = available.len().min(buf.remaining());
+                buf.put_slice(&available[..len]);
+                this.position += len;
+                return Poll::Ready(Ok(()));
+            }
+            if let State::Done = this.state {
+                return Poll::Ready(Ok(()));
+            }
+
+            this.writer.get_mut().clear();
+            this.position = 0;
+            let has_output = this
+                .fill_output()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", err)))?;
+            while !has_output && !this.input.is_over && this.input.available() < this.required_input
+            {
+                ready!(this.poll_input(cx))?;
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+    fn decompress_all(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        crate::decompress(data, &mut output).unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn small_reads() -> io::Result<()> {
+        for data in [
+            &include_bytes!("../data/ok/02-doc.pdf.gz")[..],
+            include_bytes!("../data/ok/09-concat.gz"),
+            include_bytes!("../data/ok/10-header-crc16.gz"),
+        ] {
+            let mut output = vec![];
+            AsyncGzipDecoder::new(BufReader::with_capacity(5, data))
+                .read_to_end(&mut output)
+                .await?;
+            assert_eq!(output, decompress_all(data));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_input() -> io::Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let (mut sender, receiver) = tokio::io::duplex(1000);
+        let writer = tokio::spawn(async move {
+            for chunk in data.chunks(777) {
+                sender.write_all(chunk).await?;
+                tokio::task::yield_now().await;
+            }
+            Ok::<_, io::Error>(())
+        });
+
+        let mut output = vec![];
+        AsyncGzipDecoder::new(BufReader::new(receiver))
+            .read_to_end(&mut output)
+            .await?;
+        writer.await??;
+        assert_eq!(output, decompress_all(data));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        for (data, msg) in [
+            (
+                &include_bytes!("../data/corrupted/00-bad-length.gz")[..],
+                "length check failed",
+            ),
+            (
+                include_bytes!("../data/corrupted/06-invalid-btype.gz"),
+                "unsupported block type",
+            ),
+            (
+                include_bytes!("../data/corrupted/02-unexpected-eof.gz"),
+                "failed to decompress member #0",
+            ),
+        ] {
+            let mut output = vec![];
+            let err = AsyncGzipDecoder::new(data)
+                .read_to_end(&mut output)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(msg), "{}", err);
+        }
+    }
+}
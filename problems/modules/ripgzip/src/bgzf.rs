@@ -0,0 +1,428 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+use anyhow::{ensure, Context, Result};
+use log::*;
+
+use crate::{
+    counting_reader::CountingReader,
+    deflate_writer::{self, DeflateWriter},
+    gzip::{self, ExtraSubfield, GzipReader, MemberFooter, MemberHeader},
+    tracking_writer::CRC32,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// ID of the extra subfield holding the block size.
+const BLOCK_SIZE_SUBFIELD_ID: [u8; 2] = *b"BC";
+
+/// Offset of the block size in a block written by `BgzfWriter`.
+const BLOCK_SIZE_OFFSET: usize = 16;
+
+/// Largest size of a whole block, including the header and the footer.
+const MAX_BLOCK_SIZE: usize = 1 << 16;
+
+/// Uncompressed data per block, the same as in htslib. Compressed, it fits
+/// into `MAX_BLOCK_SIZE` almost always.
+pub const BGZF_BLOCK_DATA_SIZE: usize = 0xff00;
+
+/// Empty block marking the end of BGZF data.
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Position in BGZF data: the offset of a block in the compressed data in the upper
+/// 48 bits and the offset in its uncompressed data in the lower 16 bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtualOffset(pub u64);
+
+impl VirtualOffset {
+    pub fn new(block_offset: u64, data_offset: u16) -> Self {
+        assert!(
+            block_offset < 1 << 48,
+            "block offset does not fit into 48 bits"
+        );
+        Self(block_offset << 16 | data_offset as u64)
+    }
+
+    pub fn block_offset(self) -> u64 {
+        self.0 >> 16
+    }
+
+    pub fn data_offset(self) -> u16 {
+        self.0 as u16
+    }
+}
+
+/// Size of the whole block as stored in the `BC` subfield of its header.
+fn block_size(header: &MemberHeader) -> Result<u64> {
+    let subfields = header.extra_subfields()?;
+    let subfield = subfields
+        .iter()
+        .find(|subfield| subfield.id == BLOCK_SIZE_SUBFIELD_ID)
+        .context("not a BGZF block: no BC subfield")?;
+    ensure!(subfield.data.len() == 2, "BC subfield must take 2 bytes");
+    Ok(u16::from_le_bytes([subfield.data[0], subfield.data[1]]) as u64 + 1)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Compresses data into BGZF blocks: gzip members of at most 64 KiB recording their
+/// own size, which enables random access with virtual offsets. Call `finish` to
+/// append the end-of-file marker.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    level: u32,
+    buffer: Vec<u8>,
+    /// Amount of compressed data written so far.
+    block_offset: u64,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// Fails with `InvalidInput` if `level` is above `MAX_LEVEL`.
+    pub fn new(inner: W, level: u32) -> io::Result<Self> {
+        deflate_writer::check_level(level)?;
+        Ok(Self {
+            inner,
+            level,
+            buffer: Vec::with_capacity(BGZF_BLOCK_DATA_SIZE),
+            block_offset: 0,
+        })
+    }
+
+    /// Virtual offset the next written byte will have.
+    pub fn virtual_offset(&self) -> VirtualOffset {
+        VirtualOffset::new(self.block_offset, self.buffer.len() as u16)
+    }
+
+    fn compress_block(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let extra = ExtraSubfield::encode_all(&[ExtraSubfield {
+            id: BLOCK_SIZE_SUBFIELD_ID,
+            data: vec![0; 2],
+        }]);
+        let mut block = vec![];
+        MemberHeader {
+            extra: Some(extra),
+            ..MemberHeader::new(self.level)
+        }
+        .write_to(&mut block)?;
+
+        let mut deflate_writer = DeflateWriter::new(block, self.level);
+        deflate_writer.write_all(data)?;
+        let mut block = deflate_writer.finish()?;
+        MemberFooter {
+            data_crc32: CRC32.checksum(data),
+            data_size: data.len() as u32,
+        }
+        .write_to(&mut block)?;
+
+        let size = ((block.len() - 1) as u16).to_le_bytes();
+        block[BLOCK_SIZE_OFFSET..BLOCK_SIZE_OFFSET + 2].copy_from_slice(&size);
+        Ok(block)
+    }
+
+    fn write_block(&mut self, data: &[u8]) -> io::Result<()> {
+        let block = self.compress_block(data)?;
+        if block.len() > MAX_BLOCK_SIZE {
+            // Incompressible data may grow a little, so split it.
+            let (head, tail) = data.split_at(data.len() / 2);
+            self.write_block(head)?;
+            return self.write_block(tail);
+        }
+        self.inner.write_all(&block)?;
+        self.block_offset += block.len() as u64;
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.buffer);
+        self.write_block(&data)?;
+        self.buffer = data;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Write the pending data and the end-of-file marker, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BGZF_BLOCK_DATA_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == BGZF_BLOCK_DATA_SIZE {
+            self.flush_block()?;
+        }
+        Ok(len)
+    }
+
+    /// Write the pending data as a block of its own.
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.inner.flush()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Decompresses BGZF data block by block, checking that the blocks match their sizes.
+/// Seeking to virtual offsets is possible if the underlying reader is seekable.
+pub struct BgzfReader<R> {
+    reader: R,
+    block: Vec<u8>,
+    position: usize,
+    block_offset: u64,
+    next_block_offset: u64,
+}
+
+impl<R: BufRead> BgzfReader<R> {
+    /// Create a reader over data starting with a block.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            block: vec![],
+            position: 0,
+            block_offset: 0,
+            next_block_offset: 0,
+        }
+    }
+
+    /// Virtual offset of the next byte to be read.
+    pub fn virtual_offset(&self) -> VirtualOffset {
+        if self.position == self.block.len() {
+            VirtualOffset::new(self.next_block_offset, 0)
+        } else {
+            VirtualOffset::new(self.block_offset, self.position as u16)
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Decompress the next block. Returns `false` if the data is over.
+    fn read_block(&mut self) -> Result<bool> {
+        let offset = self.next_block_offset;
+        let mut reader = CountingReader::new(&mut self.reader);
+        let (header, member_reader) = match GzipReader::new(&mut reader).next_member() {
+            Some(member) => member,
+            None => return Ok(false),
+        }
+        .with_context(|| format!("failed to read block header at offset {}", offset))?;
+        let size = block_size(&header)?;
+
+        self.block.clear();
+        gzip::decompress_member(member_reader, &mut self.block)
+            .with_context(|| format!("failed to decompress block at offset {}", offset))?;
+        ensure!(
+            reader.position() == size,
+            "block at offset {} takes {} bytes instead of {}",
+            offset,
+            reader.position(),
+            size
+        );
+        ensure!(
+            self.block.len() <= MAX_BLOCK_SIZE,
+            "block at offset {} is too big",
+            offset
+        );
+        trace!("read block at offset {}: {} bytes", offset, size);
+
+        self.block_offset = offset;
+        self.next_block_offset = offset + size;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: BufRead + Seek> BgzfReader<R> {
+    /// Continue reading from `offset`, e.g. one recorded by `BgzfWriter::virtual_offset`.
+    pub fn seek_virtual(&mut self, offset: VirtualOffset) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset.block_offset()))?;
+        self.block.clear();
+        self.position = 0;
+        self.block_offset = offset.block_offset();
+        self.next_block_offset = offset.block_offset();
+        if offset.data_offset() == 0 {
+            return Ok(());
+        }
+
+        ensure!(
+            self.read_block()?,
+            "no block at offset {}",
+            offset.block_offset()
+        );
+        let data_offset = offset.data_offset() as usize;
+        ensure!(
+            data_offset <= self.block.len(),
+            "block at offset {} has only {} bytes",
+            offset.block_offset(),
+            self.block.len()
+        );
+        self.position = data_offset;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for BgzfReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // Skip empty blocks, like the end-of-file marker.
+        while self.position == self.block.len() {
+            let is_read = self
+                .read_block()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", err)))?;
+            if !is_read {
+                break;
+            }
+        }
+        Ok(&self.block[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.block.len());
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompress_all(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        crate::decompress(data, &mut output).unwrap();
+        output
+    }
+
+    fn compress_bgzf(data: &[u8], level: u32) -> Result<Vec<u8>> {
+        let mut writer = BgzfWriter::new(vec![], level)?;
+        writer.write_all(data)?;
+        Ok(writer.finish()?)
+    }
+
+    #[test]
+    fn subfields() -> Result<()> {
+        let subfields = vec![
+            ExtraSubfield {
+                id: *b"AB",
+                data: b"hello".to_vec(),
+            },
+            ExtraSubfield {
+                id: *b"CD",
+                data: vec![],
+            },
+        ];
+        let extra = ExtraSubfield::encode_all(&subfields);
+        assert_eq!(extra, b"AB\x05\x00helloCD\x00\x00");
+        assert_eq!(ExtraSubfield::parse_all(&extra)?, subfields);
+        assert!(ExtraSubfield::parse_all(&extra[..extra.len() - 1]).is_err());
+        assert!(ExtraSubfield::parse_all(&extra[..6]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn blocks() -> Result<()> {
+        let data = decompress_all(include_bytes!("../data/ok/09-concat.gz"));
+        // Pseudorandom bytes do not compress at all.
+        let mut state = 1u32;
+        let noise = (0..200_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+
+        for (data, level) in [(&data, 6), (&data, 0), (&noise, 9), (&noise, 1)] {
+            let compressed = compress_bgzf(data, level)?;
+            assert!(compressed.ends_with(&BGZF_EOF));
+            assert_eq!(&decompress_all(&compressed), data);
+
+            for member in crate::members(compressed.as_slice()) {
+                let member = member?;
+                assert!(member.compressed_size <= MAX_BLOCK_SIZE as u64);
+                assert_eq!(block_size(&member.header)?, member.compressed_size);
+            }
+
+            let mut output = vec![];
+            BgzfReader::new(compressed.as_slice()).read_to_end(&mut output)?;
+            assert_eq!(&output, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_level() {
+        let err = BgzfWriter::new(vec![], 10).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn seek_virtual() -> Result<()> {
+        let data = decompress_all(include_bytes!("../data/ok/01-page.gz"));
+        let mut writer = BgzfWriter::new(vec![], 6)?;
+        let mut offsets = vec![];
+        for (i, chunk) in data.chunks(10_007).enumerate() {
+            offsets.push((i * 10_007, writer.virtual_offset()));
+            writer.write_all(chunk)?;
+        }
+        let compressed = writer.finish()?;
+
+        let mut reader = BgzfReader::new(io::Cursor::new(&compressed));
+        for &(position, offset) in offsets.iter().rev() {
+            reader.seek_virtual(offset)?;
+            assert_eq!(reader.virtual_offset(), offset);
+            let mut buf = vec![0; 5000.min(data.len() - position)];
+            reader.read_exact(&mut buf)?;
+            assert_eq!(buf, data[position..position + buf.len()]);
+        }
+
+        reader.seek_virtual(offsets[0].1)?;
+        let mut output = vec![];
+        reader.read_to_end(&mut output)?;
+        assert_eq!(output, data);
+        assert_eq!(
+            reader.virtual_offset(),
+            VirtualOffset::new(compressed.len() as u64, 0)
+        );
+
+        let err = reader
+            .seek_virtual(VirtualOffset::new(0, u16::MAX))
+            .unwrap_err();
+        assert!(err.to_string().contains("has only"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn not_bgzf() {
+        let data = include_bytes!("../data/ok/00-Cargo.toml.gz");
+        let err = BgzfReader::new(&data[..])
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert!(err.to_string().contains("no BC subfield"), "{}", err);
+    }
+}
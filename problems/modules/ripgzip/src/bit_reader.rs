@@ -2,6 +2,14 @@
 
 use std::io::{self, BufRead};
 
+use byteorder::ReadBytesExt;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// At most 7 bits of the current byte are buffered, so 7 more bytes always fit into u64.
+const PEEK_BYTES: usize = 7;
+const PEEK_MASK: u64 = (1 << (8 * PEEK_BYTES)) - 1;
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -13,49 +21,162 @@ pub struct BitSequence {
 impl BitSequence {
     pub fn new(bits: u16, len: u8) -> Self {
         // NB: make sure to zero unused bits so that Eq and Hash work as expected.
-        // TODO: your code goes here.
-        unimplemented!()
+        assert!(len <= 16, "bit sequence is too long");
+        let mask = ((1u32 << len) - 1) as u16;
+        Self {
+            bits: bits & mask,
+            len,
+        }
     }
 
     pub fn bits(&self) -> u16 {
-        // TODO: your code goes here.
-        unimplemented!()
+        self.bits
     }
 
     pub fn len(&self) -> u8 {
-        // TODO: your code goes here.
-        unimplemented!()
+        self.len
     }
 
-    pub fn concat(self, other: Self) -> Self {
-        // TODO: your code goes here.
-        unimplemented!()
+    /// Reverse the order of bits, e.g. to turn a Huffman code into the order it is stored in.
+    pub fn reversed(self) -> Self {
+        if self.len == 0 {
+            return self;
+        }
+        Self::new(self.bits.reverse_bits() >> (16 - self.len), self.len)
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Unread bits of the byte a `BitReader` is in the middle of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartialByte {
+    bits: u64,
+    len: u8,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reads the stream bit by bit, never consuming more bytes of the underlying reader
+/// than the bits already read require: the rest of the current byte is all that is kept.
 pub struct BitReader<T> {
     stream: T,
-    // TODO: your code goes here.
+    buffer: u64,
+    buffer_len: u8,
 }
 
 impl<T: BufRead> BitReader<T> {
     pub fn new(stream: T) -> Self {
-        // TODO: your code goes here.
-        unimplemented!()
+        Self {
+            stream,
+            buffer: 0,
+            buffer_len: 0,
+        }
+    }
+
+    /// Continue reading from the middle of a byte, where another reader over the same
+    /// stream has stopped.
+    pub fn with_partial_byte(stream: T, partial_byte: PartialByte) -> Self {
+        Self {
+            stream,
+            buffer: partial_byte.bits,
+            buffer_len: partial_byte.len,
+        }
     }
 
-    pub fn read_bits(&mut self, mut len: u8) -> io::Result<BitSequence> {
-        // TODO: your code goes here.
-        unimplemented!()
+    pub fn partial_byte(&self) -> PartialByte {
+        PartialByte {
+            bits: self.buffer,
+            len: self.buffer_len,
+        }
+    }
+
+    /// Return up to 63 next bits of the stream without consuming them, along with
+    /// their count. Only the data already buffered by the underlying reader is looked at,
+    /// so fewer bits may be returned near the end of its buffer.
+    pub fn peek_bits(&mut self) -> io::Result<(u64, u8)> {
+        let buf = self.stream.fill_buf()?;
+        let byte_count = buf.len().min(PEEK_BYTES);
+        let word = if buf.len() >= 8 {
+            u64::from_le_bytes(buf[..8].try_into().unwrap()) & PEEK_MASK
+        } else {
+            let mut bytes = [0u8; 8];
+            bytes[..byte_count].copy_from_slice(&buf[..byte_count]);
+            u64::from_le_bytes(bytes)
+        };
+        Ok((
+            self.buffer | word << self.buffer_len,
+            self.buffer_len + 8 * byte_count as u8,
+        ))
+    }
+
+    /// Consume `len` bits previously returned by `peek_bits`.
+    pub fn consume_bits(&mut self, len: u8) -> io::Result<()> {
+        if len <= self.buffer_len {
+            self.buffer >>= len;
+            self.buffer_len -= len;
+            return Ok(());
+        }
+
+        let rest = len - self.buffer_len;
+        self.stream.consume((rest / 8) as usize);
+        self.buffer = 0;
+        self.buffer_len = 0;
+        if !rest.is_multiple_of(8) {
+            let byte = self.stream.read_u8()?;
+            self.buffer = (byte >> (rest % 8)) as u64;
+            self.buffer_len = 8 - rest % 8;
+        }
+        Ok(())
+    }
+
+    pub fn read_bits(&mut self, len: u8) -> io::Result<BitSequence> {
+        assert!(len <= 16, "cannot read more than 16 bits at once");
+        if len <= self.buffer_len {
+            let seq = BitSequence::new(self.buffer as u16, len);
+            self.buffer >>= len;
+            self.buffer_len -= len;
+            return Ok(seq);
+        }
+
+        let (bits, available) = self.peek_bits()?;
+        if len <= available {
+            self.consume_bits(len)?;
+            return Ok(BitSequence::new(bits as u16, len));
+        }
+
+        // Near the end of the reader buffer: fetch the bytes one by one.
+        while self.buffer_len < len {
+            let byte = self.stream.read_u8()?;
+            self.buffer |= (byte as u64) << self.buffer_len;
+            self.buffer_len += 8;
+        }
+        let seq = BitSequence::new(self.buffer as u16, len);
+        self.buffer >>= len;
+        self.buffer_len -= len;
+        Ok(seq)
+    }
+
+    /// Number of unread bits left in the last byte taken from the underlying reader.
+    pub fn buffered_len(&self) -> u8 {
+        self.buffer_len
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.stream
     }
 
     /// Discard all the unread bits in the current byte and return a mutable reference
     /// to the underlying reader.
     pub fn borrow_reader_from_boundary(&mut self) -> &mut T {
-        // TODO: your code goes here.
-        unimplemented!()
+        self.buffer = 0;
+        self.buffer_len = 0;
+        &mut self.stream
+    }
+
+    /// Discard all the unread bits in the current byte and return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.stream
     }
 }
 
@@ -64,7 +185,6 @@ impl<T: BufRead> BitReader<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use byteorder::ReadBytesExt;
 
     #[test]
     fn read_bits() -> io::Result<()> {
@@ -83,6 +203,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn peek_and_consume() -> io::Result<()> {
+        let data: &[u8] = &[0b01100011, 0b11011011, 0b10101111];
+        let mut reader = BitReader::new(data);
+        assert_eq!(reader.peek_bits()?, (0b101011111101101101100011, 24));
+        reader.consume_bits(3)?;
+        assert_eq!(reader.peek_bits()?, (0b101011111101101101100, 21));
+        reader.consume_bits(7)?;
+        assert_eq!(reader.read_bits(4)?, BitSequence::new(0b0110, 4));
+        assert_eq!(reader.peek_bits()?, (0b1010111111, 10));
+        reader.consume_bits(10)?;
+        assert_eq!(reader.peek_bits()?, (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn no_read_ahead() -> io::Result<()> {
+        // A reader that hands out one byte at a time.
+        let data: &[u8] = &[0b01100011, 0b11011011, 0b10101111, 0b00001111];
+        let mut reader = BitReader::new(io::BufReader::with_capacity(1, data));
+        assert_eq!(reader.read_bits(9)?, BitSequence::new(0b101100011, 9));
+        assert_eq!(reader.read_bits(2)?, BitSequence::new(0b01, 2));
+        assert_eq!(reader.borrow_reader_from_boundary().read_u8()?, 0b10101111);
+        assert_eq!(reader.read_bits(8)?, BitSequence::new(0b00001111, 8));
+        Ok(())
+    }
+
     #[test]
     fn borrow_reader_from_boundary() -> io::Result<()> {
         let data: &[u8] = &[0b01100011, 0b11011011, 0b10101111];
@@ -92,4 +239,17 @@ mod tests {
         assert_eq!(reader.read_bits(8)?, BitSequence::new(0b10101111, 8));
         Ok(())
     }
+
+    #[test]
+    fn partial_byte() -> io::Result<()> {
+        let mut data: &[u8] = &[0b01100011, 0b11011011, 0b10101111];
+        let mut reader = BitReader::new(&mut data);
+        assert_eq!(reader.read_bits(3)?, BitSequence::new(0b011, 3));
+        let partial_byte = reader.partial_byte();
+
+        let mut reader = BitReader::with_partial_byte(&mut data, partial_byte);
+        assert_eq!(reader.read_bits(9)?, BitSequence::new(0b101101100, 9));
+        assert_eq!(reader.read_bits(12)?, BitSequence::new(0b101011111101, 12));
+        Ok(())
+    }
 }
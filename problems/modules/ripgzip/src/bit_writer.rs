@@ -0,0 +1,93 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, Write};
+
+use crate::bit_reader::BitSequence;
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct BitWriter<T> {
+    stream: T,
+    buffer: u64,
+    buffer_len: u8,
+}
+
+impl<T: Write> BitWriter<T> {
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            buffer: 0,
+            buffer_len: 0,
+        }
+    }
+
+    /// Write the bits of `seq` starting from the least significant one,
+    /// i.e. in the same order `BitReader::read_bits` returns them.
+    pub fn write_bits(&mut self, seq: BitSequence) -> io::Result<()> {
+        self.buffer |= (seq.bits() as u64) << self.buffer_len;
+        self.buffer_len += seq.len();
+        if self.buffer_len >= 32 {
+            self.stream.write_all(&(self.buffer as u32).to_le_bytes())?;
+            self.buffer >>= 32;
+            self.buffer_len -= 32;
+        }
+        Ok(())
+    }
+
+    /// Pad the current byte with zeros and return a mutable reference to the underlying writer.
+    pub fn borrow_writer_from_boundary(&mut self) -> io::Result<&mut T> {
+        let byte_count = (self.buffer_len as usize).div_ceil(8);
+        self.stream
+            .write_all(&self.buffer.to_le_bytes()[..byte_count])?;
+        self.buffer = 0;
+        self.buffer_len = 0;
+        Ok(&mut self.stream)
+    }
+
+    pub fn into_inner(mut self) -> io::Result<T> {
+        self.borrow_writer_from_boundary()?;
+        Ok(self.stream)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_reader::BitReader;
+
+    #[test]
+    fn write_bits() -> io::Result<()> {
+        let mut writer = BitWriter::new(vec![]);
+        writer.write_bits(BitSequence::new(0b1, 1))?;
+        writer.write_bits(BitSequence::new(0b01, 2))?;
+        writer.write_bits(BitSequence::new(0b100, 3))?;
+        writer.write_bits(BitSequence::new(0b1101, 4))?;
+        writer.write_bits(BitSequence::new(0b10110, 5))?;
+        writer.write_bits(BitSequence::new(0b01011111, 8))?;
+        writer.write_bits(BitSequence::new(0b1, 1))?;
+        assert_eq!(
+            writer.into_inner()?,
+            vec![0b01100011, 0b11011011, 0b10101111]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() -> io::Result<()> {
+        let lens = (0..1000).map(|i| (i * 7 % 17) as u8).collect::<Vec<_>>();
+
+        let mut writer = BitWriter::new(vec![]);
+        for (i, &len) in lens.iter().enumerate() {
+            writer.write_bits(BitSequence::new(i as u16 * 31, len))?;
+        }
+        let data = writer.into_inner()?;
+
+        let mut reader = BitReader::new(data.as_slice());
+        for (i, &len) in lens.iter().enumerate() {
+            assert_eq!(reader.read_bits(len)?, BitSequence::new(i as u16 * 31, len));
+        }
+        Ok(())
+    }
+}
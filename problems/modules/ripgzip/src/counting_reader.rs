@@ -0,0 +1,50 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, Read};
+
+use crate::bit_reader::BitReader;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Counts the bytes consumed from the underlying reader.
+pub struct CountingReader<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+
+    /// Number of bytes consumed so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<R: BufRead> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+/// Number of bits consumed by `bit_reader` from the start of the counted input.
+pub fn position_in_bits<R: BufRead>(bit_reader: &BitReader<CountingReader<R>>) -> u64 {
+    bit_reader.get_ref().position() * 8 - bit_reader.buffered_len() as u64
+}
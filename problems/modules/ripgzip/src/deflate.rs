@@ -1,26 +1,26 @@
 #![forbid(unsafe_code)]
 
-use std::{
-    convert::TryFrom,
-    io::{BufRead, Write},
-};
+use std::io::{BufRead, Write};
 
 use anyhow::{anyhow, ensure, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::*;
+use serde::Serialize;
 
 use crate::bit_reader::BitReader;
-use crate::huffman_coding::{self, LitLenToken};
+use crate::deflate_writer::Token;
+use crate::huffman_coding::{self, DistanceToken, HuffmanCoding, LitLenToken};
 use crate::tracking_writer::TrackingWriter;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Serialize)]
 pub struct BlockHeader {
     pub is_final: bool,
     pub compression_type: CompressionType,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
 pub enum CompressionType {
     Uncompressed = 0,
     FixedTree = 1,
@@ -32,19 +32,182 @@ pub enum CompressionType {
 
 pub struct DeflateReader<T> {
     bit_reader: BitReader<T>,
-    // TODO: your code goes here.
+    is_final_seen: bool,
 }
 
 impl<T: BufRead> DeflateReader<T> {
     pub fn new(bit_reader: BitReader<T>) -> Self {
-        // TODO: your code goes here.
-        unimplemented!()
+        Self {
+            bit_reader,
+            is_final_seen: false,
+        }
+    }
+
+    pub fn bit_reader_mut(&mut self) -> &mut BitReader<T> {
+        &mut self.bit_reader
+    }
+
+    pub fn into_inner(self) -> BitReader<T> {
+        self.bit_reader
     }
 
     pub fn next_block(&mut self) -> Option<Result<(BlockHeader, &mut BitReader<T>)>> {
-        // TODO: your code goes here.
-        unimplemented!()
+        if self.is_final_seen {
+            return None;
+        }
+        Some(
+            self.read_block_header()
+                .map(|header| (header, &mut self.bit_reader)),
+        )
+    }
+
+    fn read_block_header(&mut self) -> Result<BlockHeader> {
+        let is_final = self.bit_reader.read_bits(1)?.bits() == 1;
+        let compression_type = match self.bit_reader.read_bits(2)?.bits() {
+            0 => CompressionType::Uncompressed,
+            1 => CompressionType::FixedTree,
+            2 => CompressionType::DynamicTree,
+            _ => CompressionType::Reserved,
+        };
+        self.is_final_seen = is_final;
+        Ok(BlockHeader {
+            is_final,
+            compression_type,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Decode all the blocks up to the final one, leaving `reader` at the next byte boundary.
+pub fn decompress_blocks<T: BufRead, W: Write>(
+    reader: T,
+    writer: &mut TrackingWriter<W>,
+) -> Result<()> {
+    let mut deflate_reader = DeflateReader::new(BitReader::new(reader));
+    while let Some(block) = deflate_reader.next_block() {
+        let (header, bit_reader) = block?;
+        trace!(
+            "decoding {:?} block (final: {})",
+            header.compression_type,
+            header.is_final
+        );
+        decompress_block(&header, bit_reader, writer)?;
     }
+    Ok(())
 }
 
-// TODO: your code goes here.
+/// Decode the body of the block described by `header`, writing its contents to `writer`.
+pub fn decompress_block<T: BufRead, W: Write>(
+    header: &BlockHeader,
+    bit_reader: &mut BitReader<T>,
+    writer: &mut TrackingWriter<W>,
+) -> Result<()> {
+    let mut decoder = BlockDecoder::new(header, bit_reader)?;
+    while !decoder.decode(bit_reader, writer, usize::MAX)? {}
+    Ok(())
+}
+
+/// Incremental decoder of a single block body, which can be paused between tokens.
+pub enum BlockDecoder {
+    Uncompressed {
+        remaining: usize,
+    },
+    Huffman {
+        litlen_coding: HuffmanCoding<LitLenToken>,
+        distance_coding: HuffmanCoding<DistanceToken>,
+    },
+}
+
+impl BlockDecoder {
+    /// Read everything preceding the block data: `LEN` and `NLEN` for stored blocks
+    /// or code lengths for dynamic ones.
+    pub fn new<T: BufRead>(header: &BlockHeader, bit_reader: &mut BitReader<T>) -> Result<Self> {
+        match header.compression_type {
+            CompressionType::Uncompressed => {
+                // See RFC 1951, section 3.2.4.
+                let reader = bit_reader.borrow_reader_from_boundary();
+                let len = reader.read_u16::<LittleEndian>()?;
+                let nlen = reader.read_u16::<LittleEndian>()?;
+                ensure!(len == !nlen, "nlen check failed");
+                Ok(Self::Uncompressed {
+                    remaining: len as usize,
+                })
+            }
+            CompressionType::FixedTree => {
+                let (litlen_coding, distance_coding) = huffman_coding::fixed_trees()?;
+                Ok(Self::Huffman {
+                    litlen_coding,
+                    distance_coding,
+                })
+            }
+            CompressionType::DynamicTree => {
+                let (litlen_coding, distance_coding) =
+                    huffman_coding::decode_litlen_distance_trees(bit_reader)?;
+                Ok(Self::Huffman {
+                    litlen_coding,
+                    distance_coding,
+                })
+            }
+            CompressionType::Reserved => Err(anyhow!("unsupported block type")),
+        }
+    }
+
+    /// Decode until the block is over or at least `limit` bytes are written.
+    /// Returns `true` if the end of the block was reached.
+    pub fn decode<T: BufRead, W: Write>(
+        &mut self,
+        bit_reader: &mut BitReader<T>,
+        writer: &mut TrackingWriter<W>,
+        limit: usize,
+    ) -> Result<bool> {
+        let start_count = writer.byte_count();
+        match self {
+            Self::Uncompressed { remaining } => {
+                let reader = bit_reader.borrow_reader_from_boundary();
+                while *remaining > 0 && writer.byte_count() - start_count < limit {
+                    let buf = reader.fill_buf()?;
+                    ensure!(!buf.is_empty(), "unexpected eof in uncompressed block");
+                    let chunk = (*remaining).min(buf.len());
+                    writer.write_all(&buf[..chunk])?;
+                    reader.consume(chunk);
+                    *remaining -= chunk;
+                }
+                Ok(*remaining == 0)
+            }
+            Self::Huffman {
+                litlen_coding,
+                distance_coding,
+            } => {
+                while writer.byte_count() - start_count < limit {
+                    match read_token(litlen_coding, distance_coding, bit_reader)? {
+                        Some(Token::Literal(byte)) => writer.write_u8(byte)?,
+                        Some(Token::Match { len, dist }) => writer
+                            .write_previous(dist as usize, len as usize)
+                            .context("failed to copy previous bytes")?,
+                        None => return Ok(true),
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Read the next token of a Huffman-coded block body. Returns `None` at the end of the block.
+pub fn read_token<T: BufRead>(
+    litlen_coding: &HuffmanCoding<LitLenToken>,
+    distance_coding: &HuffmanCoding<DistanceToken>,
+    bit_reader: &mut BitReader<T>,
+) -> Result<Option<Token>> {
+    match litlen_coding.read_symbol(bit_reader)? {
+        LitLenToken::Literal(byte) => Ok(Some(Token::Literal(byte))),
+        LitLenToken::EndOfBlock => Ok(None),
+        LitLenToken::Length { base, extra_bits } => {
+            let len = base + bit_reader.read_bits(extra_bits)?.bits();
+            let distance = distance_coding.read_symbol(bit_reader)?;
+            let dist = distance.base + bit_reader.read_bits(distance.extra_bits)?.bits();
+            Ok(Some(Token::Match { len, dist }))
+        }
+    }
+}
@@ -0,0 +1,665 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, Write};
+
+use crate::bit_reader::BitSequence;
+use crate::bit_writer::BitWriter;
+use crate::huffman_coding::{
+    self, HuffmanEncoder, CODE_LENGTH_ORDER, DISTANCE_BASES, DISTANCE_EXTRA_BITS, END_OF_BLOCK,
+    LENGTH_BASES, LENGTH_EXTRA_BITS,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub const MAX_LEVEL: u32 = 9;
+pub const DEFAULT_LEVEL: u32 = 6;
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: usize = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const MAX_STORED_BLOCK_SIZE: usize = 65535;
+
+/// Amount of input collected before a block is emitted.
+const BLOCK_SIZE: usize = 1 << 16;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy)]
+struct MatchParams {
+    /// How many hash chain links to follow looking for a match.
+    max_chain: usize,
+    /// Stop searching once a match of this length is found.
+    nice_len: usize,
+    /// Try a match at the next position if the current one is shorter than this.
+    /// Zero disables lazy matching.
+    lazy_len: usize,
+}
+
+impl MatchParams {
+    fn for_level(level: u32) -> Option<Self> {
+        let (max_chain, nice_len, lazy_len) = match level {
+            0 => return None,
+            1 => (4, 8, 0),
+            2 => (8, 16, 0),
+            3 => (32, 32, 0),
+            4 => (16, 16, 4),
+            5 => (32, 32, 16),
+            6 => (128, 128, 16),
+            7 => (256, 128, 32),
+            8 => (1024, MAX_MATCH, 128),
+            9 => (4096, MAX_MATCH, MAX_MATCH),
+            _ => panic!("unsupported compression level: {}", level),
+        };
+        Some(Self {
+            max_chain,
+            nice_len,
+            lazy_len,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Literal(u8),
+    Match { len: u16, dist: u16 },
+}
+
+fn length_symbol(len: u16) -> (u16, BitSequence) {
+    let index = LENGTH_BASES.partition_point(|&base| base <= len) - 1;
+    (
+        257 + index as u16,
+        BitSequence::new(len - LENGTH_BASES[index], LENGTH_EXTRA_BITS[index]),
+    )
+}
+
+fn distance_symbol(dist: u16) -> (u16, BitSequence) {
+    let index = DISTANCE_BASES.partition_point(|&base| base <= dist) - 1;
+    (
+        index as u16,
+        BitSequence::new(dist - DISTANCE_BASES[index], DISTANCE_EXTRA_BITS[index]),
+    )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Fail with `InvalidInput` for levels above `MAX_LEVEL`, which `DeflateWriter::new`
+/// doesn't accept.
+pub(crate) fn check_level(level: u32) -> io::Result<()> {
+    if level > MAX_LEVEL {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported compression level: {}", level),
+        ));
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A DEFLATE (RFC 1951) compressor. Input is collected into blocks of `BLOCK_SIZE` bytes,
+/// each of which is emitted as a stored, fixed or dynamic Huffman block, whichever is smaller.
+pub struct DeflateWriter<W: Write> {
+    bit_writer: BitWriter<W>,
+    params: Option<MatchParams>,
+    window: Vec<u8>,
+    window_start: usize,
+    pending_start: usize,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl<W: Write> DeflateWriter<W> {
+    pub fn new(inner: W, level: u32) -> Self {
+        Self {
+            bit_writer: BitWriter::new(inner),
+            params: MatchParams::for_level(level),
+            window: Vec::with_capacity(WINDOW_SIZE + BLOCK_SIZE),
+            window_start: 0,
+            pending_start: 0,
+            head: vec![0; HASH_SIZE],
+            prev: vec![0; WINDOW_SIZE],
+        }
+    }
+
+    /// Make the tail of `dictionary` available to back references as if it was written
+    /// right before the data, without emitting it. Must be called before any data is written.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        assert!(
+            self.window.is_empty(),
+            "dictionary must be set before writing data"
+        );
+        let tail = &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE)..];
+        self.window.extend_from_slice(tail);
+        self.pending_start = tail.len();
+        if self.params.is_some() {
+            for pos in 0..tail.len() {
+                self.insert(pos, tail.len());
+            }
+        }
+    }
+
+    /// Emit all the pending input followed by an empty stored block, which aligns the output
+    /// to a byte boundary. Data written afterwards may still refer to the data before.
+    pub fn sync_flush(&mut self) -> io::Result<()> {
+        if self.pending_start < self.window_end() {
+            self.emit_block(self.window_end(), false)?;
+        }
+        write_stored_blocks(&mut self.bit_writer, &[], false)?;
+        self.bit_writer.borrow_writer_from_boundary()?.flush()
+    }
+
+    /// Emit all the pending input as the final block and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.emit_block(self.window_end(), true)?;
+        self.bit_writer.into_inner()
+    }
+
+    /// Sync flush and return the underlying writer without terminating the stream,
+    /// so that separately compressed blocks can follow.
+    pub fn finish_sync(mut self) -> io::Result<W> {
+        self.sync_flush()?;
+        self.bit_writer.into_inner()
+    }
+
+    fn window_end(&self) -> usize {
+        self.window_start + self.window.len()
+    }
+
+    fn emit_block(&mut self, end: usize, is_final: bool) -> io::Result<()> {
+        let tokens = match self.params {
+            Some(params) => self.tokenize(end, params),
+            None => vec![],
+        };
+
+        let raw = &self.window[self.pending_start - self.window_start..end - self.window_start];
+        if self.params.is_some() {
+            write_block(&mut self.bit_writer, &tokens, raw, is_final)?;
+        } else {
+            write_stored_blocks(&mut self.bit_writer, raw, is_final)?;
+        }
+        self.pending_start = end;
+
+        let history_len = self.pending_start - self.window_start;
+        if history_len > WINDOW_SIZE {
+            self.window.drain(..history_len - WINDOW_SIZE);
+            self.window_start += history_len - WINDOW_SIZE;
+        }
+        Ok(())
+    }
+
+    fn tokenize(&mut self, end: usize, params: MatchParams) -> Vec<Token> {
+        let mut tokens = vec![];
+        let mut pos = self.pending_start;
+        while pos < end {
+            let (mut len, mut dist) = self.longest_match(pos, end, params);
+            self.insert(pos, end);
+
+            if len >= MIN_MATCH && len < params.lazy_len {
+                // A longer match may start at the next byte, in which case
+                // the current one is better emitted as a literal.
+                while pos + 1 < end {
+                    let (next_len, next_dist) = self.longest_match(pos + 1, end, params);
+                    if next_len <= len {
+                        break;
+                    }
+                    tokens.push(Token::Literal(self.window[pos - self.window_start]));
+                    pos += 1;
+                    self.insert(pos, end);
+                    len = next_len;
+                    dist = next_dist;
+                    if len >= params.lazy_len {
+                        break;
+                    }
+                }
+            }
+
+            if len >= MIN_MATCH {
+                tokens.push(Token::Match {
+                    len: len as u16,
+                    dist: dist as u16,
+                });
+                for next in pos + 1..pos + len {
+                    self.insert(next, end);
+                }
+                pos += len;
+            } else {
+                tokens.push(Token::Literal(self.window[pos - self.window_start]));
+                pos += 1;
+            }
+        }
+        tokens
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = &self.window[pos - self.window_start..];
+        ((bytes[0] as usize) << 10 ^ (bytes[1] as usize) << 5 ^ bytes[2] as usize) & (HASH_SIZE - 1)
+    }
+
+    fn insert(&mut self, pos: usize, end: usize) {
+        if pos + MIN_MATCH > end {
+            return;
+        }
+        let hash = self.hash(pos);
+        self.prev[pos % WINDOW_SIZE] = self.head[hash];
+        // Positions are stored shifted by one, so that zero means "no position".
+        self.head[hash] = pos + 1;
+    }
+
+    fn longest_match(&self, pos: usize, end: usize, params: MatchParams) -> (usize, usize) {
+        if pos + MIN_MATCH > end {
+            return (0, 0);
+        }
+
+        let max_len = MAX_MATCH.min(end - pos);
+        let current = &self.window[pos - self.window_start..pos - self.window_start + max_len];
+
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..params.max_chain {
+            if candidate == 0 {
+                break;
+            }
+            let start = candidate - 1;
+            if start < self.window_start || pos - start > WINDOW_SIZE {
+                break;
+            }
+
+            let previous = &self.window[start - self.window_start..];
+            if previous[best.0] == current[best.0] {
+                let len = current
+                    .iter()
+                    .zip(previous)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, pos - start);
+                    if len >= params.nice_len || len == max_len {
+                        break;
+                    }
+                }
+            }
+
+            let next = self.prev[start % WINDOW_SIZE];
+            if next >= candidate {
+                // The chain link was overwritten by a newer position.
+                break;
+            }
+            candidate = next;
+        }
+        best
+    }
+}
+
+impl<W: Write> Write for DeflateWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for chunk in buf.chunks(BLOCK_SIZE) {
+            self.window.extend_from_slice(chunk);
+            while self.window_end() - self.pending_start >= BLOCK_SIZE {
+                self.emit_block(self.pending_start + BLOCK_SIZE, false)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct BlockStats {
+    litlen_frequencies: [u32; 286],
+    distance_frequencies: [u32; 30],
+    extra_bit_count: u64,
+}
+
+impl BlockStats {
+    fn collect(tokens: &[Token]) -> Self {
+        let mut stats = Self {
+            litlen_frequencies: [0; 286],
+            distance_frequencies: [0; 30],
+            extra_bit_count: 0,
+        };
+        stats.litlen_frequencies[END_OF_BLOCK as usize] = 1;
+
+        for token in tokens {
+            match *token {
+                Token::Literal(byte) => stats.litlen_frequencies[byte as usize] += 1,
+                Token::Match { len, dist } => {
+                    let (len_symbol, len_extra) = length_symbol(len);
+                    let (dist_symbol, dist_extra) = distance_symbol(dist);
+                    stats.litlen_frequencies[len_symbol as usize] += 1;
+                    stats.distance_frequencies[dist_symbol as usize] += 1;
+                    stats.extra_bit_count += (len_extra.len() + dist_extra.len()) as u64;
+                }
+            }
+        }
+        stats
+    }
+
+    fn cost(&self, litlen_lengths: &[u8], distance_lengths: &[u8]) -> u64 {
+        let litlen_cost = self
+            .litlen_frequencies
+            .iter()
+            .zip(litlen_lengths)
+            .map(|(&freq, &len)| freq as u64 * len as u64)
+            .sum::<u64>();
+        let distance_cost = self
+            .distance_frequencies
+            .iter()
+            .zip(distance_lengths)
+            .map(|(&freq, &len)| freq as u64 * len as u64)
+            .sum::<u64>();
+        litlen_cost + distance_cost + self.extra_bit_count
+    }
+}
+
+struct DynamicTrees {
+    litlen_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    code_length_lengths: Vec<u8>,
+    encoded_lengths: Vec<(u16, BitSequence)>,
+    hclen: usize,
+}
+
+impl DynamicTrees {
+    fn new(stats: &BlockStats) -> Self {
+        let mut litlen_lengths =
+            huffman_coding::lengths_from_frequencies(&stats.litlen_frequencies, 15);
+        let mut distance_lengths =
+            huffman_coding::lengths_from_frequencies(&stats.distance_frequencies, 15);
+        trim_zeros(&mut litlen_lengths, 257);
+        trim_zeros(&mut distance_lengths, 1);
+
+        let all_lengths = [litlen_lengths.as_slice(), distance_lengths.as_slice()].concat();
+        let encoded_lengths = run_length_encode(&all_lengths);
+
+        let mut code_length_frequencies = [0u32; 19];
+        for (symbol, _) in encoded_lengths.iter() {
+            code_length_frequencies[*symbol as usize] += 1;
+        }
+        let code_length_lengths =
+            huffman_coding::lengths_from_frequencies(&code_length_frequencies, 7);
+        let hclen = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|&symbol| code_length_lengths[symbol] > 0)
+            .map_or(0, |index| index + 1)
+            .max(4);
+
+        Self {
+            litlen_lengths,
+            distance_lengths,
+            code_length_lengths,
+            encoded_lengths,
+            hclen,
+        }
+    }
+
+    fn header_cost(&self) -> u64 {
+        let lengths_cost = self
+            .encoded_lengths
+            .iter()
+            .map(|(symbol, extra)| {
+                (self.code_length_lengths[*symbol as usize] + extra.len()) as u64
+            })
+            .sum::<u64>();
+        5 + 5 + 4 + 3 * self.hclen as u64 + lengths_cost
+    }
+
+    fn write<W: Write>(&self, writer: &mut BitWriter<W>) -> io::Result<()> {
+        writer.write_bits(BitSequence::new(self.litlen_lengths.len() as u16 - 257, 5))?;
+        writer.write_bits(BitSequence::new(self.distance_lengths.len() as u16 - 1, 5))?;
+        writer.write_bits(BitSequence::new(self.hclen as u16 - 4, 4))?;
+        for &symbol in CODE_LENGTH_ORDER.iter().take(self.hclen) {
+            writer.write_bits(BitSequence::new(self.code_length_lengths[symbol] as u16, 3))?;
+        }
+
+        let encoder = encoder_from_lengths(&self.code_length_lengths);
+        for &(symbol, extra) in self.encoded_lengths.iter() {
+            writer.write_bits(encoder.code(symbol))?;
+            writer.write_bits(extra)?;
+        }
+        Ok(())
+    }
+}
+
+fn trim_zeros(lengths: &mut Vec<u8>, min_len: usize) {
+    while lengths.len() > min_len && lengths.last() == Some(&0) {
+        lengths.pop();
+    }
+}
+
+/// Encode code lengths with the repeat symbols 16, 17 and 18, see RFC 1951, section 3.2.7.
+fn run_length_encode(lengths: &[u8]) -> Vec<(u16, BitSequence)> {
+    let no_extra = BitSequence::new(0, 0);
+    let mut encoded = vec![];
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let mut run = lengths[i..].iter().take_while(|&&x| x == len).count();
+        i += run;
+
+        if len == 0 {
+            while run >= 11 {
+                let count = run.min(138);
+                encoded.push((18, BitSequence::new(count as u16 - 11, 7)));
+                run -= count;
+            }
+            if run >= 3 {
+                encoded.push((17, BitSequence::new(run as u16 - 3, 3)));
+                run = 0;
+            }
+        } else {
+            encoded.push((len as u16, no_extra));
+            run -= 1;
+            while run >= 3 {
+                let count = run.min(6);
+                encoded.push((16, BitSequence::new(count as u16 - 3, 2)));
+                run -= count;
+            }
+        }
+        encoded.extend(std::iter::repeat_n((len as u16, no_extra), run));
+    }
+    encoded
+}
+
+fn encoder_from_lengths(lengths: &[u8]) -> HuffmanEncoder {
+    HuffmanEncoder::from_lengths(lengths).expect("generated code lengths must be valid")
+}
+
+fn stored_cost(len: usize) -> u64 {
+    let block_count = len.div_ceil(MAX_STORED_BLOCK_SIZE).max(1) as u64;
+    // Block header, padding to the byte boundary (at most 7 bits), LEN and NLEN.
+    block_count * (3 + 7 + 32) + 8 * len as u64
+}
+
+fn write_block_header<W: Write>(
+    writer: &mut BitWriter<W>,
+    is_final: bool,
+    block_type: u16,
+) -> io::Result<()> {
+    writer.write_bits(BitSequence::new(is_final as u16 | block_type << 1, 3))
+}
+
+fn write_block<W: Write>(
+    writer: &mut BitWriter<W>,
+    tokens: &[Token],
+    raw: &[u8],
+    is_final: bool,
+) -> io::Result<()> {
+    let stats = BlockStats::collect(tokens);
+    let fixed_litlen_lengths = huffman_coding::fixed_litlen_lengths();
+    let fixed_distance_lengths = huffman_coding::fixed_distance_lengths();
+    let fixed_cost = stats.cost(&fixed_litlen_lengths, &fixed_distance_lengths);
+
+    let trees = DynamicTrees::new(&stats);
+    let dynamic_cost =
+        trees.header_cost() + stats.cost(&trees.litlen_lengths, &trees.distance_lengths);
+
+    if stored_cost(raw.len()) <= 3 + fixed_cost.min(dynamic_cost) {
+        write_stored_blocks(writer, raw, is_final)
+    } else if fixed_cost <= dynamic_cost {
+        write_block_header(writer, is_final, 1)?;
+        write_tokens(
+            writer,
+            tokens,
+            &encoder_from_lengths(&fixed_litlen_lengths),
+            &encoder_from_lengths(&fixed_distance_lengths),
+        )
+    } else {
+        write_block_header(writer, is_final, 2)?;
+        trees.write(writer)?;
+        write_tokens(
+            writer,
+            tokens,
+            &encoder_from_lengths(&trees.litlen_lengths),
+            &encoder_from_lengths(&trees.distance_lengths),
+        )
+    }
+}
+
+fn write_stored_blocks<W: Write>(
+    writer: &mut BitWriter<W>,
+    raw: &[u8],
+    is_final: bool,
+) -> io::Result<()> {
+    let chunk_count = raw.len().div_ceil(MAX_STORED_BLOCK_SIZE).max(1);
+    for index in 0..chunk_count {
+        let start = index * MAX_STORED_BLOCK_SIZE;
+        let chunk = &raw[start..raw.len().min(start + MAX_STORED_BLOCK_SIZE)];
+
+        write_block_header(writer, is_final && index + 1 == chunk_count, 0)?;
+        let stream = writer.borrow_writer_from_boundary()?;
+        stream.write_all(&(chunk.len() as u16).to_le_bytes())?;
+        stream.write_all(&(!(chunk.len() as u16)).to_le_bytes())?;
+        stream.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+fn write_tokens<W: Write>(
+    writer: &mut BitWriter<W>,
+    tokens: &[Token],
+    litlen_encoder: &HuffmanEncoder,
+    distance_encoder: &HuffmanEncoder,
+) -> io::Result<()> {
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => writer.write_bits(litlen_encoder.code(byte as u16))?,
+            Token::Match { len, dist } => {
+                let (len_symbol, len_extra) = length_symbol(len);
+                let (dist_symbol, dist_extra) = distance_symbol(dist);
+                writer.write_bits(litlen_encoder.code(len_symbol))?;
+                writer.write_bits(len_extra)?;
+                writer.write_bits(distance_encoder.code(dist_symbol))?;
+                writer.write_bits(dist_extra)?;
+            }
+        }
+    }
+    writer.write_bits(litlen_encoder.code(END_OF_BLOCK))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_reader::BitReader;
+    use crate::deflate::{self, DeflateReader};
+    use crate::tracking_writer::TrackingWriter;
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        let mut writer = TrackingWriter::new(&mut output);
+        let mut reader = DeflateReader::new(BitReader::new(data));
+        while let Some(block) = reader.next_block() {
+            let (header, bit_reader) = block.unwrap();
+            deflate::decompress_block(&header, bit_reader, &mut writer).unwrap();
+        }
+        output
+    }
+
+    fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+        let mut writer = DeflateWriter::new(vec![], level);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn sample_data() -> Vec<u8> {
+        let mut state = 42u32;
+        let mut data = vec![];
+        for i in 0..200_000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let byte = if i % 1000 < 300 {
+                (state >> 16) as u8
+            } else {
+                b"abracadabra, hello world! "[i % 26]
+            };
+            data.push(byte);
+        }
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample_data();
+        for level in 0..=MAX_LEVEL {
+            let compressed = deflate(&data, level);
+            assert_eq!(inflate(&compressed), data, "level {}", level);
+            if level > 0 {
+                assert!(compressed.len() < data.len() / 2, "level {}", level);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_and_tiny() {
+        for data in [&b""[..], b"a", b"ab", b"aaaaaaaaaaaaaaaaaaaaaaaa"] {
+            for level in [0, 1, 6, 9] {
+                assert_eq!(inflate(&deflate(data, level)), data);
+            }
+        }
+    }
+
+    #[test]
+    fn dictionary_and_sync_flush() {
+        let data = sample_data();
+        let (head, tail) = data.split_at(120_000);
+        for level in [0, 1, 6] {
+            let mut writer = DeflateWriter::new(vec![], level);
+            writer.write_all(&head[..1000]).unwrap();
+            writer.sync_flush().unwrap();
+            writer.write_all(&head[1000..]).unwrap();
+            let mut compressed = writer.finish_sync().unwrap();
+            // An empty stored block ends on a byte boundary.
+            assert!(compressed.ends_with(&[0, 0, 0xff, 0xff]));
+
+            let mut writer = DeflateWriter::new(compressed, level);
+            writer.set_dictionary(head);
+            writer.write_all(tail).unwrap();
+            compressed = writer.finish().unwrap();
+            assert_eq!(inflate(&compressed), data, "level {}", level);
+        }
+
+        // Back references into the dictionary make the output smaller.
+        let mut writer = DeflateWriter::new(vec![], 6);
+        writer.set_dictionary(&data[..20_000]);
+        writer.write_all(&data[..20_000]).unwrap();
+        assert!(writer.finish().unwrap().len() < 1000);
+    }
+
+    #[test]
+    fn symbols() {
+        assert_eq!(length_symbol(3), (257, BitSequence::new(0, 0)));
+        assert_eq!(length_symbol(12), (265, BitSequence::new(1, 1)));
+        assert_eq!(length_symbol(257), (284, BitSequence::new(30, 5)));
+        assert_eq!(length_symbol(258), (285, BitSequence::new(0, 0)));
+        assert_eq!(distance_symbol(1), (0, BitSequence::new(0, 0)));
+        assert_eq!(distance_symbol(32768), (29, BitSequence::new(8191, 13)));
+    }
+}
@@ -1,15 +1,15 @@
 #![forbid(unsafe_code)]
 
-use std::io::{self, BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
 
 use anyhow::{bail, ensure, Context, Result};
-use byteorder::{LittleEndian, ReadBytesExt};
-use crc::Crc;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::{
-    bit_reader::BitReader,
-    deflate::{self, DeflateReader},
-    tracking_writer::TrackingWriter,
+    deflate,
+    deflate_writer::MAX_LEVEL,
+    gzip_writer::GzipWriter,
+    tracking_writer::{TrackingWriter, CRC32},
 };
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -19,6 +19,11 @@ const ID2: u8 = 0x8b;
 
 const CM_DEFLATE: u8 = 8;
 
+const XFL_MAX_COMPRESSION: u8 = 2;
+const XFL_FASTEST: u8 = 4;
+
+const OS_UNKNOWN: u8 = 255;
+
 const FTEXT_OFFSET: u8 = 0;
 const FHCRC_OFFSET: u8 = 1;
 const FEXTRA_OFFSET: u8 = 2;
@@ -27,13 +32,15 @@ const FCOMMENT_OFFSET: u8 = 4;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MemberHeader {
     pub compression_method: CompressionMethod,
     pub modification_time: u32,
     pub extra: Option<Vec<u8>>,
-    pub name: Option<String>,
-    pub comment: Option<String>,
+    /// Raw bytes of FNAME without the terminating zero, see `decoded_name()`.
+    pub name: Option<Vec<u8>>,
+    /// Raw bytes of FCOMMENT without the terminating zero, see `decoded_comment()`.
+    pub comment: Option<Vec<u8>>,
     pub extra_flags: u8,
     pub os: u8,
     pub has_crc: bool,
@@ -41,9 +48,67 @@ pub struct MemberHeader {
 }
 
 impl MemberHeader {
+    /// Header without optional fields, as written for data compressed at `level`.
+    pub fn new(level: u32) -> Self {
+        Self {
+            compression_method: CompressionMethod::Deflate,
+            modification_time: 0,
+            extra: None,
+            name: None,
+            comment: None,
+            extra_flags: match level {
+                1 => XFL_FASTEST,
+                MAX_LEVEL => XFL_MAX_COMPRESSION,
+                _ => 0,
+            },
+            os: OS_UNKNOWN,
+            has_crc: false,
+            is_text: false,
+        }
+    }
+
+    /// Parse the header of the member at the start of `reader`.
+    pub fn read_from<R: BufRead>(reader: R) -> Result<Self> {
+        match GzipReader::new(reader).next_member() {
+            Some(member) => Ok(member?.0),
+            None => bail!("no gzip member found"),
+        }
+    }
+
+    /// Serialize the header, see RFC 1952, section 2.3. The header CRC is computed
+    /// if `has_crc` is set.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[ID1, ID2, self.compression_method.into(), self.flags().0])?;
+        writer.write_u32::<LittleEndian>(self.modification_time)?;
+        writer.write_all(&[self.extra_flags, self.os])?;
+
+        if let Some(extra) = &self.extra {
+            let len = u16::try_from(extra.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "extra field is too long")
+            })?;
+            writer.write_u16::<LittleEndian>(len)?;
+            writer.write_all(extra)?;
+        }
+        for (field, value) in [("name", &self.name), ("comment", &self.comment)] {
+            if let Some(value) = value {
+                if value.contains(&0) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} contains a zero byte", field),
+                    ));
+                }
+                writer.write_all(value)?;
+                writer.write_u8(0)?;
+            }
+        }
+        if self.has_crc {
+            writer.write_u16::<LittleEndian>(self.crc16())?;
+        }
+        Ok(())
+    }
+
     pub fn crc16(&self) -> u16 {
-        let crc = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-        let mut digest = crc.digest();
+        let mut digest = CRC32.digest();
 
         digest.update(&[ID1, ID2, self.compression_method.into(), self.flags().0]);
         digest.update(&self.modification_time.to_le_bytes());
@@ -55,18 +120,36 @@ impl MemberHeader {
         }
 
         if let Some(name) = &self.name {
-            digest.update(name.as_bytes());
+            digest.update(name);
             digest.update(&[0]);
         }
 
         if let Some(comment) = &self.comment {
-            digest.update(comment.as_bytes());
+            digest.update(comment);
             digest.update(&[0]);
         }
 
         (digest.finalize() & 0xffff) as u16
     }
 
+    /// The name as UTF-8, or as ISO 8859-1 if it isn't valid UTF-8.
+    pub fn decoded_name(&self) -> Option<String> {
+        self.name.as_deref().map(decode_string)
+    }
+
+    /// The comment as UTF-8, or as ISO 8859-1 if it isn't valid UTF-8.
+    pub fn decoded_comment(&self) -> Option<String> {
+        self.comment.as_deref().map(decode_string)
+    }
+
+    /// Parse the extra field into subfields. Returns an empty list if there is none.
+    pub fn extra_subfields(&self) -> Result<Vec<ExtraSubfield>> {
+        match &self.extra {
+            Some(extra) => ExtraSubfield::parse_all(extra),
+            None => Ok(vec![]),
+        }
+    }
+
     pub fn flags(&self) -> MemberFlags {
         let mut flags = MemberFlags(0);
         flags.set_is_text(self.is_text);
@@ -80,7 +163,54 @@ impl MemberHeader {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Clone, Copy, Debug)]
+/// A record of the extra field, see RFC 1952, section 2.3.1.1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtraSubfield {
+    /// Subfield ID: SI1 and SI2.
+    pub id: [u8; 2],
+    pub data: Vec<u8>,
+}
+
+impl ExtraSubfield {
+    pub fn parse_all(mut extra: &[u8]) -> Result<Vec<Self>> {
+        let mut subfields = vec![];
+        while !extra.is_empty() {
+            ensure!(extra.len() >= 4, "truncated extra subfield header");
+            let id = [extra[0], extra[1]];
+            let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+            ensure!(
+                extra.len() >= 4 + len,
+                "extra subfield {:?} is truncated",
+                String::from_utf8_lossy(&id)
+            );
+            subfields.push(Self {
+                id,
+                data: extra[4..4 + len].to_vec(),
+            });
+            extra = &extra[4 + len..];
+        }
+        Ok(subfields)
+    }
+
+    /// Serialize `subfields` into the contents of the extra field.
+    pub fn encode_all(subfields: &[Self]) -> Vec<u8> {
+        let mut extra = vec![];
+        for subfield in subfields {
+            assert!(
+                subfield.data.len() <= u16::MAX as usize,
+                "subfield is too long"
+            );
+            extra.extend_from_slice(&subfield.id);
+            extra.extend_from_slice(&(subfield.data.len() as u16).to_le_bytes());
+            extra.extend_from_slice(&subfield.data);
+        }
+        extra
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CompressionMethod {
     Deflate,
     Unknown(u8),
@@ -166,12 +296,29 @@ impl MemberFlags {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct MemberFooter {
     pub data_crc32: u32,
     pub data_size: u32,
 }
 
+impl MemberFooter {
+    /// Check that the data tracked by `writer` matches the footer.
+    pub fn verify<W: Write>(&self, writer: &TrackingWriter<W>) -> Result<()> {
+        ensure!(
+            self.data_size == writer.byte_count() as u32,
+            "length check failed"
+        );
+        ensure!(self.data_crc32 == writer.crc32(), "crc32 check failed");
+        Ok(())
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.data_crc32)?;
+        writer.write_u32::<LittleEndian>(self.data_size)
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct GzipReader<T> {
@@ -183,13 +330,102 @@ impl<T: BufRead> GzipReader<T> {
         Self { reader }
     }
 
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> T {
+        self.reader
+    }
+
     fn parse_header(mut header: &[u8]) -> Result<(MemberHeader, MemberFlags)> {
         // See RFC 1952, section 2.3.
-        // TODO: your code goes here.
-        unimplemented!()
+        let id1 = header.read_u8()?;
+        let id2 = header.read_u8()?;
+        ensure!(id1 == ID1 && id2 == ID2, "wrong id values");
+
+        let compression_method = CompressionMethod::from(header.read_u8()?);
+        if let CompressionMethod::Unknown(method) = compression_method {
+            bail!("unsupported compression method: {}", method);
+        }
+
+        let flags = MemberFlags(header.read_u8()?);
+        let modification_time = header.read_u32::<LittleEndian>()?;
+        let extra_flags = header.read_u8()?;
+        let os = header.read_u8()?;
+
+        let member_header = MemberHeader {
+            compression_method,
+            modification_time,
+            extra: None,
+            name: None,
+            comment: None,
+            extra_flags,
+            os,
+            has_crc: flags.has_crc(),
+            is_text: flags.is_text(),
+        };
+        Ok((member_header, flags))
+    }
+
+    /// Read the next member header. Returns `None` if the stream is over.
+    pub fn next_member(mut self) -> Option<Result<(MemberHeader, MemberReader<T>)>> {
+        match self.reader.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => Some(self.read_header()),
+            Err(err) => Some(Err(err.into())),
+        }
     }
 
-    // TODO: your code goes here.
+    fn read_header(mut self) -> Result<(MemberHeader, MemberReader<T>)> {
+        let mut fixed = [0u8; 10];
+        self.reader
+            .read_exact(&mut fixed)
+            .context("failed to read member header")?;
+        let (mut header, flags) = Self::parse_header(&fixed)?;
+
+        if flags.has_extra() {
+            let len = self.reader.read_u16::<LittleEndian>()?;
+            let mut extra = vec![0u8; len as usize];
+            self.reader
+                .read_exact(&mut extra)
+                .context("failed to read extra field")?;
+            header.extra = Some(extra);
+        }
+        if flags.has_name() {
+            header.name = Some(self.read_zero_terminated().context("failed to read name")?);
+        }
+        if flags.has_comment() {
+            header.comment = Some(
+                self.read_zero_terminated()
+                    .context("failed to read comment")?,
+            );
+        }
+        if flags.has_crc() {
+            let crc16 = self.reader.read_u16::<LittleEndian>()?;
+            ensure!(crc16 == header.crc16(), "header crc16 check failed");
+        }
+
+        Ok((header, MemberReader { inner: self.reader }))
+    }
+
+    fn read_zero_terminated(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.reader.read_until(0, &mut bytes)?;
+        if bytes.pop() != Some(0) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(bytes)
+    }
+}
+
+/// RFC 1952 mandates ISO 8859-1 for the name and the comment, but in practice they
+/// are stored as raw file system bytes, which are UTF-8 most of the time.
+fn decode_string(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(string) => string.to_owned(),
+        Err(_) => bytes.iter().copied().map(char::from).collect(),
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -199,12 +435,50 @@ pub struct MemberReader<T> {
 }
 
 impl<T: BufRead> MemberReader<T> {
+    /// Continue reading a member whose body was consumed from `inner`.
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
     pub fn inner_mut(&mut self) -> &mut T {
         &mut self.inner
     }
 
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
     pub fn read_footer(mut self) -> Result<(MemberFooter, GzipReader<T>)> {
-        // TODO: your code goes here.
-        unimplemented!()
+        let data_crc32 = self.inner.read_u32::<LittleEndian>()?;
+        let data_size = self.inner.read_u32::<LittleEndian>()?;
+        Ok((
+            MemberFooter {
+                data_crc32,
+                data_size,
+            },
+            GzipReader::new(self.inner),
+        ))
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Decode a single member body and validate it against the footer.
+pub fn decompress_member<T: BufRead, W: Write>(
+    mut member_reader: MemberReader<T>,
+    output: W,
+) -> Result<(MemberFooter, GzipReader<T>)> {
+    let mut writer = TrackingWriter::new(output);
+    deflate::decompress_blocks(member_reader.inner_mut(), &mut writer)?;
+
+    let (footer, gzip_reader) = member_reader.read_footer()?;
+    footer.verify(&writer)?;
+    Ok((footer, gzip_reader))
+}
+
+/// Compress the whole `input` into a single member.
+pub fn compress_member<R: Read, W: Write>(mut input: R, output: W, level: u32) -> Result<W> {
+    let mut writer = GzipWriter::new(output, level)?;
+    io::copy(&mut input, &mut writer)?;
+    Ok(writer.finish()?)
+}
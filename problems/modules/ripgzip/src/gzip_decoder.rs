@@ -0,0 +1,268 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, Read};
+
+use anyhow::{Context, Result};
+use log::*;
+
+use crate::{
+    bit_reader::BitReader,
+    deflate::{BlockDecoder, DeflateReader},
+    gzip::{GzipReader, MemberReader},
+    tracking_writer::TrackingWriter,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Amount of decompressed data produced at once. The output buffer may exceed it
+/// by at most one back reference.
+const OUTPUT_CHUNK_SIZE: usize = 32768;
+
+enum State<R> {
+    Header(GzipReader<R>),
+    Body {
+        deflate_reader: DeflateReader<R>,
+        block: Option<BlockDecoder>,
+    },
+    Done(R),
+    Failed,
+}
+
+/// Pull-based gzip decompressor: reads compressed data from `R` on demand and
+/// verifies every member footer before moving on to the next member.
+pub struct GzipDecoder<R> {
+    state: State<R>,
+    writer: TrackingWriter<Vec<u8>>,
+    position: usize,
+    member_index: usize,
+    /// Set when decoding starts in the middle of a member, whose footer then cannot be verified.
+    is_partial_member: bool,
+}
+
+impl<R: BufRead> GzipDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            state: State::Header(GzipReader::new(reader)),
+            writer: TrackingWriter::new(Vec::with_capacity(OUTPUT_CHUNK_SIZE)),
+            position: 0,
+            member_index: 0,
+            is_partial_member: false,
+        }
+    }
+
+    /// Continue decoding from a block boundary inside a member. `window` is the data
+    /// decoded right before that boundary, used to resolve back references.
+    pub(crate) fn resume(deflate_reader: DeflateReader<R>, window: &[u8]) -> Self {
+        let mut writer = TrackingWriter::new(Vec::with_capacity(OUTPUT_CHUNK_SIZE));
+        writer.set_dictionary(window);
+        Self {
+            state: State::Body {
+                deflate_reader,
+                block: None,
+            },
+            writer,
+            position: 0,
+            member_index: 0,
+            is_partial_member: true,
+        }
+    }
+
+    /// Return the underlying reader, or `None` if decoding has failed.
+    /// The reader may be positioned anywhere within the compressed data.
+    pub fn into_inner(self) -> Option<R> {
+        match self.state {
+            State::Header(gzip_reader) => Some(gzip_reader.into_inner()),
+            State::Body { deflate_reader, .. } => Some(deflate_reader.into_inner().into_inner()),
+            State::Done(reader) => Some(reader),
+            State::Failed => None,
+        }
+    }
+
+    /// Decode until some output is available or the stream is over.
+    fn fill_output(&mut self) -> Result<()> {
+        while self.writer.get_mut().is_empty() {
+            match std::mem::replace(&mut self.state, State::Failed) {
+                State::Header(mut gzip_reader) => match gzip_reader.inner_mut().fill_buf()? {
+                    [] => {
+                        self.state = State::Done(gzip_reader.into_inner());
+                        return Ok(());
+                    }
+                    _ => {
+                        // The input is not over, so there is a member to read.
+                        let member = gzip_reader.next_member().unwrap();
+                        let (header, member_reader) = member.with_context(|| {
+                            format!("failed to read header of member #{}", self.member_index)
+                        })?;
+                        debug!("decompressing member #{}: {:?}", self.member_index, header);
+                        self.state = State::Body {
+                            deflate_reader: DeflateReader::new(BitReader::new(
+                                member_reader.into_inner(),
+                            )),
+                            block: None,
+                        };
+                    }
+                },
+                State::Body {
+                    deflate_reader,
+                    block,
+                } => {
+                    self.state = self.decode_body(deflate_reader, block).with_context(|| {
+                        format!("failed to decompress member #{}", self.member_index)
+                    })?;
+                }
+                State::Done(reader) => {
+                    self.state = State::Done(reader);
+                    return Ok(());
+                }
+                State::Failed => anyhow::bail!("decoder has failed before"),
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_body(
+        &mut self,
+        mut deflate_reader: DeflateReader<R>,
+        block: Option<BlockDecoder>,
+    ) -> Result<State<R>> {
+        if let Some(mut decoder) = block {
+            let is_over = decoder.decode(
+                deflate_reader.bit_reader_mut(),
+                &mut self.writer,
+                OUTPUT_CHUNK_SIZE,
+            )?;
+            return Ok(State::Body {
+                deflate_reader,
+                block: if is_over { None } else { Some(decoder) },
+            });
+        }
+
+        if let Some(next_block) = deflate_reader.next_block() {
+            let (header, bit_reader) = next_block?;
+            let decoder = BlockDecoder::new(&header, bit_reader)?;
+            return Ok(State::Body {
+                deflate_reader,
+                block: Some(decoder),
+            });
+        }
+
+        let member_reader = MemberReader::new(deflate_reader.into_inner().into_inner());
+        let (footer, gzip_reader) = member_reader.read_footer()?;
+        if !self.is_partial_member {
+            footer.verify(&self.writer)?;
+        }
+        self.is_partial_member = false;
+
+        // Members are independent, so start tracking from scratch.
+        let output = std::mem::replace(&mut self.writer, TrackingWriter::new(vec![])).into_inner();
+        *self.writer.get_mut() = output;
+        self.member_index += 1;
+        Ok(State::Header(gzip_reader))
+    }
+}
+
+impl<R: BufRead> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for GzipDecoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.writer.get_mut().len() {
+            self.writer.get_mut().clear();
+            self.position = 0;
+            self.fill_output()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", err)))?;
+        }
+        Ok(&self.writer.get_mut()[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.writer.get_mut().len());
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompress_all(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        crate::decompress(data, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn read_in_small_pieces() -> io::Result<()> {
+        for data in [
+            &include_bytes!("../data/ok/02-doc.pdf.gz")[..],
+            include_bytes!("../data/ok/09-concat.gz"),
+            include_bytes!("../data/ok/10-header-crc16.gz"),
+        ] {
+            let mut decoder = GzipDecoder::new(data);
+            let mut output = vec![];
+            let mut buf = [0u8; 1000];
+            loop {
+                let len = decoder.read(&mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..len]);
+            }
+            assert_eq!(output, decompress_all(data));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn split_lines() -> io::Result<()> {
+        let data = include_bytes!("../data/ok/01-page.gz");
+        let expected = decompress_all(data);
+        let lines = GzipDecoder::new(&data[..])
+            .split(b'\n')
+            .collect::<io::Result<Vec<_>>>()?;
+        assert_eq!(lines, expected.split(|&b| b == b'\n').collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn nested() -> io::Result<()> {
+        let expected = decompress_all(include_bytes!("../data/ok/02-doc.pdf.gz"));
+        let mut data = vec![];
+        let mut once = vec![];
+        crate::compress(expected.as_slice(), &mut once, 6).unwrap();
+        crate::compress(once.as_slice(), &mut data, 1).unwrap();
+
+        let outer = GzipDecoder::new(data.as_slice());
+        let mut output = vec![];
+        GzipDecoder::new(io::BufReader::new(outer)).read_to_end(&mut output)?;
+        assert_eq!(output, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn footer_errors() {
+        for (data, msg) in [
+            (
+                &include_bytes!("../data/corrupted/00-bad-length.gz")[..],
+                "length check failed",
+            ),
+            (
+                include_bytes!("../data/corrupted/01-bad-crc32.gz"),
+                "crc32 check failed",
+            ),
+        ] {
+            let mut output = vec![];
+            let err = GzipDecoder::new(data).read_to_end(&mut output).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(msg), "{}", err);
+        }
+    }
+}
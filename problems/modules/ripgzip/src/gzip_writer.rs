@@ -0,0 +1,340 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, Write};
+
+use crc::Digest;
+
+use crate::{
+    deflate_writer::{self, DeflateWriter, DEFAULT_LEVEL},
+    gzip::{ExtraSubfield, MemberFooter, MemberHeader},
+    tracking_writer::CRC32,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Configures the compression level and the header of members written by a `GzipWriter`.
+#[derive(Clone, Debug)]
+pub struct GzipWriterBuilder {
+    header: MemberHeader,
+    level: u32,
+}
+
+impl Default for GzipWriterBuilder {
+    fn default() -> Self {
+        Self {
+            header: MemberHeader::new(DEFAULT_LEVEL),
+            level: DEFAULT_LEVEL,
+        }
+    }
+}
+
+impl GzipWriterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the compression level, updating the extra flags of the header to match.
+    /// `build` fails if it is above `MAX_LEVEL`.
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level;
+        self.header.extra_flags = MemberHeader::new(level).extra_flags;
+        self
+    }
+
+    /// Set the original file name. It must not contain zero bytes.
+    pub fn name(mut self, name: impl Into<Vec<u8>>) -> Self {
+        self.header.name = Some(name.into());
+        self
+    }
+
+    /// Set the comment. It must not contain zero bytes.
+    pub fn comment(mut self, comment: impl Into<Vec<u8>>) -> Self {
+        self.header.comment = Some(comment.into());
+        self
+    }
+
+    /// Set the modification time of the original file in seconds since the Unix epoch.
+    pub fn modification_time(mut self, modification_time: u32) -> Self {
+        self.header.modification_time = modification_time;
+        self
+    }
+
+    pub fn os(mut self, os: u8) -> Self {
+        self.header.os = os;
+        self
+    }
+
+    pub fn extra(mut self, subfields: &[ExtraSubfield]) -> Self {
+        self.header.extra = Some(ExtraSubfield::encode_all(subfields));
+        self
+    }
+
+    /// Protect the header with a CRC16.
+    pub fn header_crc(mut self, has_crc: bool) -> Self {
+        self.header.has_crc = has_crc;
+        self
+    }
+
+    /// Mark the data as probably being text.
+    pub fn text(mut self, is_text: bool) -> Self {
+        self.header.is_text = is_text;
+        self
+    }
+
+    /// Use `header` as is, e.g. one read from another member.
+    pub fn header(mut self, header: MemberHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Fails with `InvalidInput` if the level is above `MAX_LEVEL`.
+    pub fn build<W: Write>(self, inner: W) -> io::Result<GzipWriter<W>> {
+        deflate_writer::check_level(self.level)?;
+        Ok(GzipWriter {
+            state: Some(State::Idle(inner)),
+            header: self.header,
+            level: self.level,
+            member_count: 0,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum State<W: Write> {
+    Idle(W),
+    Member {
+        deflate_writer: DeflateWriter<W>,
+        digest: Digest<'static, u32>,
+        data_size: u32,
+    },
+}
+
+/// Compresses data into gzip members. A member is started on the first write and
+/// completed by `finish_member`, after which writing starts the next one.
+pub struct GzipWriter<W: Write> {
+    /// `None` if writing has failed in the middle of a member transition.
+    state: Option<State<W>>,
+    header: MemberHeader,
+    level: u32,
+    member_count: usize,
+}
+
+impl<W: Write> GzipWriter<W> {
+    /// Create a writer with the minimal header for `level`. Fails with `InvalidInput`
+    /// if `level` is above `MAX_LEVEL`.
+    pub fn new(inner: W, level: u32) -> io::Result<Self> {
+        GzipWriterBuilder::new().level(level).build(inner)
+    }
+
+    /// Header of the members to be started. Changes do not affect the current member.
+    pub fn header_mut(&mut self) -> &mut MemberHeader {
+        &mut self.header
+    }
+
+    /// Number of members completed so far.
+    pub fn member_count(&self) -> usize {
+        self.member_count
+    }
+
+    fn take_state(&mut self) -> io::Result<State<W>> {
+        self.state
+            .take()
+            .ok_or_else(|| io::Error::other("writer has failed before"))
+    }
+
+    fn start_member(&mut self) -> io::Result<()> {
+        match self.take_state()? {
+            State::Idle(mut inner) => {
+                self.header.write_to(&mut inner)?;
+                self.state = Some(State::Member {
+                    deflate_writer: DeflateWriter::new(inner, self.level),
+                    digest: CRC32.digest(),
+                    data_size: 0,
+                });
+            }
+            state => self.state = Some(state),
+        }
+        Ok(())
+    }
+
+    /// Complete the current member, if any. Data written afterwards goes to a new member.
+    pub fn finish_member(&mut self) -> io::Result<()> {
+        match self.take_state()? {
+            State::Member {
+                deflate_writer,
+                digest,
+                data_size,
+            } => {
+                let mut inner = deflate_writer.finish()?;
+                MemberFooter {
+                    data_crc32: digest.finalize(),
+                    data_size,
+                }
+                .write_to(&mut inner)?;
+                self.state = Some(State::Idle(inner));
+                self.member_count += 1;
+            }
+            state => self.state = Some(state),
+        }
+        Ok(())
+    }
+
+    /// Complete the current member and return the underlying writer. If nothing
+    /// has been written, a single empty member is written, like gzip does for empty input.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.member_count == 0 {
+            self.start_member()?;
+        }
+        self.finish_member()?;
+        match self.take_state()? {
+            State::Idle(inner) => Ok(inner),
+            State::Member { .. } => unreachable!("member is finished"),
+        }
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.start_member()?;
+        match &mut self.state {
+            Some(State::Member {
+                deflate_writer,
+                digest,
+                data_size,
+            }) => {
+                let len = deflate_writer.write(buf)?;
+                digest.update(&buf[..len]);
+                *data_size = data_size.wrapping_add(len as u32);
+                Ok(len)
+            }
+            _ => unreachable!("member is started"),
+        }
+    }
+
+    /// Sync flush the current member, so that all the data written so far can be decoded.
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            Some(State::Idle(inner)) => inner.flush(),
+            Some(State::Member { deflate_writer, .. }) => deflate_writer.sync_flush(),
+            None => Err(io::Error::other("writer has failed before")),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GzipDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn header_round_trip() -> io::Result<()> {
+        let mut writer = GzipWriterBuilder::new()
+            .level(9)
+            .name("page.html")
+            .comment("just a page")
+            .modification_time(1617640000)
+            .os(3)
+            .extra(&[ExtraSubfield {
+                id: *b"RG",
+                data: b"zip".to_vec(),
+            }])
+            .header_crc(true)
+            .text(true)
+            .build(vec![])?;
+        writer.write_all(b"hello, world")?;
+        let data = writer.finish()?;
+
+        let members = crate::members(data.as_slice())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(members.len(), 1);
+        let header = &members[0].header;
+        assert_eq!(header.decoded_name().as_deref(), Some("page.html"));
+        assert_eq!(header.decoded_comment().as_deref(), Some("just a page"));
+        assert_eq!(header.modification_time, 1617640000);
+        assert_eq!((header.os, header.extra_flags), (3, 2));
+        assert!(header.has_crc && header.is_text);
+        assert_eq!(header.extra_subfields().unwrap()[0].data, b"zip");
+
+        let mut serialized = vec![];
+        header.write_to(&mut serialized)?;
+        assert_eq!(serialized, data[..serialized.len()]);
+
+        let mut output = vec![];
+        GzipDecoder::new(data.as_slice()).read_to_end(&mut output)?;
+        assert_eq!(output, b"hello, world");
+        Ok(())
+    }
+
+    #[test]
+    fn members() -> io::Result<()> {
+        let mut writer = GzipWriter::new(vec![], 6)?;
+        writer.write_all(b"first")?;
+        writer.finish_member()?;
+        writer.finish_member()?;
+        writer.header_mut().name = Some("second".into());
+        writer.write_all(b"second")?;
+        writer.flush()?;
+        writer.write_all(b" member")?;
+        assert_eq!(writer.member_count(), 1);
+        let data = writer.finish()?;
+
+        let members = crate::members(data.as_slice())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].header.name, None);
+        assert_eq!(members[1].header.name.as_deref(), Some(&b"second"[..]));
+        let mut output = vec![];
+        GzipDecoder::new(data.as_slice()).read_to_end(&mut output)?;
+        assert_eq!(output, b"firstsecond member");
+
+        let data = GzipWriter::new(vec![], 6)?.finish()?;
+        assert_eq!(crate::members(data.as_slice()).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn non_utf8_name() -> io::Result<()> {
+        let mut writer = GzipWriterBuilder::new()
+            .name(&b"caf\xe9.txt"[..])
+            .header_crc(true)
+            .build(vec![])?;
+        writer.write_all(b"data")?;
+        let data = writer.finish()?;
+
+        let header = MemberHeader::read_from(data.as_slice()).unwrap();
+        assert_eq!(header.name.as_deref(), Some(&b"caf\xe9.txt"[..]));
+        assert_eq!(header.decoded_name().as_deref(), Some("caf\u{e9}.txt"));
+
+        let mut serialized = vec![];
+        header.write_to(&mut serialized)?;
+        assert_eq!(serialized, data[..serialized.len()]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_name() {
+        let mut writer = GzipWriterBuilder::new().name("a\0b").build(vec![]).unwrap();
+        let err = writer.write_all(b"data").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn invalid_level() {
+        let err = GzipWriterBuilder::new()
+            .level(10)
+            .build(vec![])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(GzipWriter::new(vec![], 10).is_err());
+    }
+}
@@ -1,19 +1,135 @@
 #![forbid(unsafe_code)]
 
-use std::{collections::HashMap, convert::TryFrom, io::BufRead};
+use std::{cmp::Reverse, collections::BinaryHeap, convert::TryFrom, io::BufRead};
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::Serialize;
 
 use crate::bit_reader::{BitReader, BitSequence};
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Order in which code length code lengths are stored, see RFC 1951, section 3.2.7.
+pub const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+pub const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+pub const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+pub const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+pub const END_OF_BLOCK: u16 = 256;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Code lengths transmitted in the header of a dynamic block.
+#[derive(Clone, Debug, Serialize)]
+pub struct DynamicCodeLengths {
+    /// Lengths of the code length alphabet codes, in symbol order.
+    pub code_length_lengths: [u8; 19],
+    pub litlen_lengths: Vec<u8>,
+    pub distance_lengths: Vec<u8>,
+}
+
+impl DynamicCodeLengths {
+    pub fn read_from<T: BufRead>(bit_reader: &mut BitReader<T>) -> Result<Self> {
+        // See RFC 1951, section 3.2.7.
+        let hlit = bit_reader.read_bits(5)?.bits() as usize + 257;
+        let hdist = bit_reader.read_bits(5)?.bits() as usize + 1;
+        let hclen = bit_reader.read_bits(4)?.bits() as usize + 4;
+
+        let mut code_length_lengths = [0u8; 19];
+        for &index in CODE_LENGTH_ORDER.iter().take(hclen) {
+            code_length_lengths[index] = bit_reader.read_bits(3)?.bits() as u8;
+        }
+        let tree_coding = HuffmanCoding::<TreeCodeToken>::from_lengths(&code_length_lengths)
+            .context("failed to build code length coding")?;
+
+        let mut lengths = Vec::with_capacity(hlit + hdist);
+        while lengths.len() < hlit + hdist {
+            match tree_coding.read_symbol(bit_reader)? {
+                TreeCodeToken::Length(len) => lengths.push(len),
+                TreeCodeToken::CopyPrev => {
+                    let prev = *lengths
+                        .last()
+                        .context("code length repeat without previous length")?;
+                    let count = 3 + bit_reader.read_bits(2)?.bits() as usize;
+                    lengths.resize(lengths.len() + count, prev);
+                }
+                TreeCodeToken::RepeatZero { base, extra_bits } => {
+                    let count = base as usize + bit_reader.read_bits(extra_bits)?.bits() as usize;
+                    lengths.resize(lengths.len() + count, 0);
+                }
+            }
+        }
+        ensure!(
+            lengths.len() == hlit + hdist,
+            "code lengths overflow the declared alphabet sizes"
+        );
+        ensure!(
+            lengths[END_OF_BLOCK as usize] > 0,
+            "end of block symbol has no code"
+        );
+
+        let distance_lengths = lengths.split_off(hlit);
+        Ok(Self {
+            code_length_lengths,
+            litlen_lengths: lengths,
+            distance_lengths,
+        })
+    }
+
+    pub fn build_trees(
+        &self,
+    ) -> Result<(HuffmanCoding<LitLenToken>, HuffmanCoding<DistanceToken>)> {
+        let litlen_coding = HuffmanCoding::from_lengths(&self.litlen_lengths)
+            .context("failed to build literal/length coding")?;
+        let distance_coding = HuffmanCoding::from_lengths(&self.distance_lengths)
+            .context("failed to build distance coding")?;
+        Ok((litlen_coding, distance_coding))
+    }
+}
+
 pub fn decode_litlen_distance_trees<T: BufRead>(
     bit_reader: &mut BitReader<T>,
 ) -> Result<(HuffmanCoding<LitLenToken>, HuffmanCoding<DistanceToken>)> {
-    // See RFC 1951, section 3.2.7.
-    // TODO: your code goes here.
-    unimplemented!()
+    DynamicCodeLengths::read_from(bit_reader)?.build_trees()
+}
+
+/// Code lengths of the fixed literal/length alphabet, see RFC 1951, section 3.2.6.
+/// Symbols 286 and 287 never occur in the data, so they are left out.
+pub fn fixed_litlen_lengths() -> [u8; 286] {
+    let mut lengths = [8u8; 286];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths
+}
+
+/// Code lengths of the fixed distance alphabet without the unused symbols 30 and 31.
+pub fn fixed_distance_lengths() -> [u8; 30] {
+    [5u8; 30]
+}
+
+pub fn fixed_trees() -> Result<(HuffmanCoding<LitLenToken>, HuffmanCoding<DistanceToken>)> {
+    Ok((
+        HuffmanCoding::from_lengths(&fixed_litlen_lengths())?,
+        HuffmanCoding::from_lengths(&fixed_distance_lengths())?,
+    ))
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -30,8 +146,19 @@ impl TryFrom<HuffmanCodeWord> for TreeCodeToken {
 
     fn try_from(value: HuffmanCodeWord) -> Result<Self> {
         // See RFC 1951, section 3.2.7.
-        // TODO: your code goes here.
-        unimplemented!()
+        match value.0 {
+            0..=15 => Ok(Self::Length(value.0 as u8)),
+            16 => Ok(Self::CopyPrev),
+            17 => Ok(Self::RepeatZero {
+                base: 3,
+                extra_bits: 3,
+            }),
+            18 => Ok(Self::RepeatZero {
+                base: 11,
+                extra_bits: 7,
+            }),
+            x => bail!("invalid code length symbol: {}", x),
+        }
     }
 }
 
@@ -49,8 +176,18 @@ impl TryFrom<HuffmanCodeWord> for LitLenToken {
 
     fn try_from(value: HuffmanCodeWord) -> Result<Self> {
         // See RFC 1951, section 3.2.5.
-        // TODO: your code goes here.
-        unimplemented!()
+        match value.0 {
+            0..=255 => Ok(Self::Literal(value.0 as u8)),
+            END_OF_BLOCK => Ok(Self::EndOfBlock),
+            257..=285 => {
+                let index = (value.0 - 257) as usize;
+                Ok(Self::Length {
+                    base: LENGTH_BASES[index],
+                    extra_bits: LENGTH_EXTRA_BITS[index],
+                })
+            }
+            x => bail!("invalid literal/length symbol: {}", x),
+        }
     }
 }
 
@@ -67,8 +204,13 @@ impl TryFrom<HuffmanCodeWord> for DistanceToken {
 
     fn try_from(value: HuffmanCodeWord) -> Result<Self> {
         // See RFC 1951, section 3.2.5.
-        // TODO: your code goes here.
-        unimplemented!()
+        match value.0 {
+            0..=29 => Ok(Self {
+                base: DISTANCE_BASES[value.0 as usize],
+                extra_bits: DISTANCE_EXTRA_BITS[value.0 as usize],
+            }),
+            x => bail!("invalid distance symbol: {}", x),
+        }
     }
 }
 
@@ -78,33 +220,272 @@ const MAX_BITS: usize = 15;
 
 pub struct HuffmanCodeWord(pub u16);
 
+/// Assign canonical codes to the given code lengths, see RFC 1951, section 3.2.2.
+/// Symbols with zero length get no code.
+pub fn canonical_codes(code_lengths: &[u8]) -> Result<Vec<Option<BitSequence>>> {
+    let mut bl_count = [0u16; MAX_BITS + 1];
+    for &len in code_lengths {
+        ensure!(len as usize <= MAX_BITS, "code length {} is too big", len);
+        bl_count[len as usize] += 1;
+    }
+    bl_count[0] = 0;
+
+    let mut next_code = [0u32; MAX_BITS + 1];
+    let mut code = 0u32;
+    for bits in 1..=MAX_BITS {
+        code = (code + bl_count[bits - 1] as u32) << 1;
+        next_code[bits] = code;
+        ensure!(
+            code + bl_count[bits] as u32 <= 1 << bits,
+            "code lengths are oversubscribed"
+        );
+    }
+
+    Ok(code_lengths
+        .iter()
+        .map(|&len| {
+            if len == 0 {
+                return None;
+            }
+            let code = next_code[len as usize];
+            next_code[len as usize] += 1;
+            Some(BitSequence::new(code as u16, len))
+        })
+        .collect())
+}
+
+/// Number of stream bits indexing the primary decoding table. Longer codes continue
+/// in sub-tables linked from the primary one.
+const PRIMARY_BITS: u8 = 9;
+
+#[derive(Clone, Copy)]
+enum TableEntry<T> {
+    Invalid,
+    Symbol { symbol: T, len: u8 },
+    SubTable { offset: u16, bits: u8 },
+}
+
+/// Table-driven Huffman decoder. Tables are indexed by the next bits of the stream
+/// in the order they are read, i.e. by bit-reversed codes.
 pub struct HuffmanCoding<T> {
-    map: HashMap<BitSequence, T>,
+    table: Vec<TableEntry<T>>,
+    primary_bits: u8,
 }
 
 impl<T> HuffmanCoding<T>
 where
     T: Copy + TryFrom<HuffmanCodeWord, Error = anyhow::Error>,
 {
-    pub fn new(map: HashMap<BitSequence, T>) -> Self {
-        Self { map }
-    }
-
     #[allow(unused)]
     pub fn decode_symbol(&self, seq: BitSequence) -> Option<T> {
-        // TODO: your code goes here.
-        unimplemented!()
+        match self.lookup(seq.reversed().bits() as u64) {
+            TableEntry::Symbol { symbol, len } if len == seq.len() => Some(symbol),
+            _ => None,
+        }
     }
 
     pub fn read_symbol<U: BufRead>(&self, bit_reader: &mut BitReader<U>) -> Result<T> {
-        // TODO: your code goes here.
-        unimplemented!()
+        let (bits, available) = bit_reader.peek_bits()?;
+        match self.lookup(bits) {
+            TableEntry::Symbol { symbol, len } if len <= available => {
+                bit_reader.consume_bits(len)?;
+                Ok(symbol)
+            }
+            TableEntry::Invalid if available as usize >= MAX_BITS => {
+                Err(anyhow!("invalid huffman code: {:#b}", bits & 0x7fff))
+            }
+            _ => self.read_symbol_bitwise(bit_reader),
+        }
+    }
+
+    /// Slow path for the end of the reader buffer, where `peek_bits` returns
+    /// fewer bits than the code may take.
+    fn read_symbol_bitwise<U: BufRead>(&self, bit_reader: &mut BitReader<U>) -> Result<T> {
+        let mut bits = 0u64;
+        for len in 1..=MAX_BITS as u8 {
+            bits |= (bit_reader.read_bits(1)?.bits() as u64) << (len - 1);
+            if let TableEntry::Symbol {
+                symbol,
+                len: code_len,
+            } = self.lookup(bits)
+            {
+                if code_len == len {
+                    return Ok(symbol);
+                }
+            }
+        }
+        Err(anyhow!("invalid huffman code: {:#b}", bits))
     }
 
+    fn lookup(&self, bits: u64) -> TableEntry<T> {
+        let entry = self.table[(bits & mask(self.primary_bits)) as usize];
+        match entry {
+            TableEntry::SubTable {
+                offset,
+                bits: sub_bits,
+            } => {
+                let index = (bits >> self.primary_bits) & mask(sub_bits);
+                self.table[offset as usize + index as usize]
+            }
+            entry => entry,
+        }
+    }
+
+    pub fn from_lengths(code_lengths: &[u8]) -> Result<Self> {
+        let codes = canonical_codes(code_lengths)?;
+        let max_len = code_lengths.iter().copied().max().unwrap_or(0);
+        let primary_bits = max_len.min(PRIMARY_BITS);
+
+        // Every primary entry prefixing a longer code links to a sub-table
+        // wide enough for the longest of such codes.
+        let mut table = vec![TableEntry::Invalid; 1 << primary_bits];
+        let mut sub_bits = vec![0u8; 1 << primary_bits];
+        for code in codes.iter().flatten() {
+            if code.len() > primary_bits {
+                let prefix = (code.reversed().bits() as u64 & mask(primary_bits)) as usize;
+                sub_bits[prefix] = sub_bits[prefix].max(code.len() - primary_bits);
+            }
+        }
+        for (prefix, &bits) in sub_bits.iter().enumerate() {
+            if bits > 0 {
+                table[prefix] = TableEntry::SubTable {
+                    offset: table.len() as u16,
+                    bits,
+                };
+                table.resize(table.len() + (1 << bits), TableEntry::Invalid);
+            }
+        }
+
+        for (symbol, code) in codes.into_iter().enumerate() {
+            let code = match code {
+                Some(code) => code,
+                None => continue,
+            };
+            let entry = TableEntry::Symbol {
+                symbol: T::try_from(HuffmanCodeWord(symbol as u16))?,
+                len: code.len(),
+            };
+            let reversed = code.reversed().bits() as usize;
+
+            // Fill all entries whose low bits match the code.
+            let (start, index, len, width) = if code.len() <= primary_bits {
+                (0, reversed, code.len(), primary_bits)
+            } else {
+                let prefix = reversed & mask(primary_bits) as usize;
+                match table[prefix] {
+                    TableEntry::SubTable { offset, bits } => (
+                        offset as usize,
+                        reversed >> primary_bits,
+                        code.len() - primary_bits,
+                        bits,
+                    ),
+                    _ => unreachable!(),
+                }
+            };
+            for high in 0..1usize << (width - len) {
+                table[start + (index | high << len)] = entry;
+            }
+        }
+
+        Ok(Self {
+            table,
+            primary_bits,
+        })
+    }
+}
+
+fn mask(bits: u8) -> u64 {
+    (1 << bits) - 1
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Compute code lengths of a Huffman code for the given symbol frequencies,
+/// limiting them to `max_len` bits. Unused symbols get zero length, but at least
+/// two symbols always get a code so that the resulting code is complete.
+pub fn lengths_from_frequencies(frequencies: &[u32], max_len: u8) -> Vec<u8> {
+    assert!(frequencies.len() >= 2, "alphabet is too small");
+    assert!(
+        frequencies.len() <= 1 << max_len,
+        "alphabet does not fit into max_len"
+    );
+
+    let mut weights = frequencies.to_vec();
+    while weights.iter().filter(|w| **w > 0).count() < 2 {
+        let unused = weights.iter().position(|w| *w == 0).unwrap();
+        weights[unused] = 1;
+    }
+
+    loop {
+        let lengths = build_unlimited_lengths(&weights);
+        if lengths.iter().all(|len| *len <= max_len) {
+            return lengths;
+        }
+        // Flatten the distribution and try again: every halving reduces the depth.
+        for weight in weights.iter_mut().filter(|w| **w > 0) {
+            *weight = (*weight >> 1) | 1;
+        }
+    }
+}
+
+fn build_unlimited_lengths(weights: &[u32]) -> Vec<u8> {
+    let mut heap = BinaryHeap::new();
+    let mut parents = vec![];
+    for (symbol, &weight) in weights.iter().enumerate() {
+        if weight > 0 {
+            heap.push(Reverse((weight as u64, parents.len())));
+            parents.push((symbol, usize::MAX));
+        }
+    }
+
+    let leaf_count = parents.len();
+    while heap.len() > 1 {
+        let Reverse((first_weight, first)) = heap.pop().unwrap();
+        let Reverse((second_weight, second)) = heap.pop().unwrap();
+        let node = parents.len();
+        parents.push((usize::MAX, usize::MAX));
+        parents[first].1 = node;
+        parents[second].1 = node;
+        heap.push(Reverse((first_weight + second_weight, node)));
+    }
+
+    // Parents are always created after their children, so walk the nodes backwards.
+    let mut depths = vec![0u8; parents.len()];
+    for node in (0..parents.len()).rev() {
+        let parent = parents[node].1;
+        if parent != usize::MAX {
+            depths[node] = depths[parent] + 1;
+        }
+    }
+
+    let mut lengths = vec![0u8; weights.len()];
+    for (node, &(symbol, _)) in parents.iter().enumerate().take(leaf_count) {
+        lengths[symbol] = depths[node];
+    }
+    lengths
+}
+
+/// Huffman codes stored in the bit order they are written to the stream.
+pub struct HuffmanEncoder {
+    codes: Vec<BitSequence>,
+}
+
+impl HuffmanEncoder {
     pub fn from_lengths(code_lengths: &[u8]) -> Result<Self> {
-        // See RFC 1951, section 3.2.2.
-        // TODO: your code goes here.
-        unimplemented!()
+        let codes = canonical_codes(code_lengths)?
+            .into_iter()
+            .map(|code| match code {
+                Some(code) => code.reversed(),
+                None => BitSequence::new(0, 0),
+            })
+            .collect();
+        Ok(Self { codes })
+    }
+
+    pub fn code(&self, symbol: u16) -> BitSequence {
+        let code = self.codes[symbol as usize];
+        debug_assert!(code.len() > 0, "symbol {} has no code", symbol);
+        code
     }
 }
 
@@ -250,4 +631,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn long_codes() -> Result<()> {
+        // Codes up to 15 bits long, so that sub-tables are needed.
+        let frequencies = (0..40u32).map(|i| 1 << (i % 20)).collect::<Vec<_>>();
+        let lengths = lengths_from_frequencies(&frequencies, 15);
+        assert_eq!(lengths.iter().max(), Some(&15));
+
+        let encoder = HuffmanEncoder::from_lengths(&lengths)?;
+        let mut writer = crate::bit_writer::BitWriter::new(vec![]);
+        let symbols = (0..1000u16).map(|i| i * 7 % 40).collect::<Vec<_>>();
+        for &symbol in &symbols {
+            writer.write_bits(encoder.code(symbol))?;
+        }
+        let data = writer.into_inner()?;
+
+        let code = HuffmanCoding::<Value>::from_lengths(&lengths)?;
+        let mut reader = BitReader::new(data.as_slice());
+        // A one-byte buffer forces the bit-by-bit path.
+        let mut slow_reader = BitReader::new(std::io::BufReader::with_capacity(1, data.as_slice()));
+        for &symbol in &symbols {
+            assert_eq!(code.read_symbol(&mut reader)?, Value(symbol));
+            assert_eq!(code.read_symbol(&mut slow_reader)?, Value(symbol));
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,425 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::*;
+
+use crate::{
+    bit_reader::BitReader,
+    counting_reader::{position_in_bits, CountingReader},
+    deflate::{self, DeflateReader},
+    gzip::{GzipReader, MemberReader},
+    gzip_decoder::GzipDecoder,
+    tracking_writer::TrackingWriter,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Default distance between access points in the uncompressed data.
+pub const DEFAULT_SPAN: u64 = 1 << 20;
+
+const INDEX_MAGIC: &[u8; 8] = b"RGZIDX\x00\x01";
+const SIDECAR_EXTENSION: &str = "gzidx";
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A block boundary decoding can be resumed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessPoint {
+    /// Offset of the block header in the compressed data, in bits.
+    pub bit_offset: u64,
+    /// Offset of the block data in the uncompressed data.
+    pub output_offset: u64,
+    /// Up to 32 KiB of uncompressed data preceding the block in the same member.
+    pub window: Vec<u8>,
+}
+
+/// Random access index of gzip data in the spirit of zlib's `zran.c`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GzipIndex {
+    points: Vec<AccessPoint>,
+    compressed_size: u64,
+    uncompressed_size: u64,
+}
+
+impl GzipIndex {
+    /// Decompress the whole `input`, recording an access point at the first block boundary
+    /// after every `span` bytes of uncompressed data.
+    pub fn build<R: BufRead>(input: R, span: u64) -> Result<Self> {
+        ensure!(span > 0, "index span must be positive");
+
+        let mut gzip_reader = GzipReader::new(CountingReader::new(input));
+        let mut points = vec![];
+        let mut member_offset = 0u64;
+        let mut next_point = span;
+        let mut member_index = 0;
+        let compressed_size = loop {
+            let position = gzip_reader.inner_mut().position();
+            let member = match gzip_reader.next_member() {
+                Some(member) => member,
+                None => break position,
+            };
+            let (_, member_reader) = member
+                .with_context(|| format!("failed to read header of member #{}", member_index))?;
+
+            let mut writer = TrackingWriter::new(io::sink());
+            let mut deflate_reader = DeflateReader::new(BitReader::new(member_reader.into_inner()));
+            loop {
+                let bit_offset = position_in_bits(deflate_reader.bit_reader_mut());
+                let (header, bit_reader) = match deflate_reader.next_block() {
+                    Some(block) => block?,
+                    None => break,
+                };
+                let output_offset = member_offset + writer.byte_count() as u64;
+                if output_offset >= next_point {
+                    points.push(AccessPoint {
+                        bit_offset,
+                        output_offset,
+                        window: writer.history(),
+                    });
+                    next_point = output_offset + span;
+                }
+                deflate::decompress_block(&header, bit_reader, &mut writer)
+                    .with_context(|| format!("failed to decompress member #{}", member_index))?;
+            }
+
+            let member_reader = MemberReader::new(deflate_reader.into_inner().into_inner());
+            let (footer, next_reader) = member_reader.read_footer()?;
+            footer
+                .verify(&writer)
+                .with_context(|| format!("failed to decompress member #{}", member_index))?;
+            member_offset += writer.byte_count() as u64;
+            gzip_reader = next_reader;
+            member_index += 1;
+        };
+
+        debug!(
+            "built index of {} access points over {} members",
+            points.len(),
+            member_index
+        );
+        Ok(Self {
+            points,
+            compressed_size,
+            uncompressed_size: member_offset,
+        })
+    }
+
+    pub fn points(&self) -> &[AccessPoint] {
+        &self.points
+    }
+
+    /// Size of the indexed gzip data.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Total size of the decompressed data, not limited to 32 bits unlike ISIZE.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.uncompressed_size
+    }
+
+    /// Find the closest access point at or before `offset` of the uncompressed data.
+    pub fn find(&self, offset: u64) -> Option<&AccessPoint> {
+        let count = self
+            .points
+            .partition_point(|point| point.output_offset <= offset);
+        count.checked_sub(1).map(|i| &self.points[i])
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_u64::<LittleEndian>(self.compressed_size)?;
+        writer.write_u64::<LittleEndian>(self.uncompressed_size)?;
+        writer.write_u64::<LittleEndian>(self.points.len() as u64)?;
+        for point in &self.points {
+            writer.write_u64::<LittleEndian>(point.bit_offset)?;
+            writer.write_u64::<LittleEndian>(point.output_offset)?;
+            writer.write_u32::<LittleEndian>(point.window.len() as u32)?;
+            writer.write_all(&point.window)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        ensure!(&magic == INDEX_MAGIC, "not a ripgzip index");
+
+        let compressed_size = reader.read_u64::<LittleEndian>()?;
+        let uncompressed_size = reader.read_u64::<LittleEndian>()?;
+        let count = reader.read_u64::<LittleEndian>()?;
+        let mut points = vec![];
+        for _ in 0..count {
+            let bit_offset = reader.read_u64::<LittleEndian>()?;
+            let output_offset = reader.read_u64::<LittleEndian>()?;
+            let window_len = reader.read_u32::<LittleEndian>()?;
+            ensure!(window_len <= 32768, "window of an access point is too big");
+            let mut window = vec![0u8; window_len as usize];
+            reader.read_exact(&mut window)?;
+            points.push(AccessPoint {
+                bit_offset,
+                output_offset,
+                window,
+            });
+        }
+        ensure!(
+            points
+                .windows(2)
+                .all(|pair| pair[0].output_offset < pair[1].output_offset),
+            "access points are not sorted"
+        );
+
+        Ok(Self {
+            points,
+            compressed_size,
+            uncompressed_size,
+        })
+    }
+
+    /// Path of the sidecar index file for the gzip file at `path`, e.g. `logs.gz.gzidx`.
+    pub fn sidecar_path(path: &Path) -> PathBuf {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(".");
+        name.push(SIDECAR_EXTENSION);
+        PathBuf::from(name)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        Self::read_from(&mut BufReader::new(file))
+            .with_context(|| format!("failed to read index {}", path.display()))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Decompresses gzip data with random access, resuming decoding from the
+/// closest access point of a `GzipIndex` on every seek.
+pub struct SeekableGzipReader<R> {
+    decoder: Option<GzipDecoder<R>>,
+    index: GzipIndex,
+    /// Offset in the uncompressed data `decoder` will produce next.
+    decoder_position: u64,
+    position: u64,
+}
+
+impl<R: BufRead + Seek> SeekableGzipReader<R> {
+    pub fn new(mut reader: R, index: GzipIndex) -> Result<Self> {
+        let compressed_size = reader.seek(SeekFrom::End(0))?;
+        ensure!(
+            compressed_size == index.compressed_size(),
+            "index was built for {} bytes of data, got {}",
+            index.compressed_size(),
+            compressed_size
+        );
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            decoder: Some(GzipDecoder::new(reader)),
+            index,
+            decoder_position: 0,
+            position: 0,
+        })
+    }
+
+    pub fn index(&self) -> &GzipIndex {
+        &self.index
+    }
+
+    /// Return the underlying reader, or `None` if decoding has failed.
+    pub fn into_inner(self) -> Option<R> {
+        self.decoder.and_then(GzipDecoder::into_inner)
+    }
+
+    fn take_reader(&mut self) -> io::Result<R> {
+        self.decoder
+            .take()
+            .and_then(GzipDecoder::into_inner)
+            .ok_or_else(|| io::Error::other("decoder has failed before"))
+    }
+
+    /// Restart decoding from the closest access point before `offset`.
+    fn restart(&mut self, offset: u64) -> io::Result<()> {
+        let mut reader = self.take_reader()?;
+        let (decoder, position) = match self.index.find(offset) {
+            Some(point) => {
+                trace!(
+                    "resuming at bit offset {} for offset {}",
+                    point.bit_offset,
+                    offset
+                );
+                reader.seek(SeekFrom::Start(point.bit_offset / 8))?;
+                let mut bit_reader = BitReader::new(reader);
+                bit_reader.read_bits((point.bit_offset % 8) as u8)?;
+                let deflate_reader = DeflateReader::new(bit_reader);
+                (
+                    GzipDecoder::resume(deflate_reader, &point.window),
+                    point.output_offset,
+                )
+            }
+            None => {
+                reader.seek(SeekFrom::Start(0))?;
+                (GzipDecoder::new(reader), 0)
+            }
+        };
+        self.decoder = Some(decoder);
+        self.decoder_position = position;
+        Ok(())
+    }
+
+    /// Make the decoder produce data at `self.position` next.
+    fn sync_decoder(&mut self) -> io::Result<()> {
+        let closest = self.index.find(self.position).map(|p| p.output_offset);
+        if self.position < self.decoder_position
+            || closest.is_some_and(|offset| offset > self.decoder_position)
+            || self.decoder.is_none()
+        {
+            self.restart(self.position)?;
+        }
+
+        let decoder = self.decoder.as_mut().unwrap();
+        while self.decoder_position < self.position {
+            let available = decoder.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            let len = available
+                .len()
+                .min((self.position - self.decoder_position) as usize);
+            decoder.consume(len);
+            self.decoder_position += len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead + Seek> Read for SeekableGzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position != self.decoder_position {
+            self.sync_decoder()?;
+            if self.position != self.decoder_position {
+                // The position is beyond the end of the data.
+                return Ok(0);
+            }
+        }
+        let decoder = self
+            .decoder
+            .as_mut()
+            .ok_or_else(|| io::Error::other("decoder has failed before"))?;
+        let len = decoder.read(buf)?;
+        self.decoder_position += len as u64;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: BufRead + Seek> Seek for SeekableGzipReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.index.uncompressed_size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompress_all(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        crate::decompress(data, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn build() -> Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let expected = decompress_all(data);
+        let index = GzipIndex::build(&data[..], 100000)?;
+        assert_eq!(index.compressed_size(), data.len() as u64);
+        assert_eq!(index.uncompressed_size(), expected.len() as u64);
+        assert!(index.points().len() > 5);
+
+        for point in index.points() {
+            let start = point.output_offset as usize;
+            assert!(expected[..start].ends_with(&point.window));
+        }
+        assert!(index.find(99999).is_none());
+        assert_eq!(index.find(u64::MAX), index.points().last());
+
+        let mut buf = vec![];
+        index.write_to(&mut buf)?;
+        assert_eq!(GzipIndex::read_from(&mut buf.as_slice())?, index);
+        Ok(())
+    }
+
+    #[test]
+    fn seek_and_read() -> Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let expected = decompress_all(data);
+        let index = GzipIndex::build(&data[..], 50000)?;
+        let mut reader = SeekableGzipReader::new(io::Cursor::new(&data[..]), index)?;
+
+        let mut buf = vec![0u8; 5000];
+        for &offset in &[
+            1000000u64, 0, 88190, 500, 1700000, 1000001, 88194, 150000, 5,
+        ] {
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut buf)?;
+            let start = offset as usize;
+            assert_eq!(
+                buf,
+                &expected[start..start + buf.len()],
+                "offset {}",
+                offset
+            );
+        }
+
+        reader.seek(SeekFrom::End(-10))?;
+        let mut tail = vec![];
+        reader.read_to_end(&mut tail)?;
+        assert_eq!(tail, &expected[expected.len() - 10..]);
+
+        reader.seek(SeekFrom::End(10))?;
+        assert_eq!(reader.read(&mut buf)?, 0);
+        assert!(reader.seek(SeekFrom::Current(-1_000_000_000)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stale_index() -> Result<()> {
+        let data = include_bytes!("../data/ok/01-page.gz");
+        let index = GzipIndex::build(&data[..], DEFAULT_SPAN)?;
+        let other = include_bytes!("../data/ok/02-doc.pdf.gz");
+        assert!(SeekableGzipReader::new(io::Cursor::new(&other[..]), index).is_err());
+        Ok(())
+    }
+}
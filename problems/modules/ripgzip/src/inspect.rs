@@ -0,0 +1,289 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ReadBytesExt};
+use serde::Serialize;
+
+use crate::{
+    bit_reader::BitReader,
+    counting_reader::{position_in_bits, CountingReader},
+    deflate::{self, BlockDecoder, BlockHeader, CompressionType, DeflateReader},
+    deflate_writer::Token,
+    gzip::{GzipReader, MemberReader},
+    huffman_coding::DynamicCodeLengths,
+    tracking_writer::TrackingWriter,
+    zlib, Format,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Summary of a single DEFLATE block, see `blocks`.
+#[derive(Debug, Serialize)]
+pub struct BlockInfo {
+    /// Index of the gzip member the block belongs to. Always 0 for other formats.
+    pub stream: usize,
+    /// Index of the block in its stream.
+    pub index: usize,
+    /// Offset of the block header from the start of the input, in bits.
+    pub bit_offset: u64,
+    /// Size of the block including its header, in bits.
+    pub bit_size: u64,
+    #[serde(flatten)]
+    pub header: BlockHeader,
+    /// Code lengths of dynamic blocks.
+    pub code_lengths: Option<DynamicCodeLengths>,
+    pub literal_count: u64,
+    pub match_count: u64,
+    pub uncompressed_size: u64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum State<R> {
+    /// Before the container header of the next stream.
+    Start(CountingReader<R>),
+    Body(DeflateReader<CountingReader<R>>),
+    Done,
+}
+
+/// Iterator over the blocks of compressed data, see `blocks`.
+pub struct Blocks<R> {
+    state: State<R>,
+    format: Format,
+    writer: TrackingWriter<io::Sink>,
+    stream: usize,
+    index: usize,
+}
+
+impl<R: BufRead> Blocks<R> {
+    /// Read the container header, if any. Returns `None` if there are no streams left.
+    fn start_stream(
+        &mut self,
+        mut reader: CountingReader<R>,
+    ) -> Result<Option<DeflateReader<CountingReader<R>>>> {
+        let reader = match self.format {
+            Format::Gzip => match GzipReader::new(reader).next_member() {
+                Some(member) => member?.1.into_inner(),
+                None => return Ok(None),
+            },
+            _ if self.stream > 0 => return Ok(None),
+            Format::Zlib => {
+                let header = zlib::StreamHeader::read_from(&mut reader)?;
+                if header.dictionary_id.is_some() {
+                    bail!("stream requires a preset dictionary");
+                }
+                reader
+            }
+            Format::Deflate => reader,
+        };
+        self.writer = TrackingWriter::new(io::sink());
+        self.index = 0;
+        Ok(Some(DeflateReader::new(BitReader::new(reader))))
+    }
+
+    /// Skip the container footer after the final block.
+    fn finish_stream(&mut self, mut reader: CountingReader<R>) -> Result<CountingReader<R>> {
+        match self.format {
+            Format::Gzip => reader = MemberReader::new(reader).read_footer()?.1.into_inner(),
+            Format::Zlib => {
+                reader.read_u32::<BigEndian>()?;
+            }
+            Format::Deflate => {}
+        }
+        self.stream += 1;
+        Ok(reader)
+    }
+
+    fn inspect_block(
+        &mut self,
+        header: BlockHeader,
+        bit_offset: u64,
+        bit_reader: &mut BitReader<CountingReader<R>>,
+    ) -> Result<BlockInfo> {
+        let start_size = self.writer.byte_count();
+        let (mut decoder, code_lengths) = match header.compression_type {
+            CompressionType::DynamicTree => {
+                let code_lengths = DynamicCodeLengths::read_from(bit_reader)?;
+                let (litlen_coding, distance_coding) = code_lengths.build_trees()?;
+                let decoder = BlockDecoder::Huffman {
+                    litlen_coding,
+                    distance_coding,
+                };
+                (decoder, Some(code_lengths))
+            }
+            _ => (BlockDecoder::new(&header, bit_reader)?, None),
+        };
+
+        let mut literal_count = 0;
+        let mut match_count = 0;
+        match &mut decoder {
+            BlockDecoder::Huffman {
+                litlen_coding,
+                distance_coding,
+            } => {
+                while let Some(token) =
+                    deflate::read_token(litlen_coding, distance_coding, bit_reader)?
+                {
+                    match token {
+                        Token::Literal(byte) => {
+                            literal_count += 1;
+                            self.writer.write_all(&[byte])?;
+                        }
+                        Token::Match { len, dist } => {
+                            match_count += 1;
+                            self.writer
+                                .write_previous(dist as usize, len as usize)
+                                .context("failed to copy previous bytes")?;
+                        }
+                    }
+                }
+            }
+            BlockDecoder::Uncompressed { .. } => {
+                while !decoder.decode(bit_reader, &mut self.writer, usize::MAX)? {}
+            }
+        }
+
+        Ok(BlockInfo {
+            stream: self.stream,
+            index: self.index,
+            bit_offset,
+            bit_size: position_in_bits(bit_reader) - bit_offset,
+            header,
+            code_lengths,
+            literal_count,
+            match_count,
+            uncompressed_size: (self.writer.byte_count() - start_size) as u64,
+        })
+    }
+
+    fn next_block(&mut self) -> Result<Option<BlockInfo>> {
+        loop {
+            match std::mem::replace(&mut self.state, State::Done) {
+                State::Start(reader) => match self.start_stream(reader)? {
+                    Some(deflate_reader) => self.state = State::Body(deflate_reader),
+                    None => return Ok(None),
+                },
+                State::Body(mut deflate_reader) => {
+                    let bit_offset = position_in_bits(deflate_reader.bit_reader_mut());
+                    let block = match deflate_reader.next_block() {
+                        Some(block) => block,
+                        None => {
+                            let reader = deflate_reader.into_inner().into_inner();
+                            self.state = State::Start(self.finish_stream(reader)?);
+                            continue;
+                        }
+                    };
+                    let index = self.index;
+                    let (header, bit_reader) = block
+                        .with_context(|| format!("failed to read header of block #{}", index))?;
+                    let info = self
+                        .inspect_block(header, bit_offset, bit_reader)
+                        .with_context(|| {
+                            format!("block #{} at bit offset {} is corrupted", index, bit_offset)
+                        })?;
+                    self.index += 1;
+                    self.state = State::Body(deflate_reader);
+                    return Ok(Some(info));
+                }
+                State::Done => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Blocks<R> {
+    type Item = Result<BlockInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_block().transpose()
+    }
+}
+
+/// Decode `input` block by block, reporting the layout of each one. Container
+/// checksums are not verified. The iteration stops after the first error.
+pub fn blocks<R: BufRead>(input: R, format: Format) -> Blocks<R> {
+    Blocks {
+        state: State::Start(CountingReader::new(input)),
+        format,
+        writer: TrackingWriter::new(io::sink()),
+        stream: 0,
+        index: 0,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress;
+
+    #[test]
+    fn gzip_members() -> Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let infos = blocks(&data[..], Format::Gzip).collect::<Result<Vec<_>>>()?;
+
+        let streams = infos.iter().map(|info| info.stream).max();
+        assert_eq!(streams, Some(2));
+        let total: u64 = infos.iter().map(|info| info.uncompressed_size).sum();
+        assert_eq!(total, 1784657);
+
+        // The first block of each member starts right after its 10-byte header.
+        assert_eq!(infos[0].bit_offset, 80);
+        for pair in infos.windows(2) {
+            if pair[0].stream == pair[1].stream {
+                assert_eq!(pair[0].index + 1, pair[1].index);
+                assert_eq!(pair[0].bit_offset + pair[0].bit_size, pair[1].bit_offset);
+            } else {
+                assert!(pair[0].header.is_final);
+                assert_eq!(pair[1].index, 0);
+            }
+        }
+        for info in &infos {
+            let code_lengths = info.code_lengths.as_ref();
+            match info.header.compression_type {
+                CompressionType::DynamicTree => {
+                    assert!(code_lengths.unwrap().litlen_lengths[256] > 0)
+                }
+                _ => assert!(code_lengths.is_none()),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn token_counts() -> Result<()> {
+        let data = b"abcabcabcabcabcabcabcabcabcabc".repeat(100);
+        let mut compressed = vec![];
+        compress(data.as_slice(), &mut compressed, 6)?;
+
+        let infos = blocks(compressed.as_slice(), Format::Gzip).collect::<Result<Vec<_>>>()?;
+        assert_eq!(infos.len(), 1);
+        assert!(infos[0].header.is_final);
+        assert_eq!(infos[0].literal_count, 3);
+        assert!(infos[0].match_count > 0);
+        assert_eq!(infos[0].uncompressed_size, data.len() as u64);
+
+        let mut stored = vec![];
+        compress(data.as_slice(), &mut stored, 0)?;
+        let infos = blocks(stored.as_slice(), Format::Gzip).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            infos[0].header.compression_type,
+            CompressionType::Uncompressed
+        );
+        assert_eq!((infos[0].literal_count, infos[0].match_count), (0, 0));
+        assert_eq!(infos[0].bit_size, 8 * (5 + data.len() as u64));
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted() {
+        let data = include_bytes!("../data/corrupted/06-invalid-btype.gz");
+        let err = blocks(&data[..], Format::Gzip)
+            .find_map(Result::err)
+            .unwrap();
+        assert!(format!("{:#}", err).contains("unsupported block type"));
+    }
+}
@@ -1,19 +1,193 @@
 #![forbid(unsafe_code)]
 
-use std::io::{BufRead, Write};
+use std::{
+    io::{self, BufRead, BufWriter, Read, Write},
+    str::FromStr,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use log::*;
 
 use crate::gzip::GzipReader;
 
+#[cfg(feature = "tokio")]
+mod async_decoder;
+mod bgzf;
 mod bit_reader;
+mod bit_writer;
+mod counting_reader;
 mod deflate;
+mod deflate_writer;
 mod gzip;
+mod gzip_decoder;
+mod gzip_writer;
 mod huffman_coding;
+mod index;
+mod inspect;
+mod members;
+mod parallel;
+mod recover;
 mod tracking_writer;
+mod zlib;
+
+#[cfg(feature = "tokio")]
+pub use async_decoder::AsyncGzipDecoder;
+pub use bgzf::{BgzfReader, BgzfWriter, VirtualOffset, BGZF_BLOCK_DATA_SIZE, BGZF_EOF};
+pub use deflate::{BlockHeader, CompressionType};
+pub use deflate_writer::{DEFAULT_LEVEL, MAX_LEVEL};
+pub use gzip::{CompressionMethod, ExtraSubfield, MemberFooter, MemberHeader};
+pub use gzip_decoder::GzipDecoder;
+pub use gzip_writer::{GzipWriter, GzipWriterBuilder};
+pub use huffman_coding::DynamicCodeLengths;
+pub use index::{AccessPoint, GzipIndex, SeekableGzipReader, DEFAULT_SPAN};
+pub use inspect::{blocks, BlockInfo, Blocks};
+pub use members::{members, MemberInfo, Members};
+pub use parallel::{crc32_combine, DEFAULT_CHUNK_SIZE};
+pub use recover::{recover, DamagedRange, RecoveryReport};
+pub use zlib::adler32;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Container around DEFLATE data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// RFC 1952 members, possibly concatenated.
+    Gzip,
+    /// A single RFC 1950 stream.
+    Zlib,
+    /// A bare RFC 1951 stream without any framing.
+    Deflate,
+}
+
+impl Format {
+    /// Guess the format by the first bytes of the stream. Anything that is
+    /// neither gzip nor zlib is assumed to be raw DEFLATE.
+    pub fn detect<R: BufRead>(input: &mut R) -> Result<Self> {
+        let prefix = input.fill_buf().context("failed to read input")?;
+        Ok(match *prefix {
+            [] | [0x1f] | [0x1f, 0x8b, ..] => Self::Gzip,
+            [cmf, flg, ..] if zlib::StreamHeader::is_valid(cmf, flg) => Self::Zlib,
+            _ => Self::Deflate,
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zlib" => Ok(Self::Zlib),
+            "deflate" | "raw" => Ok(Self::Deflate),
+            _ => bail!("unknown format: {}", s),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
 
 pub fn decompress<R: BufRead, W: Write>(input: R, mut output: W) -> Result<()> {
-    // TODO: your code goes here.
-    unimplemented!()
+    let mut gzip_reader = GzipReader::new(input);
+    let mut member_index = 0;
+    while let Some(member) = gzip_reader.next_member() {
+        let (header, member_reader) =
+            member.with_context(|| format!("failed to read header of member #{}", member_index))?;
+        debug!("decompressing member #{}: {:?}", member_index, header);
+
+        gzip_reader = gzip::decompress_member(member_reader, &mut output)
+            .with_context(|| format!("failed to decompress member #{}", member_index))?
+            .1;
+        member_index += 1;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Decompress a single zlib stream.
+pub fn decompress_zlib<R: BufRead, W: Write>(input: R, output: W) -> Result<()> {
+    decompress_zlib_inner(input, output, None)
+}
+
+/// Decompress a single zlib stream that may have been compressed with a preset dictionary.
+pub fn decompress_zlib_with_dictionary<R: BufRead, W: Write>(
+    input: R,
+    output: W,
+    dictionary: &[u8],
+) -> Result<()> {
+    decompress_zlib_inner(input, output, Some(dictionary))
+}
+
+fn decompress_zlib_inner<R: BufRead, W: Write>(
+    input: R,
+    mut output: W,
+    dictionary: Option<&[u8]>,
+) -> Result<()> {
+    zlib::decompress_stream(input, &mut output, dictionary)
+        .context("failed to decompress zlib stream")?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Decompress raw DEFLATE data, e.g. a ZIP entry. Anything after the final block is ignored.
+pub fn decompress_deflate<R: BufRead, W: Write>(input: R, mut output: W) -> Result<()> {
+    let mut writer = tracking_writer::TrackingWriter::new(&mut output);
+    deflate::decompress_blocks(input, &mut writer)
+        .context("failed to decompress deflate stream")?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Decompress `input` in the given `format`.
+pub fn decompress_format<R: BufRead, W: Write>(input: R, output: W, format: Format) -> Result<()> {
+    match format {
+        Format::Gzip => decompress(input, output),
+        Format::Zlib => decompress_zlib(input, output),
+        Format::Deflate => decompress_deflate(input, output),
+    }
+}
+
+/// Compress `input` into a single gzip member. `level` ranges from 0 (no compression)
+/// to `MAX_LEVEL` (best compression), just like in `gzip`.
+pub fn compress<R: Read, W: Write>(input: R, output: W, level: u32) -> Result<()> {
+    let mut output = gzip::compress_member(input, BufWriter::new(output), level)
+        .context("failed to compress data")?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Compress `input` into BGZF blocks, i.e. gzip members of at most 64 KiB each,
+/// followed by the end-of-file marker.
+pub fn compress_bgzf<R: Read, W: Write>(mut input: R, output: W, level: u32) -> Result<()> {
+    let mut writer = BgzfWriter::new(BufWriter::new(output), level)?;
+    io::copy(&mut input, &mut writer).context("failed to compress data")?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+/// Compress `input` into a single gzip member using `threads` threads. The output is
+/// slightly bigger than the one of `compress`, since every chunk of `DEFAULT_CHUNK_SIZE`
+/// bytes is compressed on its own.
+pub fn compress_parallel<R: Read, W: Write>(
+    input: R,
+    output: W,
+    level: u32,
+    threads: usize,
+) -> Result<()> {
+    ensure!(
+        level <= MAX_LEVEL,
+        "unsupported compression level: {}",
+        level
+    );
+    ensure!(threads > 0, "number of threads must be positive");
+    let mut output = parallel::compress_member_parallel(
+        input,
+        BufWriter::new(output),
+        level,
+        threads,
+        DEFAULT_CHUNK_SIZE,
+    )
+    .context("failed to compress data")?;
+    output.flush()?;
+    Ok(())
 }
@@ -1,11 +1,21 @@
 #![forbid(unsafe_code)]
 
-use std::io::{stdin, stdout};
+use std::{
+    fs::{self, File, FileTimes, Metadata},
+    io::{self, stdin, stdout, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context, Result};
 
 use log::*;
 use structopt::StructOpt;
 
-use ripgzip::decompress;
+use ripgzip::{
+    blocks, compress, compress_bgzf, compress_parallel, decompress_format, members, recover,
+    Format, GzipIndex, GzipWriterBuilder, MemberHeader, MAX_LEVEL,
+};
 
 #[derive(StructOpt, Debug)]
 #[structopt()]
@@ -13,11 +23,560 @@ struct Opts {
     /// Decompress data
     #[structopt(short = "d", long = "decompress")]
     decompress: bool,
+    /// List the members of gzip data: sizes, ratio, modification time and original name
+    #[structopt(short = "l", long = "list", conflicts_with = "test")]
+    list: bool,
+    /// Check integrity of the compressed data without writing the output
+    #[structopt(short = "t", long = "test")]
+    test: bool,
+    /// Print the layout of every DEFLATE block of the compressed data as JSON lines
+    #[structopt(long = "dump-blocks", conflicts_with_all = &["list", "test"])]
+    dump_blocks: bool,
+    /// Build a random access index of the given gzip file and save it next to the file
+    #[structopt(
+        long = "index",
+        parse(from_os_str),
+        conflicts_with_all = &["list", "test", "dump-blocks"]
+    )]
+    index: Option<PathBuf>,
+    /// Decompress damaged gzip data, skipping the parts that cannot be decoded,
+    /// and report the damaged ranges
+    #[structopt(
+        long = "recover",
+        conflicts_with_all = &["list", "test", "dump-blocks", "index"]
+    )]
+    recover: bool,
+    /// Distance between index access points in the uncompressed data, in bytes
+    #[structopt(long = "index-span", default_value = "1048576")]
+    index_span: u64,
+    /// Format of the compressed data when decompressing: gzip, zlib or deflate.
+    /// Detected from the first bytes by default.
+    #[structopt(long = "format")]
+    format: Option<Format>,
+    /// Write output to standard output and keep the input files. This is the default
+    /// when reading standard input
+    #[structopt(short = "c", long = "stdout")]
+    stdout: bool,
+    /// Keep the input files instead of removing them
+    #[structopt(short = "k", long = "keep")]
+    keep: bool,
+    /// Overwrite existing output files
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+    /// Suffix of compressed files
+    #[structopt(short = "S", long = "suffix", default_value = ".gz")]
+    suffix: String,
+    /// Process the files in the given directories recursively
+    #[structopt(short = "r", long = "recursive")]
+    recursive: bool,
+    /// Compression level, from 0 (no compression) to 9 (best compression)
+    #[structopt(long = "level", default_value = "6")]
+    level: u32,
+    /// Compress faster (same as --level 1)
+    #[structopt(long = "fast", conflicts_with_all = &["best", "level"])]
+    fast: bool,
+    /// Compress better (same as --level 9)
+    #[structopt(long = "best", conflicts_with = "level")]
+    best: bool,
+    /// Number of compression threads. With more than one, input is compressed
+    /// in independent chunks like pigz does
+    #[structopt(short = "p", long = "processes", default_value = "1")]
+    threads: usize,
+    /// Compress into BGZF blocks of at most 64 KiB, which allows random access
+    #[structopt(long = "bgzf", conflicts_with = "threads")]
+    bgzf: bool,
+    /// When compressing FILE, do not save its name and modification time in the header.
+    /// They are never saved with -p or --bgzf
+    #[structopt(short = "n", long = "no-name")]
+    no_name: bool,
+    /// When decompressing, restore the original file name and modification time
+    /// saved in the header
+    #[structopt(short = "N", long = "name", conflicts_with = "no-name")]
+    name: bool,
+    /// Files to process, "-" for standard input. Standard input is used if none is
+    /// given. Files are replaced with their compressed or decompressed versions unless
+    /// -c is given
+    #[structopt(parse(from_os_str))]
+    files: Vec<PathBuf>,
     /// Verbose mode (-v, -vv, -vvv, etc)
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
 }
 
+impl Opts {
+    fn level(&self) -> u32 {
+        if self.fast {
+            1
+        } else if self.best {
+            MAX_LEVEL
+        } else {
+            self.level
+        }
+    }
+
+    /// Whether the output is a report or a check rather than the processed data.
+    fn is_inspection(&self) -> bool {
+        self.list || self.test || self.dump_blocks || self.recover
+    }
+}
+
+fn open_file(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Log the error of `result`, if any. Returns whether it has succeeded.
+fn report_error(result: Result<()>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            error!("{:#}", err);
+            false
+        }
+    }
+}
+
+/// Process a command line argument: a file, "-" for standard input or, with -r,
+/// a directory. Like in gzip, an error does not stop processing of the other files.
+/// Returns whether all the files have been processed successfully.
+fn run_path(opts: &Opts, path: &Path) -> bool {
+    if path == Path::new("-") {
+        return report_error(run_stream(opts, stdin().lock(), None));
+    }
+    let metadata =
+        fs::symlink_metadata(path).with_context(|| format!("failed to read {}", path.display()));
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(err) => return report_error(Err(err)),
+    };
+    if metadata.file_type().is_symlink() {
+        // Like in gzip, the link is only followed when nothing is written next to it
+        // or with -f, since the output replaces the link rather than its target.
+        if !opts.force && !opts.stdout && !opts.is_inspection() {
+            warn!(
+                "{} is a symbolic link -- ignored, use -f to follow it",
+                path.display()
+            );
+            return true;
+        }
+        // Directories are never entered through links, so that a link to a parent
+        // cannot make the recursion loop.
+        if path.is_dir() {
+            warn!(
+                "{} is a symbolic link to a directory -- ignored",
+                path.display()
+            );
+            return true;
+        }
+        return report_error(run_file(opts, path));
+    }
+    if !metadata.is_dir() {
+        return report_error(run_file(opts, path));
+    }
+    if !opts.recursive {
+        warn!("{} is a directory -- ignored", path.display());
+        return true;
+    }
+
+    let entries = fs::read_dir(path).and_then(|entries| {
+        entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()
+    });
+    match entries.with_context(|| format!("failed to read directory {}", path.display())) {
+        Ok(mut entries) => {
+            entries.sort();
+            let mut success = true;
+            for entry in entries {
+                success &= run_path(opts, &entry);
+            }
+            success
+        }
+        Err(err) => report_error(Err(err)),
+    }
+}
+
+fn run_file(opts: &Opts, path: &Path) -> Result<()> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?;
+    if !metadata.is_file() {
+        warn!("{} is not a regular file -- ignored", path.display());
+        return Ok(());
+    }
+    if opts.stdout || opts.is_inspection() {
+        return run_stream(opts, open_file(path)?, Some(path));
+    }
+
+    ensure!(!opts.suffix.is_empty(), "suffix must not be empty");
+    if opts.decompress {
+        decompress_file(opts, path, &metadata)
+    } else {
+        compress_file(opts, path, &metadata)
+    }
+}
+
+/// Process `input` in the mode given by `opts`, writing the result to standard output.
+/// `path` is the file `input` comes from, if any.
+fn run_stream<R: BufRead>(opts: &Opts, input: R, path: Option<&Path>) -> Result<()> {
+    if opts.list {
+        run_list(opts, input)
+    } else if opts.test {
+        run_test(opts, input)
+    } else if opts.dump_blocks {
+        run_dump_blocks(opts, input)
+    } else if opts.recover {
+        run_recover(input)
+    } else if opts.decompress {
+        run_decompress(opts, input, stdout().lock())
+    } else {
+        run_compress(opts, input, stdout().lock(), path)
+    }
+}
+
+fn run_decompress<R: BufRead, W: Write>(opts: &Opts, mut input: R, output: W) -> Result<()> {
+    let format = input_format(opts, &mut input)?;
+    debug!("decompressing {:?} data", format);
+    decompress_format(input, output, format)
+}
+
+fn run_compress<R: BufRead, W: Write>(
+    opts: &Opts,
+    input: R,
+    output: W,
+    path: Option<&Path>,
+) -> Result<()> {
+    let level = opts.level();
+    if opts.bgzf {
+        compress_bgzf(input, output, level)
+    } else if opts.threads > 1 {
+        compress_parallel(input, output, level, opts.threads)
+    } else {
+        match path {
+            Some(path) if !opts.no_name => compress_named(input, output, path, level),
+            _ => compress(input, output, level),
+        }
+    }
+}
+
+/// Compress `path` into a file with the suffix appended.
+fn compress_file(opts: &Opts, path: &Path, metadata: &Metadata) -> Result<()> {
+    let has_suffix = path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(&opts.suffix));
+    if has_suffix {
+        warn!(
+            "{} already has {} suffix -- unchanged",
+            path.display(),
+            opts.suffix
+        );
+        return Ok(());
+    }
+
+    let mut output_path = path.as_os_str().to_owned();
+    output_path.push(&opts.suffix);
+    let input = open_file(path)?;
+    write_output_file(
+        opts,
+        path,
+        metadata,
+        Path::new(&output_path),
+        metadata.modified().ok(),
+        |output| run_compress(opts, input, output, Some(path)),
+    )
+}
+
+/// Decompress `path` into a file without the suffix or, with -N, into a file next to it
+/// named as saved in the header of the first member.
+fn decompress_file(opts: &Opts, path: &Path, metadata: &Metadata) -> Result<()> {
+    let stem = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(&opts.suffix))
+        .filter(|stem| !stem.is_empty());
+    let mut output_path = match stem {
+        Some(stem) => path.with_file_name(stem),
+        None => {
+            warn!("{} has an unknown suffix -- ignored", path.display());
+            return Ok(());
+        }
+    };
+
+    let mut input = open_file(path)?;
+    let format = input_format(opts, &mut input)?;
+    let mut mtime = metadata.modified().ok();
+    if opts.name && format == Format::Gzip {
+        let header = MemberHeader::read_from(&mut input)?;
+        input.seek(SeekFrom::Start(0))?;
+        // Only the last component is used, so that a crafted name cannot point elsewhere.
+        if let Some(name) = header.decoded_name() {
+            if let Some(name) = Path::new(&name).file_name() {
+                output_path = path.with_file_name(name);
+            }
+        }
+        if header.modification_time != 0 {
+            mtime =
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(header.modification_time.into()));
+        }
+    }
+
+    debug!("decompressing {:?} data", format);
+    write_output_file(opts, path, metadata, &output_path, mtime, |output| {
+        decompress_format(input, output, format)
+    })
+}
+
+/// Create `output_path` and fill it with `write`, then give it the permissions of the input,
+/// its access time and `mtime`, and remove the input unless -k is given. The output is
+/// removed if anything fails, like in gzip.
+fn write_output_file<F>(
+    opts: &Opts,
+    input_path: &Path,
+    metadata: &Metadata,
+    output_path: &Path,
+    mtime: Option<SystemTime>,
+    write: F,
+) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    ensure!(
+        output_path != input_path,
+        "{} would be overwritten by its own output",
+        input_path.display()
+    );
+    let mut options = File::options();
+    if opts.force {
+        options.write(true).create(true).truncate(true);
+    } else {
+        options.write(true).create_new(true);
+    }
+    let file = options.open(output_path).with_context(|| {
+        format!(
+            "failed to create {}, use -f to overwrite existing files",
+            output_path.display()
+        )
+    })?;
+
+    let result = (|| -> Result<()> {
+        let mut output = BufWriter::new(file);
+        write(&mut output)?;
+        let file = output.into_inner().map_err(|err| err.into_error())?;
+        file.set_permissions(metadata.permissions())
+            .context("failed to set permissions")?;
+        let mut times = FileTimes::new();
+        if let Ok(atime) = metadata.accessed() {
+            times = times.set_accessed(atime);
+        }
+        if let Some(mtime) = mtime {
+            times = times.set_modified(mtime);
+        }
+        file.set_times(times).context("failed to set timestamps")?;
+        Ok(())
+    })();
+    if let Err(err) = result {
+        let _ = fs::remove_file(output_path);
+        return Err(err.context(format!("failed to write {}", output_path.display())));
+    }
+
+    if !opts.keep {
+        fs::remove_file(input_path)
+            .with_context(|| format!("failed to remove {}", input_path.display()))?;
+    }
+    info!("{} -> {}", input_path.display(), output_path.display());
+    Ok(())
+}
+
+/// Compress `input` saving the name and modification time of `path` in the header,
+/// like `gzip -N` does.
+fn compress_named<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    path: &Path,
+    level: u32,
+) -> Result<()> {
+    // Times that do not fit the header are not saved, as zero means no time.
+    let mtime = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|mtime| mtime.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .and_then(|mtime| u32::try_from(mtime.as_secs()).ok())
+        .unwrap_or(0);
+    let mut builder = GzipWriterBuilder::new()
+        .level(level)
+        .modification_time(mtime);
+    if let Some(name) = path.file_name() {
+        builder = builder.name(name.to_string_lossy().into_owned());
+    }
+
+    let mut writer = builder.build(BufWriter::new(output))?;
+    io::copy(&mut input, &mut writer).context("failed to compress data")?;
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+fn run_recover<R: Read>(mut input: R) -> Result<()> {
+    let mut data = vec![];
+    input
+        .read_to_end(&mut data)
+        .context("failed to read input")?;
+    let report = recover(&data, BufWriter::new(stdout().lock()))?;
+
+    for range in &report.damaged {
+        warn!(
+            "damaged input bytes {}..{} at output offset {}: {}",
+            range.start, range.end, range.output_offset, range.error
+        );
+    }
+    if !report.damaged.is_empty() {
+        bail!(
+            "recovered {} bytes, {} damaged ranges found",
+            report.output_size,
+            report.damaged.len()
+        );
+    }
+    Ok(())
+}
+
+fn input_format<R: io::BufRead>(opts: &Opts, input: &mut R) -> Result<Format> {
+    match opts.format {
+        Some(format) => Ok(format),
+        None => Format::detect(input),
+    }
+}
+
+fn run_list<R: BufRead>(opts: &Opts, mut input: R) -> Result<()> {
+    let format = input_format(opts, &mut input)?;
+    ensure!(
+        format == Format::Gzip,
+        "cannot list members of {:?} data",
+        format
+    );
+
+    let mut output = stdout().lock();
+    writeln!(
+        output,
+        "{:>19} {:>19} {:>6} {:>19} uncompressed_name",
+        "compressed", "uncompressed", "ratio", "mtime"
+    )?;
+    let mut member_count = 0;
+    let mut totals = [0u64; 3];
+    for info in members(input) {
+        let info = info?;
+        let sizes = [
+            info.compressed_size,
+            info.body_size(),
+            info.uncompressed_size() as u64,
+        ];
+        let mtime = format_mtime(info.header.modification_time);
+        let name = info.header.decoded_name();
+        print_list_row(&mut output, sizes, &mtime, name.as_deref().unwrap_or("-"))?;
+
+        member_count += 1;
+        for (total, size) in totals.iter_mut().zip(sizes) {
+            *total += size;
+        }
+    }
+    if member_count > 1 {
+        print_list_row(&mut output, totals, "", "(totals)")?;
+    }
+    Ok(())
+}
+
+/// `sizes` are the member size, its compressed data size and the uncompressed size.
+fn print_list_row<W: Write>(
+    output: &mut W,
+    sizes: [u64; 3],
+    mtime: &str,
+    name: &str,
+) -> io::Result<()> {
+    let [compressed, body, uncompressed] = sizes;
+    writeln!(
+        output,
+        "{:>19} {:>19} {:>5.1}% {:>19} {}",
+        compressed,
+        uncompressed,
+        ratio(body, uncompressed),
+        mtime,
+        name
+    )
+}
+
+/// Space saved by compression in percent, counting only the compressed data, like `gzip -l` does.
+fn ratio(compressed: u64, uncompressed: u64) -> f64 {
+    if uncompressed == 0 {
+        return 0.;
+    }
+    100. * (1. - compressed as f64 / uncompressed as f64)
+}
+
+/// Format a Unix timestamp as UTC date and time, or "-" if it is not set.
+fn format_mtime(mtime: u32) -> String {
+    if mtime == 0 {
+        return "-".to_string();
+    }
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let days = mtime as i64 / 86400 + 719468;
+    let seconds = mtime as i64 % 86400;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn run_test<R: BufRead>(opts: &Opts, mut input: R) -> Result<()> {
+    let format = input_format(opts, &mut input)?;
+    if format != Format::Gzip {
+        return decompress_format(input, io::sink(), format);
+    }
+    for info in members(input) {
+        let info = info?;
+        info!(
+            "member #{} at offset {}: OK, {} bytes",
+            info.index,
+            info.offset,
+            info.uncompressed_size()
+        );
+    }
+    Ok(())
+}
+
+fn run_dump_blocks<R: BufRead>(opts: &Opts, mut input: R) -> Result<()> {
+    let format = input_format(opts, &mut input)?;
+    let mut output = stdout().lock();
+    for info in blocks(input, format) {
+        serde_json::to_writer(&mut output, &info?)?;
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+fn run_index(path: &Path, span: u64) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let index = GzipIndex::build(BufReader::new(file), span)?;
+    let index_path = GzipIndex::sidecar_path(path);
+    index.save(&index_path)?;
+    info!(
+        "saved {} access points to {}",
+        index.points().len(),
+        index_path.display()
+    );
+    Ok(())
+}
+
 fn main() {
     let opts = Opts::from_args();
 
@@ -27,10 +586,18 @@ fn main() {
         .init()
         .expect("failed to initialize logging");
 
-    if opts.decompress {
-        if let Err(err) = decompress(stdin().lock(), stdout().lock()) {
-            error!("{:#}", err);
-            std::process::exit(1);
+    let success = if let Some(path) = &opts.index {
+        report_error(run_index(path, opts.index_span))
+    } else if opts.files.is_empty() {
+        run_path(&opts, Path::new("-"))
+    } else {
+        let mut success = true;
+        for path in &opts.files {
+            success &= run_path(&opts, path);
         }
+        success
+    };
+    if !success {
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,157 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    counting_reader::CountingReader,
+    gzip::{self, GzipReader, MemberFooter, MemberHeader},
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Location and metadata of a single gzip member.
+#[derive(Debug)]
+pub struct MemberInfo {
+    pub index: usize,
+    /// Offset of the member header in the input.
+    pub offset: u64,
+    /// Size of the header, including all the optional fields.
+    pub header_size: u64,
+    /// Size of the whole member, including the header and the footer.
+    pub compressed_size: u64,
+    pub header: MemberHeader,
+    pub footer: MemberFooter,
+}
+
+impl MemberInfo {
+    /// Size of the uncompressed data modulo 2^32, as stored in ISIZE.
+    pub fn uncompressed_size(&self) -> u32 {
+        self.footer.data_size
+    }
+
+    /// Size of the compressed data without the header and the footer.
+    pub fn body_size(&self) -> u64 {
+        self.compressed_size - self.header_size - 8
+    }
+}
+
+/// Iterator over the members of a gzip stream, see `members`.
+pub struct Members<R> {
+    gzip_reader: Option<GzipReader<CountingReader<R>>>,
+    index: usize,
+}
+
+impl<R: BufRead> Members<R> {
+    fn next_member(
+        &mut self,
+        gzip_reader: GzipReader<CountingReader<R>>,
+        offset: u64,
+    ) -> Option<Result<MemberInfo>> {
+        let (header, mut member_reader) = match gzip_reader.next_member()? {
+            Ok(member) => member,
+            Err(err) => return Some(Err(err.context("failed to read header"))),
+        };
+        let header_size = member_reader.inner_mut().position() - offset;
+
+        let (footer, mut gzip_reader) = match gzip::decompress_member(member_reader, io::sink()) {
+            Ok(result) => result,
+            Err(err) => return Some(Err(err.context("failed to decompress data"))),
+        };
+        let compressed_size = gzip_reader.inner_mut().position() - offset;
+        self.gzip_reader = Some(gzip_reader);
+
+        Some(Ok(MemberInfo {
+            index: self.index,
+            offset,
+            header_size,
+            compressed_size,
+            header,
+            footer,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Members<R> {
+    type Item = Result<MemberInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut gzip_reader = self.gzip_reader.take()?;
+        let offset = gzip_reader.inner_mut().position();
+        let index = self.index;
+        let result = self
+            .next_member(gzip_reader, offset)?
+            .with_context(|| format!("member #{} at offset {} is corrupted", index, offset));
+        self.index += 1;
+        Some(result)
+    }
+}
+
+/// Decompress every member of the gzip stream `input` without writing the data anywhere,
+/// verifying the header CRC16, CRC32 and ISIZE of each one. The iteration stops after
+/// the first error.
+pub fn members<R: BufRead>(input: R) -> Members<R> {
+    Members {
+        gzip_reader: Some(GzipReader::new(CountingReader::new(input))),
+        index: 0,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concat() -> Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let infos = members(&data[..]).collect::<Result<Vec<_>>>()?;
+        assert_eq!(infos.len(), 3);
+
+        let mut offset = 0;
+        for (index, info) in infos.iter().enumerate() {
+            assert_eq!(info.index, index);
+            assert_eq!(info.offset, offset);
+            offset += info.compressed_size;
+        }
+        assert_eq!(offset, data.len() as u64);
+        assert_eq!(infos[1].uncompressed_size(), 153333);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata() -> Result<()> {
+        let data = include_bytes!("../data/ok/10-header-crc16.gz");
+        let info = members(&data[..]).next().unwrap()?;
+        assert!(info.header.has_crc);
+        let extra_len = info.header.extra.as_ref().unwrap().len() as u64;
+        assert_eq!(info.header_size, 10 + 2 + extra_len + 2);
+        assert_eq!(info.uncompressed_size(), 625254);
+        assert_eq!(info.footer.data_crc32, 0xe88c3c1f);
+        assert_eq!(info.body_size(), data.len() as u64 - info.header_size - 8);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut data = include_bytes!("../data/ok/09-concat.gz").to_vec();
+        let last_offset: u64 = members(data.as_slice())
+            .take(2)
+            .map(|info| info.unwrap().compressed_size)
+            .sum();
+        let len = data.len();
+        data[len - 5] ^= 1;
+
+        let mut iter = members(data.as_slice());
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_ok());
+        let err = iter.next().unwrap().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("member #2 at offset {}", last_offset)));
+        assert!(format!("{:#}", err).contains("crc32 check failed"));
+        assert!(iter.next().is_none());
+    }
+}
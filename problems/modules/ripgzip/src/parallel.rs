@@ -0,0 +1,250 @@
+#![forbid(unsafe_code)]
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    deflate_writer::DeflateWriter,
+    gzip::{MemberFooter, MemberHeader},
+    tracking_writer::CRC32,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Amount of input compressed as a single job, the same as in pigz.
+pub const DEFAULT_CHUNK_SIZE: usize = 128 * 1024;
+
+/// Amount of preceding input used as a dictionary for every chunk.
+const DICTIONARY_SIZE: usize = 32768;
+
+/// CRC-32 polynomial in the reversed bit order.
+const CRC32_POLYNOMIAL: u32 = 0xedb88320;
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn gf2_matrix_times(matrix: &[u32; 32], mut vector: u32) -> u32 {
+    let mut sum = 0;
+    for row in matrix {
+        if vector == 0 {
+            break;
+        }
+        if vector & 1 != 0 {
+            sum ^= row;
+        }
+        vector >>= 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(matrix: &[u32; 32]) -> [u32; 32] {
+    let mut square = [0; 32];
+    for (row, &value) in square.iter_mut().zip(matrix) {
+        *row = gf2_matrix_times(matrix, value);
+    }
+    square
+}
+
+/// Compute the CRC-32 of two concatenated pieces of data given the CRC-32 of each
+/// one and the length of the second, like `crc32_combine` from zlib.
+pub fn crc32_combine(mut crc1: u32, crc2: u32, mut len2: u64) -> u32 {
+    // Appending a zero bit to the data is a linear operator on the CRC.
+    let mut operator = [0u32; 32];
+    operator[0] = CRC32_POLYNOMIAL;
+    for (n, row) in operator.iter_mut().enumerate().skip(1) {
+        *row = 1 << (n - 1);
+    }
+    // Square it up to appending a zero byte.
+    for _ in 0..3 {
+        operator = gf2_matrix_square(&operator);
+    }
+
+    // Apply it for every bit of len2, squaring to double the number of zero bytes.
+    while len2 != 0 {
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&operator, crc1);
+        }
+        len2 >>= 1;
+        if len2 != 0 {
+            operator = gf2_matrix_square(&operator);
+        }
+    }
+    crc1 ^ crc2
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Job {
+    data: Vec<u8>,
+    dictionary: Vec<u8>,
+    result: mpsc::SyncSender<io::Result<CompressedChunk>>,
+}
+
+struct CompressedChunk {
+    data: Vec<u8>,
+    crc32: u32,
+    len: usize,
+}
+
+fn compress_chunk(job: &Job, level: u32) -> io::Result<CompressedChunk> {
+    let mut writer = DeflateWriter::new(vec![], level);
+    writer.set_dictionary(&job.dictionary);
+    writer.write_all(&job.data)?;
+    Ok(CompressedChunk {
+        data: writer.finish_sync()?,
+        crc32: CRC32.checksum(&job.data),
+        len: job.data.len(),
+    })
+}
+
+fn run_worker(jobs: &Mutex<mpsc::Receiver<Job>>, level: u32) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // The receiver is gone only if compression has already failed.
+        let _ = job.result.send(compress_chunk(&job, level));
+    }
+}
+
+fn read_chunk<R: Read>(input: &mut R, size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    input.take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Compress `input` into a single member using `threads` threads in the pigz style.
+/// Input is split into chunks of `chunk_size` bytes compressed independently, each
+/// primed with 32 KiB of input preceding it. Chunks are terminated with an empty
+/// stored block, so that the next one starts at a byte boundary.
+pub fn compress_member_parallel<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    level: u32,
+    threads: usize,
+    chunk_size: usize,
+) -> Result<W> {
+    assert!(threads > 0, "at least one thread is required");
+    assert!(chunk_size > 0, "chunk size must be positive");
+    MemberHeader::new(level)
+        .write_to(&mut output)
+        .context("failed to write member header")?;
+
+    let footer = thread::scope(|scope| -> Result<MemberFooter> {
+        // Workers stop once the sender is dropped, which happens on errors too,
+        // before the scope waits for them.
+        let (job_sender, job_receiver) = mpsc::channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for _ in 0..threads {
+            let job_receiver = job_receiver.clone();
+            scope.spawn(move || run_worker(&job_receiver, level));
+        }
+
+        let mut in_flight = VecDeque::new();
+        let mut crc32 = CRC32.checksum(&[]);
+        let mut data_size = 0u32;
+        let mut write_result = |receiver: mpsc::Receiver<io::Result<CompressedChunk>>| {
+            let chunk = receiver
+                .recv()
+                .map_err(|_| anyhow!("compression thread has failed"))??;
+            output
+                .write_all(&chunk.data)
+                .context("failed to write output")?;
+            crc32 = crc32_combine(crc32, chunk.crc32, chunk.len as u64);
+            data_size = data_size.wrapping_add(chunk.len as u32);
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let mut dictionary = vec![];
+        loop {
+            let data = read_chunk(&mut input, chunk_size).context("failed to read input")?;
+            if data.is_empty() {
+                break;
+            }
+            let next_dictionary = data[data.len().saturating_sub(DICTIONARY_SIZE)..].to_vec();
+
+            let (result_sender, result_receiver) = mpsc::sync_channel(1);
+            job_sender
+                .send(Job {
+                    data,
+                    dictionary: std::mem::replace(&mut dictionary, next_dictionary),
+                    result: result_sender,
+                })
+                .map_err(|_| anyhow!("compression threads have failed"))?;
+            in_flight.push_back(result_receiver);
+
+            // Bound the memory used by chunks waiting to be written.
+            if in_flight.len() >= 2 * threads {
+                write_result(in_flight.pop_front().unwrap())?;
+            }
+        }
+        drop(job_sender);
+        for receiver in in_flight {
+            write_result(receiver)?;
+        }
+
+        Ok(MemberFooter {
+            data_crc32: crc32,
+            data_size,
+        })
+    })?;
+
+    // Terminate the stream with an empty final block.
+    let mut output = DeflateWriter::new(output, level).finish()?;
+    footer
+        .write_to(&mut output)
+        .context("failed to write member footer")?;
+    Ok(output)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32() {
+        let data = b"The quick brown fox jumps over the lazy dog".repeat(100);
+        for split in [0, 1, 17, 1000, data.len()] {
+            let (head, tail) = data.split_at(split);
+            assert_eq!(
+                crc32_combine(
+                    CRC32.checksum(head),
+                    CRC32.checksum(tail),
+                    tail.len() as u64
+                ),
+                CRC32.checksum(&data),
+                "split {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let mut data = vec![];
+        crate::decompress(&include_bytes!("../data/ok/01-page.gz")[..], &mut data)?;
+        for (level, threads, chunk_size) in
+            [(6, 4, 10000), (1, 3, 50000), (0, 2, 70000), (9, 1, 1 << 20)]
+        {
+            let compressed =
+                compress_member_parallel(data.as_slice(), vec![], level, threads, chunk_size)?;
+            let mut output = vec![];
+            crate::decompress(compressed.as_slice(), &mut output)?;
+            assert_eq!(output, data, "level {}, {} threads", level, threads);
+        }
+
+        let compressed = compress_member_parallel(&b""[..], vec![], 6, 4, 1000)?;
+        let mut output = vec![];
+        crate::decompress(compressed.as_slice(), &mut output)?;
+        assert!(output.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+#![forbid(unsafe_code)]
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use log::*;
+
+use crate::{
+    bit_reader::BitReader,
+    deflate::{self, BlockHeader, CompressionType, DeflateReader},
+    gzip::{GzipReader, MemberReader},
+    tracking_writer::TrackingWriter,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const ID1: u8 = 0x1f;
+const ID2: u8 = 0x8b;
+const CM_DEFLATE: u8 = 8;
+/// Flag bits reserved by RFC 1952, which must be zero.
+const RESERVED_FLAGS: u8 = 0xe0;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A range of the input that could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedRange {
+    /// Offset of the first damaged byte in the input.
+    pub start: u64,
+    /// Offset right after the damaged range, where decoding was resumed.
+    pub end: u64,
+    /// Offset in the output where the missing data belongs.
+    pub output_offset: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    pub damaged: Vec<DamagedRange>,
+    /// Total number of bytes written to the output.
+    pub output_size: u64,
+}
+
+/// Where decoding starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Resync {
+    MemberHeader,
+    /// `LEN` and `NLEN` of a stored block whose header bits precede them.
+    StoredBlock,
+}
+
+enum RunEnd {
+    /// The member is over at the given input offset.
+    Complete(usize),
+    /// Decoding failed at the given input offset after some data was recovered.
+    Failed(usize, anyhow::Error),
+    /// Decoding failed before the start point proved plausible.
+    Rejected,
+}
+
+struct Recovery<'a, W> {
+    data: &'a [u8],
+    output: W,
+    report: RecoveryReport,
+}
+
+impl<W: Write> Recovery<'_, W> {
+    fn position(&self, bit_reader: &BitReader<&[u8]>) -> usize {
+        let byte_position = self.data.len() - bit_reader.get_ref().len();
+        // A partially read byte belongs to what follows.
+        byte_position - (bit_reader.buffered_len() > 0) as usize
+    }
+
+    fn emit(&mut self, writer: &mut TrackingWriter<Vec<u8>>) -> Result<()> {
+        let buf = writer.get_mut();
+        self.output
+            .write_all(buf)
+            .context("failed to write output")?;
+        self.report.output_size += buf.len() as u64;
+        buf.clear();
+        Ok(())
+    }
+
+    /// Decode a member or the rest of it starting at `start`. If `validate` is set, nothing
+    /// is written until a whole block after the start point is decoded successfully.
+    fn run(&mut self, start: usize, resync: Resync, validate: bool) -> Result<RunEnd> {
+        let mut writer = TrackingWriter::new(vec![]);
+        let reader = &self.data[start..];
+        let mut decoded_blocks = 0;
+
+        let bit_reader = match resync {
+            Resync::MemberHeader => match GzipReader::new(reader).next_member() {
+                Some(Ok((_, member_reader))) => BitReader::new(member_reader.into_inner()),
+                Some(Err(err)) if !validate => {
+                    return Ok(RunEnd::Failed(start, err.context("failed to read header")))
+                }
+                _ => return Ok(RunEnd::Rejected),
+            },
+            Resync::StoredBlock => {
+                let mut bit_reader = BitReader::new(reader);
+                let header = BlockHeader {
+                    is_final: false,
+                    compression_type: CompressionType::Uncompressed,
+                };
+                if deflate::decompress_block(&header, &mut bit_reader, &mut writer).is_err() {
+                    return Ok(RunEnd::Rejected);
+                }
+                bit_reader
+            }
+        };
+
+        let mut deflate_reader = DeflateReader::new(bit_reader);
+        loop {
+            let block_start = self.position(deflate_reader.bit_reader_mut());
+            let result = match deflate_reader.next_block() {
+                None => break,
+                Some(block) => block.and_then(|(header, bit_reader)| {
+                    deflate::decompress_block(&header, bit_reader, &mut writer)
+                }),
+            };
+            match result {
+                Ok(()) => decoded_blocks += 1,
+                Err(_) if validate && decoded_blocks == 0 => return Ok(RunEnd::Rejected),
+                Err(err) => {
+                    // Keep whatever the broken block has produced before the error.
+                    self.emit(&mut writer)?;
+                    return Ok(RunEnd::Failed(block_start, err));
+                }
+            }
+            self.emit(&mut writer)?;
+        }
+        let bit_reader = deflate_reader.into_inner();
+
+        let footer_start = self.position(&bit_reader);
+        let member_reader = MemberReader::new(bit_reader.into_inner());
+        let (footer, gzip_reader) = match member_reader.read_footer() {
+            Ok(result) => result,
+            Err(err) => return Ok(RunEnd::Failed(footer_start, err)),
+        };
+        let end = self.data.len() - gzip_reader.into_inner().len();
+
+        // The checksums cover the whole member, so they are useless after a resync.
+        if resync == Resync::MemberHeader {
+            if let Err(err) = footer.verify(&writer) {
+                self.report.damaged.push(DamagedRange {
+                    start: start as u64,
+                    end: end as u64,
+                    output_offset: self.report.output_size - writer.byte_count() as u64,
+                    error: format!("{:#}", err),
+                });
+            }
+        }
+        Ok(RunEnd::Complete(end))
+    }
+
+    /// Find the next plausible point to resume decoding at, starting from `from`.
+    fn find_resync(&self, from: usize) -> Option<(usize, Resync)> {
+        let data = self.data;
+        (from..data.len()).find_map(|pos| {
+            let rest = &data[pos..];
+            if rest.len() >= 10
+                && rest[..3] == [ID1, ID2, CM_DEFLATE]
+                && rest[3] & RESERVED_FLAGS == 0
+            {
+                return Some((pos, Resync::MemberHeader));
+            }
+            if rest.len() >= 4 {
+                let len = u16::from_le_bytes([rest[0], rest[1]]);
+                let nlen = u16::from_le_bytes([rest[2], rest[3]]);
+                if len == !nlen && rest.len() >= 4 + len as usize {
+                    return Some((pos, Resync::StoredBlock));
+                }
+            }
+            None
+        })
+    }
+}
+
+/// Decompress as much of the gzip `data` as possible. On corruption, all the data
+/// decoded before the error is kept, and decoding resumes at the next plausible
+/// member header or byte-aligned stored block. Returns the damaged input ranges.
+pub fn recover<W: Write>(data: &[u8], output: W) -> Result<RecoveryReport> {
+    let mut recovery = Recovery {
+        data,
+        output,
+        report: RecoveryReport::default(),
+    };
+
+    let mut position = 0;
+    let mut resync = Resync::MemberHeader;
+    let mut damage: Option<(usize, u64, anyhow::Error)> = None;
+    while position < data.len() {
+        let end = recovery.run(position, resync, damage.is_some())?;
+        if let RunEnd::Rejected = end {
+            match recovery.find_resync(position + 1) {
+                Some(next) => (position, resync) = next,
+                None => position = data.len(),
+            }
+            continue;
+        }
+
+        if let Some((start, output_offset, err)) = damage.take() {
+            debug!("resumed decoding at offset {} ({:?})", position, resync);
+            recovery.report.damaged.push(DamagedRange {
+                start: start as u64,
+                end: position as u64,
+                output_offset,
+                error: format!("{:#}", err),
+            });
+        }
+
+        match end {
+            RunEnd::Complete(end) => {
+                position = end;
+                resync = Resync::MemberHeader;
+            }
+            RunEnd::Failed(failed_at, err) => {
+                debug!("decoding failed at offset {}: {:#}", failed_at, err);
+                damage = Some((failed_at, recovery.report.output_size, err));
+                match recovery.find_resync(failed_at + 1) {
+                    Some(next) => (position, resync) = next,
+                    None => position = data.len(),
+                }
+            }
+            RunEnd::Rejected => unreachable!(),
+        }
+    }
+
+    if let Some((start, output_offset, err)) = damage {
+        recovery.report.damaged.push(DamagedRange {
+            start: start as u64,
+            end: data.len() as u64,
+            output_offset,
+            error: format!("{:#}", err),
+        });
+    }
+    recovery.output.flush()?;
+    Ok(recovery.report)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blocks, BlockInfo, Format};
+
+    fn decompress_all(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        crate::decompress(data, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn intact() -> Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let mut output = vec![];
+        let report = recover(data, &mut output)?;
+        assert!(report.damaged.is_empty());
+        assert_eq!(output, decompress_all(data));
+        assert_eq!(report.output_size, output.len() as u64);
+        Ok(())
+    }
+
+    /// Make the block type of the given block reserved.
+    fn break_block(data: &mut [u8], block: &BlockInfo) {
+        let offset = block.bit_offset as usize / 8;
+        data[offset..offset + 2].fill(0xff);
+    }
+
+    fn find_block(data: &[u8], stream: usize, index: usize) -> BlockInfo {
+        blocks(data, Format::Gzip)
+            .map(Result::unwrap)
+            .find(|block| block.stream == stream && block.index == index)
+            .unwrap()
+    }
+
+    #[test]
+    fn damaged_member() -> Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let members = crate::members(&data[..]).collect::<Result<Vec<_>>>()?;
+        let block = find_block(data, 0, 1);
+
+        let mut damaged = data.to_vec();
+        break_block(&mut damaged, &block);
+        let mut output = vec![];
+        let report = recover(&damaged, &mut output)?;
+
+        assert_eq!(report.damaged.len(), 1);
+        let range = &report.damaged[0];
+        assert_eq!(range.start, block.bit_offset / 8);
+        assert_eq!(range.end, members[1].offset);
+        assert!(range.error.contains("unsupported block type"));
+
+        // The data before the damage and the following members are intact.
+        let expected = decompress_all(data);
+        let head = range.output_offset as usize;
+        assert!(head > 0);
+        assert_eq!(output[..head], expected[..head]);
+        let first_size = members[0].uncompressed_size() as usize;
+        assert_eq!(output[head..], expected[first_size..]);
+        Ok(())
+    }
+
+    #[test]
+    fn stored_blocks() -> Result<()> {
+        // Level 0 produces stored blocks only, so decoding resumes within the member.
+        let expected = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut data = vec![];
+        crate::compress(expected.as_slice(), &mut data, 0)?;
+        let block = find_block(&data, 0, 1);
+        let next_block = find_block(&data, 0, 2);
+        break_block(&mut data, &block);
+
+        let mut output = vec![];
+        let report = recover(&data, &mut output)?;
+        assert_eq!(report.damaged.len(), 1);
+        let range = &report.damaged[0];
+        assert_eq!(range.start, block.bit_offset / 8);
+        // Decoding resumes at LEN of the next block.
+        assert_eq!(range.end, next_block.bit_offset / 8 + 1);
+
+        let head = range.output_offset as usize;
+        let tail = output.len() - head;
+        assert_eq!(output[..head], expected[..head]);
+        assert_eq!(
+            output[head..],
+            expected[head + block.uncompressed_size as usize..]
+        );
+        assert_eq!(
+            expected.len(),
+            head + tail + block.uncompressed_size as usize
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn truncated() -> Result<()> {
+        let data = include_bytes!("../data/ok/01-page.gz");
+        let expected = decompress_all(data);
+        let mut output = vec![];
+        let report = recover(&data[..data.len() / 2], &mut output)?;
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].end, data.len() as u64 / 2);
+        assert!(!output.is_empty());
+        assert!(expected.starts_with(&output));
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_between_members() -> Result<()> {
+        let data = include_bytes!("../data/ok/00-Cargo.toml.gz");
+        let mut joined = data.to_vec();
+        joined.extend_from_slice(b"garbage");
+        joined.extend_from_slice(data);
+
+        let mut output = vec![];
+        let report = recover(&joined, &mut output)?;
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].start, data.len() as u64);
+        assert_eq!(report.damaged[0].end, data.len() as u64 + 7);
+        assert_eq!(output, decompress_all(&[&data[..], &data[..]].concat()));
+        Ok(())
+    }
+}
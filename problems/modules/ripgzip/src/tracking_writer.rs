@@ -10,43 +10,98 @@ use crc::{Crc, Digest};
 
 const HISTORY_SIZE: usize = 32768;
 
+pub static CRC32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
 pub struct TrackingWriter<T> {
     inner: T,
-    // TODO: your code goes here.
+    history: VecDeque<u8>,
+    byte_count: usize,
+    digest: Digest<'static, u32>,
 }
 
 impl<T: Write> Write for TrackingWriter<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // TODO: your code goes here.
-        unimplemented!()
+        let written = self.inner.write(buf)?;
+        self.track(&buf[..written]);
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        // TODO: your code goes here.
-        unimplemented!()
+        self.inner.flush()
     }
 }
 
 impl<T: Write> TrackingWriter<T> {
     pub fn new(inner: T) -> Self {
-        // TODO: your code goes here.
-        unimplemented!()
+        Self {
+            inner,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            byte_count: 0,
+            digest: CRC32.digest(),
+        }
+    }
+
+    /// Make `dictionary` available to back references as if it was written
+    /// just before the data, without passing it to the inner writer.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        let tail = &dictionary[dictionary.len().saturating_sub(HISTORY_SIZE)..];
+        self.history.clear();
+        self.history.extend(tail);
     }
 
     /// Write a sequence of `len` bytes written `dist` bytes ago.
     pub fn write_previous(&mut self, dist: usize, len: usize) -> Result<()> {
-        // TODO: your code goes here.
-        unimplemented!()
+        ensure!(
+            dist > 0 && dist <= self.history.len(),
+            "distance {} is out of history bounds ({} bytes available)",
+            dist,
+            self.history.len()
+        );
+
+        let start = self.history.len() - dist;
+        let mut buf = Vec::with_capacity(len);
+        for i in 0..len {
+            let byte = if i < dist {
+                self.history[start + i]
+            } else {
+                buf[i - dist]
+            };
+            buf.push(byte);
+        }
+
+        self.write_all(&buf)
+            .map_err(|err| anyhow!("failed to write previous bytes: {}", err))
+    }
+
+    /// Return the last bytes written, up to the maximum back reference distance.
+    pub fn history(&self) -> Vec<u8> {
+        self.history.iter().copied().collect()
     }
 
     pub fn byte_count(&self) -> usize {
-        // TODO: your code goes here.
-        unimplemented!()
+        self.byte_count
     }
 
-    pub fn crc32(mut self) -> u32 {
-        // TODO: your code goes here.
-        unimplemented!()
+    pub fn crc32(&self) -> u32 {
+        self.digest.clone().finalize()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn track(&mut self, buf: &[u8]) {
+        self.byte_count += buf.len();
+        self.digest.update(buf);
+
+        let tail = &buf[buf.len().saturating_sub(HISTORY_SIZE)..];
+        let overflow = (self.history.len() + tail.len()).saturating_sub(HISTORY_SIZE);
+        self.history.drain(..overflow);
+        self.history.extend(tail);
     }
 }
 
@@ -0,0 +1,230 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{BigEndian, ReadBytesExt};
+use log::*;
+
+use crate::{deflate, tracking_writer::TrackingWriter};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const CM_DEFLATE: u8 = 8;
+const MAX_CINFO: u8 = 7;
+const FDICT_OFFSET: u8 = 5;
+
+const ADLER_MOD: u32 = 65521;
+/// Largest number of bytes that can be summed before `b` may overflow u32.
+const ADLER_NMAX: usize = 5552;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self { a: 1, b: 0 }
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        for chunk in buf.chunks(ADLER_NMAX) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+pub fn adler32(buf: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(buf);
+    adler.finalize()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Passes the data through, computing its Adler-32 checksum.
+struct Adler32Writer<W> {
+    inner: W,
+    adler: Adler32,
+}
+
+impl<W: Write> Write for Adler32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.adler.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub struct StreamHeader {
+    pub window_size: usize,
+    pub level: u8,
+    pub dictionary_id: Option<u32>,
+}
+
+impl StreamHeader {
+    /// Check whether `cmf` and `flg` look like the beginning of a zlib stream.
+    pub fn is_valid(cmf: u8, flg: u8) -> bool {
+        cmf & 0x0f == CM_DEFLATE && cmf >> 4 <= MAX_CINFO && Self::check_bits_match(cmf, flg)
+    }
+
+    fn check_bits_match(cmf: u8, flg: u8) -> bool {
+        (cmf as u16 * 256 + flg as u16).is_multiple_of(31)
+    }
+
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Self> {
+        // See RFC 1950, section 2.2.
+        let cmf = reader.read_u8()?;
+        let flg = reader.read_u8()?;
+        if cmf & 0x0f != CM_DEFLATE {
+            bail!("unsupported compression method: {}", cmf & 0x0f);
+        }
+        ensure!(cmf >> 4 <= MAX_CINFO, "window size is too big");
+        ensure!(Self::check_bits_match(cmf, flg), "zlib header check failed");
+
+        let dictionary_id = if (flg >> FDICT_OFFSET) & 1 != 0 {
+            Some(reader.read_u32::<BigEndian>()?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            window_size: 1 << (8 + (cmf >> 4)),
+            level: flg >> 6,
+            dictionary_id,
+        })
+    }
+}
+
+/// Decode a single zlib stream and verify its Adler-32 checksum.
+/// Streams compressed with a preset dictionary require the same `dictionary`.
+pub fn decompress_stream<R: BufRead, W: Write>(
+    mut reader: R,
+    output: W,
+    dictionary: Option<&[u8]>,
+) -> Result<R> {
+    let header = StreamHeader::read_from(&mut reader).context("failed to read zlib header")?;
+    debug!(
+        "zlib stream: window size {}, level {}",
+        header.window_size, header.level
+    );
+
+    let mut writer = TrackingWriter::new(Adler32Writer {
+        inner: output,
+        adler: Adler32::new(),
+    });
+    if let Some(dictionary_id) = header.dictionary_id {
+        let dictionary = dictionary.context("stream requires a preset dictionary")?;
+        ensure!(
+            adler32(dictionary) == dictionary_id,
+            "preset dictionary id mismatch"
+        );
+        writer.set_dictionary(dictionary);
+    }
+
+    deflate::decompress_blocks(&mut reader, &mut writer)?;
+
+    let expected = reader
+        .read_u32::<BigEndian>()
+        .context("failed to read adler32")?;
+    ensure!(
+        writer.into_inner().adler.finalize() == expected,
+        "adler32 check failed"
+    );
+    Ok(reader)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"hello, hello, hello zlib world! hello, hello, hello zlib world! \
+        hello, hello, hello zlib world! hello, hello, hello zlib world! ";
+
+    const STREAM: &[u8] = &[
+        120, 156, 203, 72, 205, 201, 201, 215, 81, 200, 64, 162, 20, 170, 114, 50, 147, 20, 202,
+        243, 139, 114, 82, 20, 81, 36, 104, 33, 15, 0, 86, 117, 44, 185,
+    ];
+
+    const DICTIONARY: &[u8] = b"hello zlib world";
+    const DICTIONARY_STREAM: &[u8] = &[
+        120, 187, 52, 90, 6, 46, 203, 64, 227, 235, 40, 128, 69, 0, 103, 183, 8, 142,
+    ];
+
+    #[test]
+    fn adler() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32(DICTIONARY), 0x345a062e);
+
+        let big = vec![255u8; 100000];
+        let mut adler = Adler32::new();
+        for chunk in big.chunks(777) {
+            adler.update(chunk);
+        }
+        assert_eq!(adler.finalize(), adler32(&big));
+    }
+
+    #[test]
+    fn stream() -> Result<()> {
+        let mut output = vec![];
+        let rest = decompress_stream(STREAM, &mut output, None)?;
+        assert_eq!(output, DATA);
+        assert!(rest.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn preset_dictionary() -> Result<()> {
+        let mut output = vec![];
+        decompress_stream(DICTIONARY_STREAM, &mut output, Some(DICTIONARY))?;
+        assert_eq!(output, b"hello zlib world, hello");
+
+        let err = decompress_stream(DICTIONARY_STREAM, io::sink(), None).unwrap_err();
+        assert!(err.to_string().contains("preset dictionary"));
+        let err = decompress_stream(DICTIONARY_STREAM, io::sink(), Some(b"other")).unwrap_err();
+        assert!(err.to_string().contains("dictionary id mismatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let mut corrupted = STREAM.to_vec();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let err = decompress_stream(corrupted.as_slice(), io::sink(), None).unwrap_err();
+        assert!(err.to_string().contains("adler32 check failed"));
+
+        let err = decompress_stream(&[0x78, 0x9d, 0][..], io::sink(), None).unwrap_err();
+        assert!(format!("{:#}", err).contains("zlib header check failed"));
+        assert!(!StreamHeader::is_valid(0x1f, 0x8b));
+        assert!(StreamHeader::is_valid(STREAM[0], STREAM[1]));
+    }
+}
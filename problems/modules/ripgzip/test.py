@@ -1,10 +1,15 @@
 #!/usr/bin/env python3
 
 import gzip
+import io
+import json
+import os
 import pathlib
 import subprocess
 import sys
 import random
+import tempfile
+import zlib
 
 DIR = pathlib.Path(__file__).parent.absolute()
 DEBUG_BINARY_PATH = DIR / ".." / ".." / ".." / "target" / "debug" / "ripgzip"
@@ -14,12 +19,29 @@ CORRUPTED_TESTS_PATH = DIR / "data" / "corrupted"
 DUMP_PATH = DIR / "dump.gz"
 
 
-def decompress_file_ripgzip(data, debug=False):
+def decompress_file_ripgzip(data, debug=False, args=()):
     path = DEBUG_BINARY_PATH if debug else RELEASE_BINARY_PATH
-    proc = subprocess.run([path, "-d"], input=data, capture_output=True, check=True)
+    proc = subprocess.run(
+        [path, "-d", *args], input=data, capture_output=True, check=True
+    )
     return proc.stdout
 
 
+def compress_file_ripgzip(data, level, debug=False, args=()):
+    path = DEBUG_BINARY_PATH if debug else RELEASE_BINARY_PATH
+    proc = subprocess.run(
+        [path, "--level", str(level), *args],
+        input=data,
+        capture_output=True,
+        check=True,
+    )
+    return proc.stdout
+
+
+def run_ripgzip(data, args):
+    return subprocess.run([RELEASE_BINARY_PATH, *args], input=data, capture_output=True)
+
+
 def test_static_cases():
     for file_path in sorted(OK_TESTS_PATH.iterdir()):
         print(f"checking file '{file_path}'")
@@ -71,11 +93,223 @@ def test_big_random_cases():
             raise
 
 
+def test_compression_cases():
+    random.seed(83457345)
+
+    samples = [b"", b"a", (DIR / "src" / "huffman_coding.rs").read_bytes()]
+    for file_path in sorted(OK_TESTS_PATH.iterdir()):
+        with open(file_path, "rb") as f:
+            samples.append(gzip.decompress(f.read()))
+    samples.append(bytes(random.randrange(4) for _ in range(100000)))
+
+    for i, data in enumerate(samples):
+        for level, threads in [(0, 1), (1, 1), (6, 1), (9, 1), (1, 3), (6, 4)]:
+            print(f"testing compression, case #{i + 1}, level {level}, {threads} threads")
+
+            compressed = compress_file_ripgzip(data, level, args=["-p", str(threads)])
+            try:
+                assert gzip.decompress(compressed) == data
+                assert decompress_file_ripgzip(compressed) == data
+            except Exception:
+                with open(DUMP_PATH, "wb") as f:
+                    f.write(compressed)
+                print(f"check failed, wrote problematic data to {DUMP_PATH}")
+                raise
+
+    print("testing bgzf compression")
+    compressed = compress_file_ripgzip(samples[-1], 6, args=["--bgzf"])
+    assert gzip.decompress(compressed) == samples[-1]
+    proc = run_ripgzip(samples[-1], ["--bgzf", "-p", "2"])
+    assert proc.returncode != 0
+    assert proc.stdout == b""
+
+
+def test_container_cases():
+    random.seed(45745234)
+
+    for i in range(20):
+        print(f"testing zlib and raw deflate data, case #{i + 1}")
+
+        data = bytes(random.randrange(16) for _ in range(random.randrange(100000)))
+        level = random.randrange(10)
+        raw = zlib.compressobj(level, wbits=-15)
+        cases = [
+            (zlib.compress(data, level), "zlib"),
+            (raw.compress(data) + raw.flush(), "deflate"),
+        ]
+
+        for compressed, fmt in cases:
+            try:
+                assert decompress_file_ripgzip(compressed) == data
+                assert decompress_file_ripgzip(compressed, args=["--format", fmt]) == data
+            except Exception:
+                with open(DUMP_PATH, "wb") as f:
+                    f.write(compressed)
+                print(f"check failed, wrote problematic data to {DUMP_PATH}")
+                raise
+
+
+def test_inspection_modes():
+    for file_path in sorted(OK_TESTS_PATH.iterdir()):
+        print(f"listing file '{file_path}'")
+
+        data = file_path.read_bytes()
+        proc = run_ripgzip(data, ["-t"])
+        assert proc.returncode == 0, proc.stderr
+
+        proc = run_ripgzip(data, ["-l"])
+        assert proc.returncode == 0, proc.stderr
+        rows = proc.stdout.decode().splitlines()[1:]
+        if len(rows) > 1:
+            assert rows.pop().endswith("(totals)")
+        compressed = sum(int(row.split()[0]) for row in rows)
+        uncompressed = sum(int(row.split()[1]) for row in rows)
+        assert compressed == len(data)
+        assert uncompressed == len(gzip.decompress(data))
+
+        proc = run_ripgzip(data, ["--dump-blocks"])
+        assert proc.returncode == 0, proc.stderr
+        blocks = [json.loads(line) for line in proc.stdout.splitlines()]
+        assert sum(block["uncompressed_size"] for block in blocks) == uncompressed
+        assert sum(block["is_final"] for block in blocks) == len(rows)
+
+        proc = run_ripgzip(data, ["--recover"])
+        assert proc.returncode == 0, proc.stderr
+        assert proc.stdout == gzip.decompress(data)
+
+    for file_path in sorted(CORRUPTED_TESTS_PATH.iterdir()):
+        print(f"testing file '{file_path}'")
+
+        proc = run_ripgzip(file_path.read_bytes(), ["-t", "--format", "gzip"])
+        assert proc.returncode != 0
+        assert proc.stdout == b""
+        assert b"at offset" in proc.stderr, proc.stderr
+
+        proc = run_ripgzip(file_path.read_bytes(), ["--recover"])
+        assert proc.returncode != 0
+        assert b"damaged input bytes" in proc.stderr, proc.stderr
+
+
+def run_in(directory, args, input=None):
+    return subprocess.run(
+        [RELEASE_BINARY_PATH, *args], cwd=directory, input=input, capture_output=True
+    )
+
+
+def test_file_names():
+    mtime = 1617624000
+    data = gzip.decompress((OK_TESTS_PATH / "01-page.gz").read_bytes())
+    with tempfile.TemporaryDirectory() as tmp:
+        source = pathlib.Path(tmp) / "page.html"
+        source.write_bytes(data)
+        os.utime(source, (mtime, mtime))
+
+        print("checking saved name and modification time")
+        proc = run_in(tmp, ["-c", "page.html"])
+        assert proc.returncode == 0, proc.stderr
+        with gzip.GzipFile(fileobj=io.BytesIO(proc.stdout)) as f:
+            assert f.read() == data
+            assert f.mtime == mtime
+        assert b"page.html\0" in proc.stdout[:32]
+
+        print("checking restored name and modification time")
+        compressed = pathlib.Path(tmp) / "renamed.gz"
+        compressed.write_bytes(proc.stdout)
+        source.unlink()
+        proc = run_in(tmp, ["-d", "-N", "renamed.gz"])
+        assert proc.returncode == 0, proc.stderr
+        assert source.read_bytes() == data
+        assert source.stat().st_mtime == mtime
+        assert not compressed.exists()
+
+        proc = run_in(tmp, ["-c", "-n", "page.html"])
+        assert proc.returncode == 0, proc.stderr
+        assert proc.stdout[3] == 0 and proc.stdout[4:8] == bytes(4)
+
+
+def test_file_operations():
+    mtime = 1617624000
+    data = gzip.decompress((OK_TESTS_PATH / "01-page.gz").read_bytes())
+    with tempfile.TemporaryDirectory() as tmp:
+        tmp = pathlib.Path(tmp)
+        source = tmp / "page.html"
+        source.write_bytes(data)
+        source.chmod(0o640)
+        os.utime(source, (mtime, mtime))
+
+        print("checking in-place compression")
+        proc = run_in(tmp, ["page.html"])
+        assert proc.returncode == 0, proc.stderr
+        compressed = tmp / "page.html.gz"
+        assert not source.exists()
+        assert gzip.decompress(compressed.read_bytes()) == data
+        assert compressed.stat().st_mode & 0o777 == 0o640
+        assert compressed.stat().st_mtime == mtime
+
+        print("checking in-place decompression")
+        proc = run_in(tmp, ["-d", "-k", "page.html.gz"])
+        assert proc.returncode == 0, proc.stderr
+        assert compressed.exists()
+        assert source.read_bytes() == data
+        assert source.stat().st_mode & 0o777 == 0o640
+        assert source.stat().st_mtime == mtime
+
+        print("checking existing output files")
+        proc = run_in(tmp, ["-d", "page.html.gz"])
+        assert proc.returncode != 0
+        assert b"-f" in proc.stderr, proc.stderr
+        assert compressed.exists()
+        source.write_bytes(b"stale")
+        proc = run_in(tmp, ["-d", "-f", "page.html.gz"])
+        assert proc.returncode == 0, proc.stderr
+        assert source.read_bytes() == data
+        assert not compressed.exists()
+
+        print("checking unknown suffixes")
+        proc = run_in(tmp, ["-d", "page.html"])
+        assert proc.returncode == 0, proc.stderr
+        assert b"unknown suffix" in proc.stderr
+        proc = run_in(tmp, ["-S", ".z", "page.html"])
+        assert proc.returncode == 0, proc.stderr
+        proc = run_in(tmp, ["-d", "--suffix", ".z", "page.html.z"])
+        assert proc.returncode == 0, proc.stderr
+        assert source.read_bytes() == data
+
+        print("checking recursive mode")
+        nested = tmp / "a" / "b"
+        nested.mkdir(parents=True)
+        for path in [tmp / "a" / "one", nested / "two"]:
+            path.write_bytes(data)
+        proc = run_in(tmp, ["a"])
+        assert proc.returncode == 0, proc.stderr
+        assert b"is a directory" in proc.stderr
+        proc = run_in(tmp, ["-r", "a"])
+        assert proc.returncode == 0, proc.stderr
+        assert sorted(p.name for p in tmp.glob("a/**/*") if p.is_file()) == [
+            "one.gz",
+            "two.gz",
+        ]
+        proc = run_in(tmp, ["-d", "-r", "a", "missing.gz"])
+        assert proc.returncode != 0
+        assert (nested / "two").read_bytes() == data
+
+        print("checking multiple files to stdout")
+        proc = run_in(tmp, ["-c", "page.html", "-", "a/one"], input=data)
+        assert proc.returncode == 0, proc.stderr
+        assert gzip.decompress(proc.stdout) == data * 3
+        assert source.exists()
+
+
 def main():
     bundles = [
         test_static_cases,
         test_small_random_cases,
         test_big_random_cases,
+        test_compression_cases,
+        test_container_cases,
+        test_inspection_modes,
+        test_file_names,
+        test_file_operations,
     ]
 
     if len(sys.argv) > 1: